@@ -0,0 +1,95 @@
+//Two users chatting through their own in process Dwns, run with
+//cargo run --example chat --features test-utils
+use web5_rust::prelude::*;
+use web5_rust::agent::{SharedPermissions, SharesNeedingRefresh};
+use web5_rust::test_utils::{TestNet, TestUser};
+
+use std::collections::BTreeSet;
+
+use uuid::Uuid;
+
+struct Chatter {
+    name: &'static str,
+    user: TestUser,
+    log: RecordPath,
+    //Messages already shown, shares are listed again on every ProcessShares
+    seen: BTreeSet<RecordPath>,
+}
+
+//Posts the message as a child of the senders log and shares it, the first share establishes
+//the DM channel between the two
+async fn post(
+    from: &mut Chatter, to: &Chatter, message: Protocol, text: &str
+) -> Result<(), Error> {
+    let path = from.log.extend(&[Uuid::new_v4()])?;
+    let record = Record::new_typed(path.clone(), message, &text)?;
+    from.user.agent.run::<()>(&mut from.user.cache, scripts::CreatePrivate::new(record, None)).await?;
+    from.user.agent.run::<()>(&mut from.user.cache, scripts::Share::new(path, None, to.user.did.clone())).await
+}
+
+//Reads the DMs and prints every message of the sender not shown yet
+async fn receive(to: &mut Chatter, from: &Chatter) -> Result<(), Error> {
+    to.user.agent.process_commands(&mut to.user.cache, vec![scripts::ScanDM::new()]).await?;
+    let (shares, _) = to.user.agent.run::<(Vec<SharedPermissions>, SharesNeedingRefresh)>(
+        &mut to.user.cache, scripts::ProcessShares::new(from.user.did.clone())
+    ).await?;
+    for share in shares {
+        let record = to.user.agent.run::<Option<Record>>(&mut to.user.cache, scripts::ReadPrivate::shared_from(share, from.user.did.clone())).await?;
+        if let Some(record) = record.filter(|r| to.seen.insert(r.path.clone())) {
+            println!("[{} -> {}] {}", from.name, to.name, serde_json::from_slice::<String>(&record.payload)?);
+        }
+    }
+    Ok(())
+}
+
+pub async fn run() -> Result<(), Error> {
+    let (mut users, _) = TestNet::new().users(2).build().await?;
+    let message = Protocol::new(
+        "ChatMessage", false, PermissionOptions::new(true, true, false, None),
+        Some(r#"{"type": "string"}"#.to_string()), None
+    )?;
+    let chat_log = Protocol::new(
+        "ChatLog", false,
+        PermissionOptions::new(true, true, false, Some(ChannelPermissionOptions::new(true, true))),
+        None, Some(ChannelProtocol::new(Some(vec![&message])))
+    )?;
+
+    let mut chatters = Vec::new();
+    for name in ["alice", "bob"] {
+        let mut user = users.remove(0);
+        let log = RecordPath::new(&[Uuid::new_v4()])?;
+        user.agent.run::<()>(&mut user.cache, scripts::CreatePrivate::new(Record::new(log.clone(), chat_log.clone(), b""), None)).await?;
+        chatters.push(Chatter{name, user, log, seen: BTreeSet::new()});
+    }
+    let mut bob = chatters.remove(1);
+    let mut alice = chatters.remove(0);
+    println!("alice is {}", alice.user.did);
+    println!("bob is {}", bob.user.did);
+
+    let script = [
+        (true, "Hi Bob, did the Dwn come up?"),
+        (false, "It did, I can read you"),
+        (true, "Great, talk later"),
+    ];
+    for (from_alice, text) in script {
+        if from_alice {
+            post(&mut alice, &bob, message.clone(), text).await?;
+            receive(&mut bob, &alice).await?;
+        } else {
+            post(&mut bob, &alice, message.clone(), text).await?;
+            receive(&mut alice, &bob).await?;
+        }
+    }
+
+    //Each log holds the messages its owner sent as channel children
+    for chatter in [&mut alice, &mut bob] {
+        let sent = chatter.user.agent.run::<Vec<Record>>(&mut chatter.user.cache, scripts::Scan::new(chatter.log.clone(), 0)).await?;
+        println!("{} sent {} message(s)", chatter.name, sent.len());
+    }
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    run().await.unwrap()
+}
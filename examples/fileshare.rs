@@ -0,0 +1,46 @@
+//One user sharing a file with another through their own in process Dwns, run with
+//cargo run --example fileshare --features test-utils
+use web5_rust::prelude::*;
+use web5_rust::agent::{SharedPermissions, SharesNeedingRefresh};
+use web5_rust::test_utils::TestNet;
+
+use uuid::Uuid;
+
+pub async fn run() -> Result<(), Error> {
+    let (mut users, _) = TestNet::new().users(2).build().await?;
+    let mut bob = users.remove(1);
+    let mut alice = users.remove(0);
+    println!("alice is {}", alice.did);
+    println!("bob is {}", bob.did);
+
+    //Shares of a file only let the recipient read it
+    let file = Protocol::new(
+        "File", false, PermissionOptions::new(false, true, false, None),
+        Some(r#"{"type": "object", "required": ["name", "contents"]}"#.to_string()), None
+    )?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let contents = serde_json::json!({"name": "notes.txt", "contents": "Milk, eggs and a new Dwn"});
+    alice.agent.run::<()>(&mut alice.cache, scripts::CreatePrivate::new(Record::new_typed(path.clone(), file, &contents)?, None)).await?;
+    println!("alice stored notes.txt at {}", path);
+
+    alice.agent.run::<()>(&mut alice.cache, scripts::Share::new(path, None, bob.did.clone())).await?;
+    println!("alice shared it with bob");
+
+    bob.agent.process_commands(&mut bob.cache, vec![scripts::ScanDM::new()]).await?;
+    let (shares, _) = bob.agent.run::<(Vec<SharedPermissions>, SharesNeedingRefresh)>(
+        &mut bob.cache, scripts::ProcessShares::new(alice.did.clone())
+    ).await?;
+    println!("bob has {} share(s) from alice", shares.len());
+    for share in shares {
+        let record = bob.agent.run::<Option<Record>>(&mut bob.cache, scripts::ReadPrivate::shared_from(share, alice.did.clone())).await?
+            .ok_or(Error::not_found("Shared file"))?;
+        let contents = serde_json::from_slice::<serde_json::Value>(&record.payload)?;
+        println!("bob read {} from {}: {}", contents["name"], record.path, contents["contents"]);
+    }
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    run().await.unwrap()
+}
@@ -3,27 +3,63 @@ use super::error::Error;
 //TODO: remove
 pub use permission::PermissionSet;
 
-mod permission;
+use crate::model::permission;
 pub use permission::{PermissionOptions, ChannelPermissionOptions};
-mod structs;
-pub use structs::{RecordPath, Record};
-mod protocol;
-pub use protocol::{ChannelProtocol, Protocol};
+pub(crate) mod structs;
+pub use structs::{SharedRecordInfo, SharesNeedingRefresh, SharedFilter, ParentPolicy, UsageEntry, UsageGroup, UsageTotal, ValidationIssue, Validators, DEFAULT_BLOCKING_VALIDATION, RecordPath, Record, TypedRecord, MAX_PATH_DEPTH};
+pub use structs::{ConflictStrategy, ConflictStrategies, MergerId, RedactionSpec, EndpointPolicy};
+pub use structs::{OnInvalid, RecordState, MigratorId, DropReason, ReadDiagnostics};
+pub use structs::{ShareGroup, ShareFailures, RecordUpdated, SharedPermissions, AgentKeys};
+pub use structs::{ShareUpgradeRequest, ShareResponse, PendingShareUpgrade, ShareAuditEntry};
+pub use structs::{BlobManifest, BlobChunkRef, BlobChunk};
+pub use structs::{KeyDomain, PathedKey, Placement, Subscribers, CapabilityGrant, CapabilityToken, ChildSlot, ScanPredicate, ScanStop};
+pub use crate::model::protocol::{ChannelProtocol, Protocol, ProtocolLock, ProtocolRegistry, LockFile, LockEntry, SystemProtocols};
 mod traits;
-pub use traits::{Response, TypeDebug};
+mod journal;
+pub use journal::{CommandJournal, JournalEntry};
+#[cfg(feature = "import")]
+mod import;
+#[cfg(feature = "import")]
+pub use import::{FileImporter, ImportedFile, ImportSummary, ImportProgress, ProtocolMap, detect_mime};
+#[cfg(feature = "import")]
+pub use import::{DEFAULT_MAX_FILE_SIZE, DEFAULT_IMPORT_CONCURRENCY};
+mod blob;
+pub use blob::{BlobWriter, BlobReader, DEFAULT_BLOB_CHUNK};
+mod session;
+pub use session::AgentSession;
+mod telemetry;
+pub use telemetry::{Outcome, NoTelemetry, OpStats, TelemetryAggregator, LATENCY_BUCKETS};
+pub use traits::{PayloadValidator, PayloadMerger, PayloadMigrator, AgentTelemetry, Response};
+pub use crate::common::TypeDebug;
 
 pub mod compiler;
 pub mod scripts;
 
-#[cfg(not(feature = "advanced"))]
-mod commands;
+#[cfg(not(feature = "unstable-internals"))]
+#[path = "agent/commands.rs"]
+mod command_impls;
 
-#[cfg(feature = "advanced")]
+//Builds with advanced reached commands here, without the feature the path still resolves but warns
+#[cfg(not(feature = "unstable-internals"))]
+#[deprecated(note = "Commands are unstable internals, enable the unstable-internals feature or use scripts")]
+pub mod commands {
+    pub use super::command_impls::*;
+}
+
+//Protocols lived here before they moved into model
+#[deprecated(note = "Use web5_rust::agent::Protocol or the prelude")]
+pub mod protocol {
+    pub type Protocol = crate::model::protocol::Protocol;
+    pub type ChannelProtocol = crate::model::protocol::ChannelProtocol;
+}
+
+//Commands and the compiler change with the wire format, they are not covered by the prelude
+#[cfg(feature = "unstable-internals")]
 pub mod commands;
 
-pub use compiler::CompilerCache;
+pub use compiler::{CompilerCache, CacheStats, DEFAULT_CACHE_CAPACITY, DEFAULT_MAX_SCAN_BATCH};
 
-#[cfg(feature = "advanced")]
+#[cfg(feature = "unstable-internals")]
 pub mod custom_commands {
     pub use super::traits::Command;
     pub use super::structs::Header;
@@ -31,16 +67,24 @@ pub mod custom_commands {
     pub use super::compiler::CompilerMemory;
 }
 
+//What Agent::send_raw takes and returns, the wire format may change between releases
+#[cfg(feature = "unstable-internals")]
+pub mod raw {
+    pub use crate::dwn::structs::{DwnRequest, DwnResponse, DwnItem, PublicDwnItem, Packet};
+}
+
 use compiler::Compiler;
-use structs::{BoxCommand, PathedKey};
+use structs::{BoxCommand, RngSource, DmMessage};
 
 use crate::ed25519::SecretKey as EdSecretKey;
 
 use crate::dwn::traits::Client;
-use crate::dwn::router::Router;
+use crate::dwn::structs::{PublicLimits, DwnCapabilities, DmCursor, DmPage};
+use crate::dwn::router::{Router, RouterConfig, HealthTable};
 use crate::dwn::json_rpc::JsonRpcClient;
 
 use crate::dids::DidResolver;
+use crate::dids::signing::{Verifier, VerifiedBy};
 use crate::dids::{
     DidKeyPurpose,
     DhtDocument,
@@ -49,10 +93,19 @@ use crate::dids::{
     DidKey,
     Did
 };
+#[cfg(feature = "unstable-internals")]
+use crate::dids::Endpoint;
+
+use simple_crypto::{SecretKey, Hashable};
+use simple_database::KeyValueStore;
 
-use simple_crypto::{SecretKey};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use serde::{Serialize, Deserialize};
+use futures::Stream;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Identity {
@@ -60,6 +113,9 @@ pub struct Identity {
     sig_key: DidKeyPair,
     enc_key: PathedKey,
     com_key: PathedKey,
+    //Com keys replaced by a rotation, still tried when a DM does not open with the com key
+    #[serde(default)]
+    legacy_com_keys: Vec<SecretKey>,
 }
 
 impl Identity {
@@ -86,10 +142,23 @@ impl Identity {
                 sig_key,
                 enc_key: PathedKey::new_root(SecretKey::new()),
                 com_key: PathedKey::new_root(com_key),
+                legacy_com_keys: Vec::new(),
             },
             DhtDocument::default(did_pub, sig_pub, com_pub, service_endpoints)?
         ))
     }
+
+    //Replaces the com key, the returned document has to be published and the old DMs moved over
+    //with commands::RotateComKey using the returned old key
+    pub fn rotate_com_key(&mut self, service_endpoints: Vec<String>) -> Result<(SecretKey, DhtDocument), Error> {
+        let com_key = SecretKey::new();
+        let document = DhtDocument::default(
+            self.did_key.public_key(), self.sig_key.public.public_key.clone(), com_key.public_key(), service_endpoints
+        )?;
+        let old = std::mem::replace(&mut self.com_key, PathedKey::new_root(com_key)).key;
+        self.legacy_com_keys.push(old.clone());
+        Ok((old, document))
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -97,6 +166,8 @@ pub struct AgentKey {
     sig_key: DidKeyPair,
     pub enc_key: PathedKey,
     com_key: PathedKey,
+    #[serde(default)]
+    legacy_com_keys: Vec<SecretKey>,
 }
 
 pub struct Wallet {
@@ -111,12 +182,12 @@ impl Wallet {
     }
 
     pub fn root(&self) -> AgentKey {
-        AgentKey{sig_key: self.identity.sig_key.clone(), enc_key: self.identity.enc_key.clone(), com_key: self.identity.com_key.clone()}
+        AgentKey{sig_key: self.identity.sig_key.clone(), enc_key: self.identity.enc_key.clone(), com_key: self.identity.com_key.clone(), legacy_com_keys: self.identity.legacy_com_keys.clone()}
     }
 
     pub fn get_agent_key(&self, path: RecordPath) -> Result<AgentKey, Error> {
         let enc_key = self.identity.enc_key.derive_path(path.as_slice())?;
-        Ok(AgentKey{sig_key: self.identity.sig_key.clone(), enc_key, com_key: self.identity.com_key.clone()})
+        Ok(AgentKey{sig_key: self.identity.sig_key.clone(), enc_key, com_key: self.identity.com_key.clone(), legacy_com_keys: self.identity.legacy_com_keys.clone()})
     }
 }
 
@@ -124,6 +195,14 @@ impl Wallet {
 pub struct Agent {
     agent_key: AgentKey,
     did_resolver: Box<dyn DidResolver>,
+    validators: Validators,
+    conflicts: ConflictStrategies,
+    protocols: ProtocolRegistry,
+    public_limits: PublicLimits,
+    max_scan_batch: usize,
+    rng: RngSource,
+    journal: Option<CommandJournal>,
+    telemetry: Arc<dyn AgentTelemetry>,
     router: Router,
 }
 
@@ -131,11 +210,40 @@ impl Agent {
     pub async fn new(
         agent_key: AgentKey,
         did_resolver: Box<dyn DidResolver>,
+        router_config: Option<RouterConfig>,
+        protocol_lock: Option<ProtocolLock>,
+    ) -> Result<Self, Error> {
+        let router_config = router_config.unwrap_or_default();
+        let client = Box::new(JsonRpcClient::new(&router_config)?) as Box<dyn Client>;
+        Self::connect(agent_key, did_resolver, client, router_config, protocol_lock).await
+    }
+
+    //Reaches the Dwns through the given client instead of json rpc
+    #[cfg(any(test, feature = "test-utils"))]
+    pub(crate) async fn with_client(
+        agent_key: AgentKey,
+        did_resolver: Box<dyn DidResolver>,
+        client: Box<dyn Client>,
+        protocol_lock: Option<ProtocolLock>,
+    ) -> Result<Self, Error> {
+        Self::connect(agent_key, did_resolver, client, RouterConfig::default(), protocol_lock).await
+    }
+
+    async fn connect(
+        agent_key: AgentKey,
+        did_resolver: Box<dyn DidResolver>,
+        client: Box<dyn Client>,
+        router_config: RouterConfig,
+        protocol_lock: Option<ProtocolLock>,
     ) -> Result<Self, Error> {
-        let client = Box::new(JsonRpcClient{}) as Box<dyn Client>;
-        let router = Router::new(did_resolver.clone(), client);
+        let mut protocols = ProtocolRegistry::new(&SystemProtocols::all());
+        if let Some(lock) = protocol_lock {
+            lock.check()?;
+            lock.protocols.iter().for_each(|p| protocols.register(p));
+        }
+        let router = Router::new(did_resolver.clone(), client).with_config(router_config);
         let path = agent_key.enc_key.path.clone();
-        let agent = Agent{agent_key, did_resolver, router};
+        let agent = Agent{agent_key, did_resolver, validators: Validators::default(), conflicts: ConflictStrategies::default(), protocols, public_limits: PublicLimits::default(), max_scan_batch: DEFAULT_MAX_SCAN_BATCH, rng: RngSource::default(), journal: None, telemetry: Arc::new(NoTelemetry{}), router};
         let mut cache = CompilerCache::default();
         agent.process_commands(
             &mut cache, vec![Box::new(commands::Init::new(vec![path])) as BoxCommand]
@@ -145,7 +253,112 @@ impl Agent {
 
     pub fn tenant(&self) -> &Did {&self.agent_key.sig_key.public.did}
 
-    #[cfg(feature = "advanced")]
+    //Only used to show the name next to the hash in errors and logs
+    pub fn register_protocol(&mut self, protocol: &Protocol) {
+        self.protocols.register(protocol)
+    }
+
+    pub fn protocols(&self) -> &ProtocolRegistry {&self.protocols}
+
+    pub fn register_validator(
+        &mut self, protocol: &Protocol, validator: impl PayloadValidator + 'static, validate_on_read: bool
+    ) -> Result<(), Error> {
+        self.protocols.register(protocol);
+        self.validators.register(protocol, validator, validate_on_read)
+    }
+
+    pub fn register_migrator(&mut self, id: MigratorId, migrator: impl PayloadMigrator + 'static) -> Result<(), Error> {
+        self.validators.register_migrator(id, migrator)
+    }
+
+    pub fn set_on_invalid(&mut self, protocol: &Protocol, policy: OnInvalid) -> Result<(), Error> {
+        self.validators.set_on_invalid(protocol, policy)
+    }
+
+    //Payloads larger than this are validated on the blocking pool instead of the executor
+    pub fn set_validation_threshold(&mut self, bytes: usize) {
+        self.validators.set_blocking_threshold(bytes)
+    }
+
+    pub fn require_signer_purpose(&mut self, protocol: &Protocol, purpose: DidKeyPurpose) {
+        self.validators.require_purpose(protocol, purpose)
+    }
+
+    pub fn register_merger(&mut self, id: MergerId, merger: impl PayloadMerger + 'static) -> Result<(), Error> {
+        self.conflicts.register_merger(id, merger)
+    }
+
+    pub fn set_conflict_strategy(&mut self, protocol: &Protocol, strategy: ConflictStrategy) -> Result<(), Error> {
+        self.protocols.register(protocol);
+        self.conflicts.set(protocol, strategy)
+    }
+
+    //Public records are checked against these before they are sent
+    pub fn set_public_limits(&mut self, limits: PublicLimits) {
+        self.public_limits = limits;
+    }
+
+    pub fn public_limits(&self) -> PublicLimits {self.public_limits}
+
+    //Asks the tenants Dwn what it accepts and checks public records against that from then on
+    pub async fn probe_limits(&mut self, cache: &mut CompilerCache) -> Result<PublicLimits, Error> {
+        let capabilities = *self.process_commands(
+            cache, vec![Box::new(commands::ProbeCapabilities::new()) as BoxCommand]
+        ).await?.remove(0).downcast::<DwnCapabilities>()?;
+        self.public_limits = capabilities.public;
+        Ok(capabilities.public)
+    }
+
+    //Caps how many children a scan reads in parallel
+    pub fn set_max_scan_batch(&mut self, batch: usize) {
+        self.max_scan_batch = batch;
+    }
+
+    pub fn set_journal(&mut self, journal: CommandJournal) {
+        self.journal = Some(journal);
+    }
+
+    //A cache holding the record info flushed by earlier runs of this agent
+    pub async fn load_cache<KVS: KeyValueStore + 'static>(&self, path: PathBuf) -> Result<CompilerCache, Error> {
+        CompilerCache::load::<KVS>(path, &self.agent_key.enc_key.key).await
+    }
+
+    pub fn endpoint_health(&self) -> HealthTable {self.router.health()}
+
+    //Should be called when the document of a did changes, None resets every did
+    pub fn reset_endpoint_health(&self, did: Option<&Did>) {
+        self.router.reset_health(did)
+    }
+
+    //Sends the requests as they are to one endpoint, skipping the compiler. Nothing is cached or
+    //validated and the requests are not ordered against any batch the agent is running, a request
+    //the Dwn rejects comes back as its error response rather than an Err
+    #[cfg(feature = "unstable-internals")]
+    pub async fn send_raw(
+        &self, endpoint: Endpoint, requests: Vec<raw::DwnRequest>
+    ) -> Result<Vec<raw::DwnResponse>, Error> {
+        let ids = requests.iter().map(|_| uuid::Uuid::new_v4()).collect::<Vec<_>>();
+        let batch = ids.iter().copied().zip(requests.into_iter().map(Box::new)).collect();
+        let mut responses = self.router.send(BTreeMap::from([(endpoint.clone(), batch)])).await
+            .remove(&endpoint).unwrap_or_default();
+        ids.into_iter().map(|id| match responses.remove(&id) {
+            Some(response) => response.map_err(Error::arc),
+            None => Err(Error::bad_response("Missing response for raw request"))
+        }).collect()
+    }
+
+    //Ids the agent picks are drawn from the seed so a batch can be replayed, keys stay random
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn with_rng_seed(mut self, seed: u64) -> Self {
+        self.rng = RngSource::seeded(seed);
+        self
+    }
+
+    pub fn set_telemetry(&mut self, telemetry: Arc<dyn AgentTelemetry>) {
+        self.telemetry = telemetry;
+    }
+
+    #[cfg(feature = "unstable-internals")]
     pub fn new_compiler<'a>(&'a self, cache: &'a mut CompilerCache) -> Compiler<'a> {
         self.internal_new_compiler(cache)
     }
@@ -154,20 +367,150 @@ impl Agent {
         Compiler::<'a>::new(
             cache,
             &*self.did_resolver,
-            //&self.protocols,
+            &self.validators,
+            &self.conflicts,
+            &self.protocols,
+            self.public_limits,
+            self.max_scan_batch,
+            &self.rng,
             &self.agent_key.sig_key,
             &self.agent_key.enc_key,
             &self.agent_key.com_key,
+            &self.agent_key.legacy_com_keys,
             &self.router,
+            &*self.telemetry,
             self.tenant().clone()
         )
     }
 
     pub async fn process_commands<'a>(&'a self, cache: &'a mut CompilerCache, commands: Vec<BoxCommand>) -> Result<Vec<Box<dyn Response>>, Error> {
+        let commands = commands.into_iter().enumerate().collect::<Vec<_>>();
+        self.process_commands_keyed(cache, commands).await?.remove(&0)
+        .ok_or(Error::bad_request("No commands provided"))?
+    }
+
+    //Calls made through a session share its cache and the child indexes its creates took
+    pub fn session(&self, cache: Option<CompilerCache>) -> AgentSession<'_> {
+        AgentSession::new(self, cache)
+    }
+
+    //Runs one command and downcasts what the first endpoint answered
+    pub async fn run<R: Response>(&self, cache: &mut CompilerCache, command: BoxCommand) -> Result<R, Error> {
+        Self::first_as(self.process_commands(cache, vec![command]).await?)
+    }
+
+    //Results come back in the order of the commands, the first failure fails the batch
+    pub async fn run_all<R: Response>(&self, cache: &mut CompilerCache, commands: Vec<BoxCommand>) -> Result<Vec<R>, Error> {
+        let commands = commands.into_iter().enumerate().collect::<Vec<_>>();
+        self.process_commands_keyed(cache, commands).await?.into_values().map(|responses|
+            Self::first_as(responses?)
+        ).collect()
+    }
+
+    fn first_as<R: Response>(responses: Vec<Box<dyn Response>>) -> Result<R, Error> {
+        let expected = std::any::type_name::<R>();
+        let response = responses.into_iter().next()
+            .ok_or(Error::bad_response(&format!("Expected {} got no response", expected)))?;
+        response.downcast::<R>().map(|r| *r).map_err(|r|
+            Error::bad_response(&format!("Expected {} got {}", expected, (*r).get_full_type()))
+        )
+    }
+
+    //Keys must be unique, the batch is rejected before any processing if they are not
+    pub async fn process_commands_keyed<'a, K: Ord + Clone>(
+        &'a self, cache: &'a mut CompilerCache, commands: Vec<(K, BoxCommand)>
+    ) -> Result<BTreeMap<K, Result<Vec<Box<dyn Response>>, Error>>, Error> {
+        let mut keys = Vec::new();
+        for (key, _) in &commands {
+            if keys.contains(key) {return Err(Error::bad_request("Duplicate command key"));}
+            keys.push(key.clone());
+        }
         let mut comp = self.internal_new_compiler(cache);
-        for command in commands.into_iter() {
+        for (_, command) in commands.into_iter() {
             comp.add_command(command, None).await?;
         }
-        Ok(comp.compile().await.remove(0))
+        Ok(keys.into_iter().zip(comp.compile().await).collect())
+    }
+
+    //The batch is journaled under the token before dispatch and marked complete once it succeeds,
+    //a failed batch may have been partly applied so it stays in doubt for recover
+    pub async fn process_commands_journaled<'a>(
+        &'a self, cache: &'a mut CompilerCache, token: &str, commands: Vec<BoxCommand>
+    ) -> Result<Vec<Box<dyn Response>>, Error> {
+        let journal = self.journal.as_ref().ok_or(Error::bad_request("No journal set"))?;
+        journal.dispatch(token, &commands).await?;
+        let responses = self.process_commands(cache, commands).await?;
+        journal.complete(token).await?;
+        Ok(responses)
+    }
+
+    pub async fn acknowledge(&self, token: &str) -> Result<(), Error> {
+        self.journal.as_ref().ok_or(Error::bad_request("No journal set"))?.acknowledge(token).await
+    }
+
+    //Batches that were dispatched but never completed, batches whose records are all
+    //present on the Dwn unchanged are resolved and pruned instead of being reported
+    pub async fn recover(&self) -> Result<Vec<JournalEntry>, Error> {
+        let journal = self.journal.as_ref().ok_or(Error::bad_request("No journal set"))?;
+        let mut in_doubt = Vec::new();
+        for entry in journal.in_doubt().await? {
+            if let Some(records) = &entry.records {
+                let mut cache = CompilerCache::default();
+                let mut resolved = true;
+                for record in records {
+                    let stored = self.process_commands(&mut cache, vec![
+                        scripts::ReadPrivate::new(record.path.clone())
+                    ]).await.and_then(|mut r| Ok(*r.remove(0).downcast::<Option<Record>>()?));
+                    if !matches!(stored, Ok(Some(stored)) if stored.hash() == record.hash()) {
+                        resolved = false;
+                        break;
+                    }
+                }
+                if resolved {
+                    journal.acknowledge(&entry.token).await?;
+                    continue;
+                }
+            }
+            in_doubt.push(entry);
+        }
+        Ok(in_doubt)
+    }
+
+    //DMs as they reach the Dwn, each page along with the cursor to start from after a reconnect.
+    //Failures are yielded and the next round starts from the same cursor so nothing is dropped.
+    //The pause follows rounds that came back empty or failed
+    pub fn watch_dms(
+        &self, cursor: DmCursor, pause: Duration
+    ) -> impl Stream<Item = Result<(Vec<(Verifier, DmMessage)>, DmCursor), Error>> + '_ {
+        futures::stream::unfold((cursor, None::<CompilerCache>), move |(mut cursor, cache)| async move {
+            let mut cache = match cache {
+                Some(cache) => cache,
+                None => {
+                    let mut cache = CompilerCache::default();
+                    let _ = self.process_commands(&mut cache, vec![scripts::ProbeCapabilities::new()]).await;
+                    cache
+                }
+            };
+            loop {
+                let watch = self.run::<(Vec<(VerifiedBy, DmMessage)>, DmPage)>(
+                    &mut cache, Box::new(commands::WatchDM::new(cursor.clone()))
+                ).await;
+                match watch {
+                    Ok((messages, page)) if !messages.is_empty() => {
+                        let messages = messages.into_iter().map(|(s, m)| (Verifier::from(s), m)).collect();
+                        return Some((Ok((messages, page.next.clone())), (page.next, Some(cache))));
+                    },
+                    //Items that could not be read still move the cursor on
+                    Ok((_, page)) => {
+                        cursor = page.next;
+                        tokio::time::sleep(pause).await;
+                    },
+                    Err(error) => {
+                        tokio::time::sleep(pause).await;
+                        return Some((Err(error), (cursor, Some(cache))));
+                    }
+                }
+            }
+        })
     }
 }
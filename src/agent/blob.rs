@@ -0,0 +1,116 @@
+use super::Error;
+
+use super::structs::{BlobManifest, BlobChunk, Record, RecordPath};
+use crate::model::protocol::SystemProtocols;
+use super::compiler::CompilerCache;
+use super::scripts::{CreatePrivate, UpdatePrivate, ReadBlob};
+use super::Agent;
+
+//Each chunk is one record, base64 in the payload makes it about a third bigger on the Dwn
+pub const DEFAULT_BLOB_CHUNK: usize = 1024 * 1024;
+
+//Writes a payload too big for one record as a blob, a root record with the manifest and a child per chunk.
+//At most one chunk is buffered, each is sent once it is full
+pub struct BlobWriter<'a> {
+    agent: &'a Agent,
+    cache: CompilerCache,
+    path: RecordPath,
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    manifest: BlobManifest
+}
+
+impl<'a> BlobWriter<'a> {
+    //The root is created up front with an incomplete manifest, so the chunks have a parent
+    pub async fn create(agent: &'a Agent, path: RecordPath) -> Result<Self, Error> {
+        let mut cache = CompilerCache::default();
+        let manifest = BlobManifest::default();
+        let record = Record::new(path.clone(), SystemProtocols::blob(), &serde_json::to_vec(&manifest)?);
+        agent.run::<()>(&mut cache, CreatePrivate::new(record, None)).await?;
+        Ok(BlobWriter{agent, cache, path, chunk_size: DEFAULT_BLOB_CHUNK, buffer: Vec::new(), manifest})
+    }
+
+    pub fn with_chunk_size(mut self, bytes: usize) -> Self {
+        self.chunk_size = bytes.max(1);
+        self
+    }
+
+    pub async fn write(&mut self, mut bytes: &[u8]) -> Result<(), Error> {
+        while !bytes.is_empty() {
+            let take = (self.chunk_size - self.buffer.len()).min(bytes.len());
+            self.buffer.extend_from_slice(&bytes[..take]);
+            bytes = &bytes[take..];
+            if self.buffer.len() == self.chunk_size {self.flush().await?;}
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        if self.buffer.is_empty() {return Ok(());}
+        let (record, chunk) = BlobChunk::into_record(&self.buffer, &self.path)?;
+        self.agent.run::<()>(&mut self.cache, CreatePrivate::new(record, None)).await?;
+        self.manifest.size += self.buffer.len() as u64;
+        self.manifest.chunks.push(chunk);
+        self.buffer.clear();
+        Ok(())
+    }
+
+    //Sends what is left and marks the manifest complete, a blob that is never finished can not be read
+    pub async fn finish(mut self) -> Result<BlobManifest, Error> {
+        self.flush().await?;
+        self.manifest.complete = true;
+        let record = Record::new(self.path.clone(), SystemProtocols::blob(), &serde_json::to_vec(&self.manifest)?);
+        self.agent.run::<()>(&mut self.cache, UpdatePrivate::new(record, None)).await?;
+        Ok(self.manifest)
+    }
+}
+
+//Reads a blob a chunk at a time, each chunk is checked against the manifest before it is handed out
+pub struct BlobReader<'a> {
+    agent: &'a Agent,
+    cache: CompilerCache,
+    path: RecordPath,
+    manifest: BlobManifest,
+    next: usize,
+    buffer: Vec<u8>,
+    offset: usize
+}
+
+impl<'a> BlobReader<'a> {
+    pub async fn open(agent: &'a Agent, path: RecordPath) -> Result<Self, Error> {
+        let mut cache = CompilerCache::default();
+        let manifest = agent.run::<Option<BlobManifest>>(&mut cache, ReadBlob::manifest(path.clone())).await?
+            .ok_or(Error::not_found(&format!("No blob at {}", path)))?;
+        if !manifest.complete {
+            return Err(Error::bad_request(&format!("Blob at {} is still being written", path)));
+        }
+        Ok(BlobReader{agent, cache, path, manifest, next: 0, buffer: Vec::new(), offset: 0})
+    }
+
+    pub fn manifest(&self) -> &BlobManifest {&self.manifest}
+
+    //None once every chunk was read
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let Some(chunk) = self.manifest.chunks.get(self.next).cloned() else {return Ok(None)};
+        let bytes = self.agent.run::<Vec<u8>>(&mut self.cache, ReadBlob::chunk(self.path.clone(), chunk)).await?;
+        self.next += 1;
+        Ok(Some(bytes))
+    }
+
+    //Fills as much of buf as the current chunk has left, 0 at the end of the blob
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if self.offset == self.buffer.len() {
+            match self.next_chunk().await? {
+                Some(chunk) => {
+                    self.buffer = chunk;
+                    self.offset = 0;
+                },
+                None => return Ok(0)
+            }
+        }
+        let read = (self.buffer.len() - self.offset).min(buf.len());
+        buf[..read].copy_from_slice(&self.buffer[self.offset..self.offset+read]);
+        self.offset += read;
+        Ok(read)
+    }
+}
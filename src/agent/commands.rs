@@ -2,12 +2,40 @@ use super::Error;
 
 use super::compiler::{CompilerMemory, CompilerCache};
 use super::permission::{PermissionOptions, PermissionSet};
-use super::protocol::{SystemProtocols, Protocol};
-use super::traits::{Response, Command, TypeDebug};
+use crate::model::protocol::{SystemProtocols, Protocol};
+use super::traits::{Response, Command};
+use crate::common::TypeDebug;
 use super::structs::{
     MutableAgentRequest,
     PrivateRecord,
+    AgentKeys,
     AgentRequest,
+    ChildSlot,
+    SharesNeedingRefresh,
+    SharedPermissions,
+    SharedRecordInfo,
+    ShareEnvelope,
+    SharedPointer,
+    SharedFilter,
+    RecordUpdated,
+    DmMessage,
+    DropReason,
+    ReadDiagnostics,
+    PendingShareUpgrade,
+    ShareAuditEntry,
+    RedactedView,
+    Placement,
+    CapabilityGrant,
+    CapabilityToken,
+    ConflictStrategy,
+    ParentPolicy,
+    EndpointPolicy,
+    ScanPredicate,
+    ScanStop,
+    UsageGroup,
+    BlobManifest,
+    BlobChunkRef,
+    BlobChunk,
     RecordPath,
     RecordInfo,
     BoxCommand,
@@ -19,15 +47,19 @@ use super::structs::{
     Task,
 };
 
-use crate::dids::signing::{SignedObject, Verifier, Signer};
-use crate::dids::Did;
-use crate::dwn::structs::{PublicRecord, DwnResponse, DwnItem};
+use crate::dids::signing::{SignedObject, VerifiedBy, Verifier, Signer};
+use crate::dids::{Did, Endpoint};
+use crate::common::{SortPaging, FilterExpr, Convert, Compression};
+use crate::dwn::structs::{PublicRecord, PublicDwnItem, DwnResponse, DwnItem, DmCursor, DmPage, Receipt};
+use crate::dwn::structs::{FEATURE_SUBSCRIBE_DM, FEATURE_COUNT};
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Duration;
 
 use simple_database::database::{IndexBuilder, SortOptions, Filters, Filter};
 use simple_database::Indexable;
-use simple_crypto::{Hashable, SecretKey, PublicKey};
+use simple_crypto::{Hashable, SecretKey};
 use chrono::{DateTime, Utc};
 use serde::Serialize;
 use uuid::Uuid;
@@ -51,24 +83,46 @@ impl Hashable for Complete {}
 
 #[derive(Serialize, Debug, Clone)]
 pub struct EnsureEmpty {
-    responses: Responses
+    responses: Responses,
+    receipts: bool
 }
 
 impl EnsureEmpty {
     pub fn new(responses: Responses) -> Self {
-        EnsureEmpty{responses}
+        EnsureEmpty{responses, receipts: false}
+    }
+
+    //Completes with the receipts given instead of ()
+    pub fn with_receipts(responses: Responses) -> Self {
+        EnsureEmpty{responses, receipts: true}
     }
 
     pub fn is_empty(responses: Responses) -> Result<(), Error> {
+        Self::receipts(responses).map(|_| ())
+    }
+
+    //Every failure is kept, one per response, so a command sent to several endpoints shows which ones failed
+    pub fn receipts(responses: Responses) -> Result<Vec<SignedObject<Receipt>>, Error> {
+        let mut receipts = Vec::new();
+        let mut errors = Vec::new();
         for response in responses {
-            match response {
-                response if response.downcast_ref::<()>().is_some() => {},
+            let result = match response {
+                response if response.downcast_ref::<()>().is_some() => Ok(Vec::new()),
+                response if response.downcast_ref::<Arc<Error>>().is_some() => {
+                    errors.push(response.downcast::<Arc<Error>>()?);
+                    continue;
+                },
                 response if response.downcast_ref::<DwnResponse>().is_some() =>
-                    (*response.downcast::<DwnResponse>()?).into_empty()?,
-                _ => Self::is_empty(*response.downcast::<Responses>()?)?
+                    (*response.downcast::<DwnResponse>()?).into_receipt().map(|r| r.into_iter().collect()),
+                _ => Self::receipts(*response.downcast::<Responses>()?)
+            };
+            match result {
+                Ok(r) => receipts.extend(r),
+                Err(e) => errors.push(Box::new(Arc::new(e)))
             }
         }
-        Ok(())
+        if !errors.is_empty() {return Err(Error::multi(errors));}
+        Ok(receipts)
     }
 }
 
@@ -78,7 +132,8 @@ impl Command for EnsureEmpty {
         self: Box<Self>, uuid: Uuid, _: Header,
         _: &mut CompilerMemory, _: &mut CompilerCache
     ) -> Result<Tasks, Error> {
-        Task::completed(uuid, Self::is_empty(self.responses)?)
+        let receipts = Self::receipts(self.responses)?;
+        if self.receipts {Task::completed(uuid, receipts)} else {Task::completed(uuid, ())}
     }
 }
 impl Hashable for EnsureEmpty {}
@@ -87,7 +142,36 @@ impl Hashable for EnsureEmpty {}
 pub enum CreatePrivate {
     #[allow(non_camel_case_types)]
     new(Record, Option<PermissionOptions>),
-    Create(Responses, Record, Option<PermissionOptions>),
+    #[allow(non_camel_case_types)]
+    with_policy(Record, Option<PermissionOptions>, ParentPolicy),
+    Parent(Responses, Record, Option<PermissionOptions>),
+    Ancestor(Responses, Record, Option<PermissionOptions>),
+    Created(Responses, Record, Option<PermissionOptions>),
+    Create(Responses, Record, Option<PermissionOptions>, ParentPolicy),
+}
+
+impl CreatePrivate {
+    fn start(
+        uuid: Uuid, header: Header, record: Record,
+        p_opts: Option<PermissionOptions>, policy: ParentPolicy
+    ) -> Result<Tasks, Error> {
+        println!("Start Create");
+        record.path.check_writable("create")?;
+        let parent_path = record.path.parent()?;
+        let path = record.path.clone();
+        let parent: Option<BoxCommand> = match policy {
+            ParentPolicy::Require => Some(Box::new(ReadInfo::new(parent_path, PermissionOptions::create_child()))),
+            ParentPolicy::CreateMissing => Some(Box::new(ReadPrivate::path(parent_path))),
+            ParentPolicy::Skip => None
+        };
+        let callback = move |r: Responses| match policy {
+            ParentPolicy::CreateMissing => Self::Parent(r, record, p_opts),
+            policy => Self::Create(r, record, p_opts, policy)
+        };
+        let mut tasks = vec![Task::ready(header.clone(), ReadPrivate::path(path))];
+        tasks.extend(parent.map(|command| Task::Ready(header.clone(), command)));
+        Task::waiting(uuid, header, Callback::new(callback), tasks)
+    }
 }
 
 #[async_trait::async_trait]
@@ -97,46 +181,93 @@ impl Command for CreatePrivate {
         memory: &mut CompilerMemory, cache: &mut CompilerCache
     ) -> Result<Tasks, Error> {
         match *self {
-            Self::new(record, p_opts) => {
-                println!("Start Create");
-                let parent_path = record.path.parent()?;
-                let path = record.path.clone();
-                let callback = move |r: Responses| {Self::Create(r, record, p_opts)};
+            Self::new(record, p_opts) =>
+                Self::start(uuid, header, record, p_opts, ParentPolicy::Require),
+            Self::with_policy(record, p_opts, policy) =>
+                Self::start(uuid, header, record, p_opts, policy),
+            Self::Parent(mut results, record, p_opts) => {
+                match results[1].downcast_ref::<(Option<Box<PrivateRecord>>, bool)>() {
+                    Some((Some(_), _)) => {
+                        results.remove(1);
+                        Task::next(uuid, header, Self::Create(results, record, p_opts, ParentPolicy::Require))
+                    },
+                    _ => {
+                        //Only our own records can be replicated, the ancestor is read from the tenants dwns
+                        let parent_path = record.path.parent()?;
+                        let callback = move |r: Responses| {Self::Ancestor(r, record, p_opts)};
+                        Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                            Task::ready(header, Send::new(
+                                ReadPrivate::path(parent_path), vec![memory.tenant().clone()]
+                            ))
+                        ])
+                    }
+                }
+            },
+            Self::Ancestor(mut results, record, p_opts) => {
+                let parent = results.remove(0).downcast::<Responses>()?.into_iter().find_map(|r|
+                    r.downcast::<(Option<Box<PrivateRecord>>, bool)>().ok().and_then(|r| r.0)
+                ).ok_or(Error::not_found("Parent record on tenant endpoints"))?;
+                let callback = move |r: Responses| {Self::Created(r, record, p_opts)};
                 Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
-                    Task::ready(header.clone(), ReadPrivate::path(path)),
-                    Task::ready(header, ReadInfo::new(parent_path, PermissionOptions::create_child())),
+                    Task::ready(header, Self::with_policy(
+                        parent.into_record(), None, ParentPolicy::CreateMissing
+                    ))
                 ])
             },
-            Self::Create(mut results, record, p_opts) => {
+            Self::Created(mut results, record, p_opts) => {
+                //Anything but the unit a create completes with is a parent that was not written
+                let result = results.remove(0);
+                if result.downcast_ref::<()>().is_none() {
+                    let reason = result.downcast_ref::<&'static str>().copied().unwrap_or("Unexpected response");
+                    return Err(Error::bad_response(&format!("Could not create parent: {}", reason)));
+                }
+                Self::start(uuid, header, record, p_opts, ParentPolicy::Require)
+            },
+            Self::Create(mut results, record, p_opts, policy) => {
                 match *results.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()? {
                     (Some(precord), true) if precord.clone().into_record().hash() == record.hash() => {
                         return Task::completed(uuid, ());
                     },
+                    //Not a conflict an application can resolve by retrying, see ReplaceRecordProtocol
+                    (Some(precord), true) if precord.protocol != record.protocol => {
+                        return Err(Error::protocol_mismatch(
+                            &record.path.to_string(), &record.protocol.label(), &precord.protocol.label()
+                        ));
+                    },
                     (_, true) => {return Task::completed(uuid, "Conflict");},
                     _ => {
+                        memory.validate_payload(&record.protocol, &record.payload, false).await?;
                         let perms = memory.get_perms(header.enc, &record.path, Some(&record.protocol))?;
                         let min_perms = record.protocol.subset_permission(perms.clone(), None)?;
                         let req = MutableAgentRequest::create_private(
-                            perms.clone(), p_opts.as_ref(), record.protocol.clone(), record.payload
+                            perms.clone(), p_opts.as_ref(), record.protocol.clone(), record.payload, record.expires
                         )?;
 
-                        cache.record_info.insert(
+                        cache.insert_info(
                             (header.endpoint.clone(), header.enc, record.path.clone()),
                             (record.protocol, perms)
                         );
 
                         println!("Creating Index and Req");
-                        Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
-                            Task::ready(header.clone(), CreatePrivateChild::new(
+                        let mut tasks = vec![Task::MutableRequest(header.clone(), req, header.order)];
+                        if policy != ParentPolicy::Skip {
+                            tasks.insert(0, Task::ready(header.clone(), CreatePrivateChild::new(
                                 record.path.parent()?, Box::new(min_perms)
-                            )),
-                            Task::MutableRequest(header, req, 0)
-                        ])
+                            )));
+                        }
+                        Task::waiting(uuid, header, Callback::new(EnsureEmpty::new), tasks)
                     }
                 }
             }
         }
     }
+
+    fn idempotent_record(&self) -> Option<Record> {
+        match self {
+            Self::new(record, _) | Self::with_policy(record, _, _) => Some(record.clone()),
+            _ => None
+        }
+    }
 }
 impl Hashable for CreatePrivate {}
 
@@ -171,8 +302,8 @@ impl Command for CreatePrivateChild {
                 let index_perms = memory.get_perms(header.enc, &path.index(), None)?;
 
                 let index_key = (header.endpoint.clone(), header.enc, path);
-                let index = memory.create_index.get(&index_key).unwrap()+1;
-                memory.create_index.insert(index_key, index);
+                let index = *memory.create_index.get(&index_key).unwrap();
+                memory.create_index.insert(index_key, index+1);
 
                 let index_req = MutableAgentRequest::update_index(index_perms, index)?;
                 let child_req = MutableAgentRequest::create_private_child(&info.1, &perms, index)?;
@@ -186,34 +317,76 @@ impl Command for CreatePrivateChild {
 }
 impl Hashable for CreatePrivateChild {}
 
+const MAX_MERGE_ATTEMPTS: usize = 3;
+
 #[derive(Serialize, Debug, Clone)]
 pub enum UpdatePrivate {
     #[allow(non_camel_case_types)]
     new(Record, Option<PermissionOptions>),
     UpdateOrCreate(Responses, Record, Option<PermissionOptions>),
+    Guard(Responses, Record, Option<PermissionOptions>, Box<PermissionSet>),
+    Resolve(Responses, Record, Option<PermissionOptions>, Box<PermissionSet>, Vec<u8>, usize),
+}
+
+impl UpdatePrivate {
+    //Sends the update guarded on the stored item, base is the payload that item holds
+    #[allow(clippy::too_many_arguments)]
+    async fn guarded(
+        uuid: Uuid, header: Header, memory: &CompilerMemory<'_>, record: Record, p_opts: Option<PermissionOptions>,
+        perms: Box<PermissionSet>, item: DwnItem, base: Vec<u8>, attempt: usize
+    ) -> Result<Tasks, Error> {
+        memory.validate_payload(&record.protocol, &record.payload, false).await?;
+        let req = MutableAgentRequest::guarded_update_private(
+            (*perms).clone(), p_opts.as_ref(), record.protocol.clone(), record.payload.clone(), record.expires, item.hash_bytes()
+        )?;
+        let order = header.order;
+        let callback = move |r: Responses| {Self::Resolve(r, record, p_opts, perms, base, attempt)};
+        Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+            Task::MutableRequest(header, req, order)
+        ])
+    }
+
+    async fn payload(memory: &CompilerMemory<'_>, perms: &PermissionSet, item: DwnItem) -> Result<Vec<u8>, Error> {
+        let record = ReadPrivate::read_private(memory, perms, &DwnResponse::ReadPrivate(Some(item))).await?.0;
+        Ok(record.ok_or(Error::bad_response("Missing Record"))?.payload)
+    }
 }
 
 #[async_trait::async_trait]
 impl Command for UpdatePrivate {
     async fn process<'a>(
         self: Box<Self>, uuid: Uuid, header: Header,
-        _: &mut CompilerMemory, _: &mut CompilerCache
+        memory: &mut CompilerMemory, _: &mut CompilerCache
     ) -> Result<Tasks, Error> {
         match *self {
             Self::new(record, p_opts) => {
+                record.path.check_writable("update")?;
                 let path = record.path.clone();
                 let callback = move |r: Responses| {Self::UpdateOrCreate(r, record, p_opts)};
-                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                //A record that does not exist yet has no info, so its failure is looked at after the read
+                Task::tolerant(uuid, header.clone(), Callback::new(callback), vec![
                     Task::ready(header.clone(), ReadInfo::new(path.clone(), PermissionOptions::update())),
                     Task::ready(header, ReadPrivate::path(path)),
                 ])
             },
             Self::UpdateOrCreate(mut r, record, p_opts) => {
+                let failed = |r: &dyn Response| r.downcast_ref::<Arc<Error>>().map(|e| Error::arc(e.clone()));
+                if let Some(error) = failed(&*r[1]) {return Err(error);}
                 match *r.remove(1).downcast::<(Option<Box<PrivateRecord>>, bool)>()? {
                     (Some(_), _) => {
+                        if let Some(error) = failed(&*r[0]) {return Err(error);}
                         let perms = r.remove(0).downcast::<RecordInfo>()?.1;
+                        if memory.conflicts.get(&record.protocol) != ConflictStrategy::LastWriterWins {
+                            //The guard needs the stored item itself rather than the decrypted record
+                            let discover = perms.discover();
+                            let callback = move |r: Responses| {Self::Guard(r, record, p_opts, Box::new(perms))};
+                            return Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                                Task::Request(header, AgentRequest::ReadPrivate(discover))
+                            ]);
+                        }
+                        memory.validate_payload(&record.protocol, &record.payload, false).await?;
                         let req = MutableAgentRequest::update_private(
-                            perms, p_opts.as_ref(), record.protocol, record.payload
+                            perms, p_opts.as_ref(), record.protocol, record.payload, record.expires
                         )?;
                         let order = header.order;
                         Task::waiting(uuid, header.clone(),
@@ -223,11 +396,41 @@ impl Command for UpdatePrivate {
                     },
                     (old_record, exists) => {
                         Task::next(uuid, header, CreatePrivate::Create(
-                            vec![Box::new((old_record, exists))], record, p_opts
+                            vec![Box::new((old_record, exists))], record, p_opts, ParentPolicy::Require
                         ))
                     }
                 }
             },
+            Self::Guard(mut r, record, p_opts, perms) => {
+                match *r.remove(0).downcast::<DwnResponse>()? {
+                    DwnResponse::ReadPrivate(Some(item)) => {
+                        let base = Self::payload(memory, &perms, item.clone()).await?;
+                        Self::guarded(uuid, header, memory, record, p_opts, perms, item, base, 0).await
+                    },
+                    DwnResponse::ReadPrivate(None) => Err(Error::not_found("Record removed during update")),
+                    other => Err(Error::bad_response(&format!("Expected ReadPrivate(_) got {:?}", other)))
+                }
+            },
+            Self::Resolve(mut r, mut record, p_opts, perms, base, attempt) => {
+                let (item, context) = match *r.remove(0).downcast::<DwnResponse>()? {
+                    DwnResponse::Conflict(item, context) => (item, context),
+                    other => {
+                        other.into_empty()?;
+                        return Task::completed(uuid, ());
+                    }
+                };
+                match memory.conflicts.get(&record.protocol) {
+                    ConflictStrategy::Merge(id) if attempt < MAX_MERGE_ATTEMPTS => {
+                        let theirs = Self::payload(memory, &perms, item.clone()).await?;
+                        record.payload = memory.conflicts.merge(&id, &base, &record.payload, &theirs)?;
+                        Self::guarded(uuid, header, memory, record, p_opts, perms, item, theirs, attempt+1).await
+                    },
+                    ConflictStrategy::Merge(_) => Err(Error::update_rejected(
+                        &format!("{} after {} merge attempts", context, attempt)
+                    )),
+                    _ => Err(Error::update_rejected(&context.to_string()))
+                }
+            }
         }
     }
 }
@@ -239,12 +442,26 @@ pub enum ReadPrivate {
     path(RecordPath),
     #[allow(non_camel_case_types)]
     new(Box<PermissionSet>, bool),
-    Complete(Responses, Box<PermissionSet>, bool, bool),
+    //Fails with the error of a record that can not be read instead of treating it as missing,
+    //and with a bad response when the record is not of the expected protocol
+    #[allow(non_camel_case_types)]
+    strict(RecordPath, Uuid),
+    Complete(Responses, Box<PermissionSet>, bool, bool, Option<Uuid>),
 }
 
 impl ReadPrivate {
-    fn read_private(
-        perms: &PermissionSet, response: &DwnResponse
+    fn request(
+        uuid: Uuid, header: Header, perms: PermissionSet, resolve: bool, expected: Option<Uuid>
+    ) -> Result<Tasks, Error> {
+        let req = AgentRequest::ReadPrivate(perms.discover());
+        let callback = move |r: Responses| {Self::Complete(r, Box::new(perms), resolve, false, expected)};
+        Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+            Task::Request(header, req)
+        ])
+    }
+
+    async fn read_private(
+        memory: &CompilerMemory<'_>, perms: &PermissionSet, response: &DwnResponse
     ) -> Result<(Option<PrivateRecord>, bool), Error> {
         let discover = perms.discover.public_key();
         let create = perms.create.public_key();
@@ -252,13 +469,16 @@ impl ReadPrivate {
 
         if let DwnResponse::ReadPrivate(item) = response {
             if let Some(item) = item {
-                let dc = read.decrypt(&item.payload)?;
+                let dc = Compression::decode(read.decrypt(&item.payload)?)?;
                 let signed = serde_json::from_slice::<SignedObject<PrivateRecord>>(&dc)?;
                 let mut record = signed.verify_with_key(&create)?;
+                //An expired record the Dwn has not swept yet reads as missing
+                if record.is_expired() {return Ok((None, false));}
                 let perms = record.protocol.trim_permission(perms.clone());
                 let delete = perms.delete.as_ref().map(|d| d.public_key());
                 perms.validate(&record.perms)?;
-                record.protocol.validate_payload(&record.payload)?;
+                let own = memory.is_own(&perms.path, &create);
+                (record.payload, record.state) = memory.validate_read(&record.protocol, &record.payload, own).await?;
                 record.protocol.validate_permission(&record.perms)?;
                 if item.discover != discover || item.delete != delete {
                     return Err(Error::bad_response("Internal and External Key Mismatch"));
@@ -288,33 +508,42 @@ impl Command for ReadPrivate {
                     Task::next(uuid, header, Self::new(Box::new(perms), true))
                 }
             },
-            Self::new(perms, resolve) => {
-                let req = AgentRequest::ReadPrivate(perms.discover());
-                let callback = move |r: Responses| {Self::Complete(r, Box::new(*perms), resolve, false)};
-                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
-                    Task::Request(header, req)
-                ])
-            }
-            Self::Complete(mut results, perms, resolve, exists) => {
+            Self::new(perms, resolve) => Self::request(uuid, header, *perms, resolve, None),
+            Self::strict(path, expected) => {
+                let perms = memory.get_perms(header.enc, &path, None)?;
+                Self::request(uuid, header, perms, true, Some(expected))
+            },
+            Self::Complete(mut results, perms, resolve, exists, expected) => {
                 let res = results.remove(0).downcast::<DwnResponse>()?;
-                let record = if let Ok((record, nexists)) = Self::read_private(&perms, &res) {
+                let read = Self::read_private(memory, &perms, &res).await;
+                let read = if expected.is_some() {Ok(read?)} else {read};
+                let record = if let Ok((record, nexists)) = read {
                     let exists = exists || nexists;
                     if let Some(record) = record {
                         if resolve && record.protocol == SystemProtocols::perm_pointer() {
                             let perms: PermissionSet = serde_json::from_slice(&record.payload)?;
                             let req = AgentRequest::ReadPrivate(perms.discover());
-                            let callback = move |r: Responses| {Self::Complete(r, Box::new(perms), false, exists)};
+                            let callback = move |r: Responses| {Self::Complete(r, Box::new(perms), false, exists, expected)};
                             return Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
                                 Task::Request(header, req)
                             ]);
                         }
-                        cache.record_info.insert(
-                            (header.endpoint.clone(), header.enc, perms.path.clone()),
-                            (record.protocol.clone(), record.perms.clone())
-                        );
+                        if let Some(expected) = expected.filter(|e| *e != record.protocol.uuid()) {
+                            return Err(Error::bad_response(&format!(
+                                "Record at {} has protocol {}, expected {}",
+                                perms.path, record.protocol.uuid(), expected
+                            )));
+                        }
+                        //Pointers are not at a path of their own, their perms carry the root path
+                        if record.protocol != SystemProtocols::perm_pointer() {
+                            let key = (header.endpoint.clone(), header.enc, perms.path.clone());
+                            cache.check_protocol(&key, &record.protocol)?;
+                            cache.insert_info(key, (record.protocol.clone(), record.perms.clone()));
+                        }
                         (Some(Box::new(record)), exists)
                     } else {(None, exists)}
-                } else {(None, exists)};
+                //Stored but unreadable, it still takes up the path
+                } else {(None, exists || matches!(*res, DwnResponse::ReadPrivate(Some(_))))};
                 Task::completed(uuid, record)
             },
         }
@@ -322,6 +551,52 @@ impl Command for ReadPrivate {
 }
 impl Hashable for ReadPrivate {}
 
+//A blob is read a piece at a time, the manifest first and then each chunk it lists.
+//A manifest reads as None when there is no blob at the path
+#[derive(Serialize, Debug, Clone)]
+pub enum ReadBlob {
+    #[allow(non_camel_case_types)]
+    manifest(RecordPath),
+    #[allow(non_camel_case_types)]
+    chunk(RecordPath, BlobChunkRef),
+    Manifest(Responses),
+    Chunk(Responses, BlobChunkRef),
+}
+
+#[async_trait::async_trait]
+impl Command for ReadBlob {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::manifest(path) => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Manifest), vec![
+                    Task::ready(header, ReadPrivate::strict(path, SystemProtocols::blob().uuid()))
+                ])
+            },
+            Self::chunk(path, chunk) => {
+                let path = path.extend(&[chunk.id])?;
+                let callback = move |r: Responses| {Self::Chunk(r, chunk)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, ReadPrivate::strict(path, SystemProtocols::blob_chunk().uuid()))
+                ])
+            },
+            Self::Manifest(mut responses) => {
+                let manifest = responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                    .map(|record| serde_json::from_slice::<BlobManifest>(&record.payload)).transpose()?;
+                Task::completed(uuid, manifest)
+            },
+            Self::Chunk(mut responses, chunk) => {
+                let record = responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                    .ok_or(Error::bad_response(&format!("Blob chunk {} is missing", chunk.id)))?;
+                Task::completed(uuid, BlobChunk::from_record(&record, &chunk)?)
+            }
+        }
+    }
+}
+impl Hashable for ReadBlob {}
+
 #[derive(Serialize, Debug, Clone)]
 pub enum ReadPrivateChild {
     #[allow(non_camel_case_types)]
@@ -353,7 +628,7 @@ impl Command for ReadPrivateChild {
             },
             Self::Complete(mut r, parent_protocol) => {
                 let mut child = *r.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?;
-                child.0.as_mut().filter(|c| parent_protocol.validate_child(&c.protocol.uuid()).is_ok());
+                child.0.as_mut().filter(|c| parent_protocol.validate_child(&c.protocol).is_ok());
                 Task::completed(uuid, child)
             }
         }
@@ -361,6 +636,203 @@ impl Command for ReadPrivateChild {
 }
 impl Hashable for ReadPrivateChild {}
 
+#[derive(Serialize, Debug, Clone)]
+pub enum ReadShared {
+    #[allow(non_camel_case_types)]
+    new(Box<SharedPermissions>),
+    Complete(Responses, Box<SharedPermissions>),
+}
+
+#[async_trait::async_trait]
+impl Command for ReadShared {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(shared) => {
+                let perms = Box::new(shared.perms.clone());
+                let callback = move |r: Responses| {Self::Complete(r, shared)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, ReadPrivate::new(perms, true))
+                ])
+            },
+            Self::Complete(mut responses, shared) => {
+                let record = responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0;
+                if let Some(record) = &record {shared.verify(&record.protocol, memory.protocols)?;}
+                Task::completed(uuid, (record, true))
+            }
+        }
+    }
+}
+impl Hashable for ReadShared {}
+
+//Writes a capability record pointing at a read only subset of the records permissions and
+//completes with a token that lets anyone holding it read the record, see RevokeReadCapability
+#[derive(Serialize, Debug, Clone)]
+pub enum PublishReadCapability {
+    #[allow(non_camel_case_types)]
+    new(RecordPath, PermissionOptions),
+    #[allow(non_camel_case_types)]
+    expiring(RecordPath, PermissionOptions, DateTime<Utc>),
+    Create(Responses, PermissionOptions, Option<DateTime<Utc>>),
+    Complete(Responses, Box<CapabilityToken>),
+}
+
+impl PublishReadCapability {
+    fn read_info(
+        uuid: Uuid, header: Header, path: RecordPath, p_opts: PermissionOptions, expires: Option<DateTime<Utc>>
+    ) -> Result<Tasks, Error> {
+        if p_opts.can_create || p_opts.can_delete || p_opts.channel.as_ref().map(|c| c.can_create).unwrap_or(false) {
+            return Err(Error::bad_request("Capabilities only carry read and discover permissions"));
+        }
+        let read_info = ReadInfo::new(path, p_opts.clone());
+        let callback = move |r: Responses| {Self::Create(r, p_opts, expires)};
+        Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+            Task::ready(header, read_info)
+        ])
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for PublishReadCapability {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(path, p_opts) => Self::read_info(uuid, header, path, p_opts, None),
+            Self::expiring(path, p_opts, expires) => Self::read_info(uuid, header, path, p_opts, Some(expires)),
+            Self::Create(mut responses, p_opts, expires) => {
+                let (_, perms) = *responses.remove(0).downcast::<RecordInfo>()?;
+                let grant = CapabilityGrant::new(perms.subset(&p_opts)?, expires)?;
+                let protocol = SystemProtocols::capability();
+                let path = CapabilityGrant::path_for(memory.uuid());
+                let perms = memory.get_perms(header.enc, &path, Some(&protocol))?;
+                let token = CapabilityToken::new(
+                    memory.tenant().clone(), perms.clone().subset(&PermissionOptions::new(false, true, false, None))?
+                )?;
+                let payload = serde_json::to_vec(&grant)?;
+                memory.validate_payload(&protocol, &payload, false).await?;
+                let req = MutableAgentRequest::create_private(perms, None, protocol, payload, None)?;
+                let callback = move |r: Responses| {Self::Complete(r, Box::new(token))};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::MutableRequest(header, req, 0)
+                ])
+            },
+            Self::Complete(mut responses, token) => {
+                responses.remove(0).downcast::<DwnResponse>()?.into_empty()?;
+                Task::completed(uuid, token.encode()?)
+            }
+        }
+    }
+}
+impl Hashable for PublishReadCapability {}
+
+//Run on the owners Dwn, needs nothing but the token
+#[derive(Serialize, Debug, Clone)]
+pub enum ReadCapability {
+    #[allow(non_camel_case_types)]
+    new(Box<PermissionSet>),
+    Grant(Responses),
+    Complete(Responses),
+}
+
+#[async_trait::async_trait]
+impl Command for ReadCapability {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(perms) => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Grant), vec![
+                    Task::ready(header, ReadPrivate::new(perms, false))
+                ])
+            },
+            Self::Grant(mut responses) => {
+                let record = responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                .ok_or(Error::not_found("Capability revoked or unknown"))?;
+                if record.protocol != SystemProtocols::capability() {
+                    return Err(Error::invalid_auth("Not a capability record"));
+                }
+                let grant = serde_json::from_slice::<CapabilityGrant>(&record.payload)?;
+                grant.check(Utc::now())?;
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Complete), vec![
+                    Task::ready(header, ReadPrivate::new(Box::new(grant.perms), true))
+                ])
+            },
+            Self::Complete(mut responses) => {
+                let record = responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?;
+                Task::completed(uuid, *record)
+            }
+        }
+    }
+}
+impl Hashable for ReadCapability {}
+
+//The grant hands holders the record keys, deleting it alone would leave those working. The record
+//is relocated first so its keys change with its path, then the grant is deleted. Every other
+//capability on the record stops reading it too
+#[derive(Serialize, Debug, Clone)]
+pub enum RevokeReadCapability {
+    #[allow(non_camel_case_types)]
+    new(Box<PermissionSet>, RecordPath),
+    Grant(Responses, Box<PermissionSet>, RecordPath),
+    Relocate(Responses, Box<PermissionSet>, RecordPath, RecordPath),
+    Remove(Responses, Box<PermissionSet>),
+}
+
+#[async_trait::async_trait]
+impl Command for RevokeReadCapability {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(perms, to) => {
+                let read = ReadPrivate::new(perms.clone(), false);
+                let callback = move |r: Responses| {Self::Grant(r, perms, to)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, read)
+                ])
+            },
+            Self::Grant(mut responses, perms, to) => {
+                let record = responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                .ok_or(Error::not_found("Capability revoked or unknown"))?;
+                if record.protocol != SystemProtocols::capability() {
+                    return Err(Error::invalid_auth("Not a capability record"));
+                }
+                let from = serde_json::from_slice::<CapabilityGrant>(&record.payload)?.perms.path;
+                let source = from.clone();
+                let callback = move |r: Responses| {Self::Relocate(r, perms, from, to)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, ReadPrivate::path(source))
+                ])
+            },
+            Self::Relocate(mut responses, perms, from, to) => {
+                let record = responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                .ok_or(Error::not_found("Record of the capability"))?;
+                //A relocation would keep the old copy, still readable with the old keys
+                if !record.protocol.delete {
+                    return Err(Error::bad_request("Capabilities on records that can not be deleted can not be revoked"));
+                }
+                let callback = move |r: Responses| {Self::Remove(r, perms)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, RelocateRecord::new(from, to))
+                ])
+            },
+            Self::Remove(results, perms) => {
+                EnsureEmpty::is_empty(results)?;
+                Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
+                    Task::ready(header, DeletePrivate::new(perms.path, false))
+                ])
+            }
+        }
+    }
+}
+impl Hashable for RevokeReadCapability {}
+
 #[derive(Serialize, Debug, Clone)]
 pub enum ReadInfo {
     #[allow(non_camel_case_types)]
@@ -376,9 +848,9 @@ impl Command for ReadInfo {
     ) -> Result<Tasks, Error> {
         match *self {
             Self::new(path, p_opts) => {
-                match cache.record_info.get(&(header.endpoint.clone(), header.enc, path.clone())) {
+                match cache.get_info(&(header.endpoint.clone(), header.enc, path.clone())) {
                     Some(info) if info.clone().1.subset(&p_opts).is_ok() => {
-                        Task::completed(uuid, info.clone())
+                        Task::completed(uuid, info)
                     },
                     _ => {
                         println!("Reading Info");
@@ -391,10 +863,9 @@ impl Command for ReadInfo {
             Self::Complete(mut results) => {
                 let record = results.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0;
                 if let Some(record) = record {
-                    cache.record_info.insert(
-                        (header.endpoint, header.enc, record.perms.path.clone()),
-                        (record.protocol.clone(), record.perms.clone())
-                    );
+                    let key = (header.endpoint, header.enc, record.perms.path.clone());
+                    cache.check_protocol(&key, &record.protocol)?;
+                    cache.insert_info(key, (record.protocol.clone(), record.perms.clone()));
                     Task::completed(uuid, (record.protocol, record.perms))
                 } else {Err(Error::not_found("Record information"))}
             },
@@ -514,6 +985,30 @@ impl Command for Exists {
 }
 impl Hashable for Exists {}
 
+#[derive(Serialize, Debug, Clone)]
+pub struct ExistsPath {
+    path: RecordPath
+}
+
+impl ExistsPath {
+    pub fn new(path: RecordPath) -> Self {
+        ExistsPath{path}
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for ExistsPath {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        if self.path.is_empty() {return Task::completed(uuid, true);}
+        let discover = memory.get_discover(header.enc, &self.path)?;
+        Task::next(uuid, header, Exists::new(discover))
+    }
+}
+impl Hashable for ExistsPath {}
+
 #[derive(Serialize, Debug, Clone)]
 pub enum ReadIndex {
     #[allow(non_camel_case_types)]
@@ -541,10 +1036,14 @@ impl Command for ReadIndex {
             },
             Self::Complete(mut results) => {
                 let record = results.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0;
-                let payload = record.map(|r|
-                    serde_json::from_slice::<usize>(&r.payload)
-                ).transpose()?;
-                Task::completed(uuid, payload.unwrap_or_default())
+                //A corrupted index falls back to 0 so NextIndex can recover by probing
+                let index = record.map(|r|
+                    serde_json::from_slice::<usize>(&r.payload).unwrap_or_else(|e| {
+                        log::warn!("Ignoring unreadable index at {}: {}", r.perms.path, e);
+                        0
+                    })
+                ).unwrap_or_default();
+                Task::completed(uuid, index)
             }
         }
     }
@@ -554,16 +1053,22 @@ impl Hashable for ReadIndex {}
 #[derive(Serialize, Debug, Clone)]
 pub struct Send {
     command: BoxCommand,
-    recipients: Vec<Did>
+    recipients: Vec<Did>,
+    policy: EndpointPolicy
 }
 
 impl Send {
     #[allow(non_snake_case)]
     pub fn New(command: Box<dyn Command>, recipients: Vec<Did>) -> Self {
-        Send{command, recipients}
+        Send{command, recipients, policy: EndpointPolicy::All}
     }
     pub fn new(command: (impl Command + 'static), recipients: Vec<Did>) -> Self {
-        Send{command: Box::new(command), recipients}
+        Send{command: Box::new(command), recipients, policy: EndpointPolicy::All}
+    }
+
+    pub fn with_policy(mut self, policy: EndpointPolicy) -> Self {
+        self.policy = policy;
+        self
     }
 }
 
@@ -573,22 +1078,94 @@ impl Command for Send {
         self: Box<Self>, uuid: Uuid, header: Header,
         memory: &mut CompilerMemory, _: &mut CompilerCache
     ) -> Result<Tasks, Error> {
-        let endpoints = memory.did_resolver.get_endpoints(&self.recipients).await?;
-        let tasks = endpoints.into_iter().map(|ep| {
+        let on = |ep: Endpoint| {
             let mut header = header.clone();
             header.endpoint = ep;
-            Task::Ready(header, self.command.clone())
-        }).collect::<Vec<_>>();
+            header
+        };
+        let tasks = if self.policy == EndpointPolicy::All {
+            let endpoints = memory.did_resolver.get_endpoints(&self.recipients).await?;
+            endpoints.into_iter().map(|ep| Task::Ready(on(ep), self.command.clone())).collect::<Vec<_>>()
+        } else {
+            let mut tasks = vec![];
+            for recipient in &self.recipients {
+                let mut endpoints = memory.did_resolver.get_ordered_endpoints(recipient).await?;
+                if endpoints.is_empty() {continue;}
+                tasks.push(match self.policy {
+                    EndpointPolicy::Failover => Task::ready(header.clone(), Failover::new(self.command.clone(), endpoints)),
+                    _ => Task::Ready(on(endpoints.remove(0)), self.command.clone())
+                });
+            }
+            tasks
+        };
         Task::waiting(uuid, header.clone(), Callback::new(Complete::new), tasks)
     }
+
+    //The wrapped command is serialized without its type, two commands with the same fields
+    //would otherwise be deduplicated into one
+    fn serialize(&self) -> String {
+        format!(
+            "{}::{}::{:?}::{:?}",
+            (*self).get_full_type(),
+            Command::serialize(&*self.command),
+            self.recipients,
+            self.policy
+        )
+    }
 }
 impl Hashable for Send {}
 
+//Runs the command on the first endpoint that can be reached, any other failure is the result
+#[derive(Serialize, Debug, Clone)]
+pub enum Failover {
+    #[allow(non_camel_case_types)]
+    new(BoxCommand, Vec<Endpoint>),
+    Tried(Responses, BoxCommand, Vec<Endpoint>),
+}
+
+#[async_trait::async_trait]
+impl Command for Failover {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(command, mut endpoints) => {
+                let mut on = header.clone();
+                on.endpoint = endpoints.remove(0);
+                let task = Task::Ready(on, command.clone());
+                let callback = move |r: Responses| {Self::Tried(r, command, endpoints)};
+                Task::tolerant(uuid, header, Callback::new(callback), vec![task])
+            },
+            Self::Tried(responses, command, endpoints) => {
+                let response = responses.into_iter().next().ok_or(Error::bad_response("Missing failover response"))?;
+                if let (Some(error), Some(next)) = (response.downcast_ref::<Arc<Error>>(), endpoints.first()) {
+                    if matches!(error.code(), "TRANSPORT" | "UNREACHABLE") {
+                        log::warn!("{}, failing over to {}", error, next.1);
+                        return Task::next(uuid, header, Self::new(command, endpoints));
+                    }
+                }
+                Ok(vec![(uuid, Task::Completed(response))])
+            }
+        }
+    }
+
+    //Only a new Failover is ever deduplicated, see Send
+    fn serialize(&self) -> String {
+        let (state, command, endpoints) = match self {
+            Self::new(command, endpoints) => ("new", command, endpoints),
+            Self::Tried(_, command, endpoints) => ("Tried", command, endpoints)
+        };
+        format!("{}::{}::{}::{:?}", (*self).get_full_type(), state, Command::serialize(&**command), endpoints)
+    }
+}
+impl Hashable for Failover {}
+
 #[derive(Serialize, Debug, Clone)]
 pub enum CreateDM {
     #[allow(non_camel_case_types)]
-    new(PermissionSet, Did),
-    Request(PermissionSet, Did)
+    new(DmMessage, Did),
+    Request(DmMessage, Did)
 }
 
 #[async_trait::async_trait]
@@ -598,12 +1175,12 @@ impl Command for CreateDM {
         memory: &mut CompilerMemory, _: &mut CompilerCache
     ) -> Result<Tasks, Error> {
         match *self {
-            Self::new(perms, recipient) => {
-                Task::next(uuid, header, Send::new(Self::Request(perms, recipient.clone()), vec![recipient]))
+            Self::new(message, recipient) => {
+                Task::next(uuid, header, Send::new(Self::Request(message, recipient.clone()), vec![recipient]))
             },
-            Self::Request(perms, recipient) => {
+            Self::Request(message, recipient) => {
                 let (_, com_key) = memory.did_resolver.resolve_dwn_keys(&recipient).await?;
-                let req = MutableAgentRequest::create_dm(perms, memory.signer(), com_key)?;
+                let req = MutableAgentRequest::create_dm(memory.uuid(), message, memory.signer(), com_key)?;
                 Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
                     Task::MutableRequest(header, req, 0)
                 ])
@@ -613,32 +1190,62 @@ impl Command for CreateDM {
 }
 impl Hashable for CreateDM {}
 
+//DMs asked for per page
+pub const DM_PAGE_SIZE: usize = 100;
+//Pages a ScanDM reads before leaving the rest for a later compile
+pub const DEFAULT_DM_PAGES: usize = 10;
+
+//Reads one page of DMs from the cursor, the stored one when none is given. Diagnosed reads also
+//return the ReadDiagnostics of the DMs dropped
 #[derive(Serialize, Debug, Clone)]
 pub enum ReadDM {
     #[allow(non_camel_case_types)]
-    new(),
-    Timestamp(Responses),
-    Completed(Responses),
+    new(Option<DmCursor>, usize),
+    #[allow(non_camel_case_types)]
+    diagnosed(Option<DmCursor>, usize),
+    Read(Option<DmCursor>, usize, bool),
+    Cursor(Responses, usize, bool),
+    Completed(Responses, bool),
 }
 
 impl ReadDM {
+    pub fn cursor_path() -> RecordPath {
+        RecordPath::from_segments(&[Uuid::new_v5(&Uuid::NAMESPACE_OID, b"LDC")])
+    }
+
     async fn read_dm<'a>(
         memory: &CompilerMemory<'a>, item: DwnItem
-    ) -> Result<(Verifier, PermissionSet), Error> {
-        let dc = memory.com_decrypt(&item.payload)?;
-        let signed = serde_json::from_slice::<SignedObject<PermissionSet>>(&dc)?;
-        let signer = signed.verify(memory.did_resolver, None).await?;
+    ) -> Result<(VerifiedBy, DmMessage), DropReason> {
+        let dc = memory.com_decrypt(&item.payload).map_err(|_| DropReason::Undecryptable)?;
+        let dc = Compression::decode(dc).map_err(|_| DropReason::Malformed)?;
+        //Share DMs sent before DmMessage carry the bare PermissionSet of a dms channel, signed as such
+        if let Ok(legacy) = serde_json::from_slice::<SignedObject<PermissionSet>>(&dc) {
+            let signer = legacy.verify_by(memory.did_resolver, None).await.map_err(|_| DropReason::BadSignature)?;
+            let shared = SharedPermissions::new(SystemProtocols::dms_channel().uuid(), legacy.unwrap());
+            return Ok((signer, DmMessage::Share(Box::new(shared))));
+        }
+        let signed = serde_json::from_slice::<SignedObject<DmMessage>>(&dc).map_err(|_| DropReason::Malformed)?;
+        let signer = signed.verify_by(memory.did_resolver, None).await.map_err(|_| DropReason::BadSignature)?;
         Ok((signer, signed.unwrap()))
     }
 
     async fn read_dms<'a>(
         memory: &CompilerMemory<'a>, response: DwnResponse
-    ) -> Result<Vec<(Verifier, PermissionSet)>, Error> {
-        if let DwnResponse::ReadDM(items) = response {
-            Ok(futures::future::join_all(items.into_iter().map(|item| async {
-                Self::read_dm(memory, item).await.ok()
-            })).await.into_iter().flatten().collect::<Vec<_>>())
-        } else {Err(Error::bad_response(&format!("Expected ReadPrivate(_) got {:?}", response)))}
+    ) -> Result<(Vec<(VerifiedBy, DmMessage)>, DmPage, ReadDiagnostics), Error> {
+        if let DwnResponse::ReadDM(items, mut page) = response {
+            let mut diagnostics = ReadDiagnostics::default();
+            let stored = std::mem::take(&mut page.stored);
+            let read = futures::future::join_all(items.into_iter().map(|item| async {
+                let fingerprint = Convert::Base64UrlUnpadded.encode(&item.payload.hash_bytes());
+                (fingerprint, Self::read_dm(memory, item).await)
+            })).await.into_iter().enumerate().filter_map(|(i, (fingerprint, dm))|
+                dm.map_err(|reason| diagnostics.record(reason, &fingerprint)).ok().map(|dm| (dm, stored.get(i).copied().flatten()))
+            ).collect::<Vec<_>>();
+            //Stored times stay in line with the DMs that were kept
+            let (read, stored) = read.into_iter().unzip();
+            page.stored = stored;
+            Ok((read, page, diagnostics))
+        } else {Err(Error::bad_response(&format!("Expected ReadDM(_) got {:?}", response)))}
     }
 }
 
@@ -649,265 +1256,1647 @@ impl Command for ReadDM {
         memory: &mut CompilerMemory, _: &mut CompilerCache
     ) -> Result<Tasks, Error> {
         match *self {
-            Self::new() => {
-                let ldc_path = RecordPath::new(&[Uuid::new_v5(&Uuid::NAMESPACE_OID, b"LDC")]);
-                let ldc_perms = memory.get_perms(false, &ldc_path, None)?;
-                Task::waiting(uuid, header.clone(), Callback::new(Self::Timestamp), vec![
-                    Task::ready(header.com(), ReadIndex::new(Box::new(ldc_perms)))
+            Self::new(cursor, limit) => Task::next(uuid, header, Self::Read(cursor, limit, false)),
+            Self::diagnosed(cursor, limit) => Task::next(uuid, header, Self::Read(cursor, limit, true)),
+            Self::Read(Some(cursor), limit, diagnose) => {
+                let callback = move |r: Responses| {Self::Completed(r, diagnose)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::Request(header, AgentRequest::ReadDM(cursor, limit, memory.com_signer()))
                 ])
-
             },
-            Self::Timestamp(mut responses) => {
-                let timestamp = DateTime::<Utc>::from_timestamp(
-                    *responses.remove(0).downcast::<usize>()? as i64, 0
-                ).unwrap();
-                Task::waiting(uuid, header.clone(), Callback::new(Self::Completed), vec![
-                    Task::Request(header, AgentRequest::ReadDM(timestamp, memory.com_signer()))
+            Self::Read(None, limit, diagnose) => {
+                let protocol = SystemProtocols::dm_cursor();
+                let perms = memory.get_perms(false, &Self::cursor_path(), Some(&protocol))?;
+                let callback = move |r: Responses| {Self::Cursor(r, limit, diagnose)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header.com(), ReadPrivate::new(Box::new(perms), false))
                 ])
             },
-            Self::Completed(mut responses) => {
-                let dwn_items = *responses.remove(0).downcast::<DwnResponse>()?;
-                let path = RecordPath::new(&[]).index();
-                let protocol = SystemProtocols::usize();
-                let timestamp = Utc::now().timestamp() as usize;
-                let req = MutableAgentRequest::update_private(
-                    memory.get_perms(false, &path, Some(&protocol))?,
-                    None, protocol, serde_json::to_vec(&timestamp)?
-                )?;
-                Ok(vec![
-                    (uuid, Task::Completed(Box::new(Self::read_dms(memory, dwn_items).await?))),
-                    (Uuid::new_v4(), Task::MutableRequest(header, req, timestamp))
-                ])
-            }
+            Self::Cursor(mut responses, limit, diagnose) => {
+                let record = responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0;
+                let cursor = record.map(|r| r.into_record().decode::<DmCursor>()).transpose()?;
+                Task::next(uuid, header, Self::Read(Some(cursor.unwrap_or_default()), limit, diagnose))
+            },
+            Self::Completed(mut responses, diagnose) => {
+                let response = *responses.remove(0).downcast::<DwnResponse>()?;
+                let (messages, page, diagnostics) = Self::read_dms(memory, response).await?;
+                match diagnose {
+                    true => Task::completed(uuid, (messages, page, diagnostics)),
+                    false => Task::completed(uuid, (messages, page))
+                }
+            }
+        }
+    }
+}
+impl Hashable for ReadDM {}
+
+//One page of DMs after the cursor, waiting on the Dwn until one arrives. Dwns that can not hold
+//the request open are read once. The wait holds up the whole packet so it is best run alone
+#[derive(Serialize, Debug, Clone)]
+pub enum WatchDM {
+    #[allow(non_camel_case_types)]
+    new(DmCursor),
+    Completed(Responses),
+}
+
+#[async_trait::async_trait]
+impl Command for WatchDM {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, cache: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(cursor) => {
+                let request = match cache.lacks(&header.endpoint, FEATURE_SUBSCRIBE_DM) {
+                    true => AgentRequest::ReadDM(cursor, DM_PAGE_SIZE, memory.com_signer()),
+                    false => AgentRequest::SubscribeDM(cursor, DM_PAGE_SIZE, memory.com_signer())
+                };
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Completed), vec![
+                    Task::Request(header, request)
+                ])
+            },
+            Self::Completed(mut responses) => {
+                let response = *responses.remove(0).downcast::<DwnResponse>()?;
+                let (messages, page, _) = ReadDM::read_dms(memory, response).await?;
+                Task::completed(uuid, (messages, page))
+            }
+        }
+    }
+}
+impl Hashable for WatchDM {}
+
+//Removes the DMs the Dwn stored before the instant, which may not be in the future
+#[derive(Serialize, Debug, Clone)]
+pub enum DeleteDM {
+    #[allow(non_camel_case_types)]
+    new(DateTime<Utc>),
+}
+
+#[async_trait::async_trait]
+impl Command for DeleteDM {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(before) => {
+                let req = MutableAgentRequest::DeleteDM(memory.uuid(), before, memory.com_signer());
+                Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
+                    Task::MutableRequest(header, req, 0)
+                ])
+            }
+        }
+    }
+}
+impl Hashable for DeleteDM {}
+
+//Moves the DMs stored for a com key replaced by a rotation over to the current one, still signed by
+//their senders. The stored cursor is reset so the next ScanDM processes them again
+#[derive(Serialize, Debug, Clone)]
+pub enum RotateComKey {
+    #[allow(non_camel_case_types)]
+    new(SecretKey),
+    Read(SecretKey, DmCursor, usize),
+    Resend(Responses, SecretKey, usize),
+    Resent(Responses, SecretKey, DmPage, usize),
+}
+
+#[async_trait::async_trait]
+impl Command for RotateComKey {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(old) => Task::next(uuid, header, Self::Read(old, DmCursor::default(), 0)),
+            Self::Read(old, cursor, moved) => {
+                let request = AgentRequest::ReadDM(cursor, DM_PAGE_SIZE, Signer::Right(old.clone()));
+                let callback = move |r: Responses| {Self::Resend(r, old, moved)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::Request(header, request)
+                ])
+            },
+            Self::Resend(mut responses, old, moved) => {
+                let (items, page) = match *responses.remove(0).downcast::<DwnResponse>()? {
+                    DwnResponse::ReadDM(items, page) => (items, page),
+                    DwnResponse::InvalidAuth(c) => return Err(Error::invalid_auth(&c.to_string())),
+                    other => return Err(Error::bad_response(&format!("Expected ReadDM(_) got {:?}", other)))
+                };
+                //DMs the old key can not open were unreadable before the rotation too
+                let tasks = items.into_iter().filter_map(|item|
+                    serde_json::from_slice::<SignedObject<DmMessage>>(&Compression::decode(old.decrypt(&item.payload).ok()?).ok()?).ok()
+                ).map(|message| Ok(Task::MutableRequest(
+                    header.clone(), MutableAgentRequest::resend_dm(memory.uuid(), message, memory.com_pub())?, 0
+                ))).collect::<Result<Vec<_>, Error>>()?;
+                let moved = moved + tasks.len();
+                let callback = move |r: Responses| {Self::Resent(r, old, page, moved)};
+                Task::waiting(uuid, header, Callback::new(callback), tasks)
+            },
+            Self::Resent(responses, old, page, moved) => {
+                EnsureEmpty::is_empty(responses)?;
+                if page.remaining > 0 {
+                    return Task::next(uuid, header, Self::Read(old, page.next, moved));
+                }
+                let protocol = SystemProtocols::dm_cursor();
+                let req = MutableAgentRequest::update_private(
+                    memory.get_perms(false, &ReadDM::cursor_path(), Some(&protocol))?,
+                    None, protocol, serde_json::to_vec(&DmCursor::default())?, None
+                )?;
+                Ok(vec![
+                    (uuid, Task::Completed(Box::new(moved))),
+                    (memory.uuid(), Task::MutableRequest(header.com(), req, 0))
+                ])
+            }
+        }
+    }
+}
+impl Hashable for RotateComKey {}
+
+//Rough count of the DMs ScanDM has yet to process
+#[derive(Serialize, Debug, Clone)]
+pub enum PendingDMs {
+    #[allow(non_camel_case_types)]
+    new(),
+    Completed(Responses),
+}
+
+#[async_trait::async_trait]
+impl Command for PendingDMs {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new() => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Completed), vec![
+                    Task::ready(header, ReadDM::new(None, 0))
+                ])
+            },
+            Self::Completed(mut responses) => {
+                let (_, page) = *responses.remove(0).downcast::<(Vec<(VerifiedBy, DmMessage)>, DmPage)>()?;
+                Task::completed(uuid, page.remaining)
+            }
+        }
+    }
+}
+impl Hashable for PendingDMs {}
+
+//Asks the Dwn to log access to these records, an empty list stops auditing
+#[derive(Serialize, Debug, Clone)]
+pub enum AuditAccess {
+    #[allow(non_camel_case_types)]
+    new(Vec<RecordPath>),
+}
+
+#[async_trait::async_trait]
+impl Command for AuditAccess {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(paths) => {
+                let discovers = paths.iter().map(|path|
+                    memory.get_discover(header.enc, path)
+                ).collect::<Result<Vec<_>, Error>>()?;
+                let req = MutableAgentRequest::AuditAccess(memory.uuid(), discovers, memory.signer());
+                Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
+                    Task::MutableRequest(header, req, 0)
+                ])
+            }
+        }
+    }
+}
+impl Hashable for AuditAccess {}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum ReadAccessLog {
+    #[allow(non_camel_case_types)]
+    new(DateTime<Utc>),
+    Completed(Responses),
+}
+
+#[async_trait::async_trait]
+impl Command for ReadAccessLog {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(since) => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Completed), vec![
+                    Task::Request(header, AgentRequest::ReadAccessLog(since, memory.signer()))
+                ])
+            },
+            Self::Completed(mut responses) => {
+                match *responses.remove(0).downcast::<DwnResponse>()? {
+                    DwnResponse::ReadAccessLog(entries) => Task::completed(uuid, entries),
+                    DwnResponse::InvalidAuth(c) => Err(Error::invalid_auth(&c.to_string())),
+                    other => Err(Error::bad_response(&format!("Expected ReadAccessLog(_) got {:?}", other)))
+                }
+            }
+        }
+    }
+}
+impl Hashable for ReadAccessLog {}
+
+//Every ScanDM in a compile shares one sync so the DM bookkeeping records are written at most once.
+//Reads page by page up to a number of pages, the cursor is stored once the pages are processed
+#[derive(Serialize, Debug, Clone)]
+pub enum ScanDM {
+    #[allow(non_camel_case_types)]
+    new(),
+    #[allow(non_camel_case_types)]
+    pages(usize),
+    Sync(usize),
+    Scan(Responses, usize, Vec<(Verifier, RecordUpdated)>),
+    Complete(Responses, usize, DmPage, Vec<(Verifier, RecordUpdated)>),
+    Synced(Responses),
+}
+
+#[async_trait::async_trait]
+impl Command for ScanDM {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new() => Task::next(uuid, header, Self::pages(DEFAULT_DM_PAGES)),
+            //The first ScanDM of a compile decides the number of pages
+            Self::pages(pages) => memory.shared(uuid, header, "ScanDM", Self::Sync(pages), Callback::new(Self::Synced)),
+            Self::Sync(pages) => {
+                let callback = move |r: Responses| {Self::Scan(r, pages.saturating_sub(1), Vec::new())};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header.clone(), ReadDM::new(None, DM_PAGE_SIZE))
+                ])
+            },
+            Self::Scan(mut responses, pages, mut updates) => {
+                let (messages, page) = *responses.remove(0).downcast::<(Vec<(VerifiedBy, DmMessage)>, DmPage)>()?;
+                let mut tasks = Vec::new();
+                for (i, (sender, message)) in messages.into_iter().enumerate() {
+                    let sender = Verifier::from(sender);
+                    let received_at = page.stored.get(i).copied().flatten().unwrap_or_else(Utc::now);
+                    match message {
+                        DmMessage::Share(shared) => {
+                            let path = SharedPointer::path(&sender, &shared.perms.path)?;
+                            let pointer = SharedPointer::new(sender, *shared, received_at);
+                            let record = Record::new_typed(path, SystemProtocols::pointer(), &pointer)?;
+                            tasks.push(Task::ready(header.com(), UpdatePrivate::new(record, None)));
+                        },
+                        //Repeated notifications for the same payload are only surfaced once
+                        DmMessage::RecordUpdated(update) => {
+                            if !updates.iter().any(|(s, u): &(Verifier, RecordUpdated)|
+                                *s == sender && u.path == update.path && u.payload == update.payload
+                            ) {updates.push((sender, update));}
+                        },
+                        //The tombstone left in place of the record keeps the reason
+                        DmMessage::Takedown(takedown) => {
+                            log::warn!("Public record {} was taken down by {}: {}", takedown.record, sender, takedown.reason);
+                        },
+                        //Only dids can be shared with so only they can ask or answer
+                        DmMessage::ShareUpgradeRequest(request) => if let Verifier::Left(requester) = sender {
+                            let path = PendingShareUpgrade::path(&requester, &request.path)?;
+                            let pending = PendingShareUpgrade{requester, request, received_at};
+                            let record = Record::new_typed(path, SystemProtocols::share_upgrade(), &pending)?;
+                            tasks.push(Task::ready(header.com(), UpdatePrivate::new(record, None)));
+                        },
+                        DmMessage::ShareResponse(response) => if let Verifier::Left(sharer) = sender {
+                            let entry = ShareAuditEntry::new(sharer, response);
+                            let record = Record::new_typed(entry.record_path()?, SystemProtocols::share_audit(), &entry)?;
+                            tasks.push(Task::ready(header.com(), UpdatePrivate::new(record, None)));
+                        }
+                    }
+                }
+                let callback = move |r: Responses| {Self::Complete(r, pages, page, updates)};
+                Task::waiting(uuid, header, Callback::new(callback), tasks)
+            },
+            Self::Complete(responses, pages, page, updates) => {
+                EnsureEmpty::is_empty(responses)?;
+                if page.remaining > 0 && pages > 0 {
+                    let callback = move |r: Responses| {Self::Scan(r, pages-1, updates)};
+                    return Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                        Task::ready(header, ReadDM::new(Some(page.next), DM_PAGE_SIZE))
+                    ]);
+                }
+                let protocol = SystemProtocols::dm_cursor();
+                let req = MutableAgentRequest::update_private(
+                    memory.get_perms(false, &ReadDM::cursor_path(), Some(&protocol))?,
+                    None, protocol, serde_json::to_vec(&page.next)?, None
+                )?;
+                Ok(vec![
+                    (uuid, Task::Completed(Box::new(updates))),
+                    (memory.uuid(), Task::MutableRequest(header.com(), req, 0))
+                ])
+            },
+            Self::Synced(mut responses) => {
+                Task::completed(uuid, *responses.remove(0).downcast::<Vec<(Verifier, RecordUpdated)>>()?)
+            }
+        }
+    }
+}
+impl Hashable for ScanDM {}
+
+//Walks the channel of every sharer the way ProcessShares does, reading only pointer records and
+//never the shared records. A channel still under its legacy pointer keeps no sharer, it is only
+//found for the sharer the filter names
+#[derive(Serialize, Debug, Clone)]
+pub enum ListShared {
+    #[allow(non_camel_case_types)]
+    new(Option<SharedFilter>),
+    Channels(Responses, Option<SharedFilter>),
+    Complete(Responses, Vec<(Did, Option<DateTime<Utc>>)>, Option<SharedFilter>),
+}
+
+#[async_trait::async_trait]
+impl Command for ListShared {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(filter) => {
+                let callback = move |r: Responses| {Self::Channels(r, filter)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header.com(), Scan::new(RecordPath::root(), 0))
+                ])
+            },
+            Self::Channels(mut responses, filter) => {
+                let protocol = SystemProtocols::pointer();
+                let records = *responses.remove(0).downcast::<Vec<PrivateRecord>>()?;
+                let mut channels: BTreeMap<Did, Option<DateTime<Utc>>> = BTreeMap::new();
+                for record in records.iter().filter(|record| record.protocol == protocol) {
+                    let pointer = serde_json::from_slice::<SharedPointer>(&record.payload).map_err(|e|
+                        Error::bad_response(&format!("Shared pointer at {}: {}", record.perms.path, e))
+                    )?;
+                    //Only dids can be shared with so only they share channels
+                    if let Verifier::Left(sharer) = pointer.sharer {
+                        let received_at = channels.entry(sharer).or_insert(Some(pointer.received_at));
+                        *received_at = (*received_at).min(Some(pointer.received_at));
+                    }
+                }
+                if let Some(SharedFilter::Sharer(sharer)) = &filter {
+                    channels.retain(|did, _| did == sharer);
+                    let legacy = SharedPointer::legacy_path(&Verifier::Left(sharer.clone()));
+                    if channels.is_empty() && records.iter().any(|record| record.perms.path == legacy) {
+                        channels.insert(sharer.clone(), None);
+                    }
+                }
+                if channels.is_empty() {
+                    return Task::completed(uuid, Vec::<SharedRecordInfo>::new());
+                }
+                let channels = channels.into_iter().collect::<Vec<_>>();
+                let tasks = channels.iter().map(|(sharer, _)|
+                    Task::ready(header.clone(), ProcessShares::new(sharer.clone()))
+                ).collect::<Vec<_>>();
+                let callback = move |r: Responses| {Self::Complete(r, channels, filter)};
+                Task::waiting(uuid, header, Callback::new(callback), tasks)
+            },
+            Self::Complete(responses, channels, filter) => {
+                let mut shared = Vec::new();
+                for (response, (sharer, received_at)) in responses.into_iter().zip(channels) {
+                    let (shares, _) = *response.downcast::<(Vec<SharedPermissions>, SharesNeedingRefresh)>()?;
+                    shared.extend(shares.into_iter().map(|share| SharedRecordInfo{
+                        sharer: sharer.clone(),
+                        capabilities: share.perms.options(),
+                        path: share.perms.path,
+                        protocol: share.protocol,
+                        received_at
+                    }).filter(|info| filter.as_ref().map(|f| f.matches(info)).unwrap_or(true)));
+                }
+                Task::completed(uuid, shared)
+            }
+        }
+    }
+}
+impl Hashable for ListShared {}
+
+//Upgrade requests received through ScanDM that are still waiting on an answer
+#[derive(Serialize, Debug, Clone)]
+pub enum ListShareUpgrades {
+    #[allow(non_camel_case_types)]
+    new(),
+    Complete(Responses),
+}
+
+#[async_trait::async_trait]
+impl Command for ListShareUpgrades {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new() => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Complete), vec![
+                    Task::ready(header.com(), Scan::new(RecordPath::root(), 0))
+                ])
+            },
+            Self::Complete(mut responses) => {
+                let protocol = SystemProtocols::share_upgrade();
+                let records = *responses.remove(0).downcast::<Vec<PrivateRecord>>()?;
+                let pending = records.into_iter().filter(|record| record.protocol == protocol)
+                .flat_map(|record| record.into_record().decode::<PendingShareUpgrade>().ok())
+                .collect::<Vec<_>>();
+                Task::completed(uuid, pending)
+            }
+        }
+    }
+}
+impl Hashable for ListShareUpgrades {}
+
+//Every answer to our upgrade requests, oldest first
+#[derive(Serialize, Debug, Clone)]
+pub enum ListShareAudit {
+    #[allow(non_camel_case_types)]
+    new(),
+    Complete(Responses),
+}
+
+#[async_trait::async_trait]
+impl Command for ListShareAudit {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new() => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Complete), vec![
+                    Task::ready(header.com(), Scan::new(RecordPath::root(), 0))
+                ])
+            },
+            Self::Complete(mut responses) => {
+                let protocol = SystemProtocols::share_audit();
+                let records = *responses.remove(0).downcast::<Vec<PrivateRecord>>()?;
+                let mut entries = records.into_iter().filter(|record| record.protocol == protocol)
+                .flat_map(|record| record.into_record().decode::<ShareAuditEntry>().ok())
+                .collect::<Vec<_>>();
+                entries.sort_by_key(|entry| entry.received_at);
+                Task::completed(uuid, entries)
+            }
+        }
+    }
+}
+impl Hashable for ListShareAudit {}
+
+//Deletes a record shared with us, run on the sharers Dwn
+#[derive(Serialize, Debug, Clone)]
+pub struct DeleteShared {
+    shared: Box<SharedPermissions>
+}
+
+impl DeleteShared {
+    pub fn new(shared: Box<SharedPermissions>) -> Self {
+        DeleteShared{shared}
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for DeleteShared {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        if !self.shared.perms.options().can_delete {
+            return Err(Error::invalid_auth("Share does not grant delete"));
+        }
+        let req = MutableAgentRequest::delete_private(&self.shared.perms)?;
+        let order = header.order;
+        Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
+            Task::MutableRequest(header, req, order)
+        ])
+    }
+}
+impl Hashable for DeleteShared {}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum ProbeCapabilities {
+    #[allow(non_camel_case_types)]
+    new(),
+    Completed(Responses),
+}
+
+#[async_trait::async_trait]
+impl Command for ProbeCapabilities {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, cache: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new() => {
+                if let Some(capabilities) = cache.get_capabilities(&header.endpoint) {
+                    return Task::completed(uuid, capabilities.clone());
+                }
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Completed), vec![
+                    Task::Request(header, AgentRequest::Capabilities)
+                ])
+            },
+            Self::Completed(mut responses) => {
+                match *responses.remove(0).downcast::<DwnResponse>()? {
+                    DwnResponse::Capabilities(signed) => {
+                        signed.verify(memory.did_resolver, Some(&Verifier::Left(header.endpoint.0.clone()))).await?;
+                        let capabilities = signed.unwrap();
+                        cache.insert_capabilities(header.endpoint, capabilities.clone());
+                        Task::completed(uuid, capabilities)
+                    },
+                    other => Err(Error::bad_response(&format!("Expected Capabilities(_) got {:?}", other)))
+                }
+            }
+        }
+    }
+}
+impl Hashable for ProbeCapabilities {}
+
+//Hash and name of every protocol the agent knows, for debugging
+#[derive(Serialize, Debug, Clone)]
+pub enum ListProtocols {
+    #[allow(non_camel_case_types)]
+    new(),
+}
+
+#[async_trait::async_trait]
+impl Command for ListProtocols {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, _: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new() => Task::completed(uuid, memory.protocols.dump())
+        }
+    }
+}
+impl Hashable for ListProtocols {}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum EstablishChannel {
+    #[allow(non_camel_case_types)]
+    new(Did),
+    Read(Did),
+    Create(Responses, Did, RecordPath),
+  //ReadCreated(Responses, RecordPath),
+  //Completed(Responses)
+}
+
+#[async_trait::async_trait]
+impl Command for EstablishChannel {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(recipient) => {
+                let callback = move |_: Responses| {Self::Read(recipient)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header.clone(), ScanDM::new())
+                ])
+            },
+            Self::Read(recipient) => {
+                let path = RecordPath::from_segments(&[Uuid::new_v5(
+                    &Uuid::NAMESPACE_OID, recipient.to_string().as_bytes()
+                )]);
+                let tasks = vec![Task::ready(header.com(), ReadPrivate::path(path.clone()))];
+                let callback = move |r: Responses| {Self::Create(r, recipient, path)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), tasks)
+            },
+            Self::Create(mut responses, recipient, path) => {
+                match responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0 {
+                    Some(_) => Task::completed(uuid, ()),
+                    None => {
+                        let protocol = SystemProtocols::dms_channel();
+                        let perms = SharedPermissions::new(
+                            protocol.uuid(), memory.get_perms(false, &path, Some(&protocol))?
+                        );
+                        let channel = Record::new(path.clone(), protocol, &[]);
+                        Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
+                            Task::ready(header.com(), Send::new(
+                                CreatePrivate::new(channel.clone(), None), vec![recipient.clone()]
+                            )),
+                            Task::ready(
+                                header.com(), CreatePrivate::new(channel.clone(), None)
+                            ),
+                            Task::ready(header, CreateDM::new(DmMessage::Share(Box::new(perms)), recipient))
+                        ])
+                    }
+                }
+            },
+        }
+    }
+}
+impl Hashable for EstablishChannel {}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum ReadAgentKeys {
+    #[allow(non_camel_case_types)]
+    new(Did),
+    Complete(Responses, Did),
+}
+
+#[async_trait::async_trait]
+impl Command for ReadAgentKeys {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(did) => {
+                let filters = Filters::new(vec![
+                    ("signer", Filter::equal(did.to_string())),
+                    ("type", Filter::equal("agent_keys".to_string()))
+                ]);
+                let did_copy = did.clone();
+                let callback = move |r: Responses| {Self::Complete(r, did_copy)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, Send::new(ReadPublic::verified(filters.into(), None), vec![did]))
+                ])
+            },
+            Self::Complete(mut responses, did) => {
+                let (signer, record) = responses.remove(0).downcast::<Responses>()?.into_iter().find_map(|response|
+                    response.downcast::<(Vec<(VerifiedBy, PublicRecord)>, Option<Vec<u8>>)>().ok().and_then(|records|
+                        records.0.into_iter().max_by_key(|(_, r)| r.generation())
+                    )
+                ).ok_or(Error::bad_request("Recipient has no active agents"))?;
+                if Verifier::from(signer) != Verifier::Left(did) {
+                    return Err(Error::invalid_auth("Agent keys were not signed by the recipient"));
+                }
+                Task::completed(uuid, AgentKeys::parse(&record.payload)?)
+            }
+        }
+    }
+}
+impl Hashable for ReadAgentKeys {}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum RefreshSharesTo {
+    #[allow(non_camel_case_types)]
+    new(Did),
+    Refresh(Responses, Did),
+}
+
+#[async_trait::async_trait]
+impl Command for RefreshSharesTo {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(recipient) => {
+                let channel_path = RecordPath::from_segments(&[Uuid::new_v5(
+                    &Uuid::NAMESPACE_OID, recipient.to_string().as_bytes()
+                )]);
+                let tasks = vec![
+                    Task::ready(header.com(), Scan::new(channel_path, 0)),
+                    Task::ready(header.clone(), ReadAgentKeys::new(recipient.clone()))
+                ];
+                let callback = move |r: Responses| {Self::Refresh(r, recipient)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), tasks)
+            },
+            Self::Refresh(mut responses, recipient) => {
+                let agent_keys = *responses.remove(1).downcast::<AgentKeys>()?;
+                let records = *responses.remove(0).downcast::<Vec<PrivateRecord>>()?;
+                let protocol = SystemProtocols::shared_pointer();
+                let mut tasks = Vec::new();
+                for record in records.into_iter().filter(|r| r.protocol == protocol) {
+                    let mut record = record.into_record();
+                    let mut envelope = serde_json::from_slice::<ShareEnvelope>(&record.payload)?;
+                    let keys = agent_keys.keys_for(&envelope.path);
+                    let perms = envelope.protocol.subset_permission(
+                        memory.get_perms(header.enc, &envelope.path, Some(&envelope.protocol))?,
+                        envelope.p_opts.as_ref()
+                    )?;
+                    if envelope.seal(keys, &perms)? {
+                        record.payload = serde_json::to_vec(&envelope)?;
+                        tasks.push(Task::ready(header.com(), UpdatePrivate::new(record.clone(), None)));
+                        tasks.push(Task::ready(header.com(), Send::new(
+                            UpdatePrivate::new(record, None), vec![recipient.clone()]
+                        )));
+                    }
+                }
+                Task::waiting(uuid, header, Callback::new(EnsureEmpty::new), tasks)
+            }
+        }
+    }
+}
+impl Hashable for RefreshSharesTo {}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum ProcessShares {
+    #[allow(non_camel_case_types)]
+    new(Did),
+    Pointer(Responses, Did),
+    LegacyPointer(Responses, Did),
+    Scanning(Did, Box<PermissionSet>, Vec<PrivateRecord>, usize, Option<Responses>),
+}
+
+impl ProcessShares {
+    fn open(
+        memory: &CompilerMemory, sharer: Did, records: Vec<PrivateRecord>
+    ) -> (Vec<SharedPermissions>, SharesNeedingRefresh) {
+        let protocol = SystemProtocols::shared_pointer();
+        let mut shares = Vec::new();
+        let mut refresh = SharesNeedingRefresh{sharer, paths: Vec::new()};
+        for record in records.into_iter().filter(|r| r.protocol == protocol) {
+            if let Ok(envelope) = serde_json::from_slice::<ShareEnvelope>(&record.payload) {
+                match envelope.open(memory.agent_key()) {
+                    Some(perms) => shares.push(SharedPermissions::new(envelope.protocol.uuid(), perms)),
+                    None => refresh.paths.push(envelope.path)
+                }
+            }
+        }
+        (shares, refresh)
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for ProcessShares {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(sharer) => {
+                //The sharer keeps our channel under their com tree at the uuid of our did
+                let channel_path = RecordPath::from_segments(&[Uuid::new_v5(
+                    &Uuid::NAMESPACE_OID, memory.tenant().to_string().as_bytes()
+                )]);
+                let path = SharedPointer::path(&Verifier::Left(sharer.clone()), &channel_path)?;
+                let callback = move |r: Responses| {Self::Pointer(r, sharer)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header.com(), ReadPrivate::path(path))
+                ])
+            },
+            Self::Pointer(mut responses, sharer) => {
+                match responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0 {
+                    Some(record) => {
+                        let pointer = serde_json::from_slice::<SharedPointer>(&record.payload)?;
+                        Task::next(uuid, header, Self::Scanning(sharer, Box::new(pointer.perms), vec![], 0, None))
+                    },
+                    //Channels received before pointers moved are still under the old path until rescanned
+                    None => {
+                        let path = SharedPointer::legacy_path(&Verifier::Left(sharer.clone()));
+                        let callback = move |r: Responses| {Self::LegacyPointer(r, sharer)};
+                        Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                            Task::ready(header.com(), ReadPrivate::path(path))
+                        ])
+                    }
+                }
+            },
+            Self::LegacyPointer(mut responses, sharer) => {
+                let record = responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                .ok_or(Error::not_found("Channel with sharer"))?;
+                let perms = serde_json::from_slice::<PermissionSet>(&record.payload)?;
+                Task::next(uuid, header, Self::Scanning(sharer, Box::new(perms), vec![], 0, None))
+            },
+            Self::Scanning(sharer, perms, mut results, index, responses) => {
+                if let Some(responses) = responses {
+                    for response in responses {
+                        match *response.downcast::<(Option<Box<PrivateRecord>>, bool)>()? {
+                            (Some(record), _) => results.push(*record),
+                            (_, true) => {},
+                            (None, _) => {return Task::completed(uuid, Self::open(memory, sharer, results));}
+                        }
+                    }
+                }
+                let batch = memory.scan_batch(index);
+                let requests = (0..batch).map(|i|
+                    Ok(Task::ready(header.com(), ReadPrivate::new(Box::new(perms.pointer(index+i)?), true)))
+                ).collect::<Result<Vec<_>, Error>>()?;
+                let callback = move |r: Responses| {Self::Scanning(sharer, perms, results, batch+index, Some(r))};
+                Task::waiting(uuid, header, Callback::new(callback), requests)
+            }
+        }
+    }
+}
+impl Hashable for ProcessShares {}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum RefreshRedactedViews {
+    #[allow(non_camel_case_types)]
+    new(Option<RecordPath>),
+    Originals(Responses, Option<RecordPath>),
+    Refresh(Responses, Vec<RedactedView>),
+}
+
+impl RefreshRedactedViews {
+    pub fn views(registry: Option<Box<PrivateRecord>>) -> Result<Vec<RedactedView>, Error> {
+        Ok(match registry {
+            Some(record) => record.into_record().decode::<Vec<RedactedView>>()?,
+            None => Vec::new()
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for RefreshRedactedViews {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(original) => {
+                let callback = move |r: Responses| {Self::Originals(r, original)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, ReadPrivate::path(RedactedView::registry_path()))
+                ])
+            },
+            Self::Originals(mut responses, original) => {
+                let registry = responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0;
+                let views = Self::views(registry)?.into_iter().filter(|view|
+                    original.as_ref().map(|o| *o == view.original).unwrap_or(true)
+                ).collect::<Vec<_>>();
+                let tasks = views.iter().map(|view|
+                    Task::ready(header.clone(), ReadPrivate::path(view.original.clone()))
+                ).collect::<Vec<_>>();
+                let callback = move |r: Responses| {Self::Refresh(r, views)};
+                Task::waiting(uuid, header, Callback::new(callback), tasks)
+            },
+            Self::Refresh(responses, views) => {
+                let mut tasks = Vec::new();
+                for (response, view) in responses.into_iter().zip(views) {
+                    match response.downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0 {
+                        Some(record) => tasks.push(Task::ready(header.clone(),
+                            UpdatePrivate::new(view.derive(&record.into_record())?, None)
+                        )),
+                        None => log::warn!("Original {} of redacted view {} is missing", view.original, view.view)
+                    }
+                }
+                Task::waiting(uuid, header, Callback::new(EnsureEmpty::new), tasks)
+            }
+        }
+    }
+}
+impl Hashable for RefreshRedactedViews {}
+
+//Reads children until one is missing, until also stops once the predicate holds and
+//completes with the reason alongside the records. streaming stops after a page of records
+//and completes with the index to resume from, None once a child is missing
+#[derive(Serialize, Debug, Clone)]
+pub enum Scan {
+    #[allow(non_camel_case_types)]
+    new(RecordPath, usize),
+    #[allow(non_camel_case_types)]
+    until(RecordPath, ScanPredicate),
+    #[allow(non_camel_case_types)]
+    streaming(RecordPath, usize, usize),
+    Scanning(RecordPath, Vec<PrivateRecord>, usize, Option<Responses>, Option<ScanPredicate>, Option<usize>),
+}
+
+impl Scan {
+    fn complete(uuid: Uuid, results: Vec<PrivateRecord>, predicate: Option<ScanPredicate>, stop: ScanStop) -> Result<Tasks, Error> {
+        match predicate {
+            Some(_) => Task::completed(uuid, (results, stop)),
+            None => Task::completed(uuid, results)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for Scan {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(path, start) => {
+                Task::next(uuid, header, Self::Scanning(path, vec![], start, None, None, None))
+            },
+            Self::until(path, predicate) => {
+                Task::next(uuid, header, Self::Scanning(path, vec![], 0, None, Some(predicate), None))
+            },
+            Self::streaming(path, start, page) => {
+                Task::next(uuid, header, Self::Scanning(path, vec![], start, None, None, Some(page.max(1))))
+            },
+            Self::Scanning(path, mut results, index, responses, predicate, page) => {
+                if let Some(responses) = responses {
+                    let start = index-responses.len();
+                    for (i, response) in responses.into_iter().enumerate() {
+                        match *response.downcast::<(Option<Box<PrivateRecord>>, bool)>()? {
+                            (Some(record), _) => {
+                                results.push(*record);
+                                if predicate.as_ref().is_some_and(|p| p.matches(&results[results.len()-1], results.len())) {
+                                    return Self::complete(uuid, results, predicate, ScanStop::Matched);
+                                }
+                                if page == Some(results.len()) {
+                                    return Task::completed(uuid, (results, Some(start+i+1)));
+                                }
+                            },
+                            (_, true) => {},
+                            (None, _) if page.is_some() => return Task::completed(uuid, (results, None::<usize>)),
+                            (None, _) => {return Self::complete(uuid, results, predicate, ScanStop::Exhausted);}
+                        }
+                    }
+                }
+                //A page never reads past its end
+                let batch = memory.scan_batch(index).min(page.map(|p| p-results.len()).unwrap_or(usize::MAX));
+                let requests = (0..batch).map(|i| {
+                    println!("Scanning index {}", index+i);
+                    Task::ready(header.clone(), ReadPrivateChild::new(path.clone(), index+i))
+                }).collect::<Vec<_>>();
+
+                let callback = move |r: Responses| {Self::Scanning(path, results, batch+index, Some(r), predicate, page)};
+                Task::waiting(uuid, header, Callback::new(callback), requests)
+            }
+        }
+    }
+}
+impl Hashable for Scan {}
+
+//Like Scan but keeps reading past gaps, stopping once a whole batch comes back Missing.
+//Trailing Missing slots are dropped so any Missing left is a gap behind a written index
+#[derive(Serialize, Debug, Clone)]
+pub enum ScanSlots {
+    #[allow(non_camel_case_types)]
+    new(RecordPath, usize),
+    Scanning(RecordPath, BTreeMap<usize, ChildSlot<PrivateRecord>>, usize, Option<Responses>),
+}
+
+#[async_trait::async_trait]
+impl Command for ScanSlots {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(path, start) => {
+                Task::next(uuid, header, Self::Scanning(path, BTreeMap::new(), start, None))
+            },
+            Self::Scanning(path, mut slots, index, responses) => {
+                if let Some(responses) = responses {
+                    let start = index-responses.len();
+                    for (i, response) in responses.into_iter().enumerate() {
+                        let child = *response.downcast::<(Option<Box<PrivateRecord>>, bool)>()?;
+                        slots.insert(start+i, ChildSlot::from(child));
+                    }
+                    if slots.range(start..).all(|(_, slot)| slot.is_missing()) {
+                        while slots.last_key_value().map(|(_, s)| s.is_missing()).unwrap_or(false) {
+                            slots.pop_last();
+                        }
+                        return Task::completed(uuid, slots);
+                    }
+                }
+                let batch = memory.scan_batch(index);
+                let requests = (0..batch).map(|i|
+                    Task::ready(header.clone(), ReadPrivateChild::new(path.clone(), index+i))
+                ).collect::<Vec<_>>();
+
+                let callback = move |r: Responses| {Self::Scanning(path, slots, batch+index, Some(r))};
+                Task::waiting(uuid, header, Callback::new(callback), requests)
+            }
+        }
+    }
+}
+impl Hashable for ScanSlots {}
+
+const AWAIT_CHILDREN_BACKOFF: Duration = Duration::from_millis(100);
+const AWAIT_CHILDREN_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+//Re-reads Missing indexes with exponential backoff until they show up or the timeout passes,
+//for catching up on a channel after reconnecting. Sleeping holds up the rest of the compiler run
+#[derive(Serialize, Debug, Clone)]
+pub enum AwaitChildren {
+    #[allow(non_camel_case_types)]
+    new(RecordPath, Vec<usize>, Duration),
+    Poll(RecordPath, BTreeMap<usize, ChildSlot<PrivateRecord>>, DateTime<Utc>, Duration),
+    Polled(Responses, RecordPath, BTreeMap<usize, ChildSlot<PrivateRecord>>, DateTime<Utc>, Duration),
+}
+
+#[async_trait::async_trait]
+impl Command for AwaitChildren {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(path, indexes, timeout) => {
+                let deadline = Utc::now() + chrono::Duration::from_std(timeout).map_err(|e| Error::bad_request(&e.to_string()))?;
+                let slots = indexes.into_iter().map(|i| (i, ChildSlot::Missing)).collect();
+                Task::next(uuid, header, Self::Poll(path, slots, deadline, AWAIT_CHILDREN_BACKOFF))
+            },
+            Self::Poll(path, slots, deadline, backoff) => {
+                let missing = slots.iter().filter(|(_, s)| s.is_missing()).map(|(i, _)| *i).collect::<Vec<_>>();
+                if missing.is_empty() {return Task::completed(uuid, slots);}
+                let requests = missing.into_iter().map(|i|
+                    Task::ready(header.clone(), ReadPrivateChild::new(path.clone(), i))
+                ).collect::<Vec<_>>();
+                let callback = move |r: Responses| {Self::Polled(r, path, slots, deadline, backoff)};
+                Task::waiting(uuid, header, Callback::new(callback), requests)
+            },
+            Self::Polled(responses, path, mut slots, deadline, backoff) => {
+                let missing = slots.iter().filter(|(_, s)| s.is_missing()).map(|(i, _)| *i).collect::<Vec<_>>();
+                for (index, response) in missing.into_iter().zip(responses) {
+                    let child = *response.downcast::<(Option<Box<PrivateRecord>>, bool)>()?;
+                    slots.insert(index, ChildSlot::from(child));
+                }
+                let remaining = (deadline - Utc::now()).to_std().unwrap_or_default();
+                if remaining.is_zero() || !slots.values().any(ChildSlot::is_missing) {
+                    return Task::completed(uuid, slots);
+                }
+                tokio::time::sleep(backoff.min(remaining)).await;
+                Task::next(uuid, header, Self::Poll(path, slots, deadline, (backoff*2).min(AWAIT_CHILDREN_MAX_BACKOFF)))
+            }
+        }
+    }
+}
+impl Hashable for AwaitChildren {}
+
+const MAX_BOOTSTRAP_ATTEMPTS: usize = 5;
+
+//Devices bootstrapping at the same time race on the agent_keys record, writes are guarded on its
+//generation and a losing device re-reads and merges its keys into whatever record won
+#[derive(Serialize, Debug, Clone)]
+pub enum Init {
+    #[allow(non_camel_case_types)]
+    new(Vec<RecordPath>),
+    Read(Vec<RecordPath>, usize),
+    Complete(Responses, Vec<RecordPath>, usize),
+    Written(Responses, Vec<RecordPath>, usize),
+}
+
+impl Init {
+    fn agent_keys(record: &PublicRecord) -> Result<AgentKeys, Error> {
+        AgentKeys::parse(&record.payload)
+    }
+
+    //Records written before the id was derived keep their own, only this one is implicitly pinned
+    pub fn agent_keys_uuid(tenant: &Did) -> Uuid {
+        Uuid::new_v5(&Uuid::NAMESPACE_OID, format!("agent_keys:{}", tenant).as_bytes())
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for Init {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            //Probed first so a Dwn without guarded updates is sent plain ones, a Dwn that cannot
+            //answer is assumed to have them
+            Self::new(paths) => {
+                let callback = move |_: Responses| {Self::Read(paths, 0)};
+                Task::tolerant(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, ProbeCapabilities::new())
+                ])
+            },
+            Self::Read(paths, attempt) => {
+                let filters = Filters::new(vec![
+                    ("signer", Filter::equal(memory.tenant().to_string())),
+                    ("type", Filter::equal("agent_keys".to_string()))
+                ]);
+
+                let callback = move |r: Responses| {Self::Complete(r, paths, attempt)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, ReadPublic::new(filters.into(), None))
+                ])
+            },
+            Self::Complete(mut responses, paths, attempt) => {
+                let (mut records, _) = *responses.remove(0).downcast::<(Vec<PublicRecord>, Option<Vec<u8>>)>()?;
+                records.sort_by_key(|r| r.generation());
+                //Races before generations existed left one record per device, fold them into the newest
+                let winner = records.pop();
+                let winner_keys = winner.as_ref().map(Self::agent_keys).transpose()?.unwrap_or_default();
+                //A V1 record is rewritten as V2 even when it already holds this agent
+                let mut changed = !records.is_empty() || winner_keys.is_legacy();
+                let mut agent_keys = winner_keys.into_paths();
+                for record in &records {
+                    for (path, key) in Self::agent_keys(record).unwrap_or_default().into_paths() {
+                        agent_keys.entry(path).or_insert(key);
+                    }
+                }
+
+                for path in paths.iter() {
+                    let key = memory.get_pub(path)?;
+                    changed |= agent_keys.insert(path.clone(), key.clone()) != Some(key);
+                }
+                if !changed {return Task::completed(uuid, ());}
+
+                let record_id = winner.as_ref().map(|r| r.uuid).unwrap_or(Self::agent_keys_uuid(memory.tenant()));
+                let generation = winner.as_ref().map(|r| r.generation()).unwrap_or_default();
+                let index = IndexBuilder::build(vec![("type", "agent_keys")])?;
+                let record = PublicRecord::new(
+                    Some(record_id), SystemProtocols::agent_keys(),
+                    &serde_json::to_vec(&agent_keys)?, Some(index)
+                )?;
+                memory.validate_payload(&record.protocol, &record.payload, false).await?;
+                memory.limits.check(&record)?;
+                let req = MutableAgentRequest::guarded_update_public(record, memory.signer(), generation)?;
+                let mut tasks = vec![Task::MutableRequest(header.clone(), req, 0)];
+                tasks.extend(records.into_iter().map(|r| Task::ready(header.clone(), DeletePublic::new(r.uuid, None, true))));
+                let callback = move |r: Responses| {Self::Written(r, paths, attempt)};
+                Task::waiting(uuid, header, Callback::new(callback), tasks)
+            },
+            Self::Written(mut responses, paths, attempt) => {
+                match *responses.remove(0).downcast::<DwnResponse>()? {
+                    DwnResponse::PublicConflict(_, context) => {
+                        if attempt+1 >= MAX_BOOTSTRAP_ATTEMPTS {
+                            return Err(Error::bootstrap_race(&format!(
+                                "agent_keys still contended after {} attempts: {}", attempt+1, context
+                            )));
+                        }
+                        Task::next(uuid, header, Self::Read(paths, attempt+1))
+                    },
+                    other => {
+                        other.into_empty()?;
+                        Task::completed(uuid, ())
+                    }
+                }
+            }
+        }
+    }
+}
+impl Hashable for Init {}
+
+//Paths the tenant has pinned, kept in a private record under the com key so every agent sees them.
+//The DM cursor and the pin record itself are always pinned
+#[derive(Serialize, Debug, Clone)]
+pub enum ReadPins {
+    #[allow(non_camel_case_types)]
+    new(),
+    Completed(Responses),
+}
+
+impl ReadPins {
+    pub fn path() -> RecordPath {
+        RecordPath::from_segments(&[Uuid::new_v5(&Uuid::NAMESPACE_OID, b"PINS")])
+    }
+
+    pub fn is_pinned(pins: &BTreeSet<RecordPath>, path: &RecordPath) -> bool {
+        pins.contains(path) || *path == Self::path() || *path == ReadDM::cursor_path()
+    }
+
+    fn write(memory: &CompilerMemory, pins: &BTreeSet<RecordPath>) -> Result<MutableAgentRequest, Error> {
+        let protocol = SystemProtocols::pins();
+        MutableAgentRequest::update_private(
+            memory.get_perms(false, &Self::path(), Some(&protocol))?,
+            None, protocol, serde_json::to_vec(pins)?, None
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for ReadPins {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new() => {
+                let perms = memory.get_perms(false, &Self::path(), Some(&SystemProtocols::pins()))?;
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Completed), vec![
+                    Task::ready(header.com(), ReadPrivate::new(Box::new(perms), false))
+                ])
+            },
+            Self::Completed(mut responses) => {
+                let record = responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0;
+                let pins = record.map(|r| r.into_record().decode::<BTreeSet<RecordPath>>()).transpose()?;
+                Task::completed(uuid, pins.unwrap_or_default())
+            }
+        }
+    }
+}
+impl Hashable for ReadPins {}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum PinRecord {
+    #[allow(non_camel_case_types)]
+    new(RecordPath),
+    Write(Responses, RecordPath),
+}
+
+#[async_trait::async_trait]
+impl Command for PinRecord {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        memory: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(path) => {
+                let callback = move |r: Responses| {Self::Write(r, path)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, ReadPins::new())
+                ])
+            },
+            Self::Write(mut responses, path) => {
+                let mut pins = *responses.remove(0).downcast::<BTreeSet<RecordPath>>()?;
+                if !pins.insert(path) {return Task::completed(uuid, ());}
+                let req = ReadPins::write(memory, &pins)?;
+                Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
+                    Task::MutableRequest(header.com(), req, 0)
+                ])
+            }
         }
     }
 }
-impl Hashable for ReadDM {}
+impl Hashable for PinRecord {}
 
 #[derive(Serialize, Debug, Clone)]
-pub enum ScanDM {
+pub enum UnpinRecord {
     #[allow(non_camel_case_types)]
-    new(),
-    Scan(Responses),
+    new(RecordPath),
+    Write(Responses, RecordPath),
 }
 
 #[async_trait::async_trait]
-impl Command for ScanDM {
+impl Command for UnpinRecord {
     async fn process<'a>(
         self: Box<Self>, uuid: Uuid, header: Header,
-        _: &mut CompilerMemory, _: &mut CompilerCache
+        memory: &mut CompilerMemory, _: &mut CompilerCache
     ) -> Result<Tasks, Error> {
         match *self {
-            Self::new() => {
-                Task::waiting(uuid, header.clone(), Callback::new(Self::Scan), vec![
-                    Task::ready(header.clone(), ReadDM::new())
-                ])
-            },
-            Self::Scan(mut responses) => {
-                let channels = *responses.remove(0).downcast::<Vec<(Verifier, PermissionSet)>>()?;
-                let tasks = channels.into_iter().map(|(sender, perms)| {
-                    let path = RecordPath::new(&[Uuid::new_v5(
-                        &Uuid::NAMESPACE_OID, sender.to_string().as_bytes()
-                    )]);
-                    let record = Record::new(
-                        path, SystemProtocols::pointer(), &serde_json::to_vec(&perms)?
-                    );
-                    Ok(Task::ready(header.com(), UpdatePrivate::new(record, None)))
-                }).collect::<Result<Vec<Task>, Error>>()?;
-                Task::waiting(uuid, header, Callback::new(EnsureEmpty::new), tasks)
+            Self::new(path) => {
+                let callback = move |r: Responses| {Self::Write(r, path)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, ReadPins::new())
+                ])
+            },
+            Self::Write(mut responses, path) => {
+                let mut pins = *responses.remove(0).downcast::<BTreeSet<RecordPath>>()?;
+                if !pins.remove(&path) {return Task::completed(uuid, ());}
+                let req = ReadPins::write(memory, &pins)?;
+                Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
+                    Task::MutableRequest(header.com(), req, 0)
+                ])
             }
         }
     }
 }
-impl Hashable for ScanDM {}
+impl Hashable for UnpinRecord {}
 
+//Refuses pinned paths unless override_pins is set
 #[derive(Serialize, Debug, Clone)]
-pub enum EstablishChannel {
+pub enum DeletePrivate {
     #[allow(non_camel_case_types)]
-    new(Did),
-    Read(Did),
-    Create(Responses, Did, RecordPath),
-  //ReadCreated(Responses, RecordPath),
-  //Completed(Responses)
+    new(RecordPath, bool),
+    Pins(Responses, RecordPath),
+    Delete(RecordPath),
 }
 
 #[async_trait::async_trait]
-impl Command for EstablishChannel {
+impl Command for DeletePrivate {
     async fn process<'a>(
         self: Box<Self>, uuid: Uuid, header: Header,
         memory: &mut CompilerMemory, _: &mut CompilerCache
     ) -> Result<Tasks, Error> {
         match *self {
-            Self::new(recipient) => {
-                let callback = move |_: Responses| {Self::Read(recipient)};
+            Self::new(path, override_pins) => {
+                path.check_writable("delete")?;
+                if override_pins {return Task::next(uuid, header, Self::Delete(path));}
+                let callback = move |r: Responses| {Self::Pins(r, path)};
                 Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
-                    Task::ready(header.clone(), ScanDM::new())
+                    Task::ready(header, ReadPins::new())
                 ])
             },
-            Self::Read(recipient) => {
-                let path = RecordPath::new(&[Uuid::new_v5(
-                    &Uuid::NAMESPACE_OID, recipient.to_string().as_bytes()
-                )]);
-                let tasks = vec![Task::ready(header.com(), ReadPrivate::path(path.clone()))];
-                let callback = move |r: Responses| {Self::Create(r, recipient, path)};
-                Task::waiting(uuid, header.clone(), Callback::new(callback), tasks)
-            },
-            Self::Create(mut responses, recipient, path) => {
-                match responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0 {
-                    Some(_) => Task::completed(uuid, ()),
-                    None => {
-                        let protocol = SystemProtocols::dms_channel();
-                        let perms = memory.get_perms(false, &path, Some(&protocol))?;
-                        let channel = Record::new(path.clone(), protocol, &[]);
-                        Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
-                            Task::ready(header.com(), Send::new(
-                                CreatePrivate::new(channel.clone(), None), vec![recipient.clone()]
-                            )),
-                            Task::ready(
-                                header.com(), CreatePrivate::new(channel.clone(), None)
-                            ),
-                            Task::ready(header, CreateDM::new(perms, recipient))
-                        ])
-                    }
+            Self::Pins(mut responses, path) => {
+                let pins = responses.remove(0).downcast::<BTreeSet<RecordPath>>()?;
+                if ReadPins::is_pinned(&pins, &path) {
+                    return Err(Error::pinned(&path.to_string()));
                 }
+                Task::next(uuid, header, Self::Delete(path))
             },
+            Self::Delete(path) => {
+                let perms = memory.get_perms(header.enc, &path, None)?;
+                let req = MutableAgentRequest::delete_private(&perms)?;
+                let order = header.order;
+                Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
+                    Task::MutableRequest(header, req, order)
+                ])
+            }
         }
     }
 }
-impl Hashable for EstablishChannel {}
+impl Hashable for DeletePrivate {}
 
+//Pointers carry no delete key so the pointer stays behind as the tombstone, with its
+//target gone ReadPrivate resolves the index to (None, true) and Scan steps over it
 #[derive(Serialize, Debug, Clone)]
-pub enum Scan {
+pub enum DeletePrivateChild {
     #[allow(non_camel_case_types)]
-    new(RecordPath, usize),
-    Scanning(RecordPath, Vec<PrivateRecord>, usize, Option<Responses>),
+    new(RecordPath, usize, bool),
+    Info(Responses, usize, bool),
+    Delete(Responses, bool),
 }
 
 #[async_trait::async_trait]
-impl Command for Scan {
+impl Command for DeletePrivateChild {
     async fn process<'a>(
         self: Box<Self>, uuid: Uuid, header: Header,
         _: &mut CompilerMemory, _: &mut CompilerCache
     ) -> Result<Tasks, Error> {
         match *self {
-            Self::new(path, start) => {
-                Task::next(uuid, header, Self::Scanning(path, vec![], start, None))
+            Self::new(parent_path, index, override_pins) => {
+                let callback = move |r: Responses| {Self::Info(r, index, override_pins)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, ReadInfo::new(parent_path, PermissionOptions::read_child()))
+                ])
             },
-            Self::Scanning(path, mut results, index, responses) => {
-                if let Some(responses) = responses {
-                    for response in responses {
-                        match *response.downcast::<(Option<Box<PrivateRecord>>, bool)>()? {
-                            (Some(record), _) => results.push(*record),
-                            (_, true) => {},
-                            (None, _) => {return Task::completed(uuid, results);}
-                        }
+            Self::Info(mut responses, index, override_pins) => {
+                let info = *responses.remove(0).downcast::<RecordInfo>()?;
+                let perms = info.1.pointer(index)?;
+                let callback = move |r: Responses| {Self::Delete(r, override_pins)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, ReadPrivate::new(Box::new(perms), false))
+                ])
+            },
+            Self::Delete(mut responses, override_pins) => {
+                let pointer = match *responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()? {
+                    (Some(pointer), _) if pointer.protocol == SystemProtocols::perm_pointer() => pointer,
+                    _ => {return Err(Error::not_found("Child pointer"));}
+                };
+                let child: PermissionSet = serde_json::from_slice(&pointer.payload)?;
+                Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
+                    Task::ready(header, DeletePrivate::new(child.path, override_pins))
+                ])
+            }
+        }
+    }
+}
+impl Hashable for DeletePrivateChild {}
+
+//Moves a record and everything under it to another path on the same endpoints, children are
+//relocated before the source is deleted so an interrupted move leaves both copies readable
+#[derive(Serialize, Debug, Clone)]
+pub enum RelocateRecord {
+    #[allow(non_camel_case_types)]
+    new(RecordPath, RecordPath),
+    Create(Responses, RecordPath, RecordPath),
+    Children(Responses, RecordPath, RecordPath, bool),
+    Remove(Responses, RecordPath, bool),
+}
+
+#[async_trait::async_trait]
+impl Command for RelocateRecord {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(from, to) => {
+                from.check_writable("move")?;
+                to.check_writable("move to")?;
+                if from.parent_of(&to) {
+                    return Err(Error::bad_request("Cannot move a record into itself or its descendants"));
+                }
+                let (source, destination) = (from.clone(), to.clone());
+                let callback = move |r: Responses| {Self::Create(r, from, to)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header.clone(), ReadPrivate::path(source)),
+                    Task::ready(header.clone(), ReadPrivate::path(destination)),
+                    Task::ready(header, ReadPins::new())
+                ])
+            },
+            Self::Create(mut results, from, to) => {
+                //Checked before anything is copied, the source delete then skips the pins
+                let pins = results.remove(2).downcast::<BTreeSet<RecordPath>>()?;
+                if ReadPins::is_pinned(&pins, &from) {
+                    return Err(Error::pinned(&from.to_string()));
+                }
+                let existing = results.remove(1).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0;
+                let source = results.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                    .ok_or(Error::not_found("Record to move"))?;
+                let delete = source.protocol.delete;
+                let mut tasks = vec![];
+                if source.protocol.channel.is_some() {
+                    tasks.push(Task::ready(header.clone(), Scan::new(from.clone(), 0)));
+                }
+                let record = Record::new(to.clone(), source.protocol, &source.payload);
+                match existing {
+                    //Left behind by an earlier attempt
+                    Some(existing) if existing.clone().into_record().hash() == record.hash() => {},
+                    Some(_) => {return Err(Error::conflict("Destination holds a different record"));},
+                    None => tasks.push(Task::ready(header.clone(), CreatePrivate::new(record, None)))
+                }
+                let callback = move |r: Responses| {Self::Children(r, from, to, delete)};
+                Task::waiting(uuid, header, Callback::new(callback), tasks)
+            },
+            Self::Children(results, from, to, delete) => {
+                let mut children = vec![];
+                for result in results {
+                    if let Some(conflict) = result.downcast_ref::<&'static str>() {
+                        return Err(Error::conflict(&format!("Could not create destination: {}", conflict)));
+                    }
+                    if let Ok(records) = result.downcast::<Vec<PrivateRecord>>() {
+                        children = *records;
                     }
                 }
-                let batch = if index >= 5 {index*2} else {5};
-                let requests = (0..batch).map(|i| {
-                    println!("Scanning index {}", index+i);
-                    Task::ready(header.clone(), ReadPrivateChild::new(path.clone(), index+i))
-                }).collect::<Vec<_>>();
-
-                let callback = move |r: Responses| {Self::Scanning(path, results, batch+index, Some(r))};
-                Task::waiting(uuid, header, Callback::new(callback), requests)
+                if children.is_empty() {
+                    return Task::next(uuid, header, Self::Remove(vec![], from, delete));
+                }
+                let tasks = children.into_iter().map(|child| {
+                    let path = to.extend(&[child.perms.path.last()])?;
+                    Ok(Task::ready(header.clone(), Self::new(child.perms.path, path)))
+                }).collect::<Result<Vec<_>, Error>>()?;
+                let callback = move |r: Responses| {Self::Remove(r, from, delete)};
+                Task::waiting(uuid, header, Callback::new(callback), tasks)
+            },
+            Self::Remove(results, from, delete) => {
+                EnsureEmpty::is_empty(results)?;
+                if !delete {
+                    log::warn!("{} can not be deleted, it is kept after the move", from);
+                    return Task::completed(uuid, ());
+                }
+                Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
+                    Task::ready(header, DeletePrivate::new(from, true))
+                ])
             }
         }
     }
 }
-impl Hashable for Scan {}
+impl Hashable for RelocateRecord {}
 
+//Writes the record over one of another protocol in a single update, signed with the delete key of
+//the old record, so readers see either the old record or the new one
 #[derive(Serialize, Debug, Clone)]
-pub enum Init {
+pub enum ReplaceRecordProtocol {
     #[allow(non_camel_case_types)]
-    new(Vec<RecordPath>),
-    Complete(Responses, Vec<RecordPath>),
+    new(Record, Option<PermissionOptions>),
+    Replace(Responses, Record, Option<PermissionOptions>),
 }
 
 #[async_trait::async_trait]
-impl Command for Init {
+impl Command for ReplaceRecordProtocol {
     async fn process<'a>(
         self: Box<Self>, uuid: Uuid, header: Header,
         memory: &mut CompilerMemory, _: &mut CompilerCache
     ) -> Result<Tasks, Error> {
         match *self {
-            Self::new(paths) => {
-                let filters = Filters::new(vec![
-                    ("signer", Filter::equal(memory.tenant().to_string())),
-                    ("type", Filter::equal("agent_keys".to_string()))
-                ]);
-
-                let callback = move |r: Responses| {Self::Complete(r, paths)};
+            Self::new(record, p_opts) => {
+                record.path.check_writable("replace")?;
+                let path = record.path.clone();
+                let callback = move |r: Responses| {Self::Replace(r, record, p_opts)};
                 Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
-                    Task::ready(header, ReadPublic::new(filters, None))
+                    Task::ready(header, ReadPrivate::path(path))
                 ])
             },
-            Self::Complete(mut responses, paths) => {
-                let records = responses.remove(0).downcast::<Vec<PublicRecord>>()?;
-
-                let record_id = records.first().map(|r| r.uuid);
-                let mut agent_keys = records.first().and_then(|record|
-                    serde_json::from_slice::<BTreeMap<RecordPath, PublicKey>>(&record.payload).ok()
-                ).unwrap_or_default();
-
-                match paths.into_iter().map(|path| {
-                    let key = memory.get_pub(&path)?;
-                    let o_key = agent_keys.insert(path, key.clone());
-                    Ok(Some(key) == o_key)
-                }).collect::<Result<Vec<bool>, Error>>()?
-                .iter().all(|b| *b) {
-                    true => Task::completed(uuid, ()),
-                    false => {
-                        let index = IndexBuilder::build(vec![("type", "agent_keys")])?;
-                        let record = PublicRecord::new(
-                            record_id, SystemProtocols::agent_keys(),
-                            &serde_json::to_vec(&agent_keys)?, Some(index)
-                        )?;
-                        Ok(vec![(uuid, Task::ready(header, UpdatePublic::new(record, None)))])
-                    }
+            Self::Replace(mut responses, record, p_opts) => {
+                let existing = responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                    .ok_or(Error::not_found("Record to replace"))?;
+                if existing.protocol == record.protocol {
+                    return Err(Error::bad_request(&format!(
+                        "{} already has protocol {}, update it instead", record.path, record.protocol.label()
+                    )));
+                }
+                if !existing.protocol.delete {
+                    return Err(Error::bad_request(&format!(
+                        "{} can not be replaced, {} does not allow deletes", record.path, existing.protocol.label()
+                    )));
                 }
+                memory.validate_payload(&record.protocol, &record.payload, false).await?;
+                let perms = memory.get_perms(header.enc, &record.path, None)?;
+                let req = MutableAgentRequest::update_private(
+                    perms, p_opts.as_ref(), record.protocol, record.payload, record.expires
+                )?;
+                let order = header.order;
+                Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
+                    Task::MutableRequest(header, req, order)
+                ])
             }
         }
     }
 }
-impl Hashable for Init {}
+impl Hashable for ReplaceRecordProtocol {}
 
+//Totals of what this cache has seen written to the endpoint, nothing is read from the Dwn
 #[derive(Serialize, Debug, Clone)]
-pub struct DeletePrivate {
-    path: RecordPath
+pub struct UsageReport {
+    group_by: UsageGroup
 }
 
-impl DeletePrivate {
-    pub fn new(path: RecordPath) -> Self {
-        DeletePrivate{path}
+impl UsageReport {
+    pub fn new(group_by: UsageGroup) -> Self {
+        UsageReport{group_by}
     }
 }
 
 #[async_trait::async_trait]
-impl Command for DeletePrivate {
+impl Command for UsageReport {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, cache: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        Task::completed(uuid, cache.usage_report(&header.endpoint, self.group_by))
+    }
+}
+impl Hashable for UsageReport {}
+
+//Copies the item to the destination, verifies it, records the placement and only then
+//removes the source, a failure at any step leaves the source copy in place
+#[derive(Serialize, Debug, Clone)]
+pub enum MoveRecord {
+    #[allow(non_camel_case_types)]
+    new(RecordPath, Did, Did),
+    Fetch(Box<PermissionSet>),
+    Fetched(Responses),
+    Copy(Responses, Box<PermissionSet>, Did, Did),
+    Store(Box<DwnItem>, Box<PermissionSet>),
+    Stored(Responses, Box<DwnItem>, Box<PermissionSet>),
+    Verify(Responses, Box<PermissionSet>, Box<DwnItem>, Did, Did),
+    Placed(Responses, Box<PermissionSet>, Box<DwnItem>, Did),
+    Remove(Box<PermissionSet>),
+}
+
+impl MoveRecord {
+    fn item(responses: Responses) -> Result<Option<DwnItem>, Error> {
+        for response in responses {
+            if let DwnResponse::ReadPrivate(Some(item)) = *response.downcast::<DwnResponse>()? {
+                return Ok(Some(item));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for MoveRecord {
     async fn process<'a>(
         self: Box<Self>, uuid: Uuid, header: Header,
         memory: &mut CompilerMemory, _: &mut CompilerCache
     ) -> Result<Tasks, Error> {
-        let perms = memory.get_perms(header.enc, &self.path, None)?;
-        let req = MutableAgentRequest::delete_private(&perms)?;
-        let order = header.order;
-        Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
-            Task::MutableRequest(header, req, order)
-        ])
+        match *self {
+            Self::new(path, from, to) => {
+                let perms = Box::new(memory.get_perms(header.enc, &path, None)?);
+                let fetch = Send::new(Self::Fetch(perms.clone()), vec![from.clone()]);
+                let callback = move |r: Responses| {Self::Copy(r, perms, from, to)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, fetch)
+                ])
+            },
+            Self::Fetch(perms) => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Fetched), vec![
+                    Task::Request(header, AgentRequest::ReadPrivate(perms.discover()))
+                ])
+            },
+            Self::Fetched(mut responses) => {
+                Task::completed(uuid, *responses.remove(0).downcast::<DwnResponse>()?)
+            },
+            Self::Copy(mut responses, perms, from, to) => {
+                let item = Box::new(Self::item(*responses.remove(0).downcast::<Responses>()?)?
+                    .ok_or(Error::not_found("Record on source"))?);
+                let store = Send::new(Self::Store(item.clone(), perms.clone()), vec![to.clone()]);
+                let callback = move |r: Responses| {Self::Verify(r, perms, item, from, to)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, store)
+                ])
+            },
+            Self::Store(item, perms) => {
+                let req = MutableAgentRequest::CopyPrivate(item.clone(), perms.discover());
+                let callback = move |r: Responses| {Self::Stored(r, item, perms)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::MutableRequest(header, req, 0)
+                ])
+            },
+            //A copy left behind by an earlier attempt is kept, Verify compares it either way
+            Self::Stored(mut responses, item, perms) => {
+                match *responses.remove(0).downcast::<DwnResponse>()? {
+                    DwnResponse::Conflict(stored, _) if stored.hash() == item.hash() => {},
+                    response => response.into_empty()?
+                }
+                Task::next(uuid, header, Self::Fetch(perms))
+            },
+            Self::Verify(mut responses, perms, item, from, to) => {
+                for copy in *responses.remove(0).downcast::<Responses>()? {
+                    match *copy.downcast::<DwnResponse>()? {
+                        DwnResponse::ReadPrivate(Some(copy)) if copy.hash() == item.hash() => {},
+                        _ => return Err(Error::bad_response("Copy on destination does not match the source"))
+                    }
+                }
+                let placement = Placement::new(to).into_record(&perms.path)?;
+                let callback = move |r: Responses| {Self::Placed(r, perms, item, from)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, UpdatePrivate::new(placement, None))
+                ])
+            },
+            Self::Placed(responses, perms, item, from) => {
+                EnsureEmpty::is_empty(responses)?;
+                if item.delete.is_none() {
+                    log::warn!("{} can not be deleted, the source copy on {} is kept", perms.path, from);
+                    return Task::completed(uuid, ());
+                }
+                Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
+                    Task::ready(header, Send::new(Self::Remove(perms), vec![from]))
+                ])
+            },
+            Self::Remove(perms) => {
+                Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
+                    Task::MutableRequest(header, MutableAgentRequest::delete_private(&perms)?, 0)
+                ])
+            }
+        }
     }
 }
-impl Hashable for DeletePrivate {}
+impl Hashable for MoveRecord {}
 
 #[derive(Serialize, Debug, Clone)]
 pub struct CreatePublic {
     record: PublicRecord,
     signer: Option<Signer>,
+    receipt: bool
 }
 
 impl CreatePublic {
     pub fn new(record: PublicRecord, signer: Option<Signer>) -> Self {
-        CreatePublic{record, signer}
+        CreatePublic{record, signer, receipt: false}
+    }
+
+    //Completes with the receipts of the Dwns that gave one
+    pub fn with_receipt(record: PublicRecord, signer: Option<Signer>) -> Self {
+        CreatePublic{record, signer, receipt: true}
     }
 }
 
@@ -917,11 +2906,18 @@ impl Command for CreatePublic {
         self: Box<Self>, uuid: Uuid, header: Header,
         memory: &mut CompilerMemory, _: &mut CompilerCache
     ) -> Result<Tasks, Error> {
-        self.record.protocol.validate_payload(&self.record.payload)?;
+        memory.validate_payload(&self.record.protocol, &self.record.payload, false).await?;
+        memory.limits.check(&self.record)?;
         let signer = self.signer.unwrap_or(memory.signer());
         let req = MutableAgentRequest::create_public(self.record, signer)?;
+        let order = header.order;
+        if self.receipt {
+            return Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::with_receipts), vec![
+                Task::MutableRequest(header, req.with_receipt(), order)
+            ]);
+        }
         Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
-            Task::MutableRequest(header, req, 0)
+            Task::MutableRequest(header, req, order)
         ])
     }
 }
@@ -930,8 +2926,51 @@ impl Hashable for CreatePublic {}
 #[derive(Serialize, Debug, Clone)]
 pub enum ReadPublic {
     #[allow(non_camel_case_types)]
-    new(Filters, Option<SortOptions>),
-    Completed(Responses, Filters, Option<SortOptions>)
+    new(FilterExpr, Option<SortOptions>),
+    #[allow(non_camel_case_types)]
+    verified(FilterExpr, Option<SortOptions>),
+    #[allow(non_camel_case_types)]
+    at(FilterExpr, DateTime<Utc>, Option<SortOptions>),
+    //Also returns the ReadDiagnostics of the records dropped
+    #[allow(non_camel_case_types)]
+    diagnosed(FilterExpr, Option<SortOptions>),
+    Completed(Responses, FilterExpr, Option<SortOptions>, bool, bool)
+}
+
+impl ReadPublic {
+    //None for records the filters do not match
+    async fn verify(
+        memory: &CompilerMemory<'_>, filters: &FilterExpr, item: PublicDwnItem
+    ) -> Result<Option<(VerifiedBy, PublicRecord)>, DropReason> {
+        let signer = item.0.verify_by(memory.did_resolver, None).await.map_err(|_| DropReason::BadSignature)?;
+        let verifier = Verifier::from(signer.clone());
+        let keys = item.secondary_keys();
+        let signer_filter = Filters::new(vec![("signer", Filter::equal(verifier.to_string()))]);
+        if !signer_filter.filter(&keys) {
+            log::warn!("Dropping public record {} with spoofed signer index", item.0.inner().uuid);
+            return Err(DropReason::SpoofedSigner);
+        }
+        if !filters.filter(&keys) {return Ok(None);}
+        let mut record = item.0.unwrap();
+        if let Err(e) = memory.check_signer(&record.protocol, &signer) {
+            log::warn!("Dropping public record {}: {}", record.uuid, e);
+            return Err(DropReason::SignerPurpose);
+        }
+        let own = verifier == Verifier::Left(memory.tenant().clone());
+        (record.payload, record.state) = memory.validate_read(&record.protocol, &record.payload, own).await
+            .map_err(|_| DropReason::InvalidPayload)?;
+        Ok(Some((signer, record)))
+    }
+
+    fn request(
+        uuid: Uuid, header: Header, req: AgentRequest, filters: FilterExpr,
+        sort_options: Option<SortOptions>, verified: bool, diagnose: bool
+    ) -> Result<Tasks, Error> {
+        let callback = move |r: Responses| {Self::Completed(r, filters, sort_options, verified, diagnose)};
+        Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+            Task::Request(header, req)
+        ])
+    }
 }
 
 #[async_trait::async_trait]
@@ -944,26 +2983,59 @@ impl Command for ReadPublic {
             Self::new(filters, sort_options) => {
                 //TODO: I suspect that if sort options contains a field not in the filters it will crash the dwn
                 let req = AgentRequest::ReadPublic(filters.clone(), sort_options.clone());
-                let callback = move |r: Responses| {Self::Completed(r, filters, sort_options)};
-                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
-                    Task::Request(header, req)
-                ])
+                Self::request(uuid, header, req, filters, sort_options, false, false)
+            },
+            Self::verified(filters, sort_options) => {
+                let req = AgentRequest::ReadPublic(filters.clone(), sort_options.clone());
+                Self::request(uuid, header, req, filters, sort_options, true, false)
             },
-            Self::Completed(mut response, filters, sort_options) => {
+            //Paged by uuid on the Dwn, the options are not passed on to be sorted by
+            Self::at(filters, at, sort_options) => {
+                let req = AgentRequest::ReadPublicAt(filters.clone(), at, sort_options);
+                Self::request(uuid, header, req, filters, None, false, false)
+            },
+            Self::diagnosed(filters, sort_options) => {
+                let req = AgentRequest::ReadPublic(filters.clone(), sort_options.clone());
+                Self::request(uuid, header, req, filters, sort_options, false, true)
+            },
+            Self::Completed(mut response, filters, sort_options, verified, diagnose) => {
                 let response = *response.remove(0).downcast::<DwnResponse>()?;
-                if let DwnResponse::ReadPublic(mut records) = response {
-                    if let Some(sort_options) = sort_options {
+                if let DwnResponse::ReadPublic(mut records, mut cursor) = response {
+                    let limit = sort_options.as_ref().map(|s| s.page()).transpose()?.and_then(|(limit, _)| limit);
+                    //The Dwn sorts before it pages, the order is checked rather than trusted
+                    if let Some(sort_options) = sort_options.filter(|s| !s.in_order(&records).unwrap_or(false)) {
+                        log::warn!("Dwn returned public records out of order, sorting them");
                         sort_options.sort(&mut records)?;
                     }
-                    let records = futures::future::join_all(records.into_iter().map(|item| async {
-                        item.0.verify(memory.did_resolver, None).await.ok()?;
-                        if !filters.filter(&item.secondary_keys()) {return None;}
-                        let record = item.0.unwrap();
-                        record.protocol.validate_payload(&record.payload).ok()?;
-                        Some(record)
-                    })).await;
-                    let records = records.into_iter().flatten().collect::<Vec<_>>();
-                    Task::completed(uuid, records)
+                    //Verified a batch at a time, no more than the limit still needs, so records
+                    //past it are never verified
+                    let mut accepted = Vec::new();
+                    let mut diagnostics = ReadDiagnostics::default();
+                    let mut records = records.into_iter().peekable();
+                    loop {
+                        let wanted = limit.map(|l| l - accepted.len()).unwrap_or(usize::MAX);
+                        let batch = records.by_ref().take(wanted.min(memory.max_scan_batch.max(1))).collect::<Vec<_>>();
+                        if batch.is_empty() {break;}
+                        for (id, result) in futures::future::join_all(batch.into_iter().map(|item| async {
+                            (item.0.inner().uuid, Self::verify(memory, &filters, item).await)
+                        })).await {
+                            match result {
+                                Ok(record) => accepted.extend(record),
+                                Err(reason) => diagnostics.record(reason, &id)
+                            }
+                        }
+                    }
+                    //The cursor is the Dwn's, records dropped here do not move it unless the limit cut the page short
+                    if records.peek().is_some() {
+                        cursor = accepted.last().map(|(_, r)| r.uuid.as_bytes().to_vec());
+                    }
+                    if verified {
+                        Task::completed(uuid, (accepted, cursor))
+                    } else if diagnose {
+                        Task::completed(uuid, (accepted.into_iter().map(|(_, r)| r).collect::<Vec<_>>(), cursor, diagnostics))
+                    } else {
+                        Task::completed(uuid, (accepted.into_iter().map(|(_, r)| r).collect::<Vec<_>>(), cursor))
+                    }
                 } else {Err(Error::bad_response("Expected ReadPublic"))}
             }
         }
@@ -971,6 +3043,50 @@ impl Command for ReadPublic {
 }
 impl Hashable for ReadPublic {}
 
+//The Dwn's count is trusted, nothing comes back to filter and verify the way ReadPublic does.
+//The verified count reads the records instead, which is also the fallback for Dwns without counts
+#[derive(Serialize, Debug, Clone)]
+pub enum CountPublic {
+    #[allow(non_camel_case_types)]
+    new(FilterExpr),
+    #[allow(non_camel_case_types)]
+    verified(FilterExpr),
+    Counted(Responses),
+    Read(Responses),
+}
+
+#[async_trait::async_trait]
+impl Command for CountPublic {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, cache: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::new(filters) if cache.lacks(&header.endpoint, FEATURE_COUNT) =>
+                Task::next(uuid, header, Self::verified(filters)),
+            Self::new(filters) => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Counted), vec![
+                    Task::Request(header, AgentRequest::CountPublic(filters))
+                ])
+            },
+            Self::verified(filters) => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Read), vec![
+                    Task::ready(header, ReadPublic::new(filters, None))
+                ])
+            },
+            Self::Counted(mut responses) => match *responses.remove(0).downcast::<DwnResponse>()? {
+                DwnResponse::Count(count) => Task::completed(uuid, count),
+                _ => Err(Error::bad_response("Expected Count"))
+            },
+            Self::Read(mut responses) => {
+                let (records, _) = *responses.remove(0).downcast::<(Vec<PublicRecord>, Option<Vec<u8>>)>()?;
+                Task::completed(uuid, records.len())
+            }
+        }
+    }
+}
+impl Hashable for CountPublic {}
+
 #[derive(Serialize, Debug, Clone)]
 pub struct UpdatePublic {
     record: PublicRecord,
@@ -989,11 +3105,13 @@ impl Command for UpdatePublic {
         self: Box<Self>, uuid: Uuid, header: Header,
         memory: &mut CompilerMemory, _: &mut CompilerCache
     ) -> Result<Tasks, Error> {
-        self.record.protocol.validate_payload(&self.record.payload)?;
+        memory.validate_payload(&self.record.protocol, &self.record.payload, false).await?;
+        memory.limits.check(&self.record)?;
         let signer = self.signer.unwrap_or(memory.signer());
         let req = MutableAgentRequest::update_public(self.record, signer)?;
+        let order = header.order;
         Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
-            Task::MutableRequest(header, req, 0)
+            Task::MutableRequest(header, req, order)
         ])
     }
 }
@@ -1002,12 +3120,13 @@ impl Hashable for UpdatePublic {}
 #[derive(Serialize, Debug, Clone)]
 pub struct DeletePublic {
     uuid: Uuid,
-    signer: Option<Signer>
+    signer: Option<Signer>,
+    override_pins: bool
 }
 
 impl DeletePublic {
-    pub fn new(uuid: Uuid, signer: Option<Signer>) -> Self {
-        DeletePublic{uuid, signer}
+    pub fn new(uuid: Uuid, signer: Option<Signer>, override_pins: bool) -> Self {
+        DeletePublic{uuid, signer, override_pins}
     }
 }
 
@@ -1017,10 +3136,14 @@ impl Command for DeletePublic {
         self: Box<Self>, uuid: Uuid, header: Header,
         memory: &mut CompilerMemory, _: &mut CompilerCache
     ) -> Result<Tasks, Error> {
+        if !self.override_pins && self.uuid == Init::agent_keys_uuid(memory.tenant()) {
+            return Err(Error::pinned("agent_keys"));
+        }
         let signer = self.signer.unwrap_or(memory.signer());
         let req = MutableAgentRequest::delete_public(self.uuid, signer)?;
+        let order = header.order;
         Task::waiting(uuid, header.clone(), Callback::new(EnsureEmpty::new), vec![
-            Task::MutableRequest(header, req, usize::MAX)
+            Task::MutableRequest(header, req, order)
         ])
     }
 }
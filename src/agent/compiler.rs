@@ -1,54 +1,320 @@
 use super::Error;
 
-use super::protocol::Protocol;
+use crate::model::protocol::{Protocol, ProtocolRegistry};
 use super::permission::PermissionSet;
-use super::traits::Response;
 use super::commands::{Complete, Send};
+use super::traits::{AgentTelemetry, Command};
+use super::telemetry::Outcome;
 use super::structs::{
     MutableAgentRequest,
     AgentRequest,
     BoxResponse,
     BoxCallback,
     BoxCommand,
+    ConflictStrategies,
+    Validators,
+    RecordState,
+    RecordInfo,
     RecordPath,
     PathedKey,
+    KeyDomain,
     Responses,
     Callback,
+    RngSource,
+    UsageChange,
+    UsageEntry,
+    UsageGroup,
+    UsageTotal,
     Header,
     Tasks,
     Task,
 };
 
-use crate::dwn::structs::DwnRequest;
+use crate::dwn::structs::{DwnCapabilities, DwnRequest, DwnResponse, PublicLimits, FEATURE_GUARDED_UPDATE, FEATURE_RECEIPT};
+use crate::dwn::structs::{ReadChallenge, SessionRead, FEATURE_READ_SESSION, READ_SESSION_TTL};
 use crate::dwn::router::Router;
 use crate::dids::{DidResolver, DidKeyPair, Endpoint, Did};
-use crate::dids::signing::Signer;
+use crate::dids::signing::{SignedObject, VerifiedBy, Signer};
 
-use std::collections::BTreeMap;
-use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use simple_crypto::PublicKey;
+use simple_database::KeyValueStore;
+use simple_crypto::{SecretKey, PublicKey};
+use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
-#[derive(Default, Debug)]
+pub const DEFAULT_CACHE_CAPACITY: usize = 10_000;
+//How long a Dwns capabilities are trusted before they are probed again
+pub const CAPABILITIES_TTL: Duration = Duration::from_secs(600);
+//A proven discover key is proven again this long before its proof expires
+pub const READ_SESSION_MARGIN: Duration = Duration::from_secs(30);
+//Most child indexes a scan reads at once however far it has doubled
+pub const DEFAULT_MAX_SCAN_BATCH: usize = 64;
+
+pub type RecordInfoKey = (Endpoint, bool, RecordPath);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+    pub entries: usize
+}
+
+//Key the record info of a loaded cache is flushed under
+const RECORD_INFO_KEY: &[u8] = b"record_info";
+
+//Record info by endpoint, domain and path, least recently used entries are evicted past the
+//capacity and are simply read again by ReadInfo on the next miss
+#[derive(Debug)]
 pub struct CompilerCache {
-    pub record_info: BTreeMap<(Endpoint, bool, RecordPath), (Protocol, PermissionSet)>,
+    record_info: BTreeMap<RecordInfoKey, (RecordInfo, u64)>,
+    last_access: BTreeMap<u64, RecordInfoKey>,
+    tick: u64,
+    capacity: usize,
+    stats: CacheStats,
+    capabilities: BTreeMap<Endpoint, (Instant, DwnCapabilities)>,
+    //Records written through this cache by endpoint and request id
+    usage: BTreeMap<(Endpoint, Uuid), UsageEntry>,
+    //Key derivations made by the compiles run with this cache
+    derivations: usize,
+    //Token of the read session and until when each discover key is proven in it, by endpoint
+    read_session: Vec<u8>,
+    proven: BTreeMap<(Endpoint, Vec<u8>), DateTime<Utc>>,
+    //Signatures made for private reads by the compiles run with this cache
+    read_signatures: usize,
+    //Where flush writes the record info, encrypted to the public key as it holds secret keys
+    store: Option<(Box<dyn KeyValueStore>, PublicKey)>,
+    //Next child index by channel, only kept across compiles by a session
+    create_index: Option<BTreeMap<(Endpoint, bool, RecordPath), usize>>
+}
+
+impl Default for CompilerCache {
+    fn default() -> Self {Self::with_capacity(DEFAULT_CACHE_CAPACITY)}
+}
+
+impl CompilerCache {
+    pub fn with_capacity(capacity: usize) -> Self {
+        CompilerCache{
+            record_info: BTreeMap::new(),
+            last_access: BTreeMap::new(),
+            tick: 0,
+            capacity: capacity.max(1),
+            stats: CacheStats::default(),
+            capabilities: BTreeMap::new(),
+            usage: BTreeMap::new(),
+            derivations: 0,
+            read_session: rand::random::<[u8; 32]>().to_vec(),
+            proven: BTreeMap::new(),
+            read_signatures: 0,
+            store: None,
+            create_index: None
+        }
+    }
+
+    //Record info flushed by an earlier process, decrypted with the agent's enc key
+    pub async fn load<KVS: KeyValueStore + 'static>(path: PathBuf, key: &SecretKey) -> Result<Self, Error> {
+        let store = Box::new(KVS::new(path).await?);
+        let mut cache = Self::default();
+        if let Some(payload) = store.get(RECORD_INFO_KEY).await? {
+            let entries = serde_json::from_slice::<Vec<(RecordInfoKey, RecordInfo)>>(&key.decrypt(&payload)?)?;
+            entries.into_iter().for_each(|(k, info)| cache.insert_info(k, info));
+        }
+        cache.store = Some((store, key.public_key()));
+        Ok(cache)
+    }
+
+    //Writes the record info of a loaded cache, least recently used first so a reload keeps the order
+    pub async fn flush(&self) -> Result<(), Error> {
+        if let Some((store, key)) = &self.store {
+            let entries = self.last_access.values().map(|k|
+                (k, &self.record_info[k].0)
+            ).collect::<Vec<_>>();
+            store.set(RECORD_INFO_KEY, &key.encrypt(&serde_json::to_vec(&entries)?)?).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn keep_create_index(&mut self) {
+        self.create_index.get_or_insert_with(BTreeMap::new);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats{entries: self.record_info.len(), ..self.stats}
+    }
+
+    pub fn derivations(&self) -> usize {self.derivations}
+
+    pub fn read_signatures(&self) -> usize {self.read_signatures}
+
+    fn touch(&mut self, key: &RecordInfoKey) {
+        if let Some((_, access)) = self.record_info.get_mut(key) {
+            self.last_access.remove(access);
+            self.tick += 1;
+            *access = self.tick;
+            self.last_access.insert(self.tick, key.clone());
+        }
+    }
+
+    pub fn get_capabilities(&self, endpoint: &Endpoint) -> Option<&DwnCapabilities> {
+        self.capabilities.get(endpoint)
+            .filter(|(probed, _)| probed.elapsed() < CAPABILITIES_TTL)
+            .map(|(_, capabilities)| capabilities)
+    }
+
+    pub fn insert_capabilities(&mut self, endpoint: Endpoint, capabilities: DwnCapabilities) {
+        self.capabilities.insert(endpoint, (Instant::now(), capabilities));
+    }
+
+    //Only a probed Dwn can lack a feature, unprobed ones are assumed to have them all
+    pub fn lacks(&self, endpoint: &Endpoint, feature: &str) -> bool {
+        self.get_capabilities(endpoint).map(|c| !c.supports(feature)).unwrap_or(false)
+    }
+
+    //Private reads go through the read session of Dwns probed to support it, the discover key
+    //only signs when it is not proven there yet
+    fn read_request(&mut self, endpoint: &Endpoint, request: AgentRequest) -> Result<DwnRequest, Error> {
+        let discover = match request {
+            AgentRequest::ReadPrivate(discover) => discover,
+            request => return request.into_dwn_request()
+        };
+        self.read_signatures += 1;
+        if !self.get_capabilities(endpoint).is_some_and(|c| c.supports(FEATURE_READ_SESSION)) {
+            return AgentRequest::ReadPrivate(discover).into_dwn_request();
+        }
+        let now = Utc::now();
+        let key = (endpoint.clone(), discover.public_key().to_vec());
+        let proof = match self.proven.get(&key) {
+            Some(expires) if *expires > now+READ_SESSION_MARGIN => {
+                self.read_signatures -= 1;
+                None
+            },
+            _ => {
+                let expires = now+READ_SESSION_TTL;
+                let session = SessionRead::session(&self.read_session);
+                self.proven.insert(key, expires);
+                Some(SignedObject::from_key(&discover, ReadChallenge{dwn: endpoint.0.clone(), session, expires})?)
+            }
+        };
+        Ok(DwnRequest::ReadPrivateSession(SessionRead{
+            discover: discover.public_key(), token: self.read_session.clone(), proof
+        }))
+    }
+
+    //Dwns forget sessions on restart, the key is proven again on the next read
+    fn forget_proven(&mut self, endpoint: &Endpoint, discover: &PublicKey) {
+        self.proven.remove(&(endpoint.clone(), discover.to_vec()));
+    }
+
+    pub fn record_usage(&mut self, endpoint: Endpoint, id: Uuid, change: UsageChange) {
+        match change {
+            UsageChange::Write(entry) => {self.usage.insert((endpoint, id), entry);},
+            UsageChange::Remove => {self.usage.remove(&(endpoint, id));}
+        }
+    }
+
+    pub fn usage_report(&self, endpoint: &Endpoint, group_by: UsageGroup) -> BTreeMap<String, UsageTotal> {
+        let mut report: BTreeMap<String, UsageTotal> = BTreeMap::new();
+        for (_, entry) in self.usage.iter().filter(|((ep, _), _)| ep == endpoint) {
+            let total = report.entry(group_by.key(entry)).or_default();
+            total.records += 1;
+            total.bytes += entry.size;
+        }
+        report
+    }
+
+    pub fn get_info(&mut self, key: &RecordInfoKey) -> Option<RecordInfo> {
+        if self.record_info.contains_key(key) {
+            self.stats.hits += 1;
+            log::debug!("Record info cache hit {}", key.2);
+            self.touch(key);
+            self.record_info.get(key).map(|(info, _)| info.clone())
+        } else {
+            self.stats.misses += 1;
+            log::debug!("Record info cache miss {}", key.2);
+            None
+        }
+    }
+
+    //Drops the info of the record discovered by the key, a write replacing or removing it may change it
+    pub fn invalidate(&mut self, endpoint: &Endpoint, discover: &PublicKey) {
+        let stale = self.record_info.iter().filter(|((ep, _, _), ((_, perms), _))|
+            ep == endpoint && perms.discover.public_key() == *discover
+        ).map(|(k, (_, access))| (k.clone(), *access)).collect::<Vec<_>>();
+        for (key, access) in stale {
+            self.record_info.remove(&key);
+            self.last_access.remove(&access);
+        }
+    }
+
+    //A record is the same on every endpoint of its did, another protocol than the cached one means it
+    //was created twice or replaced behind this cache. The stale info is dropped with the error
+    pub fn check_protocol(&mut self, key: &RecordInfoKey, found: &Protocol) -> Result<(), Error> {
+        let stale = self.record_info.iter().filter(|((ep, enc, path), ((protocol, _), _))|
+            ep.0 == key.0.0 && *enc == key.1 && *path == key.2 && protocol != found
+        ).map(|(k, ((protocol, _), access))| (k.clone(), protocol.label(), *access)).collect::<Vec<_>>();
+        let Some((_, cached, _)) = stale.first().cloned() else {return Ok(())};
+        for (key, _, access) in stale {
+            self.record_info.remove(&key);
+            self.last_access.remove(&access);
+        }
+        Err(Error::protocol_mismatch(&key.2.to_string(), &cached, &found.label()))
+    }
+
+    pub fn insert_info(&mut self, key: RecordInfoKey, info: RecordInfo) {
+        if let Some(entry) = self.record_info.get_mut(&key) {
+            entry.0 = info;
+        } else {
+            while self.record_info.len() >= self.capacity {
+                let Some((_, oldest)) = self.last_access.pop_first() else {break};
+                log::debug!("Record info cache evicted {}", oldest.2);
+                self.record_info.remove(&oldest);
+                self.stats.evictions += 1;
+            }
+            self.record_info.insert(key.clone(), (info, 0));
+        }
+        self.touch(&key);
+    }
 }
 
 #[derive(Debug)]
 pub struct CompilerMemory<'a> {
     pub create_index: BTreeMap<(Endpoint, bool, RecordPath), usize>,
+    //Commands that run once per compile, their results are kept until the compile ends
+    shared: BTreeMap<(Endpoint, bool, &'static str), Uuid>,
+    //Path keys derived this compile by domain, siblings derive only their last segment
+    derived: Mutex<BTreeMap<(bool, Vec<Uuid>), SecretKey>>,
+    //Role keys of those paths by index
+    roles: Mutex<BTreeMap<RoleKey, SecretKey>>,
+    derivations: AtomicUsize,
 
     //Readonly
     pub did_resolver: &'a dyn DidResolver,
+    pub validators: &'a Validators,
+    pub conflicts: &'a ConflictStrategies,
+    pub protocols: &'a ProtocolRegistry,
+    //Public record bounds of the Dwns, checked before anything is sent
+    pub limits: PublicLimits,
+    pub max_scan_batch: usize,
+    rng: &'a RngSource,
     sig_key: &'a DidKeyPair,
     enc_key: &'a PathedKey,
     com_key: &'a PathedKey,
+    legacy_com_keys: &'a [SecretKey],
     tenant: Did,
 }
 
 impl<'a> CompilerMemory<'a> {
     pub fn tenant(&self) -> &Did {&self.tenant}
+
+    //Batches start at 5 and double with the index read so far, up to max_scan_batch
+    pub fn scan_batch(&self, index: usize) -> usize {
+        (if index >= 5 {index*2} else {5}).min(self.max_scan_batch.max(1))
+    }
     pub fn signer(&self) -> Signer {
         Signer::Left(self.sig_key.clone())
     }
@@ -58,20 +324,140 @@ impl<'a> CompilerMemory<'a> {
     }
 
     pub fn get_pub(&self, path: &RecordPath) -> Result<PublicKey, Error> {
-        Ok(self.enc_key.derive_path(path.as_slice())?.key.public_key())
+        Ok(self.derive(true, path.as_slice())?.key.public_key())
+    }
+
+    //Deep paths are refused before anything is derived, the longest prefix derived earlier is reused
+    fn derive(&self, enc: bool, path: &[Uuid]) -> Result<PathedKey, Error> {
+        RecordPath::check_depth(path)?;
+        let root = if enc {self.enc_key} else {self.com_key};
+        if !path.starts_with(root.path.as_slice()) {return Err(Error::insufficent_permission());}
+        let mut derived = self.derived.lock().unwrap();
+        let mut key = (root.path.as_slice().len()+1..=path.len()).rev().find_map(|i|
+            derived.get(&(enc, path[..i].to_vec())).map(|key| PathedKey::new(key.clone(), RecordPath::from_segments(&path[..i])))
+        ).unwrap_or_else(|| root.clone());
+        self.derivations.fetch_add(path.len()-key.path.as_slice().len(), Ordering::Relaxed);
+        for i in key.path.as_slice().len()..path.len() {
+            key = key.derive_path(&path[..=i])?;
+            derived.insert((enc, path[..=i].to_vec()), key.key.clone());
+        }
+        Ok(key)
+    }
+
+    fn roles(&self, enc: bool, path: &RecordPath, protocol: Option<&Protocol>) -> Result<PermissionSet, Error> {
+        let key = self.derive(enc, path.as_slice())?;
+        let mut roles = self.roles.lock().unwrap();
+        PathedKey::roles_with(key.path.clone(), protocol, |role| {
+            let id = (enc, path.as_slice().to_vec(), role);
+            if let Some(key) = roles.get(&id) {return Ok(key.clone());}
+            self.derivations.fetch_add(1, Ordering::Relaxed);
+            let derived = key.key.derive_usize(role)?;
+            roles.insert(id, derived.clone());
+            Ok(derived)
+        })
+    }
+
+    fn role(&self, enc: bool, path: &RecordPath, role: usize) -> Result<SecretKey, Error> {
+        if let Some(key) = self.roles.lock().unwrap().get(&(enc, path.as_slice().to_vec(), role)) {
+            return Ok(key.clone());
+        }
+        let key = self.derive(enc, path.as_slice())?.key.derive_usize(role)?;
+        self.derivations.fetch_add(1, Ordering::Relaxed);
+        self.roles.lock().unwrap().insert((enc, path.as_slice().to_vec(), role), key.clone());
+        Ok(key)
     }
 
     pub fn get_perms(&self, enc: bool, path: &RecordPath, protocol: Option<&Protocol>) -> Result<PermissionSet, Error> {
-        let key = if enc {self.enc_key} else {self.com_key};
-        key.get_perms(path, protocol)
+        let perms = self.roles(enc, path, protocol)?;
+        Ok(match protocol {
+            Some(protocol) => protocol.trim_permission(perms),
+            None => perms
+        })
     }
+
+    //For reads that only look a record up
+    pub fn get_discover(&self, enc: bool, path: &RecordPath) -> Result<SecretKey, Error> {
+        self.role(enc, path, PathedKey::DISCOVER)
+    }
+
+    pub fn derivations(&self) -> usize {self.derivations.load(Ordering::Relaxed)}
+
+    //The same check as KeyDomain::of through the memoized derivations
+    pub fn check_domain(&self, header: &Header, request: &MutableAgentRequest) -> Result<(), Error> {
+        request.check_domain(header.domain(), &|path, discover| {
+            for (domain, enc) in [(KeyDomain::Enc, true), (KeyDomain::Com, false)] {
+                let key = match self.get_discover(enc, path) {
+                    Err(Error::InsufficentPermission{..}) => continue,
+                    key => key?
+                };
+                if key.public_key() == *discover {return Ok(Some(domain));}
+            }
+            Ok(None)
+        })
+    }
+
+    //Falls back to the com keys replaced by a rotation, for DMs sent to a document not yet refreshed
     pub fn com_decrypt(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
-        Ok(self.com_key.key.decrypt(payload)?)
+        let error = match self.com_key.key.decrypt(payload) {
+            Ok(dc) => return Ok(dc),
+            Err(error) => error
+        };
+        self.legacy_com_keys.iter().find_map(|key| key.decrypt(payload).ok()).ok_or(error.into())
+    }
+
+    pub fn com_pub(&self) -> PublicKey {self.com_key.key.public_key()}
+
+    pub fn agent_key(&self) -> &SecretKey {&self.enc_key.key}
+
+    //For ids the agent picks, never for keys
+    pub fn uuid(&self) -> Uuid {self.rng.uuid()}
+
+    pub async fn validate_payload(&self, protocol: &Protocol, payload: &[u8], read: bool) -> Result<(), Error> {
+        self.validators.validate_async(protocol, payload, read).await
+    }
+
+    pub fn check_signer(&self, protocol: &Protocol, signer: &VerifiedBy) -> Result<(), Error> {
+        self.validators.check_signer(protocol, signer)
+    }
+
+    pub async fn validate_read(&self, protocol: &Protocol, payload: &[u8], own: bool) -> Result<(Vec<u8>, RecordState), Error> {
+        self.validators.validate_read_async(protocol, payload, own).await
+    }
+
+    //Runs the command at most once per compile for this key and endpoint, every caller waits on that one result
+    pub fn shared(
+        &mut self, uuid: Uuid, header: Header, key: &'static str,
+        command: impl Command + 'static, callback: BoxCallback
+    ) -> Result<Tasks, Error> {
+        let shared_key = (header.endpoint.clone(), header.enc, key);
+        if let Some(id) = self.shared.get(&shared_key) {
+            return Ok(vec![(uuid, Task::Waiting(header, callback, vec![*id]))]);
+        }
+        let id = self.uuid();
+        self.shared.insert(shared_key, id);
+        Ok(vec![
+            (uuid, Task::Waiting(header.clone(), callback, vec![id])),
+            (id, Task::ready(header, command))
+        ])
+    }
+
+    fn is_shared(&self, uuid: &Uuid) -> bool {
+        self.shared.values().any(|id| id == uuid)
+    }
+
+    //Whether the create key is one the tenant derives for this path
+    pub fn is_own(&self, path: &RecordPath, create: &PublicKey) -> bool {
+        [true, false].into_iter().any(|enc|
+            self.role(enc, path, PathedKey::CREATE).map(|key| key.public_key() == *create).unwrap_or(false)
+        )
     }
 }
 
 pub type MutableRequestPayload = (Uuid, Header, MutableAgentRequest, usize);
 pub type WaitingPayload = (Uuid, Header, BoxCallback, Vec<Uuid>);
+//Op, start, finish and endpoints reached of an original command
+pub type RoleKey = (bool, Vec<Uuid>, usize);
+pub type Timing = (&'static str, Instant, Option<Instant>, BTreeSet<Endpoint>);
 
 pub struct Compiler<'a> {
     original_requests: Option<Vec<Uuid>>,
@@ -82,6 +468,10 @@ pub struct Compiler<'a> {
     waiting: Option<Vec<WaitingPayload>>,
 
     completed: Option<BTreeMap<Uuid, BoxResponse>>,
+    tolerant: BTreeSet<Uuid>,
+
+    timings: BTreeMap<Uuid, Timing>,
+    telemetry: &'a dyn AgentTelemetry,
 
     router: &'a Router,
 
@@ -90,14 +480,22 @@ pub struct Compiler<'a> {
 }
 
 impl<'a> Compiler<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         cache: &'a mut CompilerCache,
         did_resolver: &'a dyn DidResolver,
-        //protocols: Protocols<'a>,
+        validators: &'a Validators,
+        conflicts: &'a ConflictStrategies,
+        protocols: &'a ProtocolRegistry,
+        limits: PublicLimits,
+        max_scan_batch: usize,
+        rng: &'a RngSource,
         sig_key: &'a DidKeyPair,
         enc_key: &'a PathedKey,
         com_key: &'a PathedKey,
+        legacy_com_keys: &'a [SecretKey],
         router: &'a Router,
+        telemetry: &'a dyn AgentTelemetry,
         tenant: Did
     ) -> Self {
         Compiler{
@@ -107,13 +505,27 @@ impl<'a> Compiler<'a> {
             mutable_requests: Some(Vec::new()),
             waiting: Some(Vec::new()),
             completed: Some(BTreeMap::default()),
+            tolerant: BTreeSet::default(),
+            timings: BTreeMap::default(),
+            telemetry,
             router,
             memory: CompilerMemory {
-                create_index: BTreeMap::default(),
+                create_index: cache.create_index.clone().unwrap_or_default(),
+                shared: BTreeMap::default(),
+                derived: Mutex::default(),
+                roles: Mutex::default(),
+                derivations: AtomicUsize::new(0),
                 did_resolver,
+                validators,
+                conflicts,
+                protocols,
+                limits,
+                max_scan_batch,
+                rng,
                 sig_key,
                 enc_key,
                 com_key,
+                legacy_com_keys,
                 tenant,
             },
             cache
@@ -122,10 +534,11 @@ impl<'a> Compiler<'a> {
 
     pub async fn add_command(&mut self, command: BoxCommand, dids: Option<Vec<Did>>) -> Result<(), Error> {
         let dids = dids.unwrap_or(vec![self.memory.tenant().clone()]);
-        let id = Uuid::new_v4();
+        let id = self.memory.uuid();
         let order = self.original_requests.as_ref().unwrap().len();
         self.original_requests.as_mut().unwrap().push(id);
         let header = Header::new(id, Endpoint::default(), order, true);
+        self.timings.insert(id, ((*command).get_op(), Instant::now(), None, BTreeSet::new()));
 
         self.add_tasks(Task::waiting(id, header.clone(), Callback::new(Complete::new_first), vec![
             Task::ready(header, Send::New(command, dids))
@@ -193,14 +606,60 @@ impl<'a> Compiler<'a> {
         for (uuid, task) in tasks {
             match task {
                 Task::Ready(header, command) => self.add_ready(uuid, header, command),
-                Task::Request(header, request) => {self.requests.as_mut().unwrap().push((uuid, header, request));},
-                Task::MutableRequest(header, request, prio) => {self.mutable_requests.as_mut().unwrap().push((uuid, header, request, prio));},
+                Task::Request(header, request) => {
+                    self.reached(&header);
+                    self.requests.as_mut().unwrap().push((uuid, header, request));
+                },
+                Task::MutableRequest(header, request, prio) => {
+                    let request = if request.is_guarded() && self.cache.lacks(&header.endpoint, FEATURE_GUARDED_UPDATE) {
+                        log::debug!("{} has no guarded updates, sending {:?} unguarded", header.endpoint.1, request);
+                        request.unguarded()
+                    } else {request};
+                    let request = if request.is_receipted() && self.cache.lacks(&header.endpoint, FEATURE_RECEIPT) {
+                        log::debug!("{} gives no receipts, sending {:?} without", header.endpoint.1, request);
+                        request.without_receipt()
+                    } else {request};
+                    match self.memory.check_domain(&header, &request) {
+                        Ok(()) => {
+                            self.reached(&header);
+                            self.mutable_requests.as_mut().unwrap().push((uuid, header, request, prio));
+                        },
+                        Err(e) => {self.completed.as_mut().unwrap().insert(uuid, Box::new(Arc::new(e)));}
+                    }
+                },
                 Task::Waiting(header, callback, ids) => {self.waiting.as_mut().unwrap().push((uuid, header, callback, ids));},
+                Task::Tolerant(header, callback, ids) => {
+                    self.tolerant.insert(uuid);
+                    self.waiting.as_mut().unwrap().push((uuid, header, callback, ids));
+                },
                 Task::Completed(completed) => {self.completed.as_mut().unwrap().insert(uuid, completed);},
             }
         }
     }
 
+    fn reached(&mut self, header: &Header) {
+        if let Some((_, _, _, endpoints)) = self.timings.get_mut(&header.oid) {
+            endpoints.insert(header.endpoint.clone());
+        }
+    }
+
+    fn finish_timings(&mut self) {
+        let completed = self.completed.as_ref().unwrap();
+        for (uuid, (_, _, finished, _)) in self.timings.iter_mut() {
+            if finished.is_none() && completed.contains_key(uuid) {
+                *finished = Some(Instant::now());
+            }
+        }
+    }
+
+    fn report(&self, uuid: &Uuid, success: bool) {
+        if let Some((op, started, finished, endpoints)) = self.timings.get(uuid) {
+            let outcome = if success {Outcome::Success} else {Outcome::Failure};
+            let duration = finished.unwrap_or_else(Instant::now).duration_since(*started);
+            self.telemetry.record(op, outcome, duration, endpoints.len());
+        }
+    }
+
     async fn process_ready(&mut self) {
         while !self.ready.as_ref().unwrap().is_empty() {
             for org_uuid in self.original_requests.clone().unwrap() {
@@ -222,7 +681,7 @@ impl<'a> Compiler<'a> {
                     let responses: Responses = ids.iter().map(|id| {
                         self.completed.as_ref().unwrap().get(id).unwrap().clone()
                     }).collect();
-                    if responses.iter().any(|r| r.downcast_ref::<Arc<Error>>().is_some()) {
+                    if !self.tolerant.remove(&uuid) && responses.iter().any(|r| r.downcast_ref::<Arc<Error>>().is_some()) {
                         let errors: Vec<Box<Arc<Error>>> = responses.into_iter().flat_map(|r| r.downcast::<Arc<Error>>().ok()).collect();
                         let error = Error::multi(errors);
                         self.completed.as_mut().unwrap().insert(uuid, Box::new(Arc::new(error)));
@@ -236,7 +695,8 @@ impl<'a> Compiler<'a> {
 
             self.completed = Some(BTreeMap::from_iter(self.completed.take().unwrap().into_iter().flat_map(|(uuid, res)| {
                 if self.waiting.as_ref().unwrap().iter().any(|(_, _, _, ids)| ids.contains(&uuid)) ||
-                    self.original_requests.as_ref().unwrap().contains(&uuid) {
+                    self.original_requests.as_ref().unwrap().contains(&uuid) ||
+                    self.memory.is_shared(&uuid) {
                     Some((uuid, res))
                 } else {None}
             })));
@@ -246,13 +706,18 @@ impl<'a> Compiler<'a> {
 
     async fn process_requests(&mut self) {
         let mut requests: BTreeMap<Endpoint, Vec<(Uuid, Box<DwnRequest>)>> = BTreeMap::new();
+        let mut unproven = BTreeMap::new();
         let keys: Vec<(Endpoint, Uuid)> = (0..self.requests.as_ref().unwrap().len()).flat_map(|_| {
             let (uuid, header, req) = self.requests.as_mut().unwrap().remove(0);
             if let Some(ouid) = self.requests.as_ref().unwrap().iter().find_map(|(ouid, oheader, oreq)| Some(ouid).filter(|_| header.enc == oheader.enc && header.endpoint == oheader.endpoint && req == *oreq)) {
                 self.wait_on(uuid, header, *ouid);
                 None
             } else {
-                let val = (uuid, Box::new(req.into_dwn_request().unwrap()));
+                let val = (uuid, Box::new(self.cache.read_request(&header.endpoint, req.clone()).unwrap()));
+                //Sent again with a proof when the Dwn no longer knows the session
+                if let DwnRequest::ReadPrivateSession(SessionRead{discover, proof: None, ..}) = &*val.1 {
+                    unproven.insert(uuid, (header.clone(), req, discover.clone()));
+                }
                 match requests.get_mut(&header.endpoint) {
                     Some(ep_vec) => {ep_vec.push(val);},
                     None => {requests.insert(header.endpoint.clone(), vec![val]);}
@@ -261,62 +726,84 @@ impl<'a> Compiler<'a> {
             }
         }).collect::<Vec<_>>();
 
-        let responses: Vec<(Uuid, BoxResponse)> = match self.router.send(requests).await {
-            Err(e) => {
-                let error = Box::new(Arc::new(e)) as BoxResponse;
-                keys.into_iter().map(|(_, id)| (id, error.clone())).collect()
-            },
-            Ok(mut resps) => keys.into_iter().map(|(ep, uuid)|
-                (uuid, Box::new(resps.get_mut(&ep).unwrap().remove(&uuid).unwrap()) as BoxResponse)
-            ).collect()
-        };
+        let mut resps = self.router.send(requests).await;
+        let mut responses: Vec<(Uuid, BoxResponse)> = Vec::new();
+        for (ep, uuid) in keys {
+            match resps.get_mut(&ep).unwrap().remove(&uuid).unwrap() {
+                Err(e) => responses.push((uuid, Box::new(e))),
+                Ok(response) => match unproven.remove(&uuid) {
+                    Some((header, req, discover)) if response.is_invalid_auth() => {
+                        self.cache.forget_proven(&ep, &discover);
+                        self.requests.as_mut().unwrap().push((uuid, header, req));
+                    },
+                    _ => responses.push((uuid, Box::new(response)))
+                }
+            }
+        }
 
         self.completed.as_mut().unwrap().extend(responses);
     }
 
+    //Requests to the same id are ordered by priority, the submission order of their command for
+    //writes, and only the highest is sent. A write after a delete can not be folded into it, the
+    //delete is sent alone and what follows it waits for the next round trip
     async fn process_mutable_requests(&mut self) {
+        let mut queued: BTreeMap<(Endpoint, Uuid), Vec<MutableRequestPayload>> = BTreeMap::new();
+        for payload in self.mutable_requests.replace(Vec::new()).unwrap() {
+            queued.entry((payload.1.endpoint.clone(), payload.2.get_id())).or_default().push(payload);
+        }
         let mut requests: BTreeMap<(Endpoint, Uuid), (Uuid, MutableAgentRequest, usize)> = BTreeMap::new();
-         for _ in 0..self.mutable_requests.as_ref().unwrap().len() {
-            let (uuid, header, req, prio) = self.mutable_requests.as_mut().unwrap().remove(0);
-            let key = (header.endpoint.clone(), req.get_id());
-            if let Some((ouid, _, oprio)) = requests.get(&key) {
-                if prio > *oprio {
-                    self.completed.as_mut().unwrap().insert(*ouid, Box::new(()) as BoxResponse);
-                    requests.remove(&key);
-                    requests.insert(key, (uuid, req, prio));
-                } else {
-                    self.completed.as_mut().unwrap().insert(uuid, Box::new(()) as BoxResponse);
-                }
-            } else {
-                requests.insert(key.clone(), (uuid, req, prio));
+        for (key, mut queue) in queued {
+            for (_, _, req, _) in &queue {
+                if let Some(discover) = req.replaces() {self.cache.invalidate(&key.0, &discover);}
+            }
+            queue.sort_by_key(|(_, _, _, prio)| *prio);
+            //Guarded updates each carry a payload to merge, the rest wait for the next round instead of being dropped
+            let split = if queue.iter().any(|(_, _, req, _)| req.is_guarded()) {1} else {
+                queue.windows(2).position(|w|
+                    w[0].2.is_delete() && !w[1].2.is_delete() && w[1].3 > w[0].3
+                ).map(|p| p+1).unwrap_or(queue.len())
+            };
+            self.mutable_requests.as_mut().unwrap().extend(queue.split_off(split));
+            let top = queue.iter().map(|(_, _, _, prio)| *prio).max().unwrap();
+            let sent = queue.iter().position(|(_, _, _, prio)| *prio == top).unwrap();
+            let (uuid, _, req, prio) = queue.remove(sent);
+            for (ouid, _, _, _) in queue {
+                self.completed.as_mut().unwrap().insert(ouid, Box::new(()) as BoxResponse);
             }
+            requests.insert(key, (uuid, req, prio));
         }
 
         let mut ep_requests: BTreeMap<Endpoint, Vec<(Uuid, Box<DwnRequest>)>> = BTreeMap::new();
 
-        let keys = requests.into_iter().map(|((ep, _), (uuid, req, _))| {
+        let mut usage = BTreeMap::new();
+        let keys = requests.into_iter().map(|((ep, id), (uuid, req, _))| {
+            let change = req.usage();
             let val = (uuid, Box::new(req.into_dwn_request().unwrap()));
+            if let Some(change) = change {usage.insert(uuid, change.sized(&val.1));}
             match ep_requests.get_mut(&ep) {
                 Some(ep_vec) => {ep_vec.push(val);},
                 None => {ep_requests.insert(ep.clone(), vec![val]);}
             }
-            (ep, uuid)
+            (ep, uuid, id)
         }).collect::<Vec<_>>();
 
 
-        let responses: Vec<(Uuid, BoxResponse)> = match self.router.send(ep_requests).await {
-            Err(e) => {
-                let error = Box::new(Arc::new(e)) as BoxResponse;
-                keys.into_iter().map(|(_, id)| (id, error.clone())).collect()
-            },
-            Ok(mut resps) => keys.into_iter().map(|(ep, uuid)|
-                (uuid, Box::new(resps.get_mut(&ep).unwrap().remove(&uuid).unwrap()) as BoxResponse)
-            ).collect()
-        };
+        let mut resps = self.router.send(ep_requests).await;
+        let responses: Vec<(Uuid, BoxResponse)> = keys.into_iter().map(|(ep, uuid, id)| (uuid, match resps.get_mut(&ep).unwrap().remove(&uuid).unwrap() {
+            Err(e) => Box::new(e) as BoxResponse,
+            Ok(response) => {
+                let response = response.with_id(id);
+                if let (DwnResponse::Empty | DwnResponse::Receipt(_), Some(change)) = (&response, usage.remove(&uuid)) {
+                    self.cache.record_usage(ep, id, change);
+                }
+                Box::new(response) as BoxResponse
+            }
+        })).collect();
         self.completed.as_mut().unwrap().extend(responses);
     }
 
-    pub async fn compile<'b>(mut self) -> Vec<Vec<Box<dyn Response + 'static>>> {
+    pub async fn compile<'b>(mut self) -> Vec<Result<Responses, Error>> {
         loop {
             self.process_ready().await;
             self.process_waiting().await;
@@ -330,10 +817,23 @@ impl<'a> Compiler<'a> {
                 }
                 self.process_waiting().await;
             }
+            self.finish_timings();
         }
+        self.cache.derivations += self.memory.derivations();
         let mut responses = self.completed.replace(Default::default()).unwrap();
-        self.original_requests.replace(Default::default()).unwrap().into_iter().map(|uuid| {
-            *responses.remove(&uuid).unwrap().downcast::<Vec<Box<dyn Response>>>().unwrap()
-        }).collect()
+        let results = self.original_requests.replace(Default::default()).unwrap().into_iter().map(|uuid| {
+            let response = responses.remove(&uuid).unwrap();
+            self.report(&uuid, response.downcast_ref::<Arc<Error>>().is_none());
+            match response.downcast::<Arc<Error>>() {
+                Ok(error) => Err(Error::arc(*error)),
+                Err(response) => Ok(*response.downcast::<Responses>()?)
+            }
+        }).collect::<Vec<_>>();
+        //A failed create may have taken an index without writing it, so the next compile probes again
+        if let Some(create_index) = self.cache.create_index.as_mut() {
+            *create_index = if results.iter().any(|r| r.is_err()) {BTreeMap::new()}
+                else {std::mem::take(&mut self.memory.create_index)};
+        }
+        results
     }
 }
@@ -0,0 +1,231 @@
+use super::Error;
+use crate::common::{Convert, StoreLock};
+use crate::common::backoff::CancelToken;
+
+use super::structs::{BoxCommand, Record, RecordPath};
+use crate::model::protocol::Protocol;
+use super::compiler::CompilerCache;
+use super::scripts::CreatePrivate;
+use super::Agent;
+
+use std::path::{Path, PathBuf};
+
+use simple_crypto::Hashable;
+use simple_database::KeyValueStore;
+
+use mime::Mime;
+use schemars::JsonSchema;
+use serde::{Serialize, Deserialize};
+
+//Each file is one payload, bigger files fail and belong in a BlobWriter
+pub const DEFAULT_MAX_FILE_SIZE: usize = 4 * 1024 * 1024;
+pub const DEFAULT_IMPORT_CONCURRENCY: usize = 8;
+
+//Where a file goes and under which protocol, None leaves the file out
+pub type ProtocolMap = fn(&Path, &Mime) -> Option<(RecordPath, Protocol)>;
+
+//Called after each file with the files done so far and the total
+pub type ImportProgress = Box<dyn Fn(usize, usize, &Path) + Send + Sync>;
+
+//The payload of an imported record, protocols for imports need a schema accepting it
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ImportedFile {
+    pub name: String,
+    pub mime: String,
+    pub data: String
+}
+
+impl ImportedFile {
+    pub fn schema() -> String {
+        serde_json::to_string(&schemars::schema_for!(ImportedFile)).unwrap()
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<(Self, Vec<u8>), Error> {
+        let file = serde_json::from_slice::<ImportedFile>(payload)?;
+        let data = Convert::Base64UrlUnpadded.decode(&file.data)?;
+        Ok((file, data))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub imported: Vec<PathBuf>,
+    //Already imported with the same content or left out by the protocol map
+    pub skipped: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, String)>,
+    //Files after the cancel are in none of the lists
+    pub cancelled: bool
+}
+
+//Sniffs the first bytes, anything unknown that is utf8 counts as text
+pub fn detect_mime(bytes: &[u8]) -> Mime {
+    const MAGIC: [(&[u8], &str); 6] = [
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+    ];
+    let mime = MAGIC.iter().find(|(magic, _)| bytes.starts_with(magic)).map(|(_, mime)| *mime)
+        .unwrap_or(if std::str::from_utf8(bytes).is_ok() {"text/plain"} else {"application/octet-stream"});
+    mime.parse().unwrap()
+}
+
+//Imports each file of a directory as a private record, a ledger of what was imported makes runs resumable
+pub struct FileImporter<'a> {
+    agent: &'a Agent,
+    cache: CompilerCache,
+    protocol_map: ProtocolMap,
+    ledger: Box<dyn KeyValueStore>,
+    max_size: usize,
+    concurrency: usize,
+    progress: Option<ImportProgress>,
+    cancel: Option<CancelToken>,
+    _lock: StoreLock
+}
+
+impl<'a> FileImporter<'a> {
+    pub async fn new<KVS: KeyValueStore + 'static>(
+        agent: &'a Agent, ledger: Option<PathBuf>, protocol_map: ProtocolMap
+    ) -> Result<Self, Error> {
+        let lock = match ledger {
+            Some(path) => StoreLock::acquire(&path)?,
+            None => StoreLock::default_dir("FileImporter")?
+        };
+        Self::locked::<KVS>(agent, lock, protocol_map).await
+    }
+
+    //With a ledger in a directory of its own that is removed once the importer is dropped
+    pub async fn scratch<KVS: KeyValueStore + 'static>(
+        agent: &'a Agent, protocol_map: ProtocolMap
+    ) -> Result<Self, Error> {
+        Self::locked::<KVS>(agent, StoreLock::scratch("FileImporter")?, protocol_map).await
+    }
+
+    async fn locked<KVS: KeyValueStore + 'static>(
+        agent: &'a Agent, lock: StoreLock, protocol_map: ProtocolMap
+    ) -> Result<Self, Error> {
+        let ledger = Box::new(KVS::new(lock.path().to_path_buf()).await?);
+        Ok(FileImporter{
+            agent,
+            cache: CompilerCache::default(),
+            protocol_map,
+            ledger,
+            max_size: DEFAULT_MAX_FILE_SIZE,
+            concurrency: DEFAULT_IMPORT_CONCURRENCY,
+            progress: None,
+            cancel: None,
+            _lock: lock
+        })
+    }
+
+    pub fn with_max_size(mut self, bytes: usize) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn on_progress(mut self, progress: impl Fn(usize, usize, &Path) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    //Checked between batches, a batch already sent is finished
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    pub async fn import(&mut self, dir: &Path) -> Result<ImportSummary, Error> {
+        let mut files = Vec::new();
+        Self::walk(dir, &mut files)?;
+        let total = files.len();
+        let mut summary = ImportSummary::default();
+        let mut done = 0;
+        for batch in files.chunks(self.concurrency) {
+            if self.cancel.as_ref().map(|c| c.is_cancelled()).unwrap_or(false) {
+                summary.cancelled = true;
+                break;
+            }
+            let mut commands: Vec<(PathBuf, BoxCommand)> = Vec::new();
+            let mut keys = Vec::new();
+            for file in batch {
+                match self.prepare(file).await {
+                    Ok(Some((key, command))) => {
+                        keys.push((file.clone(), key));
+                        commands.push((file.clone(), command));
+                    },
+                    Ok(None) => {
+                        summary.skipped.push(file.clone());
+                        done += 1;
+                        self.report(done, total, file);
+                    },
+                    Err(error) => {
+                        summary.failed.push((file.clone(), error.to_string()));
+                        done += 1;
+                        self.report(done, total, file);
+                    }
+                }
+            }
+            if commands.is_empty() {continue;}
+            let mut results = self.agent.process_commands_keyed(&mut self.cache, commands).await?;
+            for (file, key) in keys {
+                let result = results.remove(&file).ok_or(Error::bad_response("Missing import result"))
+                    .and_then(|r| r?.into_iter().next().ok_or(Error::bad_response("Empty import result")));
+                match result {
+                    Ok(response) if response.downcast_ref::<()>().is_some() => {
+                        self.ledger.set(&key, &[]).await?;
+                        summary.imported.push(file.clone());
+                    },
+                    Ok(response) => summary.failed.push((file.clone(), response.downcast_ref::<&'static str>()
+                        .map(|r| r.to_string()).unwrap_or(format!("{:?}", response)))),
+                    Err(error) => summary.failed.push((file.clone(), error.to_string()))
+                }
+                done += 1;
+                self.report(done, total, &file);
+            }
+        }
+        Ok(summary)
+    }
+
+    //None when the file was already imported or is not mapped to a protocol
+    async fn prepare(&self, file: &Path) -> Result<Option<(Vec<u8>, BoxCommand)>, Error> {
+        let size = std::fs::metadata(file)?.len() as usize;
+        if size > self.max_size {
+            return Err(Error::bad_request(&format!("File is {} bytes, the max is {}", size, self.max_size)));
+        }
+        let bytes = std::fs::read(file)?;
+        let mime = detect_mime(&bytes);
+        let Some((path, protocol)) = (self.protocol_map)(file, &mime) else {return Ok(None)};
+        let key = [path.to_string().as_bytes(), &bytes].concat().hash_bytes();
+        if self.ledger.get(&key).await?.is_some() {return Ok(None);}
+        let name = file.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let payload = serde_json::to_vec(&ImportedFile{
+            name, mime: mime.to_string(), data: Convert::Base64UrlUnpadded.encode(&bytes)
+        })?;
+        Ok(Some((key, CreatePrivate::new(Record::new(path, protocol, &payload), None))))
+    }
+
+    fn report(&self, done: usize, total: usize, file: &Path) {
+        if let Some(progress) = self.progress.as_ref() {progress(done, total, file);}
+    }
+
+    //Sorted so batches and progress are the same every run
+    fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+        let mut entries = std::fs::read_dir(dir)?.map(|e| Ok(e?.path())).collect::<Result<Vec<_>, Error>>()?;
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() {
+                Self::walk(&entry, files)?;
+            } else if entry.is_file() {
+                files.push(entry);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use super::Error;
+use crate::common::StoreLock;
+
+use super::structs::{BoxCommand, Record};
+use super::traits::Command;
+
+use std::path::PathBuf;
+
+use simple_database::KeyValueStore;
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+//A dispatched batch, records is only set when every command can be checked against the Dwn
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub token: String,
+    pub commands: Vec<String>,
+    pub records: Option<Vec<Record>>,
+    pub dispatched: DateTime<Utc>,
+    pub completed: bool
+}
+
+impl JournalEntry {
+    fn new(token: &str, commands: &[BoxCommand]) -> Self {
+        JournalEntry{
+            token: token.to_string(),
+            commands: commands.iter().map(|c| Command::serialize(&**c)).collect(),
+            records: commands.iter().map(|c| c.idempotent_record()).collect(),
+            dispatched: Utc::now(),
+            completed: false
+        }
+    }
+}
+
+//Write ahead log of command batches, entries stay until acknowledged
+#[derive(Debug, Clone)]
+pub struct CommandJournal {
+    store: Box<dyn KeyValueStore>,
+    //None for a store handed in, whoever made it holds it
+    _lock: Option<StoreLock>
+}
+
+impl CommandJournal {
+    pub async fn new<KVS: KeyValueStore + 'static>(path: Option<PathBuf>) -> Result<Self, Error> {
+        let lock = match path {
+            Some(path) => StoreLock::acquire(&path)?,
+            None => StoreLock::default_dir("CommandJournal")?
+        };
+        Self::locked::<KVS>(lock).await
+    }
+
+    //In a directory of its own that is removed once the journal is dropped
+    pub async fn scratch<KVS: KeyValueStore + 'static>() -> Result<Self, Error> {
+        Self::locked::<KVS>(StoreLock::scratch("CommandJournal")?).await
+    }
+
+    async fn locked<KVS: KeyValueStore + 'static>(lock: StoreLock) -> Result<Self, Error> {
+        let store = Box::new(KVS::new(lock.path().to_path_buf()).await?);
+        Ok(CommandJournal{store, _lock: Some(lock)})
+    }
+
+    pub fn from_store(store: Box<dyn KeyValueStore>) -> Self {
+        CommandJournal{store, _lock: None}
+    }
+
+    //Clears every entry, in doubt or not
+    pub async fn reset(&self) -> Result<(), Error> {
+        Ok(self.store.clear().await?)
+    }
+
+    async fn get(&self, token: &str) -> Result<Option<JournalEntry>, Error> {
+        Ok(self.store.get(token.as_bytes()).await?.map(|e|
+            serde_json::from_slice::<JournalEntry>(&e)
+        ).transpose()?)
+    }
+
+    async fn set(&self, entry: &JournalEntry) -> Result<(), Error> {
+        Ok(self.store.set(entry.token.as_bytes(), &serde_json::to_vec(entry)?).await?)
+    }
+
+    pub(crate) async fn dispatch(&self, token: &str, commands: &[BoxCommand]) -> Result<(), Error> {
+        if self.get(token).await?.is_some() {
+            return Err(Error::bad_request(&format!("Token {} was already dispatched", token)));
+        }
+        self.set(&JournalEntry::new(token, commands)).await
+    }
+
+    pub(crate) async fn complete(&self, token: &str) -> Result<(), Error> {
+        let mut entry = self.get(token).await?.ok_or(Error::not_found(token))?;
+        entry.completed = true;
+        self.set(&entry).await
+    }
+
+    pub async fn acknowledge(&self, token: &str) -> Result<(), Error> {
+        Ok(self.store.delete(token.as_bytes()).await?)
+    }
+
+    pub async fn in_doubt(&self) -> Result<Vec<JournalEntry>, Error> {
+        let mut entries = self.store.values().await?.into_iter().map(|e|
+            serde_json::from_slice::<JournalEntry>(&e)
+        ).collect::<Result<Vec<_>, _>>()?;
+        entries.retain(|e| !e.completed);
+        entries.sort_by_key(|e| e.dispatched);
+        Ok(entries)
+    }
+}
@@ -5,27 +5,59 @@ use super::permission::PermissionOptions;
 use super::traits::Command;
 use super::structs::{
     PrivateRecord,
+    AgentKeys,
     BoxCommand,
+    ShareEnvelope,
+    ShareFailures,
+    ShareGroup,
+    Subscribers,
+    RecordUpdated,
+    SharedPermissions,
+    Placement,
+    CapabilityToken,
+    ChildSlot,
+    DmMessage,
+    ShareUpgradeRequest,
+    ShareResponse,
+    PendingShareUpgrade,
+    RedactionSpec,
+    RedactedView,
+    SharedFilter,
+    ParentPolicy,
+    EndpointPolicy,
+    ScanPredicate,
+    ScanStop,
+    UsageGroup,
+    BlobChunkRef,
     RecordPath,
     Responses,
     Callback,
     Header,
+    TypedRecord,
     Record,
     Tasks,
     Task,
 };
+use crate::model::protocol::{SystemProtocols, Protocol};
+#[allow(deprecated)]
 use super::commands;
+use super::Agent;
 
 use crate::dids::signing::Signer;
 use crate::dids::Did;
-use crate::dwn::structs::PublicRecord;
+use crate::dwn::structs::{PublicRecord, DmCursor};
 
 use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
 
 use simple_database::database::{Filters, Filter, SortOptions};
-use simple_crypto::PublicKey;
+use crate::common::FilterExpr;
+use simple_crypto::SecretKey;
 
 use serde::Serialize;
+use serde::de::DeserializeOwned;
+use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
 #[derive(Serialize, Debug, Clone)]
@@ -35,12 +67,91 @@ impl CreatePrivate {
     pub fn new(record: Record, p_opts: Option<PermissionOptions>) -> BoxCommand {
         Box::new(commands::CreatePrivate::new(record, p_opts))
     }
+
+    pub fn with_policy(
+        record: Record, p_opts: Option<PermissionOptions>, policy: ParentPolicy
+    ) -> BoxCommand {
+        Box::new(commands::CreatePrivate::with_policy(record, p_opts, policy))
+    }
+
+    pub fn with_defaults(path: RecordPath, protocol: Protocol) -> BoxCommand {
+        Box::new(commands::CreatePrivate::new(Record::from_defaults(path, protocol), None))
+    }
+}
+
+const MAX_CREATE_ATTEMPTS: usize = 3;
+
+//Identical GetOrCreates in one batch share the read and the create through the compilers deduplication
+#[derive(Serialize, Debug, Clone)]
+pub enum GetOrCreate {
+    New(RecordPath, Protocol),
+    Read(Responses, RecordPath, Protocol, usize),
+    Created(Responses, Record, usize),
+}
+
+impl GetOrCreate {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(path: RecordPath, protocol: Protocol) -> BoxCommand {
+        Box::new(GetOrCreate::New(path, protocol))
+    }
+
+    fn read(uuid: Uuid, header: Header, path: RecordPath, protocol: Protocol, attempt: usize) -> Result<Tasks, Error> {
+        let read = commands::ReadPrivate::path(path.clone());
+        let callback = move |r: Responses| {Self::Read(r, path, protocol, attempt)};
+        Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+            Task::ready(header, read)
+        ])
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for GetOrCreate {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::New(path, protocol) => Self::read(uuid, header, path, protocol, 0),
+            Self::Read(mut responses, path, protocol, attempt) => {
+                let (record, exists) = *responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?;
+                match record {
+                    Some(record) if record.protocol == protocol => Task::completed(uuid, (*record).into_record()),
+                    Some(_) => Err(Error::bad_request("Record exists under a different protocol")),
+                    //A create would only conflict with it
+                    None if exists => Err(Error::insufficent_permission()),
+                    None => {
+                        let record = Record::from_defaults(path, protocol);
+                        let create = commands::CreatePrivate::new(record.clone(), None);
+                        let callback = move |r: Responses| {Self::Created(r, record, attempt)};
+                        Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                            Task::ready(header, create)
+                        ])
+                    }
+                }
+            },
+            Self::Created(mut responses, record, attempt) => {
+                //Another writer created it first, the stored record is returned instead
+                if responses.remove(0).downcast_ref::<&str>().is_some() {
+                    if attempt+1 >= MAX_CREATE_ATTEMPTS {
+                        return Err(Error::conflict(&format!("GetOrCreate of {} kept conflicting", record.path)));
+                    }
+                    return Self::read(uuid, header, record.path, record.protocol, attempt+1);
+                }
+                Task::completed(uuid, record)
+            }
+        }
+    }
 }
 
 #[derive(Serialize, Debug, Clone)]
 pub enum ReadPrivate {
     New(RecordPath),
     Child(RecordPath, usize),
+    Shared(Box<SharedPermissions>),
+    SharedFrom(Box<SharedPermissions>, Did),
+    Capability(Box<CapabilityToken>),
+    Placed(Responses, RecordPath),
+    Remote(Responses),
     Complete(Responses),
 }
 
@@ -53,6 +164,39 @@ impl ReadPrivate {
     pub fn child(path: RecordPath, index: usize) -> BoxCommand {
         Box::new(ReadPrivate::Child(path, index))
     }
+
+    pub fn shared(shared: SharedPermissions) -> BoxCommand {
+        Box::new(ReadPrivate::Shared(Box::new(shared)))
+    }
+
+    //Reads the shared record from the sharers Dwns instead of our own
+    pub fn shared_from(shared: SharedPermissions, sharer: Did) -> BoxCommand {
+        Box::new(ReadPrivate::SharedFrom(Box::new(shared), sharer))
+    }
+
+    //Works without any relationship to the owner, the token names the did to read from
+    pub fn from_capability(token: &str) -> Result<BoxCommand, Error> {
+        Ok(Box::new(ReadPrivate::Capability(Box::new(CapabilityToken::decode(token)?))))
+    }
+
+    //Reads and decodes the payload, None when there is no record at the path
+    pub async fn read_typed<T: Serialize + DeserializeOwned>(
+        agent: &Agent, cache: &mut CompilerCache, path: RecordPath
+    ) -> Result<Option<TypedRecord<T>>, Error> {
+        agent.run::<Option<Record>>(cache, Self::new(path)).await?.map(TypedRecord::from_record).transpose()
+    }
+}
+
+//BlobReader reads blobs a chunk at a time with these
+pub struct ReadBlob {}
+impl ReadBlob {
+    pub fn manifest(path: RecordPath) -> BoxCommand {
+        Box::new(commands::ReadBlob::manifest(path))
+    }
+
+    pub fn chunk(path: RecordPath, chunk: BlobChunkRef) -> BoxCommand {
+        Box::new(commands::ReadBlob::chunk(path, chunk))
+    }
 }
 
 #[async_trait::async_trait]
@@ -62,16 +206,60 @@ impl Command for ReadPrivate {
         _: &mut CompilerMemory, _: &mut CompilerCache
     ) -> Result<Tasks, Error> {
         match *self {
+            //The placement is read alongside the record so moved records cost no extra round trip
             Self::New(path) => {
-                Task::waiting(uuid, header.clone(), Callback::new(Self::Complete), vec![
-                    Task::ready(header, commands::ReadPrivate::path(path))
-                ])
+                let tasks = vec![
+                    Task::ready(header.clone(), commands::ReadPrivate::path(path.clone())),
+                    Task::ready(header.clone(), commands::ReadPrivate::path(Placement::path(&path)))
+                ];
+                let callback = move |r: Responses| {Self::Placed(r, path)};
+                Task::waiting(uuid, header, Callback::new(callback), tasks)
+            },
+            Self::Placed(mut responses, path) => {
+                let placement = Placement::from_record(
+                    responses.remove(1).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                )?;
+                let found = responses[0].downcast_ref::<(Option<Box<PrivateRecord>>, bool)>()
+                    .map(|r| r.0.is_some()).unwrap_or(true);
+                match placement {
+                    Some(placement) if !found => {
+                        Task::waiting(uuid, header.clone(), Callback::new(Self::Remote), vec![
+                            Task::ready(header, commands::Send::new(
+                                commands::ReadPrivate::path(path), vec![placement.did]
+                            ))
+                        ])
+                    },
+                    _ => Task::next(uuid, header, Self::Complete(responses))
+                }
+            },
+            Self::Remote(mut responses) => {
+                let pr = responses.remove(0).downcast::<Responses>()?.into_iter().find_map(|r|
+                    r.downcast::<(Option<Box<PrivateRecord>>, bool)>().ok().and_then(|r| r.0)
+                );
+                Task::completed(uuid, pr.map(|pr| (*pr).into_record()))
             },
             Self::Child(path, index) => {
                 Task::waiting(uuid, header.clone(), Callback::new(Self::Complete), vec![
                     Task::ready(header, commands::ReadPrivateChild::new(path, index))
                 ])
             },
+            Self::Shared(shared) => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Complete), vec![
+                    Task::ready(header, commands::ReadShared::new(shared))
+                ])
+            },
+            Self::SharedFrom(shared, sharer) => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Remote), vec![
+                    Task::ready(header, commands::Send::new(commands::ReadShared::new(shared), vec![sharer]))
+                ])
+            },
+            Self::Capability(token) => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Remote), vec![
+                    Task::ready(header, commands::Send::new(
+                        commands::ReadCapability::new(Box::new(token.perms)), vec![token.owner]
+                    ))
+                ])
+            },
             Self::Complete(mut results) => {
                 let pr = results.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0;
                 Task::completed(uuid, pr.map(|pr| (*pr).into_record()))
@@ -81,12 +269,132 @@ impl Command for ReadPrivate {
 }
 
 #[derive(Serialize, Debug, Clone)]
-pub struct UpdatePrivate {}
+pub struct ExistsPath {}
+
+impl ExistsPath {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(path: RecordPath) -> BoxCommand {
+        Box::new(commands::ExistsPath::new(path))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum UpdatePrivate {
+    New(Record, Option<PermissionOptions>),
+    Update(Responses, Record, Option<PermissionOptions>),
+    Notify(Responses, RecordUpdated, Vec<Did>),
+    Notified(Responses, Vec<Did>),
+}
 
 impl UpdatePrivate {
+    //Subscribers of the record are sent a RecordUpdated DM once the update lands
     #[allow(clippy::new_ret_no_self)]
     pub fn new(record: Record, p_opts: Option<PermissionOptions>) -> BoxCommand {
-        Box::new(commands::UpdatePrivate::new(record, p_opts))
+        Box::new(UpdatePrivate::New(record, p_opts))
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for UpdatePrivate {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::New(record, p_opts) => {
+                let sidecar = Subscribers::path(&record.path);
+                let callback = move |r: Responses| {Self::Update(r, record, p_opts)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, commands::ReadPrivate::path(sidecar))
+                ])
+            },
+            Self::Update(mut responses, record, p_opts) => {
+                let subscribers = Subscribers::from_record(
+                    responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                )?;
+                let update = RecordUpdated::new(&record.path, &record.payload);
+                let callback = move |r: Responses| {Self::Notify(r, update, subscribers.members)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, commands::UpdatePrivate::new(record, p_opts))
+                ])
+            },
+            Self::Notify(responses, update, members) => {
+                commands::EnsureEmpty::is_empty(responses)?;
+                let tasks = members.iter().map(|member|
+                    Task::ready(header.clone(), commands::CreateDM::new(
+                        DmMessage::RecordUpdated(update.clone()), member.clone()
+                    ))
+                ).collect::<Vec<_>>();
+                let callback = move |r: Responses| {Self::Notified(r, members)};
+                Task::tolerant(uuid, header, Callback::new(callback), tasks)
+            },
+            //The update itself succeeded, undelivered notifications are only logged
+            Self::Notified(responses, members) => {
+                for (response, member) in responses.into_iter().zip(members) {
+                    if let Some(e) = response.downcast_ref::<Arc<Error>>() {
+                        log::warn!("Could not notify {} of update: {}", member, e);
+                    }
+                }
+                Task::completed(uuid, ())
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MoveRecord {}
+
+impl MoveRecord {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(path: RecordPath, from: Did, to: Did) -> BoxCommand {
+        Box::new(commands::MoveRecord::new(path, from, to))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PublishReadCapability {}
+
+impl PublishReadCapability {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(path: RecordPath, p_opts: PermissionOptions) -> BoxCommand {
+        Box::new(commands::PublishReadCapability::new(path, p_opts))
+    }
+
+    pub fn expiring(path: RecordPath, p_opts: PermissionOptions, expires: DateTime<Utc>) -> BoxCommand {
+        Box::new(commands::PublishReadCapability::expiring(path, p_opts, expires))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RevokeReadCapability {}
+
+impl RevokeReadCapability {
+    //Moves the record to the path given and deletes the capability record, neither the token nor
+    //the keys it handed out read the record after
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(token: &str, to: RecordPath) -> Result<BoxCommand, Error> {
+        Ok(Box::new(commands::RevokeReadCapability::new(Box::new(CapabilityToken::decode(token)?.perms), to)))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AuditAccess {}
+
+impl AuditAccess {
+    //Replaces the records audited on the tenant's Dwns, entries only hold key fingerprints
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(paths: Vec<RecordPath>) -> BoxCommand {
+        Box::new(commands::AuditAccess::new(paths))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ReadAccessLog {}
+
+impl ReadAccessLog {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(since: DateTime<Utc>) -> BoxCommand {
+        Box::new(commands::ReadAccessLog::new(since))
     }
 }
 
@@ -96,7 +404,120 @@ pub struct DeletePrivate {}
 impl DeletePrivate {
     #[allow(clippy::new_ret_no_self)]
     pub fn new(path: RecordPath) -> BoxCommand {
-        Box::new(commands::DeletePrivate::new(path))
+        Box::new(commands::DeletePrivate::new(path, false))
+    }
+
+    //Deletes the record even when it is pinned
+    pub fn override_pins(path: RecordPath) -> BoxCommand {
+        Box::new(commands::DeletePrivate::new(path, true))
+    }
+
+    //Needs a share that grants delete, see RequestShareUpgrade
+    pub fn shared(shared: SharedPermissions, sharer: Did) -> BoxCommand {
+        Box::new(commands::Send::new(commands::DeleteShared::new(Box::new(shared)), vec![sharer]))
+    }
+}
+
+//Pinned paths are refused by the deletes unless they override the pins
+#[derive(Serialize, Debug, Clone)]
+pub struct PinRecord {}
+
+impl PinRecord {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(path: RecordPath) -> BoxCommand {
+        Box::new(commands::PinRecord::new(path))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct UnpinRecord {}
+
+impl UnpinRecord {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(path: RecordPath) -> BoxCommand {
+        Box::new(commands::UnpinRecord::new(path))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ReadPins {}
+
+impl ReadPins {
+    //Only the explicit pins, the system paths are pinned regardless
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> BoxCommand {
+        Box::new(commands::ReadPins::new())
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DeletePrivateChild {}
+
+impl DeletePrivateChild {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(parent_path: RecordPath, index: usize) -> BoxCommand {
+        Box::new(commands::DeletePrivateChild::new(parent_path, index, false))
+    }
+
+    pub fn override_pins(parent_path: RecordPath, index: usize) -> BoxCommand {
+        Box::new(commands::DeletePrivateChild::new(parent_path, index, true))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RelocateRecord {}
+
+impl RelocateRecord {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(from: RecordPath, to: RecordPath) -> BoxCommand {
+        Box::new(commands::RelocateRecord::new(from, to))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ReplaceRecordProtocol {}
+
+impl ReplaceRecordProtocol {
+    //For the rare record meant to change protocol, CreatePrivate and reads fail on a mismatch
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(record: Record, p_opts: Option<PermissionOptions>) -> BoxCommand {
+        Box::new(commands::ReplaceRecordProtocol::new(record, p_opts))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RotateComKey {}
+
+impl RotateComKey {
+    //Run by an agent holding the new com key, returns how many DMs were moved
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(old: SecretKey) -> BoxCommand {
+        Box::new(commands::RotateComKey::new(old))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct UsageReport {}
+
+impl UsageReport {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(group_by: UsageGroup) -> BoxCommand {
+        Box::new(commands::UsageReport::new(group_by))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Send {}
+
+impl Send {
+    //Runs the command on every endpoint of each recipient
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(command: BoxCommand, recipients: Vec<Did>) -> BoxCommand {
+        Box::new(commands::Send::New(command, recipients))
+    }
+
+    pub fn with_policy(command: BoxCommand, recipients: Vec<Did>, policy: EndpointPolicy) -> BoxCommand {
+        Box::new(commands::Send::New(command, recipients).with_policy(policy))
     }
 }
 
@@ -107,14 +528,53 @@ impl CreatePublic {
     pub fn new(record: PublicRecord, signer: Option<Signer>) -> BoxCommand {
         Box::new(commands::CreatePublic::new(record, signer))
     }
+
+    pub fn with_receipt(record: PublicRecord, signer: Option<Signer>) -> BoxCommand {
+        Box::new(commands::CreatePublic::with_receipt(record, signer))
+    }
 }
 
 #[derive(Serialize, Debug, Clone)]
 pub struct ReadPublic {}
 impl ReadPublic {
     #[allow(clippy::new_ret_no_self)]
-    pub fn new(filters: Filters, sort_options: Option<SortOptions>) -> BoxCommand {
-        Box::new(commands::ReadPublic::new(filters, sort_options))
+    pub fn new(filters: impl Into<FilterExpr>, sort_options: Option<SortOptions>) -> BoxCommand {
+        Box::new(commands::ReadPublic::new(filters.into(), sort_options))
+    }
+
+    //Records as they were at the given instant on the tenant's Dwn
+    pub fn as_of(filters: impl Into<FilterExpr>, at: DateTime<Utc>, sort_options: Option<SortOptions>) -> BoxCommand {
+        Box::new(commands::ReadPublic::at(filters.into(), at, sort_options))
+    }
+
+    //Completes with the records, the cursor and the ReadDiagnostics of the records left out
+    pub fn with_diagnostics(filters: impl Into<FilterExpr>, sort_options: Option<SortOptions>) -> BoxCommand {
+        Box::new(commands::ReadPublic::diagnosed(filters.into(), sort_options))
+    }
+}
+
+//Counted by the Dwn and taken on trust, the verified count reads the records and counts the ones that check out
+#[derive(Serialize, Debug, Clone)]
+pub struct CountPublic {}
+impl CountPublic {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(filters: impl Into<FilterExpr>) -> BoxCommand {
+        Box::new(commands::CountPublic::new(filters.into()))
+    }
+
+    pub fn verified(filters: impl Into<FilterExpr>) -> BoxCommand {
+        Box::new(commands::CountPublic::verified(filters.into()))
+    }
+}
+
+//The Dwn indexes every public record by protocol and signer, no index of the caller's is needed
+#[derive(Serialize, Debug, Clone)]
+pub struct ReadPublicByProtocol {}
+impl ReadPublicByProtocol {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(protocol: Uuid, mut extra: Filters) -> BoxCommand {
+        extra.add("protocol", Filter::equal(protocol.to_string()));
+        Box::new(commands::ReadPublic::new(extra.into(), None))
     }
 }
 
@@ -132,14 +592,22 @@ pub struct DeletePublic {}
 impl DeletePublic {
     #[allow(clippy::new_ret_no_self)]
     pub fn new(uuid: Uuid, signer: Option<Signer>) -> BoxCommand {
-        Box::new(commands::DeletePublic::new(uuid, signer))
+        Box::new(commands::DeletePublic::new(uuid, signer, false))
+    }
+
+    pub fn override_pins(uuid: Uuid, signer: Option<Signer>) -> BoxCommand {
+        Box::new(commands::DeletePublic::new(uuid, signer, true))
     }
 }
 
 #[derive(Serialize, Debug, Clone)]
 pub enum Scan {
     New(RecordPath, usize),
+    Until(RecordPath, ScanPredicate),
+    Streaming(RecordPath, usize, usize),
     Completed(Responses),
+    Stopped(Responses),
+    Paged(Responses),
 }
 
 impl Scan {
@@ -147,6 +615,17 @@ impl Scan {
     pub fn new(path: RecordPath, index: usize) -> BoxCommand {
         Box::new(Scan::New(path, index))
     }
+
+    //Completes with the records read and why the scan stopped
+    pub fn until(path: RecordPath, predicate: ScanPredicate) -> BoxCommand {
+        Box::new(Scan::Until(path, predicate))
+    }
+
+    //Completes with up to a page of records and the index the next page starts at,
+    //None once the scan reached a missing child
+    pub fn streaming(path: RecordPath, index: usize, page: usize) -> BoxCommand {
+        Box::new(Scan::Streaming(path, index, page))
+    }
 }
 
 #[async_trait::async_trait]
@@ -161,21 +640,126 @@ impl Command for Scan {
                     Task::ready(header, commands::Scan::new(path, start))
                 ])
             },
+            Self::Until(path, predicate) => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Stopped), vec![
+                    Task::ready(header, commands::Scan::until(path, predicate))
+                ])
+            },
+            Self::Streaming(path, start, page) => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Paged), vec![
+                    Task::ready(header, commands::Scan::streaming(path, start, page))
+                ])
+            },
             Self::Completed(mut responses) => {
                 let records = *responses.remove(0).downcast::<Vec<PrivateRecord>>()?;
                 Task::completed(uuid,
                     records.into_iter().map(|pr| pr.into_record()).collect::<Vec<_>>()
                 )
+            },
+            Self::Stopped(mut responses) => {
+                let (records, stop) = *responses.remove(0).downcast::<(Vec<PrivateRecord>, ScanStop)>()?;
+                Task::completed(uuid,
+                    (records.into_iter().map(|pr| pr.into_record()).collect::<Vec<_>>(), stop)
+                )
+            },
+            Self::Paged(mut responses) => {
+                let (records, next) = *responses.remove(0).downcast::<(Vec<PrivateRecord>, Option<usize>)>()?;
+                Task::completed(uuid,
+                    (records.into_iter().map(|pr| pr.into_record()).collect::<Vec<_>>(), next)
+                )
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum ScanSlots {
+    New(RecordPath, usize),
+    Completed(Responses),
+}
+
+impl ScanSlots {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(path: RecordPath, index: usize) -> BoxCommand {
+        Box::new(ScanSlots::New(path, index))
+    }
+
+    fn into_records(slots: BTreeMap<usize, ChildSlot<PrivateRecord>>) -> BTreeMap<usize, ChildSlot> {
+        slots.into_iter().map(|(i, slot)| (i, slot.map(PrivateRecord::into_record))).collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for ScanSlots {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::New(path, start) => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Completed), vec![
+                    Task::ready(header, commands::ScanSlots::new(path, start))
+                ])
+            },
+            Self::Completed(mut responses) => {
+                let slots = *responses.remove(0).downcast::<BTreeMap<usize, ChildSlot<PrivateRecord>>>()?;
+                Task::completed(uuid, Self::into_records(slots))
             }
         }
     }
 }
 
+#[derive(Serialize, Debug, Clone)]
+pub enum AwaitChildren {
+    New(RecordPath, Vec<usize>, Duration),
+    Completed(Responses),
+}
+
+impl AwaitChildren {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(path: RecordPath, indexes: Vec<usize>, timeout: Duration) -> BoxCommand {
+        Box::new(AwaitChildren::New(path, indexes, timeout))
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for AwaitChildren {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::New(path, indexes, timeout) => {
+                Task::waiting(uuid, header.clone(), Callback::new(Self::Completed), vec![
+                    Task::ready(header, commands::AwaitChildren::new(path, indexes, timeout))
+                ])
+            },
+            Self::Completed(mut responses) => {
+                let slots = *responses.remove(0).downcast::<BTreeMap<usize, ChildSlot<PrivateRecord>>>()?;
+                Task::completed(uuid, ScanSlots::into_records(slots))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ListShared {}
+impl ListShared {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(filter: Option<SharedFilter>) -> BoxCommand {
+        Box::new(commands::ListShared::new(filter))
+    }
+}
+
 #[derive(Serialize, Debug, Clone)]
 pub enum Share {
     New(RecordPath, Option<PermissionOptions>, Did),
-    Channel(Responses, RecordPath, Option<PermissionOptions>),
-  //Completed(Responses),
+    Channel(Responses, RecordPath, Option<PermissionOptions>, Did),
+    Redacted(RecordPath, Option<PermissionOptions>, Did, RedactionSpec),
+    Notify(RecordPath, Option<PermissionOptions>, Did),
+    Subscribe(Responses, RecordPath, Did),
+    View(Responses, RedactedView, Option<PermissionOptions>, Did),
+    ShareView(Responses, RecordPath, Option<PermissionOptions>, Did),
 }
 
 impl Share {
@@ -185,6 +769,20 @@ impl Share {
     ) -> BoxCommand {
         Box::new(Share::New(path, p_opts, recipient))
     }
+
+    //Also records the recipient as a subscriber to updates of the record
+    pub fn notify(
+        path: RecordPath, p_opts: Option<PermissionOptions>, recipient: Did
+    ) -> BoxCommand {
+        Box::new(Share::Notify(path, p_opts, recipient))
+    }
+
+    //Shares a derived record holding the payload with the spec's pointers removed
+    pub fn redacted(
+        path: RecordPath, p_opts: Option<PermissionOptions>, recipient: Did, redaction: RedactionSpec
+    ) -> BoxCommand {
+        Box::new(Share::Redacted(path, p_opts, recipient, redaction))
+    }
 }
 
 #[async_trait::async_trait]
@@ -195,83 +793,476 @@ impl Command for Share {
     ) -> Result<Tasks, Error> {
         match *self {
             Self::New(path, p_opts, recipient) => {
-                let filters = Filters::new(vec![
-                    ("signer", Filter::equal(recipient.to_string())),
-                    ("type", Filter::equal("agent_keys".to_string()))
-                ]);
-
                 let path_copy = path.clone();
-                let callback = move |r: Responses| {Self::Channel(r, path_copy, p_opts)};
+                let recipient_copy = recipient.clone();
+                let callback = move |r: Responses| {Self::Channel(r, path_copy, p_opts, recipient_copy)};
                 Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
                     Task::ready(header.clone(), commands::ReadPrivate::path(path)),
                     Task::ready(header.clone(), commands::EstablishChannel::new(recipient.clone())),
-                    Task::ready(header, commands::Send::New(ReadPublic::new(filters, None), vec![recipient]))
+                    Task::ready(header, commands::ReadAgentKeys::new(recipient))
                 ])
             },
-            Self::Channel(mut responses, path, p_opts) => {
-                let record_resp = *responses.remove(2).downcast::<Responses>()?;
-                let _channel = *responses.remove(1).downcast::<()>()?;
-                let sharing_record = *responses.remove(0).downcast::<Option<Box<PrivateRecord>>>()?;
+            Self::Channel(mut responses, path, p_opts, recipient) => {
+                let agent_keys = *responses.remove(2).downcast::<AgentKeys>()?;
+                responses.remove(1).downcast::<()>()?;
+                let sharing_record = responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0;
 
                 let protocol = sharing_record.ok_or(Error::not_found("Record"))?.protocol;
                 let perms = protocol.subset_permission(
                     memory.get_perms(header.enc, &path, Some(&protocol))?, p_opts.as_ref()
                 )?;
 
-              //let channel_path = RecordPath::new(&[Uuid::new_v5(
-              //    &Uuid::NAMESPACE_OID, recipient.to_string().as_bytes()
-              //)]);
+                let keys = agent_keys.keys_for(&path);
+                let mut envelope = ShareEnvelope::new(path, protocol, p_opts);
+                envelope.seal(keys, &perms)?;
 
-                let agent_keys = record_resp.into_iter().find_map(|response|
-                    response.downcast::<Vec<PublicRecord>>().ok().and_then(|mut records|
-                        records.pop().and_then(|record|
-                            serde_json::from_slice::<BTreeMap<RecordPath, PublicKey>>(&record.payload).ok()
-                        )
-                    )
-                ).ok_or(Error::bad_request("Recipient has no active agents"))?;
+                let channel_path = RecordPath::from_segments(&[Uuid::new_v5(
+                    &Uuid::NAMESPACE_OID, recipient.to_string().as_bytes()
+                )]);
+                let record = Record::new_typed(
+                    channel_path.extend(&[memory.uuid()])?,
+                    SystemProtocols::shared_pointer(),
+                    &envelope
+                )?;
+                Task::waiting(uuid, header.clone(), Callback::new(commands::EnsureEmpty::new), vec![
+                    Task::ready(header.com(), commands::CreatePrivate::new(record.clone(), None)),
+                    Task::ready(header.com(), commands::Send::new(
+                        commands::CreatePrivate::new(record, None), vec![recipient]
+                    ))
+                ])
+            },
+            Self::Redacted(path, p_opts, recipient, redaction) => {
+                let view = RedactedView::new(path.clone(), redaction)?;
+                let callback = move |r: Responses| {Self::View(r, view, p_opts, recipient)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header.clone(), commands::ReadPrivate::path(path)),
+                    Task::ready(header, commands::ReadPrivate::path(RedactedView::registry_path()))
+                ])
+            },
+            Self::View(mut responses, view, p_opts, recipient) => {
+                let registry = responses.remove(1).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0;
+                let original = responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                    .ok_or(Error::not_found("Record"))?.into_record();
+                let record = view.derive(&original)?;
+                memory.validate_payload(&record.protocol, &record.payload, false).await?;
 
-                let keys = agent_keys.into_iter().flat_map(|(opath, key)|
-                    Some(key).filter(|_| opath.parent_of(&path))
-                ).collect::<Vec<_>>();
-                keys.into_iter().map(|key|
-                    Ok(key.encrypt(&serde_json::to_vec(&perms)?)?)
-                ).collect::<Result<Vec<Vec<u8>>, Error>>()?;
-
-              //let record = Record::new(channel_path.
-
-              //Task::waiting(uuid, header.com(), Callback::new(EnsureEmpty::new), vec![
-              //    Task::Ready(
-              //])
-
-                todo!()
-
-              //}
-              //let channel = self.establish_direct_messages(recipient).await?;
-
-                            //let perms = serde_json::to_vec(&self.get_permission(path)?.subset(permission_options)?)?;
-
-              //let agent_keys = self.public_read(filters, None, Some(&[recipient])).await?.first().and_then(|(_, record)|
-              //    serde_json::from_slice::<Vec<PublicKey>>(&record.payload).ok()
-              //).ok_or(Error::bad_request("Agent.share", "Recipient has no agents"))?
-              //.into_iter().map(|key| Ok(key.encrypt(&perms)?)).collect::<Result<Vec<Vec<u8>>, Error>>()?;
-              //let record = Record::new(None, &SystemProtocols::shared_pointer(), serde_json::to_vec(&agent_keys)?);
-              //self.private_client.create_child(
-              //    &channel.perms,
-              //    record,
-              //    None,
-              //    &[self.tenant(), recipient]
-              //).await?;
-
-              //Task::waiting(uuid, header.clone(), Callback::new(Self::Completed), vec![
-              //    Task::ready(header, commands::Scan::new(path, start))
-              //])
-            },
-          //Self::Completed(mut responses) => {
-          //    let records = *responses.remove(0).downcast::<Vec<PrivateRecord>>()?;
-          //    Task::completed(uuid,
-          //        records.into_iter().map(|pr| pr.into_record()).collect::<Vec<_>>()
-          //    )
-          //}
+                let mut views = commands::RefreshRedactedViews::views(registry)?;
+                views.retain(|v| v.view != view.view);
+                let path = view.view.clone();
+                views.push(view);
+                let registry = Record::new_typed(
+                    RedactedView::registry_path(),
+                    SystemProtocols::redacted_views(),
+                    &views
+                )?;
+                let callback = move |r: Responses| {Self::ShareView(r, path, p_opts, recipient)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header.clone(), commands::UpdatePrivate::new(record, None)),
+                    Task::ready(header, commands::UpdatePrivate::new(registry, None))
+                ])
+            },
+            Self::Notify(path, p_opts, recipient) => {
+                let sidecar = Subscribers::path(&path);
+                let path_copy = path.clone();
+                let recipient_copy = recipient.clone();
+                let callback = move |r: Responses| {Self::Subscribe(r, path_copy, recipient_copy)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header.clone(), Self::New(path, p_opts, recipient)),
+                    Task::ready(header, commands::ReadPrivate::path(sidecar))
+                ])
+            },
+            Self::Subscribe(mut responses, path, recipient) => {
+                let mut subscribers = Subscribers::from_record(
+                    responses.remove(1).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                )?;
+                commands::EnsureEmpty::is_empty(responses)?;
+                if subscribers.members.contains(&recipient) {
+                    return Task::completed(uuid, ());
+                }
+                subscribers.members.push(recipient);
+                Task::waiting(uuid, header.clone(), Callback::new(commands::EnsureEmpty::new), vec![
+                    Task::ready(header, commands::UpdatePrivate::new(subscribers.into_record(&path)?, None))
+                ])
+            },
+            Self::ShareView(responses, path, p_opts, recipient) => {
+                commands::EnsureEmpty::is_empty(responses)?;
+                Task::next(uuid, header, Self::New(path, p_opts, recipient))
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CreateShareGroup {
+    group: ShareGroup
+}
+
+impl CreateShareGroup {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(name: &str, members: Vec<Did>) -> BoxCommand {
+        Box::new(CreateShareGroup{group: ShareGroup::new(name, members)})
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for CreateShareGroup {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        Task::next(uuid, header, commands::CreatePrivate::new(self.group.into_record()?, None))
+    }
+}
+
+fn share_failures(responses: Responses, targets: Vec<(Did, RecordPath)>) -> ShareFailures {
+    responses.into_iter().zip(targets).flat_map(|(response, (member, path))|
+        response.downcast_ref::<Arc<Error>>().map(|e| (member, path, e.to_string()))
+    ).collect()
+}
+
+//Shares to each member separately, an unreachable member is reported instead of failing the rest
+fn share_to(header: &Header, targets: &[(Did, RecordPath, Option<PermissionOptions>)]) -> Vec<Task> {
+    targets.iter().map(|(member, path, p_opts)|
+        Task::ready(header.clone(), Share::New(path.clone(), p_opts.clone(), member.clone()))
+    ).collect()
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum ShareWithGroup {
+    New(RecordPath, Option<PermissionOptions>, String),
+    Group(Responses, RecordPath, Option<PermissionOptions>),
+    Share(Responses, ShareGroup, RecordPath, Option<PermissionOptions>),
+    Complete(Responses, Vec<(Did, RecordPath)>),
+}
+
+impl ShareWithGroup {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(path: RecordPath, p_opts: Option<PermissionOptions>, group: &str) -> BoxCommand {
+        Box::new(ShareWithGroup::New(path, p_opts, group.to_string()))
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for ShareWithGroup {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::New(path, p_opts, group) => {
+                let callback = move |r: Responses| {Self::Group(r, path, p_opts)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, commands::ReadPrivate::path(ShareGroup::path(&group)))
+                ])
+            },
+            Self::Group(mut responses, path, p_opts) => {
+                let mut group = ShareGroup::from_record(
+                    responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                )?;
+                group.paths.retain(|(p, _)| *p != path);
+                group.paths.push((path.clone(), p_opts.clone()));
+                let record = group.clone().into_record()?;
+                let callback = move |r: Responses| {Self::Share(r, group, path, p_opts)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, commands::UpdatePrivate::new(record, None))
+                ])
+            },
+            Self::Share(responses, group, path, p_opts) => {
+                commands::EnsureEmpty::is_empty(responses)?;
+                let targets = group.members.into_iter().map(|m| (m, path.clone(), p_opts.clone())).collect::<Vec<_>>();
+                let tasks = share_to(&header, &targets);
+                let targets = targets.into_iter().map(|(m, p, _)| (m, p)).collect::<Vec<_>>();
+                let callback = move |r: Responses| {Self::Complete(r, targets)};
+                Task::tolerant(uuid, header, Callback::new(callback), tasks)
+            },
+            Self::Complete(responses, targets) => {
+                Task::completed(uuid, share_failures(responses, targets))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum AddGroupMember {
+    New(String, Did),
+    Group(Responses, Did),
+    Share(Responses, ShareGroup, Did),
+    Complete(Responses, Vec<(Did, RecordPath)>),
+}
+
+impl AddGroupMember {
+    //The new member receives every path already shared with the group
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(group: &str, member: Did) -> BoxCommand {
+        Box::new(AddGroupMember::New(group.to_string(), member))
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for AddGroupMember {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::New(group, member) => {
+                let callback = move |r: Responses| {Self::Group(r, member)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, commands::ReadPrivate::path(ShareGroup::path(&group)))
+                ])
+            },
+            Self::Group(mut responses, member) => {
+                let mut group = ShareGroup::from_record(
+                    responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                )?;
+                if group.members.contains(&member) {
+                    return Task::completed(uuid, ShareFailures::new());
+                }
+                group.members.push(member.clone());
+                let record = group.clone().into_record()?;
+                let callback = move |r: Responses| {Self::Share(r, group, member)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, commands::UpdatePrivate::new(record, None))
+                ])
+            },
+            Self::Share(responses, group, member) => {
+                commands::EnsureEmpty::is_empty(responses)?;
+                let targets = group.paths.into_iter().map(|(p, o)| (member.clone(), p, o)).collect::<Vec<_>>();
+                let tasks = share_to(&header, &targets);
+                let targets = targets.into_iter().map(|(m, p, _)| (m, p)).collect::<Vec<_>>();
+                let callback = move |r: Responses| {Self::Complete(r, targets)};
+                Task::tolerant(uuid, header, Callback::new(callback), tasks)
+            },
+            Self::Complete(responses, targets) => {
+                Task::completed(uuid, share_failures(responses, targets))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum RemoveGroupMember {
+    New(String, Did),
+    Group(Responses, Did),
+}
+
+impl RemoveGroupMember {
+    //Later group shares skip the member, permissions it already holds are not revoked
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(group: &str, member: Did) -> BoxCommand {
+        Box::new(RemoveGroupMember::New(group.to_string(), member))
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for RemoveGroupMember {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::New(group, member) => {
+                let callback = move |r: Responses| {Self::Group(r, member)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, commands::ReadPrivate::path(ShareGroup::path(&group)))
+                ])
+            },
+            Self::Group(mut responses, member) => {
+                let mut group = ShareGroup::from_record(
+                    responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                )?;
+                group.members.retain(|m| *m != member);
+                Task::next(uuid, header, commands::UpdatePrivate::new(group.into_record()?, None))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RefreshSharesTo {}
+impl RefreshSharesTo {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(recipient: Did) -> BoxCommand {
+        Box::new(commands::RefreshSharesTo::new(recipient))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RefreshRedactedViews {}
+impl RefreshRedactedViews {
+    //Re-applies every view's spec, or only the views of one original
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(original: Option<RecordPath>) -> BoxCommand {
+        Box::new(commands::RefreshRedactedViews::new(original))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ScanDM {}
+impl ScanDM {
+    //Stores received shares and returns the RecordUpdated notifications
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> BoxCommand {
+        Box::new(commands::ScanDM::new())
+    }
+
+    //Reads at most this many pages of DMs, the rest are left for later compiles
+    pub fn pages(pages: usize) -> BoxCommand {
+        Box::new(commands::ScanDM::pages(pages))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ReadDM {}
+impl ReadDM {
+    //One page of at most limit DMs from the cursor, or the stored one, without processing them
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(cursor: Option<DmCursor>, limit: usize) -> BoxCommand {
+        Box::new(commands::ReadDM::new(cursor, limit))
+    }
+
+    //Also returns the ReadDiagnostics of the DMs that were dropped
+    pub fn diagnosed(cursor: Option<DmCursor>, limit: usize) -> BoxCommand {
+        Box::new(commands::ReadDM::diagnosed(cursor, limit))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DeleteDM {}
+impl DeleteDM {
+    //Removes the DMs stored before the instant, the Dwn refuses instants ahead of its clock
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(before: DateTime<Utc>) -> BoxCommand {
+        Box::new(commands::DeleteDM::new(before))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PendingDMs {}
+impl PendingDMs {
+    //Estimate of the DMs still to be processed by ScanDM
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> BoxCommand {
+        Box::new(commands::PendingDMs::new())
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ProbeCapabilities {}
+impl ProbeCapabilities {
+    //What the Dwn accepts, see Agent::probe_limits to apply it
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> BoxCommand {
+        Box::new(commands::ProbeCapabilities::new())
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ListProtocols {}
+impl ListProtocols {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> BoxCommand {
+        Box::new(commands::ListProtocols::new())
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ProcessShares {}
+impl ProcessShares {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(sharer: Did) -> BoxCommand {
+        Box::new(commands::ProcessShares::new(sharer))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RequestShareUpgrade {}
+impl RequestShareUpgrade {
+    //Asks the sharer for the wanted subset of a record they shared, the answer is picked up by ScanDM
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(sharer: Did, path: RecordPath, wanted: PermissionOptions) -> BoxCommand {
+        let request = DmMessage::ShareUpgradeRequest(ShareUpgradeRequest{path, wanted});
+        Box::new(commands::CreateDM::new(request, sharer))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ListShareUpgrades {}
+impl ListShareUpgrades {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> BoxCommand {
+        Box::new(commands::ListShareUpgrades::new())
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ShareAudit {}
+impl ShareAudit {
+    //What each sharer granted or denied in answer to our upgrade requests
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> BoxCommand {
+        Box::new(commands::ListShareAudit::new())
+    }
+}
+
+//Answers a pending upgrade request. An approval shares the wanted subset before the answer is
+//sent so a record that can not be shared fails here without telling the requester anything
+#[derive(Serialize, Debug, Clone)]
+pub enum AnswerShareUpgrade {
+    New(Did, RecordPath, bool),
+    Pending(Responses, Did, RecordPath, bool),
+    Shared(Responses, Did, ShareResponse),
+    Answer(Did, ShareResponse),
+}
+
+impl AnswerShareUpgrade {
+    pub fn approve(requester: Did, path: RecordPath) -> BoxCommand {
+        Box::new(AnswerShareUpgrade::New(requester, path, true))
+    }
+
+    pub fn deny(requester: Did, path: RecordPath) -> BoxCommand {
+        Box::new(AnswerShareUpgrade::New(requester, path, false))
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for AnswerShareUpgrade {
+    async fn process<'a>(
+        self: Box<Self>, uuid: Uuid, header: Header,
+        _: &mut CompilerMemory, _: &mut CompilerCache
+    ) -> Result<Tasks, Error> {
+        match *self {
+            Self::New(requester, path, approve) => {
+                let pending = PendingShareUpgrade::path(&requester, &path)?;
+                let callback = move |r: Responses| {Self::Pending(r, requester, path, approve)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header.com(), commands::ReadPrivate::path(pending))
+                ])
+            },
+            Self::Pending(mut responses, requester, path, approve) => {
+                let pending = responses.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?.0
+                    .ok_or(Error::not_found("Share upgrade request"))?.into_record().decode::<PendingShareUpgrade>()?;
+                if !approve {
+                    return Task::next(uuid, header, Self::Answer(requester, ShareResponse{path, granted: None}));
+                }
+                let wanted = pending.request.wanted;
+                let response = ShareResponse{path: path.clone(), granted: Some(wanted.clone())};
+                let callback = move |r: Responses| {Self::Shared(r, requester.clone(), response)};
+                Task::waiting(uuid, header.clone(), Callback::new(callback), vec![
+                    Task::ready(header, Share::New(path, Some(wanted), pending.requester))
+                ])
+            },
+            Self::Shared(responses, requester, response) => {
+                commands::EnsureEmpty::is_empty(responses)?;
+                Task::next(uuid, header, Self::Answer(requester, response))
+            },
+            Self::Answer(requester, response) => {
+                let pending = PendingShareUpgrade::path(&requester, &response.path)?;
+                Task::waiting(uuid, header.clone(), Callback::new(commands::EnsureEmpty::new), vec![
+                    Task::ready(header.clone(), commands::CreateDM::new(DmMessage::ShareResponse(response), requester)),
+                    Task::ready(header.com(), commands::DeletePrivate::new(pending, false))
+                ])
+            }
         }
     }
 }
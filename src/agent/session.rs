@@ -0,0 +1,50 @@
+use super::Error;
+
+use super::compiler::CompilerCache;
+use super::structs::BoxCommand;
+use super::traits::Response;
+use super::Agent;
+
+use std::collections::BTreeMap;
+
+//Runs calls against one cache that also keeps the next child index of each channel created in,
+//so a later call creates children without probing for the index again. Sessions on one agent are
+//independent, a child index another writer took fails that create and the session probes again
+pub struct AgentSession<'a> {
+    agent: &'a Agent,
+    cache: CompilerCache
+}
+
+impl<'a> AgentSession<'a> {
+    pub(crate) fn new(agent: &'a Agent, cache: Option<CompilerCache>) -> Self {
+        let mut cache = cache.unwrap_or_default();
+        cache.keep_create_index();
+        AgentSession{agent, cache}
+    }
+
+    pub fn cache(&self) -> &CompilerCache {&self.cache}
+
+    pub async fn process_commands(&mut self, commands: Vec<BoxCommand>) -> Result<Vec<Box<dyn Response>>, Error> {
+        self.agent.process_commands(&mut self.cache, commands).await
+    }
+
+    pub async fn process_commands_keyed<K: Ord + Clone>(
+        &mut self, commands: Vec<(K, BoxCommand)>
+    ) -> Result<BTreeMap<K, Result<Vec<Box<dyn Response>>, Error>>, Error> {
+        self.agent.process_commands_keyed(&mut self.cache, commands).await
+    }
+
+    pub async fn run<R: Response>(&mut self, command: BoxCommand) -> Result<R, Error> {
+        self.agent.run(&mut self.cache, command).await
+    }
+
+    pub async fn run_all<R: Response>(&mut self, commands: Vec<BoxCommand>) -> Result<Vec<R>, Error> {
+        self.agent.run_all(&mut self.cache, commands).await
+    }
+
+    //Dropping a session loses its indexes and unflushed record info, closing writes the record
+    //info of a loaded cache first
+    pub async fn close(self) -> Result<(), Error> {
+        self.cache.flush().await
+    }
+}
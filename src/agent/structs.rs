@@ -1,31 +1,34 @@
 use super::Error;
 
 use super::permission::{
-    ChannelPermissionSet,
     PermissionOptions,
     PermissionSet
 };
-use super::protocol::{SystemProtocols, Protocol};
-use super::traits::{Response, Command};
+use crate::model::protocol::{SystemProtocols, Protocol};
+use super::traits::{PayloadValidator, PayloadMerger, PayloadMigrator, Response, Command};
 
-use crate::dids::signing::{SignedObject, Signer};
-use crate::dids::Endpoint;
+use crate::dids::signing::{SignedObject, VerifiedBy, Signer};
+use crate::dids::{DidKeyPurpose, Endpoint, Did};
 
-use crate::dwn::structs::{DwnRequest, DwnItem, PublicRecord};
+use crate::dwn::structs::{DwnRequest, DwnItem, PublicRecord, DmCursor};
 
-use std::collections::{VecDeque};
+pub use crate::model::structs::*;
 
-use simple_crypto::{Hashable, SecretKey, PublicKey, Key};
-use simple_database::database::{Filters, SortOptions};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use rand::{RngCore, SeedableRng};
+use rand::rngs::StdRng;
+
+use simple_crypto::{Hashable, SecretKey, PublicKey};
+use simple_database::database::SortOptions;
+use crate::common::{FilterExpr, Compression};
 
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc};
-use schemars::JsonSchema;
 use uuid::Uuid;
 
-use super::traits::TypeDebug;
-
-const INDEX_UUID: Uuid = Uuid::max();
+use crate::common::TypeDebug;
 
 pub type BoxCallback = Box<dyn FnOnce(Responses) -> BoxCommand + Send + Sync>;
 pub type BoxCommand = Box<dyn Command>;
@@ -53,78 +56,47 @@ impl Header {
         header.enc = false;
         header
     }
-}
-
-#[derive(JsonSchema, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
-#[derive(serde_with::SerializeDisplay)]
-#[derive(serde_with::DeserializeFromStr)]
-pub struct RecordPath {
-    inner: Vec<Uuid>
-}
-
-impl std::fmt::Display for RecordPath {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "/{}", self.inner.iter().map(|id| id.to_string()).collect::<Vec<_>>().join("/"))
-    }
-}
-
-impl std::str::FromStr for RecordPath {
-    type Err = uuid::Error;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(RecordPath{inner:
-            s[1..].split("/").collect::<Vec<_>>()
-            .into_iter().filter_map(|id|
-                if id.is_empty() {None} else {Some(Uuid::from_str(id))}
-            ).collect::<Result<Vec<Uuid>, uuid::Error>>()?
-        })
-    }
+    pub fn domain(&self) -> KeyDomain {KeyDomain::from_enc(self.enc)}
 }
 
-impl RecordPath {
-    pub fn new(path: &[Uuid]) -> Self {
-        RecordPath{inner: path.to_vec()}
-    }
-
-    pub fn parent_of(&self, path: &RecordPath) -> bool {
-        path.as_slice().strip_prefix(self.as_slice()).is_some()
-    }
-
-    pub fn root() -> Self {
-        RecordPath{inner: Vec::new()}
-    }
-
-    pub fn last(&self) -> Uuid {
-        self.inner.last().copied().unwrap_or(Uuid::nil())
-    }
-
-    pub fn is_empty(&self) -> bool {self.inner.is_empty()}
-
-    pub fn as_slice(&self) -> &[Uuid] {
-        self.inner.as_slice()
-    }
-
-    pub fn parent(&self) -> Result<Self, Error> {
-        match self.inner.split_last() {
-            Some(p) => Ok(RecordPath::new(p.1)),
-            None => {Err(Error::bad_request("Cannot Get Parent Of Root"))}
+//The derivation tree a set of permissions was taken from, records in one tree are never written with keys from the other
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyDomain {Enc, Com}
+
+impl KeyDomain {
+    pub fn from_enc(enc: bool) -> Self {
+        if enc {KeyDomain::Enc} else {KeyDomain::Com}
+    }
+
+    //None when the permissions were derived by someone else or from a parent's channel, a key
+    //scoped below the path (an agent enrolled on a folder) cannot have derived it
+    pub fn of(
+        path: &RecordPath, discover: &PublicKey, enc_key: &PathedKey, com_key: &PathedKey
+    ) -> Result<Option<Self>, Error> {
+        for (domain, key) in [(KeyDomain::Enc, enc_key), (KeyDomain::Com, com_key)] {
+            let perms = match key.get_perms(path, None) {
+                Err(Error::InsufficentPermission{..}) => continue,
+                perms => perms?
+            };
+            if perms.discover().public_key() == *discover {
+                return Ok(Some(domain));
+            }
         }
-    }
-
-    pub fn index(&self) -> Self {
-        self.extend(&[INDEX_UUID])
-    }
-
-    pub fn extend(&self, path: &[Uuid]) -> Self {
-        RecordPath::new(&[&self.inner, path].concat())
+        Ok(None)
     }
 }
 
 #[derive(Serialize, Clone, Debug, PartialEq, Eq)]
 pub enum AgentRequest {
     ReadPrivate(SecretKey),
-    ReadPublic(Filters, Option<SortOptions>),
-    ReadDM(DateTime<Utc>, Signer),
+    ReadPublic(FilterExpr, Option<SortOptions>),
+    ReadPublicAt(FilterExpr, DateTime<Utc>, Option<SortOptions>),
+    CountPublic(FilterExpr),
+    ReadDM(DmCursor, usize, Signer),
+    SubscribeDM(DmCursor, usize, Signer),
+    ReadAccessLog(DateTime<Utc>, Signer),
+    Capabilities,
 }
 
 impl AgentRequest {
@@ -134,8 +106,17 @@ impl AgentRequest {
                 DwnRequest::ReadPrivate(SignedObject::from_key(&discover, String::new())?),
             Self::ReadPublic(filters, sort_options) =>
                 DwnRequest::ReadPublic(filters, sort_options),
-            Self::ReadDM(timestamp, signer) =>
-                DwnRequest::ReadDM(SignedObject::new(signer, timestamp)?),
+            Self::ReadPublicAt(filters, at, sort_options) =>
+                DwnRequest::ReadPublicAt(filters, at, sort_options),
+            Self::CountPublic(filters) =>
+                DwnRequest::CountPublic(filters),
+            Self::ReadDM(cursor, limit, signer) =>
+                DwnRequest::ReadDM(SignedObject::new(signer, (cursor, limit))?),
+            Self::SubscribeDM(cursor, limit, signer) =>
+                DwnRequest::SubscribeDM(SignedObject::new(signer, (cursor, limit))?),
+            Self::ReadAccessLog(since, signer) =>
+                DwnRequest::ReadAccessLog(SignedObject::new(signer, since)?),
+            Self::Capabilities => DwnRequest::Capabilities,
         })
     }
 }
@@ -146,26 +127,45 @@ impl Hashable for AgentRequest {}
 pub enum MutableAgentRequest {
     CreatePrivate(Box<PrivateRecord>, SecretKey, SecretKey),
     UpdatePrivate(Box<PrivateRecord>, SecretKey, SecretKey, SecretKey),
+    GuardedUpdatePrivate(Box<PrivateRecord>, SecretKey, SecretKey, SecretKey, Vec<u8>),
     DeletePrivate(PublicKey, SecretKey),
+    //An item read from another Dwn, stored as is
+    CopyPrivate(Box<DwnItem>, SecretKey),
 
     CreatePublic(Box<PublicRecord>, Signer),
     UpdatePublic(Box<PublicRecord>, Signer),
+    GuardedUpdatePublic(Box<PublicRecord>, Signer, u64),
     DeletePublic(Uuid, Signer),
 
-    CreateDM(Box<PermissionSet>, Signer, PublicKey),
+    //Signed by the sender and encrypted to the recipient's com key
+    CreateDM(Uuid, Box<SignedObject<DmMessage>>, PublicKey),
+    //Signed by the com key, removes the DMs stored before the instant
+    DeleteDM(Uuid, DateTime<Utc>, Signer),
+
+    //Discover keys of the audited records, replaces what was audited before
+    AuditAccess(Uuid, Vec<SecretKey>, Signer),
+
+    //Answered with a receipt signed by the Dwn once accepted
+    WithReceipt(Box<MutableAgentRequest>),
 }
 
 impl std::fmt::Debug for MutableAgentRequest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let id = self.get_id();
         match self {
-            Self::CreatePrivate(p,_,_) => write!(f, "CreatePrivate({}, {:?})", id, p.payload.truncate_debug(20)),
-            Self::UpdatePrivate(p,_,_,_) => write!(f, "UpdatePrivate({}, {:?})", id, p.payload.truncate_debug(20)),
+            Self::CreatePrivate(p,_,_) => write!(f, "CreatePrivate({}, {}, {:?})", id, p.protocol.label(), p.payload.truncate_debug(20)),
+            Self::UpdatePrivate(p,_,_,_) => write!(f, "UpdatePrivate({}, {}, {:?})", id, p.protocol.label(), p.payload.truncate_debug(20)),
+            Self::GuardedUpdatePrivate(p,_,_,_,_) => write!(f, "GuardedUpdatePrivate({}, {}, {:?})", id, p.protocol.label(), p.payload.truncate_debug(20)),
             Self::DeletePrivate(_,_) => write!(f, "DeletePrivate({})", id),
-            Self::CreatePublic(r,_) => write!(f, "CreatePublic({}, {:?})", id, r.payload.truncate_debug(20)),
-            Self::UpdatePublic(r,_) => write!(f, "UpdatePublic({}, {:?})", id, r.payload.truncate_debug(20)),
+            Self::CopyPrivate(_,_) => write!(f, "CopyPrivate({})", id),
+            Self::CreatePublic(r,_) => write!(f, "CreatePublic({}, {}, {:?})", id, r.protocol.label(), r.payload.truncate_debug(20)),
+            Self::UpdatePublic(r,_) => write!(f, "UpdatePublic({}, {}, {:?})", id, r.protocol.label(), r.payload.truncate_debug(20)),
+            Self::GuardedUpdatePublic(r,_,g) => write!(f, "GuardedUpdatePublic({}, {}, {}, {:?})", id, r.protocol.label(), g, r.payload.truncate_debug(20)),
             Self::DeletePublic(_,_) => write!(f, "DeletePublic({})", id),
             Self::CreateDM(_,_,_) => write!(f, "CreateDM({})", id),
+            Self::DeleteDM(_,b,_) => write!(f, "DeleteDM({}, {})", id, b),
+            Self::AuditAccess(_,k,_) => write!(f, "AuditAccess({}, {} keys)", id, k.len()),
+            Self::WithReceipt(r) => write!(f, "WithReceipt({:?})", r),
         }
     }
 }
@@ -178,11 +178,102 @@ impl MutableAgentRequest {
         match self {
             Self::CreatePrivate(_,d,_) => Uuid::new_v5(&Uuid::NAMESPACE_OID, &d.public_key().to_vec()),
             Self::UpdatePrivate(_,d,_,_) => Uuid::new_v5(&Uuid::NAMESPACE_OID, &d.public_key().to_vec()),
+            Self::GuardedUpdatePrivate(_,d,_,_,_) => Uuid::new_v5(&Uuid::NAMESPACE_OID, &d.public_key().to_vec()),
             Self::DeletePrivate(d,_) => Uuid::new_v5(&Uuid::NAMESPACE_OID, &d.to_vec()),
+            Self::CopyPrivate(_,d) => Uuid::new_v5(&Uuid::NAMESPACE_OID, &d.public_key().to_vec()),
             Self::CreatePublic(r,_) => r.uuid,
             Self::UpdatePublic(r,_) => r.uuid,
+            Self::GuardedUpdatePublic(r,_,_) => r.uuid,
             Self::DeletePublic(u,_) => *u,
-            Self::CreateDM(_,_,_) => Uuid::new_v4()
+            Self::CreateDM(id,_,_) => *id,
+            Self::DeleteDM(id,_,_) => *id,
+            Self::AuditAccess(id,_,_) => *id,
+            Self::WithReceipt(r) => r.get_id()
+        }
+    }
+
+    //Sizes are filled in with sized once the request is signed and encrypted
+    pub fn usage(&self) -> Option<UsageChange> {
+        let (path, protocol) = match self {
+            Self::CreatePrivate(r,_,_) | Self::UpdatePrivate(r,_,_,_) | Self::GuardedUpdatePrivate(r,_,_,_,_) =>
+                (Some(r.perms.path.clone()), Some(r.protocol.label())),
+            Self::CopyPrivate(_,_) => (None, None),
+            Self::CreatePublic(r,_) | Self::UpdatePublic(r,_) | Self::GuardedUpdatePublic(r,_,_) =>
+                (None, Some(r.protocol.label())),
+            Self::DeletePrivate(_,_) | Self::DeletePublic(_,_) => return Some(UsageChange::Remove),
+            Self::CreateDM(..) | Self::DeleteDM(..) | Self::AuditAccess(..) => return None,
+            Self::WithReceipt(r) => return r.usage()
+        };
+        Some(UsageChange::Write(UsageEntry{path, protocol, size: 0}))
+    }
+
+    pub fn is_delete(&self) -> bool {
+        match self {
+            Self::WithReceipt(r) => r.is_delete(),
+            other => matches!(other, Self::DeletePrivate(..) | Self::DeletePublic(..))
+        }
+    }
+
+    //Discover key of the stored record an update or delete replaces
+    pub fn replaces(&self) -> Option<PublicKey> {
+        match self {
+            Self::UpdatePrivate(_,d,_,_) | Self::GuardedUpdatePrivate(_,d,_,_,_) => Some(d.public_key()),
+            Self::DeletePrivate(d,_) => Some(d.clone()),
+            Self::WithReceipt(r) => r.replaces(),
+            _ => None
+        }
+    }
+
+    pub fn is_guarded(&self) -> bool {
+        match self {
+            Self::WithReceipt(r) => r.is_guarded(),
+            other => matches!(other, Self::GuardedUpdatePrivate(..) | Self::GuardedUpdatePublic(..))
+        }
+    }
+
+    //The plain update for a Dwn without guarded updates, the guard is dropped
+    pub fn unguarded(self) -> Self {
+        match self {
+            Self::GuardedUpdatePrivate(r, d, c, del, _) => Self::UpdatePrivate(r, d, c, del),
+            Self::GuardedUpdatePublic(r, s, _) => Self::UpdatePublic(r, s),
+            Self::WithReceipt(r) => Self::WithReceipt(Box::new(r.unguarded())),
+            other => other
+        }
+    }
+
+    pub fn with_receipt(self) -> Self {
+        match self {
+            Self::WithReceipt(r) => Self::WithReceipt(r),
+            other => Self::WithReceipt(Box::new(other))
+        }
+    }
+
+    pub fn is_receipted(&self) -> bool {matches!(self, Self::WithReceipt(_))}
+
+    //The request alone for a Dwn without receipts, it answers Empty
+    pub fn without_receipt(self) -> Self {
+        match self {
+            Self::WithReceipt(r) => *r,
+            other => other
+        }
+    }
+
+    //domain_of finds the tree a discover key of a path was derived from
+    pub fn check_domain<F: Fn(&RecordPath, &PublicKey) -> Result<Option<KeyDomain>, Error>>(
+        &self, domain: KeyDomain, domain_of: &F
+    ) -> Result<(), Error> {
+        let (record, discover) = match self {
+            Self::CreatePrivate(r,d,_) => (r, d),
+            Self::UpdatePrivate(r,d,_,_) => (r, d),
+            Self::GuardedUpdatePrivate(r,d,_,_,_) => (r, d),
+            Self::WithReceipt(r) => return r.check_domain(domain, domain_of),
+            _ => return Ok(())
+        };
+        match domain_of(&record.perms.path, &discover.public_key())? {
+            Some(found) if found != domain => Err(Error::wrong_domain(&format!(
+                "{} was derived from {:?} but written as {:?}", record.perms.path, found, domain
+            ))),
+            _ => Ok(())
         }
     }
 
@@ -193,10 +284,10 @@ impl MutableAgentRequest {
     }
 
     fn create_dm_request(
-        signer: Signer, com_key: PublicKey, perms: PermissionSet
+        com_key: PublicKey, message: SignedObject<DmMessage>
     ) -> Result<DwnItem, Error> {
-        let payload = com_key.encrypt(&serde_json::to_vec(&SignedObject::new(signer, perms)?)?)?;
-        Ok(DwnItem{discover: com_key, delete: None, payload})
+        let payload = com_key.encrypt(&Compression::encode(serde_json::to_vec(&message)?))?;
+        Ok(DwnItem{discover: com_key, delete: None, payload, expires: None})
     }
 
     pub fn into_dwn_request(self) -> Result<DwnRequest, Error> {
@@ -207,16 +298,36 @@ impl MutableAgentRequest {
                 DwnRequest::UpdatePrivate(SignedObject::from_key(
                     &delete, Self::create_request(*record, &discover, create)?
                 )?),
+            Self::GuardedUpdatePrivate(record, discover, create, delete, guard) =>
+                DwnRequest::GuardedUpdatePrivate(SignedObject::from_key(
+                    &delete, Self::create_request(*record, &discover, create)?
+                )?, guard),
             Self::DeletePrivate(discover, delete) =>
                 DwnRequest::DeletePrivate(SignedObject::from_key(&delete, discover)?),
+            Self::CopyPrivate(item, discover) =>
+                DwnRequest::CreatePrivate(SignedObject::from_key(&discover, *item)?),
             Self::CreatePublic(record, signer) =>
                 DwnRequest::CreatePublic(record.into_item(signer)?),
             Self::UpdatePublic(record, signer) =>
                 DwnRequest::UpdatePublic(record.into_item(signer)?),
+            Self::GuardedUpdatePublic(record, signer, generation) =>
+                DwnRequest::GuardedUpdatePublic(record.into_item(signer)?, generation),
             Self::DeletePublic(uuid, signer) =>
                 DwnRequest::DeletePublic(SignedObject::new(signer, uuid)?),
-            Self::CreateDM(perms, signer, com_key) =>
-                DwnRequest::CreateDM(Self::create_dm_request(signer, com_key, *perms)?)
+            Self::CreateDM(_, message, com_key) =>
+                DwnRequest::CreateDM(Self::create_dm_request(com_key, *message)?),
+            Self::DeleteDM(_, before, signer) =>
+                DwnRequest::DeleteDM(SignedObject::new(signer, before)?),
+            Self::AuditAccess(_, discovers, signer) => {
+                let Signer::Left(tenant) = signer else {
+                    return Err(Error::bad_request("Access is audited for a did, not a key"));
+                };
+                let proofs = discovers.iter().map(|discover|
+                    SignedObject::from_key(discover, tenant.public.did.clone())
+                ).collect::<Result<Vec<_>, Error>>()?;
+                DwnRequest::AuditAccess(SignedObject::from_keypair(&tenant, proofs)?)
+            },
+            Self::WithReceipt(request) => DwnRequest::WithReceipt(Box::new(request.into_dwn_request()?))
         })
     }
 
@@ -224,13 +335,13 @@ impl MutableAgentRequest {
         perms: PermissionSet,
         p_opts: Option<&PermissionOptions>,
         protocol: Protocol,
-        payload: Vec<u8>
+        payload: Vec<u8>,
+        expires: Option<DateTime<Utc>>
     ) -> Result<Self, Error> {
-        protocol.validate_payload(&payload)?;
         let discover = perms.discover();
         let create = perms.create()?;
         let subset_perms = protocol.subset_permission(perms, p_opts)?;
-        let pr = PrivateRecord::new(subset_perms, protocol, payload);
+        let pr = PrivateRecord{expires, ..PrivateRecord::new(subset_perms, protocol, payload)};
         Ok(Self::CreatePrivate(Box::new(pr), discover, create))
     }
 
@@ -252,17 +363,31 @@ impl MutableAgentRequest {
         perms: PermissionSet,
         p_opts: Option<&PermissionOptions>,
         protocol: Protocol,
-        payload: Vec<u8>
+        payload: Vec<u8>,
+        expires: Option<DateTime<Utc>>
     ) -> Result<Self, Error> {
         let delete = perms.delete()?;
-        let req = Self::create_private(perms, p_opts, protocol, payload)?;
+        let req = Self::create_private(perms, p_opts, protocol, payload, expires)?;
         if let Self::CreatePrivate(pr, discover, create) = req {
             Ok(Self::UpdatePrivate(pr, discover, create, delete))
         } else {panic!("Impossible");}
     }
 
+    pub fn guarded_update_private(
+        perms: PermissionSet,
+        p_opts: Option<&PermissionOptions>,
+        protocol: Protocol,
+        payload: Vec<u8>,
+        expires: Option<DateTime<Utc>>,
+        guard: Vec<u8>
+    ) -> Result<Self, Error> {
+        if let Self::UpdatePrivate(pr, discover, create, delete) = Self::update_private(perms, p_opts, protocol, payload, expires)? {
+            Ok(Self::GuardedUpdatePrivate(pr, discover, create, delete, guard))
+        } else {panic!("Impossible");}
+    }
+
     pub fn update_index(perms: PermissionSet, index: usize) -> Result<Self, Error> {
-        Self::update_private(perms, None, SystemProtocols::usize(), serde_json::to_vec(&index)?)
+        Self::update_private(perms, None, SystemProtocols::usize(), serde_json::to_vec(&index)?, None)
     }
 
     pub fn delete_private(perms: &PermissionSet) -> Result<Self, Error> {
@@ -281,20 +406,38 @@ impl MutableAgentRequest {
         Ok(Self::UpdatePublic(Box::new(record), signer))
     }
 
+    //The record is written at generation+1
+    pub fn guarded_update_public(
+        mut record: PublicRecord, signer: Signer, generation: u64
+    ) -> Result<Self, Error> {
+        record.index.insert("generation".to_string(), (generation+1).into());
+        Ok(Self::GuardedUpdatePublic(Box::new(record), signer, generation))
+    }
+
     pub fn delete_public(uuid: Uuid, signer: Signer) -> Result<Self, Error> {
         Ok(Self::DeletePublic(uuid, signer))
     }
 
+    //Every DM is its own write, the id only keeps two in one compile from being merged
     pub fn create_dm(
-        perms: PermissionSet, signer: Signer, com_key: PublicKey
+        id: Uuid, message: DmMessage, signer: Signer, com_key: PublicKey
+    ) -> Result<Self, Error> {
+        Ok(Self::CreateDM(id, Box::new(SignedObject::new(signer, message)?), com_key))
+    }
+
+    //A DM someone else signed, sent on as is so the recipient still sees them as the sender
+    pub fn resend_dm(
+        id: Uuid, message: SignedObject<DmMessage>, com_key: PublicKey
     ) -> Result<Self, Error> {
-        Ok(Self::CreateDM(Box::new(perms), signer, com_key))
+        Ok(Self::CreateDM(id, Box::new(message), com_key))
     }
 }
 
 pub enum Task {
     Ready(Header, BoxCommand),
     Waiting(Header, BoxCallback, Vec<Uuid>),
+    //Like Waiting but failed tasks are handed to the callback as Arc<Error> responses
+    Tolerant(Header, BoxCallback, Vec<Uuid>),
     Request(Header, AgentRequest),
     MutableRequest(Header, MutableAgentRequest, usize),
     Completed(BoxResponse),
@@ -319,6 +462,16 @@ impl Task {
         tasks.push_front((uuid, Task::Waiting(header, Box::new(callback), ids)));
         Ok(tasks.into())
     }
+
+    pub fn tolerant(
+        uuid: Uuid, header: Header, callback: BoxCallback, tasks: Vec<Task>
+    ) -> Result<Tasks, Error> {
+        let mut tasks = Task::waiting(uuid, header, callback, tasks)?;
+        if let (_, Task::Waiting(header, callback, ids)) = tasks.remove(0) {
+            tasks.insert(0, (uuid, Task::Tolerant(header, callback, ids)));
+        }
+        Ok(tasks)
+    }
   //pub fn complete(response: impl Response) -> Result<Tasks, Error> {
   //    Task::Completed(Box::new(response))
   //}
@@ -336,113 +489,464 @@ impl Callback {
     }
 }
 
+//Result of reading one channel index, Missing means the server had nothing there yet (a gap
+//that may still replicate) while Tombstoned means the index was written but its record is gone
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
-pub struct Record {
-    pub path: RecordPath,
-    pub protocol: Protocol,
-    pub payload: Vec<u8>
+pub enum ChildSlot<R = Record> {
+    Present(Box<R>),
+    Tombstoned,
+    Missing
 }
 
-impl Record {
-    pub fn new(path: RecordPath, protocol: Protocol, payload: &[u8]) -> Self {
-        Record{path, protocol, payload: payload.to_vec()}
+impl<R> ChildSlot<R> {
+    pub fn is_missing(&self) -> bool {matches!(self, Self::Missing)}
+
+    pub fn present(self) -> Option<R> {
+        if let Self::Present(record) = self {Some(*record)} else {None}
+    }
+
+    pub fn map<T>(self, f: impl FnOnce(R) -> T) -> ChildSlot<T> {
+        match self {
+            Self::Present(record) => ChildSlot::Present(Box::new(f(*record))),
+            Self::Tombstoned => ChildSlot::Tombstoned,
+            Self::Missing => ChildSlot::Missing
+        }
+    }
+}
+
+//From the (record, exists) pair ReadPrivate resolves a child pointer into
+impl<R> From<(Option<Box<R>>, bool)> for ChildSlot<R> {
+    fn from(read: (Option<Box<R>>, bool)) -> Self {
+        match read {
+            (Some(record), _) => Self::Present(record),
+            (None, true) => Self::Tombstoned,
+            (None, false) => Self::Missing
+        }
+    }
+}
+
+//Checked against each record as Scan::until reads it, the scan stops as soon as it holds
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ScanPredicate {
+    //Hash of the payload as in RecordUpdated
+    PayloadHash(String),
+    Protocol(Uuid),
+    MaxResults(usize)
+}
+
+impl ScanPredicate {
+    pub fn matches(&self, record: &PrivateRecord, found: usize) -> bool {
+        match self {
+            Self::PayloadHash(hash) => record.payload.hash().to_string() == *hash,
+            Self::Protocol(protocol) => record.protocol.uuid() == *protocol,
+            Self::MaxResults(max) => found >= *max
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanStop {
+    //A child index was missing, every record was read
+    Exhausted,
+    Matched
+}
+
+//Why a read left an item out, items the filters did not match are not drops
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DropReason {
+    BadSignature,
+    //The signer index does not name the signer
+    SpoofedSigner,
+    SignerPurpose,
+    InvalidPayload,
+    Undecryptable,
+    Malformed,
+}
+
+//Counts of what a read dropped, only kept by the read commands asked for them
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReadDiagnostics {
+    pub dropped: BTreeMap<DropReason, usize>
+}
+
+impl ReadDiagnostics {
+    pub fn record(&mut self, reason: DropReason, item: &dyn std::fmt::Display) {
+        log::debug!("Dropped {} read: {:?}", item, reason);
+        *self.dropped.entry(reason).or_default() += 1;
+    }
+
+    pub fn count(&self, reason: DropReason) -> usize {
+        self.dropped.get(&reason).copied().unwrap_or_default()
     }
+
+    pub fn total(&self) -> usize {self.dropped.values().sum()}
+}
+
+//Payloads above this are validated on the blocking pool, below it the hop costs more than the
+//check itself (see validation_threshold in tests.rs)
+pub const DEFAULT_BLOCKING_VALIDATION: usize = 64*1024;
+
+//Randomness for the ids the agent picks, keys are never drawn from it and always come from the OS.
+//Seeded only through Agent::with_rng_seed so a compile can be replayed
+#[derive(Clone, Default)]
+pub enum RngSource {
+    #[default]
+    Os,
+    Seeded(Arc<Mutex<StdRng>>)
 }
 
-impl Hashable for Record {}
+impl RngSource {
+    pub fn seeded(seed: u64) -> Self {
+        Self::Seeded(Arc::new(Mutex::new(StdRng::seed_from_u64(seed))))
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        match self {
+            Self::Os => Uuid::new_v4(),
+            Self::Seeded(rng) => {
+                let mut bytes = [0u8; 16];
+                rng.lock().unwrap().fill_bytes(&mut bytes);
+                uuid::Builder::from_random_bytes(bytes).into_uuid()
+            }
+        }
+    }
+}
 
-impl std::fmt::Debug for PrivateRecord {
+impl std::fmt::Debug for RngSource {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self.payload)
+        match self {
+            Self::Os => write!(f, "Os"),
+            Self::Seeded(_) => write!(f, "Seeded")
+        }
     }
 }
 
-#[derive(JsonSchema, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
-pub struct PrivateRecord {
-    pub perms: PermissionSet,
-    pub protocol: Protocol,
-    pub payload: Vec<u8>
+//Application validators keyed by protocol uuid, run after the protocols schema check
+#[derive(Clone)]
+pub struct Validators {
+    validators: BTreeMap<Uuid, (Arc<dyn PayloadValidator>, bool)>,
+    policies: BTreeMap<Uuid, OnInvalid>,
+    migrators: BTreeMap<MigratorId, Arc<dyn PayloadMigrator>>,
+    purposes: BTreeMap<Uuid, DidKeyPurpose>,
+    blocking_threshold: usize
+}
+
+impl Default for Validators {
+    fn default() -> Self {
+        Validators{
+            validators: BTreeMap::new(),
+            policies: BTreeMap::new(),
+            migrators: BTreeMap::new(),
+            purposes: BTreeMap::new(),
+            blocking_threshold: DEFAULT_BLOCKING_VALIDATION
+        }
+    }
 }
 
-impl PrivateRecord {
-    pub fn new(perms: PermissionSet, protocol: Protocol, payload: Vec<u8>) -> Self {
-        PrivateRecord{perms, protocol, payload}
+impl Validators {
+    pub fn set_blocking_threshold(&mut self, bytes: usize) {
+        self.blocking_threshold = bytes;
     }
 
-    pub fn into_record(self) -> Record {
-        Record{path: self.perms.path, protocol: self.protocol, payload: self.payload}
+    pub fn blocking_threshold(&self) -> usize {self.blocking_threshold}
+
+    //Validators are all behind Arcs so the clone handed to the blocking pool is cheap
+    async fn off_thread<T: Send + 'static>(
+        &self, payload: &[u8], check: impl FnOnce(&Self, &[u8]) -> Result<T, Error> + Send + 'static
+    ) -> Result<T, Error> {
+        if payload.len() <= self.blocking_threshold {return check(self, payload);}
+        let (validators, payload) = (self.clone(), payload.to_vec());
+        tokio::task::spawn_blocking(move || check(&validators, &payload)).await
+        .map_err(|e| Error::custom(&format!("Validation task failed: {}", e)))?
     }
 
-    pub fn into_item(self, create: Option<&SecretKey>) -> Result<DwnItem, Error> {
-        let discover = self.perms.discover.public_key();
-        let delete = self.perms.delete.clone().map(|d| d.public_key());
-        let read = self.perms.read.public_key();
-        let create = match create {
-            Some(create) => {
-                if create.public_key() != self.perms.create.public_key() {
-                    return Err(Error::invalid_auth("Create"));
-                }
-                create
-            },
-            None => &self.perms.create.secret_key().ok_or(Error::invalid_auth("Create"))?
+    pub async fn validate_async(&self, protocol: &Protocol, payload: &[u8], read: bool) -> Result<(), Error> {
+        let protocol = protocol.clone();
+        self.off_thread(payload, move |v, p| v.validate(&protocol, p, read)).await
+    }
+
+    pub async fn validate_read_async(
+        &self, protocol: &Protocol, payload: &[u8], own: bool
+    ) -> Result<(Vec<u8>, RecordState), Error> {
+        let protocol = protocol.clone();
+        self.off_thread(payload, move |v, p| v.validate_read(&protocol, p, own)).await
+    }
+
+    pub fn register(
+        &mut self, protocol: &Protocol, validator: impl PayloadValidator + 'static, validate_on_read: bool
+    ) -> Result<(), Error> {
+        if self.validators.contains_key(&protocol.uuid()) {
+            return Err(Error::bad_request(&format!("Validator already registered for {}", protocol.name)));
+        }
+        self.validators.insert(protocol.uuid(), (Arc::new(validator), validate_on_read));
+        Ok(())
+    }
+
+    pub fn validate(&self, protocol: &Protocol, payload: &[u8], read: bool) -> Result<(), Error> {
+        protocol.validate_payload(payload)?;
+        match self.validators.get(&protocol.uuid()) {
+            Some((validator, on_read)) if !read || *on_read => validator.validate(payload)
+                .map_err(|issue| Error::validation(&format!("{}: {}", protocol.label(), issue))),
+            _ => Ok(())
+        }
+    }
+
+    pub fn register_migrator(&mut self, id: MigratorId, migrator: impl PayloadMigrator + 'static) -> Result<(), Error> {
+        if self.migrators.contains_key(&id) {
+            return Err(Error::bad_request(&format!("Migrator already registered for {}", id)));
+        }
+        self.migrators.insert(id, Arc::new(migrator));
+        Ok(())
+    }
+
+    pub fn set_on_invalid(&mut self, protocol: &Protocol, policy: OnInvalid) -> Result<(), Error> {
+        if let OnInvalid::Migrate(id) = &policy {
+            if !self.migrators.contains_key(id) {
+                return Err(Error::not_found(&format!("Migrator {}", id)));
+            }
+        }
+        self.policies.insert(protocol.uuid(), policy);
+        Ok(())
+    }
+
+    //Public records of this protocol must be signed by a did key with the purpose
+    pub fn require_purpose(&mut self, protocol: &Protocol, purpose: DidKeyPurpose) {
+        self.purposes.insert(protocol.uuid(), purpose);
+    }
+
+    pub fn check_signer(&self, protocol: &Protocol, signer: &VerifiedBy) -> Result<(), Error> {
+        match self.purposes.get(&protocol.uuid()) {
+            Some(purpose) if !signer.has_purpose(purpose) => Err(Error::invalid_auth(&format!(
+                "{} requires a {:?} key, signed with {:?}", protocol.label(), purpose, signer.key_id
+            ))),
+            _ => Ok(())
+        }
+    }
+
+    //Own is only set when the record verifiably came from the readers own create key
+    pub fn validate_read(&self, protocol: &Protocol, payload: &[u8], own: bool) -> Result<(Vec<u8>, RecordState), Error> {
+        let error = match self.validate(protocol, payload, true) {
+            Ok(()) => return Ok((payload.to_vec(), RecordState::Valid)),
+            Err(e) => e
         };
-        let signed = SignedObject::from_key(create, self)?;
-        let payload = read.encrypt(&serde_json::to_vec(&signed)?)?;
+        match self.policies.get(&protocol.uuid()) {
+            Some(OnInvalid::SurfaceRaw) if own => {
+                let issues = vec![ValidationIssue::new(&error.to_string())];
+                Ok((payload.to_vec(), RecordState::Invalid{issues, payload: payload.to_vec()}))
+            },
+            Some(OnInvalid::Migrate(id)) if own => {
+                let migrated = self.migrators.get(id).ok_or(Error::not_found(&format!("Migrator {}", id)))?
+                .migrate(payload)?;
+                self.validate(protocol, &migrated, true)?;
+                Ok((migrated, RecordState::Migrated))
+            },
+            _ => Err(error)
+        }
+    }
+}
 
-        Ok(DwnItem{discover, delete, payload})
+impl std::fmt::Debug for Validators {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Validators")
+        .field("protocols", &self.validators.keys().collect::<Vec<_>>())
+        .field("policies", &self.policies)
+        .field("migrators", &self.migrators.keys().collect::<Vec<_>>())
+        .field("purposes", &self.purposes)
+        .field("blocking_threshold", &self.blocking_threshold)
+        .finish()
     }
 }
 
-#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
-pub struct PathedKey {
-    pub key: SecretKey,
-    pub path: RecordPath
+pub type MergerId = Uuid;
+
+//What UpdatePrivate does when the stored record changed underneath it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ConflictStrategy {
+    //Overwrite whatever is stored, no guard is sent
+    #[default]
+    LastWriterWins,
+    //Fail the update with UpdateRejected
+    Reject,
+    //Combine both payloads with a registered PayloadMerger and retry
+    Merge(MergerId)
 }
 
-impl PathedKey {
-    pub fn new(key: SecretKey, path: RecordPath) -> Self {
-        PathedKey{key, path}
-    }
+#[derive(Clone, Default)]
+pub struct ConflictStrategies {
+    strategies: BTreeMap<Uuid, ConflictStrategy>,
+    mergers: BTreeMap<MergerId, Arc<dyn PayloadMerger>>
+}
 
-    pub fn new_root(key: SecretKey) -> Self {
-        PathedKey{key, path: RecordPath::new(&[])}
+impl ConflictStrategies {
+    pub fn register_merger(&mut self, id: MergerId, merger: impl PayloadMerger + 'static) -> Result<(), Error> {
+        if self.mergers.contains_key(&id) {
+            return Err(Error::bad_request(&format!("Merger already registered for {}", id)));
+        }
+        self.mergers.insert(id, Arc::new(merger));
+        Ok(())
     }
 
-    pub fn derive_path(&self, path: &[Uuid]) -> Result<Self, Error> {
-        if let Some(striped_path) = path.strip_prefix(self.path.as_slice()) {
-            let mut key = self.key.clone();
-            for uuid in striped_path {
-                key = key.derive_bytes(uuid.as_bytes())?;
+    pub fn set(&mut self, protocol: &Protocol, strategy: ConflictStrategy) -> Result<(), Error> {
+        if let ConflictStrategy::Merge(id) = &strategy {
+            if !self.mergers.contains_key(id) {
+                return Err(Error::not_found(&format!("Merger {}", id)));
             }
-            Ok(PathedKey::new(key, RecordPath::new(path)))
-        } else {Err(Error::insufficent_permission())}
-    }
-
-    pub fn to_permission(&self) -> Result<PermissionSet, Error> {
-        let path = self.path.clone();
-        let key = &self.key;
-        Ok(PermissionSet::new(
-            path,
-            key.derive_usize(0)?,
-            Key::new_secret(key.derive_usize(1)?),
-            Key::new_secret(key.derive_usize(2)?),
-            Some(Key::new_secret(key.derive_usize(3)?)),
-            Some(ChannelPermissionSet::new(
-                Key::new_secret(key.derive_usize(4)?),
-                Key::new_secret(key.derive_usize(5)?),
-                Key::new_secret(key.derive_usize(6)?),
-            ))
-        ))
-    }
-
-    pub fn get_perms(&self, path: &RecordPath, protocol: Option<&Protocol>) -> Result<PermissionSet, Error> {
-        self.get_perms_from_slice(path.as_slice(), protocol)
-    }
-
-    pub fn get_perms_from_slice(&self, path: &[Uuid], protocol: Option<&Protocol>) -> Result<PermissionSet, Error> {
-        let perms = self.derive_path(path)?.to_permission()?;
-        if let Some(protocol) = protocol {
-            Ok(protocol.trim_permission(perms))
-        } else {Ok(perms)}
+        }
+        self.strategies.insert(protocol.uuid(), strategy);
+        Ok(())
+    }
+
+    pub fn get(&self, protocol: &Protocol) -> ConflictStrategy {
+        self.strategies.get(&protocol.uuid()).copied().unwrap_or_default()
+    }
+
+    pub fn merge(&self, id: &MergerId, base: &[u8], ours: &[u8], theirs: &[u8]) -> Result<Vec<u8>, Error> {
+        self.mergers.get(id).ok_or(Error::not_found(&format!("Merger {}", id)))?
+        .merge(base, ours, theirs)
+    }
+}
+
+impl std::fmt::Debug for ConflictStrategies {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConflictStrategies")
+        .field("strategies", &self.strategies)
+        .field("mergers", &self.mergers.keys().collect::<Vec<_>>())
+        .finish()
+    }
+}
+
+//Which of the endpoints of each recipient Send writes to, in the order the document lists them
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum EndpointPolicy {
+    //Every endpoint gets its own copy of the command
+    #[default]
+    All,
+    First,
+    //The first endpoint, moving on to the next only when one can not be reached
+    Failover
+}
+
+//How CreatePrivate treats the parent of a record on the endpoint being written to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ParentPolicy {
+    //The parent must already exist, the record is linked as its next child
+    #[default]
+    Require,
+    //Missing ancestors are copied from the tenants own endpoints before linking
+    CreateMissing,
+    //The record is written without a parent link and stays orphaned until repaired
+    Skip
+}
+
+//A record's share of the space used on one endpoint, private sizes are of the ciphertext.
+//Public records and copied items have no path, copied items no protocol either
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UsageEntry {
+    pub path: Option<RecordPath>,
+    pub protocol: Option<String>,
+    pub size: usize
+}
+
+//What a successful mutable request does to the usage ledger
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UsageChange {
+    Write(UsageEntry),
+    Remove
+}
+
+impl UsageChange {
+    pub fn sized(self, sent: &DwnRequest) -> Self {
+        match self {
+            Self::Write(entry) => Self::Write(UsageEntry{size: sent.stored_size().unwrap_or_default(), ..entry}),
+            other => other
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsageGroup {
+    //Paths cut to this many segments, public records are grouped under "public"
+    PathDepth(usize),
+    Protocol
+}
+
+impl UsageGroup {
+    pub fn key(&self, entry: &UsageEntry) -> String {
+        match self {
+            Self::PathDepth(depth) => entry.path.as_ref().map(|path| {
+                let segments = path.as_slice();
+                RecordPath::from_segments(&segments[..segments.len().min(*depth)]).to_string()
+            }).unwrap_or("public".to_string()),
+            Self::Protocol => entry.protocol.clone().unwrap_or("unknown".to_string())
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UsageTotal {
+    pub records: usize,
+    pub bytes: usize
+}
+
+//Payload of an agent_keys record, told apart by shape so records written before paths still parse
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum AgentKeys {
+    //Enc key of each agent by the path it was derived at
+    V2(BTreeMap<RecordPath, PublicKey>),
+    //Written before agents were scoped, every key is a root agent
+    V1(Vec<PublicKey>),
+}
+
+impl Default for AgentKeys {
+    fn default() -> Self {Self::V2(BTreeMap::new())}
+}
+
+impl AgentKeys {
+    pub fn parse(payload: &[u8]) -> Result<Self, Error> {
+        serde_json::from_slice(payload).or(Err(Error::bad_request("agent_keys record is in an unknown format")))
+    }
+
+    pub fn is_legacy(&self) -> bool {matches!(self, Self::V1(_))}
+
+    //Keys of the agents able to read the path
+    pub fn keys_for(&self, path: &RecordPath) -> Vec<PublicKey> {
+        match self {
+            Self::V2(keys) => keys.iter().filter(|(opath, _)| opath.parent_of(path)).map(|(_, k)| k.clone()).collect(),
+            Self::V1(keys) => keys.clone()
+        }
+    }
+
+    //V1 keys all sit at the root where only one fits, the rest are dropped and their agents
+    //add themselves back the next time they run Init
+    pub fn into_paths(self) -> BTreeMap<RecordPath, PublicKey> {
+        match self {
+            Self::V2(keys) => keys,
+            Self::V1(keys) => keys.into_iter().take(1).map(|k| (RecordPath::root(), k)).collect()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct SharesNeedingRefresh {
+    pub sharer: Did,
+    pub paths: Vec<RecordPath>
+}
+
+//Member, path and error of every share a group operation could not deliver
+pub type ShareFailures = Vec<(Did, RecordPath, String)>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum SharedFilter {
+    Sharer(Did),
+    Protocol(Uuid)
+}
+
+impl SharedFilter {
+    pub fn matches(&self, info: &SharedRecordInfo) -> bool {
+        match self {
+            Self::Sharer(did) => info.sharer == *did,
+            Self::Protocol(protocol) => info.protocol == *protocol
+        }
     }
 }
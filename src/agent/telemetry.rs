@@ -0,0 +1,77 @@
+use super::traits::AgentTelemetry;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {Success, Failure}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoTelemetry {}
+
+impl AgentTelemetry for NoTelemetry {
+    fn record(&self, _: &'static str, _: Outcome, _: Duration, _: usize) {}
+}
+
+//Upper bounds of the latency buckets, slower commands land in a last overflow bucket
+pub const LATENCY_BUCKETS: [Duration; 12] = [
+    Duration::from_millis(1), Duration::from_millis(2), Duration::from_millis(5),
+    Duration::from_millis(10), Duration::from_millis(20), Duration::from_millis(50),
+    Duration::from_millis(100), Duration::from_millis(200), Duration::from_millis(500),
+    Duration::from_secs(1), Duration::from_secs(5), Duration::from_secs(30)
+];
+
+//Latencies are counted per bucket so the stats stay the same size however many commands ran
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpStats {
+    pub successes: usize,
+    pub failures: usize,
+    pub endpoints: usize,
+    pub latencies: [usize; LATENCY_BUCKETS.len()+1],
+    pub max_latency: Duration
+}
+
+impl OpStats {
+    fn record_latency(&mut self, duration: Duration) {
+        let bucket = LATENCY_BUCKETS.iter().position(|bound| duration <= *bound).unwrap_or(LATENCY_BUCKETS.len());
+        self.latencies[bucket] += 1;
+        self.max_latency = self.max_latency.max(duration);
+    }
+
+    //Nearest rank, p is between 0 and 1. Gives the bound of the bucket the rank falls in,
+    //never above the slowest latency seen
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let count = self.latencies.iter().sum::<usize>();
+        if count == 0 {return None;}
+        let rank = ((p.clamp(0.0, 1.0) * count as f64).ceil() as usize).max(1);
+        let mut seen = 0;
+        let bucket = self.latencies.iter().position(|n| {seen += n; seen >= rank})?;
+        Some(LATENCY_BUCKETS.get(bucket).map_or(self.max_latency, |bound| *bound.min(&self.max_latency)))
+    }
+}
+
+//Keeps counters per command type in memory, clones share the same counters
+#[derive(Debug, Clone, Default)]
+pub struct TelemetryAggregator {
+    stats: Arc<Mutex<BTreeMap<&'static str, OpStats>>>
+}
+
+impl TelemetryAggregator {
+    pub fn snapshot(&self) -> BTreeMap<&'static str, OpStats> {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+impl AgentTelemetry for TelemetryAggregator {
+    fn record(&self, op: &'static str, outcome: Outcome, duration: Duration, endpoint_count: usize) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(op).or_default();
+        match outcome {
+            Outcome::Success => stats.successes += 1,
+            Outcome::Failure => stats.failures += 1
+        }
+        stats.endpoints += endpoint_count;
+        stats.record_latency(duration);
+    }
+}
@@ -1,9 +1,12 @@
 use super::Error;
+use crate::common::TypeDebug;
 
-use super::structs::{Header, Task};
+use super::structs::{ValidationIssue, Header, Record, Task};
 use super::compiler::{CompilerMemory, CompilerCache};
+use super::telemetry::Outcome;
 
 use std::any::Any;
+use std::time::Duration;
 
 use dyn_clone::{clone_trait_object, DynClone};
 use downcast_rs::DowncastSync;
@@ -17,6 +20,9 @@ pub trait Command: TypeDebug + std::fmt::Debug + Send + Sync + DynClone + erased
         memory: &mut CompilerMemory<'a>, cache: &mut CompilerCache
     ) -> Result<Vec<(Uuid, Task)>, Error>;
 
+    //The record this command writes when running it again has no further effect
+    fn idempotent_record(&self) -> Option<Record> {None}
+
     fn serialize(&self) -> String {
         let mut buffer = std::io::BufWriter::new(Vec::<u8>::new());
         let serializer = &mut serde_json::ser::Serializer::new(&mut buffer);
@@ -33,29 +39,31 @@ pub trait Command: TypeDebug + std::fmt::Debug + Send + Sync + DynClone + erased
 clone_trait_object!(Command);
 erased_serde::serialize_trait_object!(Command);
 
-pub trait TypeDebug: std::fmt::Debug {
-    fn get_full_type(&self) -> String {
-        std::any::type_name_of_val(self).to_string()
-    }
-    fn get_type(&self) -> String {
-        let full_type = self.get_full_type();
-        let split = full_type.split("::").collect::<Vec<_>>();
-        split[split.len()-1].to_string().replace(">", "").replace("<", "")
-    }
+pub trait PayloadValidator: Send + Sync {
+    fn validate(&self, payload: &[u8]) -> Result<(), ValidationIssue>;
+}
 
-    fn debug(&self, len: usize) -> String {
-        format!("{}::{}", self.get_type(), self.truncate_debug(len))
-    }
+//Three way merge of record payloads, base is the payload both writers started from
+pub trait PayloadMerger: Send + Sync {
+    fn merge(&self, base: &[u8], ours: &[u8], theirs: &[u8]) -> Result<Vec<u8>, Error>;
+}
 
-    fn truncate_debug(&self, len: usize) -> String {
-        let debug = format!("{:?}", self);
-        if debug.len() > len {debug[..len].to_string()} else {debug}
-    }
+//Upgrades a payload written under an older version of the protocols rules
+pub trait PayloadMigrator: Send + Sync {
+    fn migrate(&self, payload: &[u8]) -> Result<Vec<u8>, Error>;
 }
 
-impl<T: std::fmt::Debug> TypeDebug for T {}
+//Called once per original command when it completes, only the command type is ever reported
+pub trait AgentTelemetry: Send + Sync {
+    fn record(&self, op: &'static str, outcome: Outcome, duration: Duration, endpoint_count: usize);
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
 
-pub trait Response: Any + erased_serde::Serialize + std::fmt::Debug + DowncastSync + DynClone + TypeDebug {
+//Sealed, every type that can be sent back from a command already implements it
+pub trait Response: sealed::Sealed + Any + erased_serde::Serialize + std::fmt::Debug + DowncastSync + DynClone + TypeDebug {
   //pub fn handle_error(self) -> Result<Box<dyn Response>, Error> {
   //    if let Some(error) = response.downcast_ref::<ErrorWrapper>() {
   //        return error.into();
@@ -79,5 +87,6 @@ erased_serde::serialize_trait_object!(Response);
 clone_trait_object!(Response);
 
 
+impl<T: Any + std::fmt::Debug + Clone + Sync + Send + TypeDebug + Serialize> sealed::Sealed for T {}
 impl<T: Any + std::fmt::Debug + Clone + Sync + Send + TypeDebug + Serialize> Response for T {}
 downcast_rs::impl_downcast!(sync Response);
@@ -2,6 +2,16 @@ use super::Error;
 
 use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
 use schemars::schema::{Schema, SchemaObject, StringValidation};
+use simple_database::database::{SortOptions, SortDirection, Filters, Filter, Index, Value};
+use serde::{Serialize, Deserialize};
+use simple_database::Indexable;
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+pub mod backoff;
 
 pub struct Schemas {}
 impl Schemas {
@@ -17,24 +27,372 @@ impl Schemas {
     }
 }
 
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
 pub enum Convert {
     ZBase32,
-    Base64UrlUnpadded
+    Base64UrlUnpadded,
+    //The bitcoin alphabet, multibase strings starting with z
+    Base58Btc
 }
 
 impl Convert {
     pub fn encode(&self, data: &[u8]) -> String {
         match &self {
             Convert::ZBase32 => zbase32::encode(data),
-            Convert::Base64UrlUnpadded => BASE64_URL_SAFE_NO_PAD.encode(data)
+            Convert::Base64UrlUnpadded => BASE64_URL_SAFE_NO_PAD.encode(data),
+            Convert::Base58Btc => Self::base58_encode(data)
         }
     }
 
     pub fn decode(&self, input: &str) -> Result<Vec<u8>, Error> {
         Ok(match &self {
             Convert::ZBase32 => zbase32::decode(input)?,
-            Convert::Base64UrlUnpadded => BASE64_URL_SAFE_NO_PAD.decode(input)?
+            Convert::Base64UrlUnpadded => BASE64_URL_SAFE_NO_PAD.decode(input)?,
+            Convert::Base58Btc => Self::base58_decode(input)?
         })
     }
+
+    //Leading zero bytes are kept as leading ones
+    fn base58_encode(data: &[u8]) -> String {
+        let zeros = data.iter().take_while(|b| **b == 0).count();
+        let mut digits: Vec<u8> = Vec::new();
+        for byte in &data[zeros..] {
+            let mut carry = *byte as u32;
+            for digit in digits.iter_mut() {
+                carry += (*digit as u32) << 8;
+                *digit = (carry % 58) as u8;
+                carry /= 58;
+            }
+            while carry > 0 {
+                digits.push((carry % 58) as u8);
+                carry /= 58;
+            }
+        }
+        std::iter::repeat_n(b'1', zeros).chain(digits.iter().rev().map(|d| BASE58_ALPHABET[*d as usize]))
+            .map(char::from).collect()
+    }
+
+    fn base58_decode(input: &str) -> Result<Vec<u8>, Error> {
+        let zeros = input.bytes().take_while(|c| *c == b'1').count();
+        let mut bytes: Vec<u8> = Vec::new();
+        for c in input.bytes().skip(zeros) {
+            let mut carry = BASE58_ALPHABET.iter().position(|a| *a == c)
+                .ok_or(Error::parse("Base58", input))? as u32;
+            for byte in bytes.iter_mut() {
+                carry += (*byte as u32) * 58;
+                *byte = carry as u8;
+                carry >>= 8;
+            }
+            while carry > 0 {
+                bytes.push(carry as u8);
+                carry >>= 8;
+            }
+        }
+        Ok(std::iter::repeat_n(0, zeros).chain(bytes.into_iter().rev()).collect())
+    }
+}
+
+
+//Encrypted payloads are json so they never start with this byte, a compressed one is prefixed with it
+#[cfg_attr(not(feature = "agent"), allow(dead_code))]
+const DEFLATED: u8 = 0x00;
+//Smaller payloads rarely get smaller
+#[cfg(feature = "compression")]
+const MIN_DEFLATE: usize = 256;
+//Inflating stops here so a small item can not expand into all of the memory
+#[cfg(feature = "compression")]
+const MAX_INFLATED: u64 = 256 * 1024 * 1024;
+
+//Compresses plaintexts before they are encrypted into a DwnItem. Items are sniffed on the way back,
+//so items from before compression or from writers without it still read
+#[cfg_attr(not(feature = "agent"), allow(dead_code))]
+pub struct Compression {}
+#[cfg_attr(not(feature = "agent"), allow(dead_code))]
+impl Compression {
+    //Returns the plaintext as is without the compression feature or when deflating does not pay off
+    #[cfg_attr(not(feature = "compression"), allow(unused_mut))]
+    pub fn encode(mut plaintext: Vec<u8>) -> Vec<u8> {
+        #[cfg(feature = "compression")]
+        if plaintext.len() >= MIN_DEFLATE {
+            use std::io::Write;
+            let mut encoder = flate2::write::DeflateEncoder::new(vec![DEFLATED], flate2::Compression::default());
+            if encoder.write_all(&plaintext).is_ok() {
+                if let Ok(deflated) = encoder.finish() {
+                    if deflated.len() < plaintext.len() {plaintext = deflated;}
+                }
+            }
+        }
+        plaintext
+    }
+
+    pub fn decode(plaintext: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if plaintext.first() != Some(&DEFLATED) {return Ok(plaintext);}
+        #[cfg(feature = "compression")]
+        {
+            use std::io::Read;
+            let mut inflated = Vec::new();
+            flate2::read::DeflateDecoder::new(&plaintext[1..]).take(MAX_INFLATED + 1).read_to_end(&mut inflated)
+                .map_err(|e| Error::bad_response(&format!("Corrupt compressed payload: {}", e)))?;
+            if inflated.len() as u64 > MAX_INFLATED {
+                return Err(Error::bad_response(&format!("Compressed payload inflates past {} bytes", MAX_INFLATED)));
+            }
+            Ok(inflated)
+        }
+        #[cfg(not(feature = "compression"))]
+        Err(Error::bad_response("Payload is compressed, reading it needs the compression feature"))
+    }
 }
 
+//Only the agent and the Dwn's router describe themselves
+#[cfg_attr(not(feature = "agent"), allow(dead_code))]
+pub trait TypeDebug: std::fmt::Debug {
+    fn get_full_type(&self) -> String {
+        std::any::type_name_of_val(self).to_string()
+    }
+    fn get_op(&self) -> &'static str {
+        let full_type = std::any::type_name_of_val(self);
+        full_type.rsplit("::").next().unwrap_or(full_type)
+    }
+    fn get_type(&self) -> String {
+        let full_type = self.get_full_type();
+        let split = full_type.split("::").collect::<Vec<_>>();
+        split[split.len()-1].to_string().replace(">", "").replace("<", "")
+    }
+
+    fn debug(&self, len: usize) -> String {
+        format!("{}::{}", self.get_type(), self.truncate_debug(len))
+    }
+
+    fn truncate_debug(&self, len: usize) -> String {
+        let debug = format!("{:?}", self);
+        if debug.len() > len {debug[..len].to_string()} else {debug}
+    }
+}
+
+impl<T: std::fmt::Debug> TypeDebug for T {}
+
+//SortOptions keeps limit and cursor_key private with no setters, serde is the only way in.
+//A Dwn reads the cursor as the primary key of the last record of the previous page
+pub trait SortPaging: Sized {
+    fn with_page(self, limit: Option<usize>, cursor: Option<Vec<u8>>) -> Result<Self, Error>;
+    fn page(&self) -> Result<(Option<usize>, Option<Vec<u8>>), Error>;
+    //Whether the values are already in this order, values missing the property never are
+    fn in_order<T: Indexable>(&self, values: &[T]) -> Result<bool, Error>;
+}
+
+impl SortPaging for SortOptions {
+    fn with_page(self, limit: Option<usize>, cursor: Option<Vec<u8>>) -> Result<Self, Error> {
+        let mut value = serde_json::to_value(self)?;
+        value["limit"] = serde_json::to_value(limit)?;
+        value["cursor_key"] = serde_json::to_value(cursor)?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    fn page(&self) -> Result<(Option<usize>, Option<Vec<u8>>), Error> {
+        let value = serde_json::to_value(self)?;
+        Ok((serde_json::from_value(value["limit"].clone())?, serde_json::from_value(value["cursor_key"].clone())?))
+    }
+
+    fn in_order<T: Indexable>(&self, values: &[T]) -> Result<bool, Error> {
+        let value = serde_json::to_value(self)?;
+        let property = serde_json::from_value::<String>(value["property"].clone())?;
+        let descending = serde_json::from_value::<SortDirection>(value["direction"].clone())? == SortDirection::Descending;
+        let keys = values.iter().map(|v| v.index().get(&property).cloned()).collect::<Option<Vec<_>>>();
+        Ok(keys.map(|keys| keys.windows(2).all(|w| match descending {
+            true => w[0] >= w[1],
+            false => w[0] <= w[1]
+        })).unwrap_or(false))
+    }
+}
+
+//How the crate evaluates a Filter. simple_database's own Filter::All answers true when a sub filter
+//fails and false when all pass, Filter::range included, and its queries apply it that way.
+//Here All matches when every sub filter does, Any when one does and Not when its filter does not.
+//A filter that can not compare to the value answers None, as does an All or Any holding one
+pub trait FilterLogic {
+    fn matches(&self, value: &Value) -> Option<bool>;
+    //Whether a database query evaluates it the same, nothing holding an All is
+    fn is_sound(&self) -> bool;
+}
+
+impl FilterLogic for Filter {
+    fn matches(&self, value: &Value) -> Option<bool> {
+        match self {
+            Filter::All(filters) => filters.iter().map(|f| f.matches(value)).collect::<Option<Vec<_>>>()
+                .map(|matched| matched.into_iter().all(|m| m)),
+            Filter::Any(filters) => filters.iter().map(|f| f.matches(value)).collect::<Option<Vec<_>>>()
+                .map(|matched| matched.into_iter().any(|m| m)),
+            Filter::Not(filter) => filter.matches(value).map(|m| !m),
+            leaf => leaf.filter(value)
+        }
+    }
+
+    fn is_sound(&self) -> bool {
+        match self {
+            Filter::All(_) => false,
+            Filter::Any(filters) => filters.iter().all(|f| f.is_sound()),
+            Filter::Not(filter) => filter.is_sound(),
+            _ => true
+        }
+    }
+}
+
+//A query across properties, Filters is an And of one Prop per property
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(from = "FilterShape", into = "FilterShape")]
+pub enum FilterExpr {
+    Prop(String, Filter),
+    And(Vec<FilterExpr>),
+    Or(Vec<FilterExpr>),
+    Not(Box<FilterExpr>),
+}
+
+impl FilterExpr {
+    pub fn prop(property: &str, filter: Filter) -> Self {FilterExpr::Prop(property.to_string(), filter)}
+    pub fn new_not(expr: FilterExpr) -> Self {FilterExpr::Not(Box::new(expr))}
+
+    //As with Filters an index missing the property does not match it
+    pub fn filter(&self, index: &Index) -> bool {
+        match self {
+            FilterExpr::Prop(property, filter) => index.get(property).and_then(|v| filter.matches(v)).unwrap_or(false),
+            FilterExpr::And(exprs) => exprs.iter().all(|e| e.filter(index)),
+            FilterExpr::Or(exprs) => exprs.iter().any(|e| e.filter(index)),
+            FilterExpr::Not(expr) => !expr.filter(index)
+        }
+    }
+
+    //The Props every match has to satisfy, those of the top level And, that the database evaluates
+    //as FilterLogic does. It narrows by these, by an indexed equality when there is one, and the
+    //whole expression is checked on what it returns
+    pub fn planned(&self) -> Filters {
+        let mut props = Vec::new();
+        self.conjuncts(&mut props);
+        props.sort_by_key(|(_, filter)| !filter.is_equal());
+        let mut filters = Filters::new(vec![]);
+        for (property, filter) in props {
+            //Filters::add would nest them in an All, one per property is enough to narrow by so an equality is kept
+            if !filters.0.contains_key(property) {filters.0.insert(property.clone(), filter.clone());}
+        }
+        filters
+    }
+
+    fn conjuncts<'a>(&'a self, props: &mut Vec<(&'a String, &'a Filter)>) {
+        match self {
+            FilterExpr::Prop(property, filter) if filter.is_sound() => props.push((property, filter)),
+            FilterExpr::And(exprs) => exprs.iter().for_each(|e| e.conjuncts(props)),
+            _ => {}
+        }
+    }
+
+    //Some when the expression is an And of Props on distinct properties
+    pub fn as_filters(&self) -> Option<Filters> {
+        let FilterExpr::And(exprs) = self else {return None;};
+        let mut filters = Filters::new(vec![]);
+        for expr in exprs {
+            let FilterExpr::Prop(property, filter) = expr else {return None;};
+            if filters.0.insert(property.clone(), filter.clone()).is_some() {return None;}
+        }
+        Some(filters)
+    }
+}
+
+impl From<Filters> for FilterExpr {
+    fn from(filters: Filters) -> Self {
+        FilterExpr::And(filters.0.into_iter().map(|(property, filter)| FilterExpr::Prop(property, filter)).collect())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "FilterExpr")]
+enum TaggedFilterExpr {
+    Prop(String, Filter),
+    And(Vec<FilterExpr>),
+    Or(Vec<FilterExpr>),
+    Not(Box<FilterExpr>),
+}
+
+//Expressions Filters can hold are sent as Filters so Dwns that only know Filters still read them
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum FilterShape {
+    Filters(Filters),
+    Expr(TaggedFilterExpr),
+}
+
+impl From<FilterShape> for FilterExpr {
+    fn from(shape: FilterShape) -> Self {
+        match shape {
+            FilterShape::Filters(filters) => filters.into(),
+            FilterShape::Expr(TaggedFilterExpr::Prop(property, filter)) => FilterExpr::Prop(property, filter),
+            FilterShape::Expr(TaggedFilterExpr::And(exprs)) => FilterExpr::And(exprs),
+            FilterShape::Expr(TaggedFilterExpr::Or(exprs)) => FilterExpr::Or(exprs),
+            FilterShape::Expr(TaggedFilterExpr::Not(expr)) => FilterExpr::Not(expr)
+        }
+    }
+}
+
+impl From<FilterExpr> for FilterShape {
+    fn from(expr: FilterExpr) -> Self {
+        if let Some(filters) = expr.as_filters() {return FilterShape::Filters(filters);}
+        FilterShape::Expr(match expr {
+            FilterExpr::Prop(property, filter) => TaggedFilterExpr::Prop(property, filter),
+            FilterExpr::And(exprs) => TaggedFilterExpr::And(exprs),
+            FilterExpr::Or(exprs) => TaggedFilterExpr::Or(exprs),
+            FilterExpr::Not(expr) => TaggedFilterExpr::Not(expr)
+        })
+    }
+}
+
+//A data directory held by one live instance at a time. The os drops the lock with the process,
+//a crash leaves nothing stale behind
+#[cfg_attr(not(feature = "dwn"), allow(dead_code))]
+#[derive(Debug, Clone)]
+pub struct StoreLock(Arc<LockedDir>);
+
+#[cfg_attr(not(feature = "dwn"), allow(dead_code))]
+#[derive(Debug)]
+struct LockedDir {
+    path: PathBuf,
+    _file: File,
+    scratch: bool
+}
+
+impl Drop for LockedDir {
+    fn drop(&mut self) {
+        if self.scratch {let _ = std::fs::remove_dir_all(&self.path);}
+    }
+}
+
+#[cfg_attr(not(feature = "dwn"), allow(dead_code))]
+impl StoreLock {
+    pub fn acquire(path: &Path) -> Result<Self, Error> {
+        Self::lock(path, false)
+    }
+
+    //Unique to the process and the call, removed once the last clone is dropped
+    pub fn scratch(name: &str) -> Result<Self, Error> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = format!("web5-{}-{}-{}", name, std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        Self::lock(&std::env::temp_dir().join(dir), true)
+    }
+
+    //Where an instance keeps its data when given no path, instances that should not share it
+    //are made with their scratch constructors instead
+    pub fn default_dir(name: &str) -> Result<Self, Error> {
+        Self::acquire(Path::new(name))
+    }
+
+    fn lock(path: &Path, scratch: bool) -> Result<Self, Error> {
+        std::fs::create_dir_all(path)?;
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(path.join("LOCK"))?;
+        match file.try_lock() {
+            Ok(()) => Ok(StoreLock(Arc::new(LockedDir{path: path.to_path_buf(), _file: file, scratch}))),
+            Err(TryLockError::WouldBlock) =>
+                Err(Error::conflict(&format!("{} is locked by another instance", path.display()))),
+            Err(TryLockError::Error(e)) => Err(e.into())
+        }
+    }
+
+    pub fn path(&self) -> &Path {&self.0.path}
+}
@@ -0,0 +1,106 @@
+use crate::Error;
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::sync::Notify;
+
+//How much of each delay is drawn at random, so clients failing together do not retry together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jitter {
+    None,
+    Full,//Anywhere between zero and the delay
+    Equal,//At least half the delay
+}
+
+//Delays doubling from the base up to the max, endless unless taken
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    jitter: Jitter,
+    attempt: u32
+}
+
+impl Backoff {
+    pub fn exponential(base: Duration, max: Duration) -> Self {
+        Backoff{base, max: max.max(base), jitter: Jitter::None, attempt: 0}
+    }
+
+    pub fn with_jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let delay = 2u32.checked_pow(self.attempt).and_then(|m| self.base.checked_mul(m))
+            .map(|d| d.min(self.max)).unwrap_or(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        let mut rng = rand::thread_rng();
+        Some(match self.jitter {
+            Jitter::None => delay,
+            Jitter::Full => delay.mul_f64(rng.gen_range(0.0..=1.0)),
+            Jitter::Equal => delay / 2 + (delay / 2).mul_f64(rng.gen_range(0.0..=1.0))
+        })
+    }
+}
+
+//Shared by every clone, once cancelled it stays cancelled
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<(AtomicBool, Notify)>);
+
+impl CancelToken {
+    pub fn new() -> Self {Self::default()}
+
+    pub fn cancel(&self) {
+        self.0.0.store(true, Ordering::SeqCst);
+        self.0.1.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {self.0.0.load(Ordering::SeqCst)}
+
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.0.1.notified();
+            if self.is_cancelled() {return;}
+            notified.await;
+        }
+    }
+}
+
+//Runs the op until it succeeds, fails with an error the caller does not retry or the policy runs out
+//of delays. A cancel stops the wait before the next attempt
+pub async fn retry<T, F, Fut>(
+    policy: impl IntoIterator<Item = Duration>,
+    is_retryable: impl Fn(&Error) -> bool,
+    cancel: Option<&CancelToken>,
+    mut op: F
+) -> Result<T, Error> where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>
+{
+    let mut delays = policy.into_iter();
+    loop {
+        let error = match op().await {
+            Ok(result) => return Ok(result),
+            Err(error) => error
+        };
+        let Some(delay) = delays.next().filter(|_| is_retryable(&error)) else {return Err(error)};
+        log::debug!("Retrying in {:?} after: {}", delay, error);
+        match cancel {
+            Some(token) => tokio::select! {
+                biased;
+                _ = token.cancelled() => {return Err(Error::cancelled(&error.to_string()));},
+                _ = tokio::time::sleep(delay) => {}
+            },
+            None => tokio::time::sleep(delay).await
+        }
+    }
+}
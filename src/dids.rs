@@ -4,6 +4,7 @@ pub use traits::{DidResolver, DidDocument};
 mod structs;
 pub use structs::{
     DefaultDidResolver,
+    DID_CACHE_TTL,
     DidKeyPurpose,
     DidService,
     DidKeyPair,
@@ -20,5 +21,8 @@ pub mod signing;
 mod dht_document;
 pub use dht_document::{DhtDocument};
 
+mod web_document;
+pub use web_document::WebDocument;
+
 mod pkarr;
 mod dns_packet;
@@ -3,7 +3,9 @@ use super::Error;
 use simple_crypto::{SecretKey, PublicKey, Hashable};
 
 use crate::dids::structs::{
+    DidKeyPurpose,
     DidKeyPair,
+    DidKeyUri,
     Did,
 };
 use crate::dids::traits::DidResolver;
@@ -18,10 +20,40 @@ use either::Either;
 pub type Verifier = Either<Did, PublicKey>;
 pub type Signer = Either<DidKeyPair, SecretKey>;
 
+//Did signatures without a key id were made with this key
+pub const DEFAULT_KEY_ID: &str = "sig";
+
+//The key that checked a signature, did, key_id and purposes are only known for did signers
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct VerifiedBy {
+    pub did: Option<Did>,
+    pub key_id: Option<String>,
+    pub key: PublicKey,
+    pub purposes: Vec<DidKeyPurpose>
+}
+
+impl VerifiedBy {
+    pub fn has_purpose(&self, purpose: &DidKeyPurpose) -> bool {
+        self.purposes.contains(purpose)
+    }
+}
+
+impl From<VerifiedBy> for Verifier {
+    fn from(verified: VerifiedBy) -> Self {
+        match verified.did {
+            Some(did) => Either::Left(did),
+            None => Either::Right(verified.key)
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Signature{//TODO: add a time stamp to propery verify old rolled key signatures
     inner: Vec<u8>,
     signer: Verifier,
+    //Skipped for the default key so existing signatures keep their form
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
 }
 
 impl Signature {
@@ -31,10 +63,12 @@ impl Signature {
             Either::Left(keypair) => Signature{
                 inner: keypair.secret.sign(payload),
                 signer: Either::Left(keypair.public.key_uri().did()),
+                key_id: Some(keypair.public.id).filter(|id| id != DEFAULT_KEY_ID),
             },
             Either::Right(key) => Signature{
                 inner: key.sign(payload),
                 signer: Either::Right(key.public_key()),
+                key_id: None,
             }
         }
     }
@@ -44,16 +78,29 @@ impl Signature {
     }
 
     pub async fn verify(&self, did_resolver: &dyn DidResolver, verifier: Option<&Verifier>, payload: &[u8]) -> Result<Verifier, Error> {
+        Ok(self.verify_by(did_resolver, verifier, payload).await?.into())
+    }
+
+    pub async fn verify_by(&self, did_resolver: &dyn DidResolver, verifier: Option<&Verifier>, payload: &[u8]) -> Result<VerifiedBy, Error> {
         let verifier = verifier.unwrap_or(&self.signer);
         if *verifier != self.signer {return Err(Error::invalid_auth("Verifier did not match Signer"));}
-        let dk = match &self.signer {
+        let verified = match &self.signer {
             Either::Left(did) => {
-                did_resolver.resolve_dwn_keys(did).await?.0
+                let key_id = self.key_id.as_deref().unwrap_or(DEFAULT_KEY_ID);
+                let Some(dk) = did_resolver.resolve_key(&DidKeyUri::new(did.clone(), key_id)).await? else {
+                    did_resolver.invalidate(did).await?;
+                    return Err(Error::not_found(&format!("Key with ID {}", key_id)));
+                };
+                VerifiedBy{did: Some(did.clone()), key_id: Some(dk.id), key: dk.public_key, purposes: dk.purposes}
             },
-            Either::Right(key) => key.clone()
+            Either::Right(key) => VerifiedBy{did: None, key_id: None, key: key.clone(), purposes: Vec::new()}
         };
-        dk.verify(payload, &self.inner)?;
-        Ok(verifier.clone())
+        //The key may have been rotated since the document was cached
+        if let Err(e) = verified.key.verify(payload, &self.inner) {
+            if let Some(did) = &verified.did {did_resolver.invalidate(did).await?;}
+            return Err(e.into());
+        }
+        Ok(verified)
     }
 }
 
@@ -91,6 +138,9 @@ impl<O: SignableObject> SignedObject<O> where O: Serialize + for<'a> Deserialize
     pub async fn verify(&self, did_resolver: &dyn DidResolver, verifier: Option<&Verifier>) -> Result<Verifier, Error> {
         self.signature.verify(did_resolver, verifier, &serde_json::to_vec(&self.inner)?).await
     }
+    pub async fn verify_by(&self, did_resolver: &dyn DidResolver, verifier: Option<&Verifier>) -> Result<VerifiedBy, Error> {
+        self.signature.verify_by(did_resolver, verifier, &serde_json::to_vec(&self.inner)?).await
+    }
 }
 
 impl<O: SignableObject> Indexable for SignedObject<O> where O: Indexable + Serialize + for<'a> Deserialize<'a> {
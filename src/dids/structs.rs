@@ -1,6 +1,6 @@
 use super::Error;
 use super::traits::{DidResolver, DidDocument};
-use super::DhtDocument;
+use super::{DhtDocument, WebDocument};
 use crate::common::Schemas;
 use simple_crypto::{SecretKey, PublicKey, Hashable};
 use std::collections::BTreeMap;
@@ -13,6 +13,8 @@ use regex::Regex;
 use url::Url;
 use chrono::{DateTime, Utc};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[derive(Serialize, Deserialize, Clone, Ord, PartialOrd, Eq, PartialEq)]
 pub struct Endpoint(pub Did, pub Url);
@@ -86,7 +88,7 @@ impl std::str::FromStr for Did {
 
 impl std::fmt::Debug for Did {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "did:{}:...{}", self.method, &self.id[..5])
+        write!(f, "did:{}:...{}", self.method, self.id.get(..5).unwrap_or(&self.id))
     }
 }
 
@@ -106,13 +108,15 @@ fn did_uri_pattern() -> String {format!("^{}{}$", did_pattern(), path_query_frag
 #[derive(serde_with::DeserializeFromStr)]
 pub enum DidMethod {
     #[default]
-    DHT
+    DHT,
+    Web
 }
 
 impl std::fmt::Display for DidMethod {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::DHT => write!(f, "dht")
+            Self::DHT => write!(f, "dht"),
+            Self::Web => write!(f, "web")
         }
     }
 }
@@ -123,6 +127,7 @@ impl std::str::FromStr for DidMethod {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Ok(match s {
             "dht" => DidMethod::DHT,
+            "web" => DidMethod::Web,
             _ => return Err(Error::parse("DidMethod", s))
         })
     }
@@ -309,44 +314,107 @@ impl DidKeyPair {
     }
 }
 
+//How long a resolved document is used before it is fetched again
+pub const DID_CACHE_TTL: Duration = Duration::from_secs(300);
+
+type Fetches = Arc<Mutex<BTreeMap<Did, Arc<tokio::sync::Mutex<()>>>>>;
+
 #[derive(Debug, Clone)]
 pub struct DefaultDidResolver {
-    cache: Box<dyn KeyValueStore>
+    cache: Box<dyn KeyValueStore>,
+    source: Option<Box<dyn DidResolver>>,
+    ttl: Duration,
+    stale_while_revalidate: bool,
+    //One fetch per did at a time, concurrent misses wait on it and read what it cached
+    fetches: Fetches,
 }
 
 impl DefaultDidResolver {
     pub async fn new<KVS: KeyValueStore + 'static>(path: Option<PathBuf>) -> Result<Self, Error> {
         let path = path.unwrap_or(PathBuf::from("DefaultDidResolver"));
         Ok(DefaultDidResolver{
-            cache: Box::new(KVS::new(path).await?)
+            cache: Box::new(KVS::new(path).await?),
+            source: None,
+            ttl: DID_CACHE_TTL,
+            stale_while_revalidate: false,
+            fetches: Fetches::default(),
         })
     }
-}
 
-#[async_trait::async_trait]
-impl DidResolver for DefaultDidResolver {
-    async fn resolve(&self, did: &Did) -> Result<Option<Box<dyn DidDocument>>, Error> {
-        let bytes = serde_json::to_vec(did)?;
-        if let Some((time, doc)) = self.cache.get(&bytes).await?.as_ref().map(|b|
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    //Expired documents are returned as they are while a refresh runs in the background
+    pub fn with_stale_while_revalidate(mut self, enabled: bool) -> Self {
+        self.stale_while_revalidate = enabled;
+        self
+    }
+
+    //Fetches through another resolver instead of the DHT or the web
+    pub fn with_source(mut self, source: Box<dyn DidResolver>) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    async fn cached(&self, did: &Did) -> Result<Option<(bool, Box<dyn DidDocument>)>, Error> {
+        Ok(self.cache.get(&serde_json::to_vec(did)?).await?.as_ref().map(|b|
             serde_json::from_slice::<(DateTime<Utc>, Box<dyn DidDocument>)>(b)
-        ).transpose()? {
-            if Utc::now().timestamp() > time.timestamp()+900 {
-                self.cache.delete(&bytes).await?;
-            } else {
-                return Ok(Some(doc));
-            }
-        }
+        ).transpose()?.map(|(time, doc)| {
+            let expired = (Utc::now() - time).to_std().map(|age| age > self.ttl).unwrap_or(false);
+            (expired, doc)
+        }))
+    }
+
+    async fn fetch(&self, did: &Did) -> Result<Option<Box<dyn DidDocument>>, Error> {
+        let fetch = self.fetches.lock().unwrap().entry(did.clone()).or_default().clone();
+        let _guard = fetch.lock().await;
+        if let Some((false, doc)) = self.cached(did).await? {return Ok(Some(doc));}
+
         log::info!("Resolving did: {}", did);
-        let doc = match did.method {
-            DidMethod::DHT => DhtDocument::resolve(&did.id).await?.map(|m|
-                Box::new(m) as Box<dyn DidDocument>
-            )
+        let doc = match &self.source {
+            Some(source) => source.resolve(did).await?,
+            None => match did.method {
+                DidMethod::DHT => DhtDocument::resolve(&did.id).await?.map(|m|
+                    Box::new(m) as Box<dyn DidDocument>
+                ),
+                DidMethod::Web => WebDocument::resolve(&did.id).await?.map(|m|
+                    Box::new(m) as Box<dyn DidDocument>
+                )
+            }
         };
 
-        if let Some(doc) = doc.clone() {
-            self.cache.set(&bytes, &serde_json::to_vec(&(Utc::now(), doc))?).await?;
+        let key = serde_json::to_vec(did)?;
+        match &doc {
+            Some(doc) => self.cache.set(&key, &serde_json::to_vec(&(Utc::now(), doc))?).await?,
+            None => self.cache.delete(&key).await?
         }
         Ok(doc)
     }
 }
 
+#[async_trait::async_trait]
+impl DidResolver for DefaultDidResolver {
+    async fn resolve(&self, did: &Did) -> Result<Option<Box<dyn DidDocument>>, Error> {
+        match self.cached(did).await? {
+            Some((false, doc)) => Ok(Some(doc)),
+            Some((true, doc)) if self.stale_while_revalidate => {
+                let resolver = self.clone();
+                let did = did.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = resolver.fetch(&did).await {
+                        log::warn!("Refreshing did {} failed: {:?}", did, e);
+                    }
+                });
+                Ok(Some(doc))
+            },
+            _ => self.fetch(did).await
+        }
+    }
+
+    async fn invalidate(&self, did: &Did) -> Result<(), Error> {
+        self.cache.delete(&serde_json::to_vec(did)?).await?;
+        Ok(())
+    }
+}
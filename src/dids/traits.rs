@@ -37,6 +37,9 @@ pub trait DidResolver: DynClone + std::fmt::Debug + Sync + Send {
     async fn resolve(&self, did: &Did) -> Result<Option<Box<dyn DidDocument>>, Error>;
 
     //Provided
+    //Drops any cached document so the next resolve fetches it again
+    async fn invalidate(&self, _did: &Did) -> Result<(), Error> {Ok(())}
+
     async fn resolve_key(&self, kid: &DidKeyUri) -> Result<Option<DidKey>, Error> {
         Ok(self.resolve(&kid.did()).await?.and_then(|doc|
             doc.get_key(&kid.id()).cloned()
@@ -52,17 +55,25 @@ pub trait DidResolver: DynClone + std::fmt::Debug + Sync + Send {
     async fn get_endpoints(&self, dids: &[Did]) -> Result<Vec<Endpoint>, Error> {
         let mut result = Vec::new();
         for did in dids {
-            let doc = self.resolve(did).await?.ok_or(Error::not_found("DID Document"))?;
-            let service = &doc.get_service("dwn").ok_or(Error::not_found("DWN Service"))?;
-            for s in &service.service_endpoints {
-                if let Ok(did) = Did::from_str(s) {
-                    result.append(&mut Box::pin(self.get_endpoints(&[did])).await?);
-                } else if let Ok(url) = Url::from_str(s) {
-                    result.push(Endpoint((*did).clone(), url));
-                }
-            }
+            result.extend(self.get_ordered_endpoints(did).await?);
         }
         Ok(BTreeSet::from_iter(result).into_iter().collect())
     }
+
+    //The endpoints of one did in the order its document lists them
+    async fn get_ordered_endpoints(&self, did: &Did) -> Result<Vec<Endpoint>, Error> {
+        let doc = self.resolve(did).await?.ok_or(Error::not_found("DID Document"))?;
+        let service = &doc.get_service("dwn").ok_or(Error::not_found("DWN Service"))?;
+        let mut result: Vec<Endpoint> = Vec::new();
+        for s in &service.service_endpoints {
+            let endpoints = if let Ok(did) = Did::from_str(s) {
+                Box::pin(self.get_ordered_endpoints(&did)).await?
+            } else if let Ok(url) = Url::from_str(s) {
+                vec![Endpoint(did.clone(), url)]
+            } else {continue;};
+            result.extend(endpoints.into_iter().filter(|ep| !result.contains(ep)).collect::<Vec<_>>());
+        }
+        Ok(result)
+    }
 }
 clone_trait_object!(DidResolver);
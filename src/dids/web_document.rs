@@ -0,0 +1,147 @@
+use super::Error;
+
+use super::structs::{DidMethod, Did, DidService, DidKey, DidKeyPurpose};
+use super::traits::DidDocument;
+
+use crate::common::Convert;
+use simple_crypto::PublicKey;
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use url::Url;
+
+//Multicodec prefix of a compressed secp256k1 public key
+const SECP256K1_PUB: [u8; 2] = [0xe7, 0x01];
+
+//A did:web document, only the secp256k1 keys are kept since no other key can sign or encrypt here
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct WebDocument {
+    pub id: String,
+    pub services: BTreeMap<String, DidService>,
+    pub keys: BTreeMap<String, DidKey>,
+}
+
+impl WebDocument {
+    //did:web:example.com is served from /.well-known, did:web:example.com:user from /user.
+    //A port is percent encoded in the domain
+    pub fn url(id: &str) -> Result<Url, Error> {
+        let mut segments = id.split(':');
+        let domain = segments.next().filter(|d| !d.is_empty()).ok_or(Error::parse("did:web", id))?
+            .replace("%3A", ":").replace("%3a", ":");
+        let path = segments.collect::<Vec<_>>();
+        let path = if path.is_empty() {".well-known".to_string()} else {path.join("/")};
+        Ok(Url::from_str(&format!("https://{}/{}/did.json", domain, path))?)
+    }
+
+    //Parses a did.json, the document has to be the one for the id
+    pub fn parse(id: &str, json: &[u8]) -> Result<Self, Error> {
+        let did = Did::new(DidMethod::Web, id.to_string());
+        let error = || Error::parse("WebDocument", id);
+        let doc = serde_json::from_slice::<Value>(json)?;
+        if doc.get("id").and_then(|i| i.as_str()) != Some(&did.to_string()) {
+            return Err(Error::parse("WebDocument", &format!("{} is not the document of {}", doc["id"], did)));
+        }
+        let fragment = |reference: &str| reference.rsplit('#').next().unwrap_or(reference).to_string();
+
+        let relationships = [
+            ("authentication", DidKeyPurpose::Auth),
+            ("assertionMethod", DidKeyPurpose::Asm),
+            ("keyAgreement", DidKeyPurpose::Agm),
+            ("capabilityInvocation", DidKeyPurpose::Inv),
+            ("capabilityDelegation", DidKeyPurpose::Del),
+        ];
+        //Methods may also be embedded in a relationship instead of referenced
+        let mut methods = doc.get("verificationMethod").and_then(|m| m.as_array()).cloned().unwrap_or_default();
+        let mut purposes: BTreeMap<String, Vec<DidKeyPurpose>> = BTreeMap::new();
+        for (name, purpose) in relationships {
+            for entry in doc.get(name).and_then(|r| r.as_array()).into_iter().flatten() {
+                let reference = match entry {
+                    Value::String(reference) => reference.clone(),
+                    Value::Object(_) => {
+                        methods.push(entry.clone());
+                        entry.get("id").and_then(|i| i.as_str()).ok_or(error())?.to_string()
+                    },
+                    _ => return Err(error())
+                };
+                purposes.entry(fragment(&reference)).or_default().push(purpose.clone());
+            }
+        }
+
+        let mut keys = BTreeMap::new();
+        for method in methods {
+            let key_id = fragment(method.get("id").and_then(|i| i.as_str()).ok_or(error())?);
+            if keys.contains_key(&key_id) {continue;}
+            let Some(public_key) = Self::public_key(&method)? else {
+                log::debug!("Skipping key {} of {}, it is not secp256k1", key_id, did);
+                continue;
+            };
+            let controller = method.get("controller").and_then(|c| c.as_str())
+                .map(Did::from_str).transpose()?.filter(|c| *c != did);
+            let purposes = purposes.get(&key_id).cloned().unwrap_or_default();
+            keys.insert(key_id.clone(), DidKey::new(Some(key_id), did.clone(), public_key, purposes, controller));
+        }
+
+        let mut services = BTreeMap::new();
+        for service in doc.get("service").and_then(|s| s.as_array()).into_iter().flatten() {
+            let service_id = fragment(service.get("id").and_then(|i| i.as_str()).ok_or(error())?);
+            let types = match service.get("type") {
+                Some(Value::String(t)) => vec![t.clone()],
+                Some(Value::Array(t)) => t.iter().filter_map(|t| t.as_str().map(|t| t.to_string())).collect(),
+                _ => return Err(error())
+            };
+            //A url, a list of them or a map with the Dwn's nodes
+            let endpoints = match service.get("serviceEndpoint") {
+                Some(Value::String(e)) => vec![e.clone()],
+                Some(Value::Array(e)) => e.iter().filter_map(|e| e.as_str().map(|e| e.to_string())).collect(),
+                Some(Value::Object(e)) => e.get("nodes").and_then(|n| n.as_array()).into_iter().flatten()
+                    .filter_map(|e| e.as_str().map(|e| e.to_string())).collect(),
+                _ => return Err(error())
+            };
+            services.insert(service_id.clone(), DidService{id: service_id, types, service_endpoints: endpoints, keys: Vec::new()});
+        }
+        Ok(WebDocument{id: id.to_string(), services, keys})
+    }
+
+    //None for keys on other curves
+    fn public_key(method: &Value) -> Result<Option<PublicKey>, Error> {
+        let error = || Error::parse("WebDocument key", &method.to_string());
+        if let Some(jwk) = method.get("publicKeyJwk") {
+            if jwk.get("crv").and_then(|c| c.as_str()) != Some("secp256k1") {return Ok(None);}
+            let coordinate = |name: &str| jwk.get(name).and_then(|c| c.as_str()).ok_or(error())
+                .and_then(|c| Convert::Base64UrlUnpadded.decode(c));
+            let bytes = [vec![0x04], coordinate("x")?, coordinate("y")?].concat();
+            Ok(Some(PublicKey::from_bytes(&bytes)?))
+        } else if let Some(multibase) = method.get("publicKeyMultibase").and_then(|m| m.as_str()) {
+            let encoded = multibase.strip_prefix('z').ok_or(error())?;
+            let bytes = Convert::Base58Btc.decode(encoded)?;
+            match bytes.strip_prefix(&SECP256K1_PUB) {
+                Some(key) => Ok(Some(PublicKey::from_bytes(key)?)),
+                None => Ok(None)
+            }
+        } else {Ok(None)}
+    }
+}
+
+#[typetag::serde(name = "WEB")]
+#[async_trait::async_trait]
+impl DidDocument for WebDocument {
+    fn method(&self) -> DidMethod { DidMethod::Web }
+    fn id(&self) -> String {self.id.clone()}
+
+    fn keys(&self) -> Vec<&DidKey> { self.keys.values().collect() }
+    fn services(&self) -> Vec<&DidService> { self.services.values().collect() }
+
+    fn get_key(&self, id: &str) -> Option<&DidKey> { self.keys.get(id) }
+    fn get_service(&self, id: &str) -> Option<&DidService> { self.services.get(id) }
+
+    async fn resolve(id: &str) -> Result<Option<Self>, Error> {
+        let res = reqwest::get(Self::url(id)?).await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {return Ok(None);}
+        if !res.status().is_success() {return Err(Error::bad_response(&res.text().await?));}
+        Ok(Some(Self::parse(id, &res.bytes().await?)?))
+    }
+}
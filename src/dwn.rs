@@ -6,7 +6,10 @@ pub mod json_rpc;
 use super::Error;
 
 use crate::ed25519::SecretKey as EdSecretKey;
-use crate::dids::signing::Verifier;
+use crate::common::{SortPaging, FilterExpr, StoreLock, Compression};
+use crate::model::protocol::{Protocol, SystemProtocols};
+use crate::model::structs::DmMessage;
+use crate::dids::signing::{SignedObject, Verifier};
 use crate::dids::{
     DefaultDidResolver,
     DidResolver,
@@ -19,23 +22,62 @@ use crate::dids::{
 };
 
 use structs::{
+    FEATURE_GUARDED_UPDATE,
+    FEATURE_ACCESS_LOG,
+    FEATURE_RECEIPT,
+    FEATURE_SUBSCRIBE_DM,
+    FEATURE_READ_SESSION,
+    FEATURE_COUNT,
+    READ_SESSION_TTL,
+    DwnCapabilities,
+    SessionRead,
+    AdminRequest,
+    AdminResponse,
+    TenantStats,
+    UsageCounter,
+    UsageScope,
+    DwnStats,
+    Usage,
+    AbuseReport,
+    StoredDM,
+    FEATURES,
     PublicDwnItem,
+    PublicLimits,
+    DmCursor,
+    ErrorContext,
+    AccessLogEntry,
+    PublicVersion,
+    PublicRecord,
+    AuditedKey,
     DwnResponse,
     DwnRequest,
     DwnItem,
+    Takedown,
+    Receipt,
+    DmPage,
     Packet,
 };
 
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use simple_crypto::SecretKey;
+use simple_crypto::{SecretKey, PublicKey, Hashable};
 use simple_database::{KeyValueStore, Indexable, Database};
-use simple_database::database::{Filters, Filter, UuidKeyed, CmpType};
+use simple_database::database::{Filters, Filter, Index, UuidKeyed, CmpType, SortOptions};
 
 use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Utc};
 use futures::future;
+use tokio::sync::broadcast;
 use uuid::Uuid;
 
+//Largest DM page served whatever limit is asked for
+pub const MAX_DM_PAGE: usize = 1000;
+//How long a SubscribeDM is held open with nothing to send
+pub const DEFAULT_DM_WAIT: Duration = Duration::from_secs(25);
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct DwnIdentity {
     did_key: EdSecretKey,
@@ -68,13 +110,40 @@ impl DwnIdentity {
     }
 }
 
+//Discover key and session hash to the expiry of the proof
+type ReadSessions = BTreeMap<(Vec<u8>, Vec<u8>), DateTime<Utc>>;
+
 #[derive(Clone)]
 pub struct Dwn {
     pub com_key: DidKeyPair,
     pub private_database: Database,
     pub public_database: Database,
     pub dms_database: Database,
+    pub history_database: Database,
+    pub audit_database: Database,
+    pub access_database: Database,
+    pub usage_database: Database,
     pub did_resolver: Box<dyn DidResolver>,
+    //Protocol uuid to the number of versions kept per record
+    pub history: BTreeMap<Uuid, usize>,
+    pub clock: fn() -> DateTime<Utc>,
+    //Entries kept per tenant, 0 leaves access logging off
+    pub access_log: usize,
+    pub limits: PublicLimits,
+    //Advertised in Capabilities, the access log is only advertised while it is on
+    pub features: BTreeSet<String>,
+    pub dm_wait: Duration,
+    //Besides the Dwn's own did, who may send AdminRequests
+    pub admins: BTreeSet<Did>,
+    //Counters are read and written back, one update at a time
+    usage: Arc<tokio::sync::Mutex<()>>,
+    //Requests are handled one at a time, subscriptions let go of it while they wait
+    requests: Arc<tokio::sync::Mutex<()>>,
+    //Proven discover keys, lost on restart
+    read_sessions: Arc<std::sync::Mutex<ReadSessions>>,
+    //Recipient fingerprint of every DM stored, wakes the subscriptions waiting on it
+    dm_arrivals: broadcast::Sender<Vec<u8>>,
+    lock: StoreLock,
 }
 
 impl Dwn {
@@ -83,7 +152,27 @@ impl Dwn {
         data_path: Option<PathBuf>,
         did_resolver: Option<Box<dyn DidResolver>>,
     ) -> Result<Self, Error> {
-        let data_path = data_path.unwrap_or(PathBuf::from("Dwn"));
+        let lock = match data_path {
+            Some(path) => StoreLock::acquire(&path)?,
+            None => StoreLock::default_dir("Dwn")?
+        };
+        Self::locked::<KVS>(dwn_identity, lock, did_resolver).await
+    }
+
+    //In a directory of its own that is removed once the Dwn is dropped, for Dwns that come and go
+    pub async fn scratch<KVS: KeyValueStore + 'static>(
+        dwn_identity: DwnIdentity,
+        did_resolver: Option<Box<dyn DidResolver>>,
+    ) -> Result<Self, Error> {
+        Self::locked::<KVS>(dwn_identity, StoreLock::scratch("Dwn")?, did_resolver).await
+    }
+
+    async fn locked<KVS: KeyValueStore + 'static>(
+        dwn_identity: DwnIdentity,
+        lock: StoreLock,
+        did_resolver: Option<Box<dyn DidResolver>>,
+    ) -> Result<Self, Error> {
+        let data_path = lock.path().to_path_buf();
         let did_resolver = did_resolver.unwrap_or(Box::new(
             DefaultDidResolver::new::<KVS>(Some(data_path.join("DefaultDidResolver"))).await?
         ));
@@ -92,10 +181,104 @@ impl Dwn {
             private_database: Database::new::<KVS>(data_path.join("DATABASE").join("PRIVATE")).await?,
             public_database: Database::new::<KVS>(data_path.join("DATABASE").join("PUBLIC")).await?,
             dms_database: Database::new::<KVS>(data_path.join("DATABASE").join("DMS")).await?,
+            history_database: Database::new::<KVS>(data_path.join("DATABASE").join("HISTORY")).await?,
+            audit_database: Database::new::<KVS>(data_path.join("DATABASE").join("AUDIT")).await?,
+            access_database: Database::new::<KVS>(data_path.join("DATABASE").join("ACCESS")).await?,
+            usage_database: Database::new::<KVS>(data_path.join("DATABASE").join("USAGE")).await?,
             did_resolver,
+            history: BTreeMap::new(),
+            clock: Utc::now,
+            access_log: 0,
+            limits: PublicLimits::default(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+            dm_wait: DEFAULT_DM_WAIT,
+            admins: BTreeSet::new(),
+            usage: Arc::default(),
+            requests: Arc::default(),
+            read_sessions: Arc::default(),
+            dm_arrivals: broadcast::channel(64).0,
+            lock,
         })
     }
 
+    pub fn data_path(&self) -> &Path {self.lock.path()}
+
+    //Clears every database, the data path stays locked to this Dwn.
+    //Removed key by key, clearing a partitioned MemoryStore deadlocks
+    pub async fn reset(&self) -> Result<(), Error> {
+        for database in [
+            &self.private_database, &self.public_database, &self.dms_database,
+            &self.history_database, &self.audit_database, &self.access_database, &self.usage_database
+        ] {
+            for key in database.keys().await? {
+                database.delete(&key).await?;
+            }
+        }
+        Ok(())
+    }
+
+    //Deletes the private items whose expiry passed, returning how many. Stored expiries are kept
+    //to the second, items expiring in the current second wait for the next sweep
+    pub async fn collect_garbage(&self) -> Result<usize, Error> {
+        let filters = Filters::new(vec![("expires", Filter::cmp(CmpType::LT, (self.clock)()))]);
+        let expired = self.private_database.query::<DwnItem>(&filters, None).await?.0;
+        for item in &expired {
+            self.private_database.delete(&item.primary_key()).await?;
+            self.account(vec![UsageScope::Private], Some(item.payload.len()), None).await?;
+        }
+        Ok(expired.len())
+    }
+
+    //Keep up to retention versions of each public record of this protocol, 0 turns history off
+    pub fn keep_history(&mut self, protocol: &Protocol, retention: usize) {
+        if retention == 0 {
+            self.history.remove(&protocol.uuid());
+        } else {
+            self.history.insert(protocol.uuid(), retention);
+        }
+    }
+
+    //Let tenants audit access to their records keeping up to capacity entries each, 0 turns it off
+    pub fn log_access(&mut self, capacity: usize) {
+        self.access_log = capacity;
+    }
+
+    pub fn with_clock(mut self, clock: fn() -> DateTime<Utc>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    //Agents learn these through a Capabilities request
+    pub fn with_limits(mut self, limits: PublicLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_dm_wait(mut self, wait: Duration) -> Self {
+        self.dm_wait = wait;
+        self
+    }
+
+    pub fn with_admins(mut self, admins: &[Did]) -> Self {
+        self.admins = admins.iter().cloned().collect();
+        self
+    }
+
+    //Agents fall back to the older requests for features left out
+    pub fn with_features(mut self, features: &[&str]) -> Self {
+        self.features = features.iter().map(|f| f.to_string()).collect();
+        self
+    }
+
+    fn capabilities(&self) -> DwnCapabilities {
+        let mut features = self.features.clone();
+        if self.access_log == 0 {features.remove(FEATURE_ACCESS_LOG);}
+        DwnCapabilities{
+            version: env!("CARGO_PKG_VERSION").to_string(), features,
+            public: self.limits, dm_page: MAX_DM_PAGE
+        }
+    }
+
     pub async fn process_packet(
         &self, packet: Packet
     ) -> Result<Vec<(Uuid, DwnResponse)>, Error> {
@@ -105,118 +288,741 @@ impl Dwn {
             let payload = self.com_key.secret.decrypt(&packet.payload)?;
             let reqs = serde_json::from_slice::<Vec<(Uuid, DwnRequest)>>(&payload)?;
             Ok(future::try_join_all(reqs.into_iter().map(|(uuid, req)| async move {
-                let response = self.process_request(req).await;
+                let response = self.process_receipted(uuid, req).await;
                 if let Err(e) = &response {println!("Error: {}", e)}
                 Ok::<(Uuid, DwnResponse), Error>((uuid, response?))
             })).await?)
         }
     }
 
+    //Writes accepted with a receipt requested are answered with one in place of Empty
+    async fn process_receipted(&self, uuid: Uuid, request: DwnRequest) -> Result<DwnResponse, Error> {
+        match request {
+            DwnRequest::WithReceipt(request) if self.features.contains(FEATURE_RECEIPT) && !request.is_idempotent() => {
+                let payload_hash = request.hash_bytes();
+                Ok(match self.process_request(*request).await? {
+                    DwnResponse::Empty => {
+                        let receipt = Receipt{request_id: uuid, payload_hash, timestamp: (self.clock)()};
+                        DwnResponse::Receipt(SignedObject::from_keypair(&self.com_key, receipt)?)
+                    },
+                    response => response
+                })
+            },
+            DwnRequest::WithReceipt(request) => self.process_request(*request).await,
+            request => self.process_request(request).await
+        }
+    }
+
     pub async fn process_request(&self, request: DwnRequest) -> Result<DwnResponse, Error> {
+        let _guard = match &request {
+            DwnRequest::SubscribeDM(_) => None,
+            _ => Some(self.requests.lock().await)
+        };
+        let accessed = if self.access_log > 0 {Self::accessed(&request)} else {None};
+        let response = self.handle_request(request).await?;
+        if let Some((discover, requester, kind)) = accessed {
+            if !response.is_invalid_auth() {
+                self.log_request(&discover, requester, kind).await?;
+            }
+        }
+        Ok(response)
+    }
+
+    async fn handle_request(&self, request: DwnRequest) -> Result<DwnResponse, Error> {
         Ok(match request {
             DwnRequest::CreatePrivate(dis_signed) => {
                 let discover = &dis_signed.inner().discover;
+                let context = ErrorContext::new("Signature").with_discover(discover);
                 if dis_signed.verify(&*self.did_resolver, Some(&Verifier::Right(discover.clone()))).await.is_ok() {
                     let item = dis_signed.unwrap();
                     if let Some(old_item) = self.private_database.get::<DwnItem>(&item.primary_key()).await? {
-                        DwnResponse::Conflict(old_item)
+                        let context = ErrorContext::new("Record Exists").with_discover(&old_item.discover);
+                        DwnResponse::Conflict(old_item, context)
                     } else {
                         self.private_database.set(&item).await?;
+                        self.account(vec![UsageScope::Private], None, Some(item.payload.len())).await?;
                         DwnResponse::Empty
                     }
-                } else {DwnResponse::InvalidAuth("Signature".to_string())}
+                } else {DwnResponse::InvalidAuth(context)}
             },
             DwnRequest::ReadPrivate(signed) => {
                 if let Ok(Verifier::Right(discover)) = signed.verify(&*self.did_resolver, None).await {
-                    DwnResponse::ReadPrivate(self.private_database.get::<DwnItem>(&discover.to_vec()).await?)
-                } else {DwnResponse::InvalidAuth("Signature".to_string())}
-
-            },
-            DwnRequest::UpdatePrivate(del_signed) => {
-                if let Ok(Verifier::Right(key)) = del_signed.verify(&*self.did_resolver, None).await {
-                    let dis_signed = del_signed.unwrap();
-                    let discover = &dis_signed.inner().discover;
-                    if dis_signed.verify(&*self.did_resolver, Some(&Verifier::Right(discover.clone()))).await.is_ok() {
-                        let item = dis_signed.unwrap();
-                        if let Some(old_item) = self.private_database.get::<DwnItem>(&item.discover.to_vec()).await? {
-                            if old_item.delete != Some(key) {
-                                return Ok(DwnResponse::InvalidAuth("Delete".to_string()));
-                            }
-                        }
-                        self.private_database.set(&item).await?;
-                        DwnResponse::Empty
-                    } else {DwnResponse::InvalidAuth("Signature".to_string())}
-                } else {DwnResponse::InvalidAuth("Signature".to_string())}
+                    let item = self.private_database.get::<DwnItem>(&discover.to_vec()).await?;
+                    DwnResponse::ReadPrivate(item.filter(|item| !item.is_expired((self.clock)())))
+                } else {DwnResponse::InvalidAuth(ErrorContext::new("Signature"))}
+
+            },
+            DwnRequest::ReadPrivateSession(_) if !self.features.contains(FEATURE_READ_SESSION) =>
+                DwnResponse::InvalidAuth(ErrorContext::new("Read Sessions Unsupported")),
+            DwnRequest::ReadPrivateSession(read) => {
+                if self.read_session(&read).await {
+                    let item = self.private_database.get::<DwnItem>(&read.discover.to_vec()).await?;
+                    DwnResponse::ReadPrivate(item.filter(|item| !item.is_expired((self.clock)())))
+                } else {DwnResponse::InvalidAuth(ErrorContext::new("Read Session").with_discover(&read.discover))}
             },
+            DwnRequest::UpdatePrivate(del_signed) => self.update_private(del_signed, None).await?,
+            DwnRequest::GuardedUpdatePrivate(_, _) if !self.features.contains(FEATURE_GUARDED_UPDATE) =>
+                DwnResponse::InvalidAuth(ErrorContext::new("Guarded Updates Unsupported")),
+            DwnRequest::GuardedUpdatePrivate(del_signed, guard) =>
+                self.update_private(del_signed, Some(guard)).await?,
             DwnRequest::DeletePrivate(discover) => {
+                let context = ErrorContext::new("Signature").with_discover(discover.inner());
                 if let Ok(Verifier::Right(delete)) = discover.verify(&*self.did_resolver, None).await {
                     let discover = discover.unwrap();
                     if let Some(old_item) = self.private_database.get::<DwnItem>(&discover.to_vec()).await? {
                         if old_item.delete != Some(delete) {
-                            return Ok(DwnResponse::InvalidAuth("Delete".to_string()));
+                            return Ok(DwnResponse::InvalidAuth(ErrorContext::new("Delete").with_discover(&discover)));
                         }
                         self.private_database.delete(&discover.to_vec()).await?;
+                        self.account(vec![UsageScope::Private], Some(old_item.payload.len()), None).await?;
                     }
                     DwnResponse::Empty
-                } else {DwnResponse::InvalidAuth("Signature".to_string())}
+                } else {DwnResponse::InvalidAuth(context)}
             },
             DwnRequest::CreatePublic(item) => {
+                let id = item.0.inner().uuid;
+                if PublicRecord::validate_index(&item.0.inner().index).is_err() {
+                    return Ok(DwnResponse::BadRequest(ErrorContext::new("Reserved Index").with_id(id)));
+                }
+                if let Err(e) = self.limits.check(item.0.inner()) {
+                    return Ok(DwnResponse::InvalidAuth(ErrorContext::new(&e.to_string()).with_id(id)));
+                }
                 if item.0.verify(&*self.did_resolver, None).await.is_ok() {
                     if let Some(item) = self.public_database.get::<PublicDwnItem>(&item.primary_key()).await? {
-                        return Ok(DwnResponse::PublicConflict(item));
+                        return Ok(DwnResponse::PublicConflict(item, ErrorContext::new("Record Exists").with_id(id)));
                     }
                     self.public_database.set(&item).await?;
+                    self.store_version(&item).await?;
+                    self.account_public(None, Some(&item)).await?;
                     DwnResponse::Empty
-                } else {DwnResponse::InvalidAuth("Signature".to_string())}
+                } else {DwnResponse::InvalidAuth(ErrorContext::new("Signature").with_id(id))}
             },
             DwnRequest::ReadPublic(filters, sort_options) => {
-                DwnResponse::ReadPublic(self.public_database.query::<PublicDwnItem>(&filters, sort_options).await?.0)
+                let (items, cursor) = self.read_public(&filters, sort_options).await?;
+                DwnResponse::ReadPublic(items, cursor)
             },
-            DwnRequest::UpdatePublic(item) => {
-                if let Ok(verifier) = item.0.verify(&*self.did_resolver, None).await {
-                    if let Some(oitem) = self.public_database.get::<PublicDwnItem>(&item.primary_key()).await? {
-                        if verifier != *oitem.0.signer() {
-                            return Ok(DwnResponse::InvalidAuth("Signature".to_string()));
-                        }
-                    }
-                    self.public_database.set(&item).await?;
-                    DwnResponse::Empty
-                } else {DwnResponse::InvalidAuth("Signature".to_string())}
+            DwnRequest::ReadPublicAt(filters, at, sort_options) => {
+                let (items, cursor) = self.read_public_at(&filters, at, sort_options).await?;
+                DwnResponse::ReadPublic(items, cursor)
             },
+            DwnRequest::CountPublic(_) if !self.features.contains(FEATURE_COUNT) =>
+                DwnResponse::InvalidAuth(ErrorContext::new("Counts Unsupported")),
+            DwnRequest::CountPublic(filters) => DwnResponse::Count(self.query_public(&filters, None).await?.len()),
+            DwnRequest::UpdatePublic(item) => self.update_public(item, None).await?,
+            DwnRequest::GuardedUpdatePublic(item, _) if !self.features.contains(FEATURE_GUARDED_UPDATE) =>
+                DwnResponse::InvalidAuth(ErrorContext::new("Guarded Updates Unsupported").with_id(item.0.inner().uuid)),
+            DwnRequest::GuardedUpdatePublic(item, generation) =>
+                self.update_public(item, Some(generation)).await?,
             DwnRequest::DeletePublic(req) => {
+                let id = *req.inner();
                 if let Ok(verifier) = req.verify(&*self.did_resolver, None).await {
                     if let Some(item) = self.public_database.get::<PublicDwnItem>(req.inner().as_bytes()).await? {
                         if verifier != *item.0.signer() {
-                            return Ok(DwnResponse::InvalidAuth("Signature".to_string()));
+                            return Ok(DwnResponse::InvalidAuth(ErrorContext::new("Not Record Signer").with_id(id)));
                         }
+                        self.public_database.delete(&item.primary_key()).await?;
+                        self.delete_versions(&item.0.inner().uuid).await?;
+                        self.account_public(Some(&item), None).await?;
                     }
                     DwnResponse::Empty
-                } else {DwnResponse::InvalidAuth("Signature".to_string())}
+                } else {DwnResponse::InvalidAuth(ErrorContext::new("Signature").with_id(id))}
             },
             DwnRequest::CreateDM(item) => {
-                let dm = UuidKeyed::new(item);
-                self.dms_database.set(&dm).await?;
+                self.store_dm(item).await?;
                 DwnResponse::Empty
             },
-            DwnRequest::ReadDM(timestamp) => {
-                if let Ok(Verifier::Right(key)) = timestamp.verify(&*self.did_resolver, None).await {
-                    let timestamp = timestamp.unwrap();
-                    let filters = Filters::new(vec![
-                        ("timestamp_stored", Filter::cmp(CmpType::GT, timestamp)),
-                        ("discover", Filter::equal(key.to_vec()))
-                    ]);
-                    DwnResponse::ReadDM(self.dms_database.query::<UuidKeyed<DwnItem>>(&filters, None).await?.0.into_iter().map(|dm| dm.inner()).collect::<Vec<DwnItem>>())
-                } else {DwnResponse::InvalidAuth("Signature".to_string())}
+            DwnRequest::ReadDM(signed) => {
+                if let Ok(Verifier::Right(key)) = signed.verify(&*self.did_resolver, None).await {
+                    let (cursor, limit) = signed.unwrap();
+                    let (items, page) = self.read_dms(key, cursor, limit).await?;
+                    DwnResponse::ReadDM(items, page)
+                } else {DwnResponse::InvalidAuth(ErrorContext::new("Signature"))}
+            },
+            DwnRequest::SubscribeDM(_) if !self.features.contains(FEATURE_SUBSCRIBE_DM) =>
+                DwnResponse::InvalidAuth(ErrorContext::new("Subscriptions Unsupported")),
+            DwnRequest::SubscribeDM(signed) => {
+                if let Ok(Verifier::Right(key)) = signed.verify(&*self.did_resolver, None).await {
+                    let (cursor, limit) = signed.unwrap();
+                    let (items, page) = self.subscribe_dms(key, cursor, limit).await?;
+                    DwnResponse::ReadDM(items, page)
+                } else {DwnResponse::InvalidAuth(ErrorContext::new("Signature"))}
+            },
+            DwnRequest::DeleteDM(signed) => {
+                if let Ok(Verifier::Right(key)) = signed.verify(&*self.did_resolver, None).await {
+                    let before = signed.unwrap();
+                    if before > (self.clock)() {
+                        return Ok(DwnResponse::InvalidAuth(ErrorContext::new("Timestamp In Future")));
+                    }
+                    self.delete_dms(&key, before).await?;
+                    DwnResponse::Empty
+                } else {DwnResponse::InvalidAuth(ErrorContext::new("Signature"))}
+            },
+            DwnRequest::Capabilities => DwnResponse::Capabilities(
+                SignedObject::from_keypair(&self.com_key, self.capabilities())?
+            ),
+            DwnRequest::AuditAccess(signed) => {
+                if self.access_log == 0 {
+                    return Ok(DwnResponse::InvalidAuth(ErrorContext::new("Access Log Disabled")));
+                }
+                if let Ok(Verifier::Left(tenant)) = signed.verify(&*self.did_resolver, None).await {
+                    let mut audited = Vec::new();
+                    for proof in signed.unwrap() {
+                        match proof.verify(&*self.did_resolver, None).await {
+                            Ok(Verifier::Right(discover)) if *proof.inner() == tenant => {
+                                audited.push(AuditedKey{fingerprint: discover.thumbprint(), tenant: tenant.clone()});
+                            },
+                            _ => return Ok(DwnResponse::InvalidAuth(ErrorContext::new("Audited Key Proof")))
+                        }
+                    }
+                    for key in &audited {
+                        if let Some(other) = self.audit_database.get::<AuditedKey>(&key.primary_key()).await? {
+                            if other.tenant != tenant {
+                                return Ok(DwnResponse::InvalidAuth(ErrorContext::new("Audited By Another Tenant")));
+                            }
+                        }
+                    }
+                    let filters = Filters::new(vec![("tenant", Filter::equal(tenant.to_string()))]);
+                    for old in self.audit_database.query::<AuditedKey>(&filters, None).await?.0 {
+                        self.audit_database.delete(&old.primary_key()).await?;
+                    }
+                    for key in audited {
+                        self.audit_database.set(&key).await?;
+                    }
+                    DwnResponse::Empty
+                } else {DwnResponse::InvalidAuth(ErrorContext::new("Signature"))}
+            },
+            DwnRequest::ReadAccessLog(since) => {
+                if let Ok(Verifier::Left(tenant)) = since.verify(&*self.did_resolver, None).await {
+                    let since = since.unwrap();
+                    DwnResponse::ReadAccessLog(self.access_entries(&tenant).await?.into_iter()
+                        .filter(|e| e.timestamp > since).collect())
+                } else {DwnResponse::InvalidAuth(ErrorContext::new("Signature"))}
+            },
+            DwnRequest::Takedown(signed) => {
+                let report = signed.inner().0;
+                match signed.verify(&*self.did_resolver, None).await {
+                    Ok(Verifier::Left(did)) if did == self.com_key.public.did => {
+                        let (report, reason) = signed.unwrap();
+                        self.take_down(report, reason).await?
+                    },
+                    _ => DwnResponse::InvalidAuth(ErrorContext::new("Not Operator").with_id(report))
+                }
+            },
+            //Only unwrapped for a request of a packet, the receipt names its id
+            DwnRequest::WithReceipt(_) => DwnResponse::InvalidAuth(ErrorContext::new("Receipt Outside Packet"))
+        })
+    }
+
+    //Reports whose record is still up, for the operator to act on
+    pub async fn open_reports(&self) -> Result<Vec<(Uuid, AbuseReport)>, Error> {
+        let protocol = SystemProtocols::abuse_report().uuid().to_string();
+        let filters = Filters::new(vec![("protocol", Filter::equal(protocol))]);
+        let mut open = Vec::new();
+        for item in self.public_database.query::<PublicDwnItem>(&filters, None).await?.0 {
+            let Ok(report) = serde_json::from_slice::<AbuseReport>(&item.0.inner().payload) else {continue;};
+            if self.reported(&report).await?.is_some() {
+                open.push((item.0.inner().uuid, report));
+            }
+        }
+        Ok(open)
+    }
+
+    //Operator side of a Takedown request, signed with the Dwn's own com key
+    pub async fn takedown(&self, report: Uuid, reason: &str) -> Result<(), Error> {
+        let signed = SignedObject::from_keypair(&self.com_key, (report, reason.to_string()))?;
+        self.process_request(DwnRequest::Takedown(signed)).await?.into_empty()
+    }
+
+    //The record a report names if it is still up and signed by the tenant named
+    async fn reported(&self, report: &AbuseReport) -> Result<Option<PublicDwnItem>, Error> {
+        Ok(self.public_database.get::<PublicDwnItem>(report.record.as_bytes()).await?.filter(|item|
+            *item.0.signer() == Verifier::Left(report.tenant.clone()) &&
+            item.0.inner().protocol != SystemProtocols::takedown()
+        ))
+    }
+
+    //Private records are out of reach, the operator can not read them. The tenant is told by a DM
+    //stored here, their public records being hosted here this is one of their Dwns
+    async fn take_down(&self, report: Uuid, reason: String) -> Result<DwnResponse, Error> {
+        let filed = match self.public_database.get::<PublicDwnItem>(report.as_bytes()).await? {
+            Some(item) if item.0.inner().protocol == SystemProtocols::abuse_report() => item,
+            _ => return Ok(DwnResponse::InvalidAuth(ErrorContext::new("Report Not Found").with_id(report)))
+        };
+        let filed = serde_json::from_slice::<AbuseReport>(&filed.0.inner().payload)?;
+        if self.reported(&filed).await?.is_none() {
+            return Ok(DwnResponse::InvalidAuth(ErrorContext::new("Reported Record Not Found").with_id(filed.record)));
+        }
+        let takedown = Takedown{
+            report, record: filed.record, tenant: filed.tenant, reason, timestamp: (self.clock)()
+        };
+        let tombstone = PublicRecord::new(
+            Some(takedown.record), SystemProtocols::takedown(), &serde_json::to_vec(&takedown)?, None
+        )?;
+        let tombstone = PublicDwnItem(SignedObject::from_keypair(&self.com_key, tombstone)?);
+        let taken = self.public_database.get::<PublicDwnItem>(&tombstone.primary_key()).await?;
+        self.public_database.set(&tombstone).await?;
+        self.store_version(&tombstone).await?;
+        self.account_public(taken.as_ref(), None).await?;
+        self.account_public(None, Some(&tombstone)).await?;
+
+        let (_, com_key) = self.did_resolver.resolve_dwn_keys(&takedown.tenant).await?;
+        let message = SignedObject::from_keypair(&self.com_key, DmMessage::Takedown(takedown))?;
+        let payload = com_key.encrypt(&Compression::encode(serde_json::to_vec(&message)?))?;
+        self.store_dm(DwnItem{discover: com_key, delete: None, payload, expires: None}).await?;
+        Ok(DwnResponse::Empty)
+    }
+
+    async fn account(&self, scopes: Vec<UsageScope>, old: Option<usize>, new: Option<usize>) -> Result<(), Error> {
+        let _guard = self.usage.lock().await;
+        for scope in scopes {
+            let key = serde_json::to_vec(&scope)?;
+            let mut counter = self.usage_database.get::<UsageCounter>(&key).await?
+                .unwrap_or(UsageCounter{scope, usage: Usage::default()});
+            counter.usage.apply(old, new, (self.clock)());
+            self.usage_database.set(&counter).await?;
+        }
+        Ok(())
+    }
+
+    //Records signed by a key alone count toward the totals only
+    async fn account_public(&self, old: Option<&PublicDwnItem>, new: Option<&PublicDwnItem>) -> Result<(), Error> {
+        let mut scopes = vec![UsageScope::Public];
+        if let Some(Verifier::Left(tenant)) = new.or(old).map(|item| item.0.signer()) {
+            scopes.push(UsageScope::Tenant(tenant.clone()));
+        }
+        let size = |item: &PublicDwnItem| item.0.inner().payload.len();
+        self.account(scopes, old.map(size), new.map(size)).await
+    }
+
+    async fn account_dm(&self, dm: &StoredDM, removed: bool) -> Result<(), Error> {
+        let scopes = vec![UsageScope::Dms, UsageScope::Recipient(StoredDM::fingerprint(&dm.item.discover))];
+        let size = Some(dm.item.payload.len());
+        if removed {self.account(scopes, size, None).await} else {self.account(scopes, None, size).await}
+    }
+
+    async fn usage(&self, scope: UsageScope) -> Result<Usage, Error> {
+        Ok(self.usage_database.get::<UsageCounter>(&serde_json::to_vec(&scope)?).await?
+            .map(|c| c.usage).unwrap_or_default())
+    }
+
+    //DMs are found by the tenant's com key, a tenant whose did does not resolve is shown without them
+    async fn tenant_stats(&self, tenant: Did) -> Result<TenantStats, Error> {
+        let dms = match self.did_resolver.resolve_dwn_keys(&tenant).await {
+            Ok((_, com)) => self.usage(UsageScope::Recipient(StoredDM::fingerprint(&com))).await?,
+            Err(e) => {
+                log::warn!("No DM stats for {}: {}", tenant, e);
+                Usage::default()
+            }
+        };
+        Ok(TenantStats{public: self.usage(UsageScope::Tenant(tenant.clone())).await?, tenant, dms})
+    }
+
+    async fn stats(&self) -> Result<DwnStats, Error> {
+        let mut tenants = Vec::new();
+        for counter in self.usage_database.get_all::<UsageCounter>().await? {
+            if let UsageScope::Tenant(tenant) = counter.scope {
+                tenants.push(self.tenant_stats(tenant).await?);
             }
+        }
+        Ok(DwnStats{
+            private: self.usage(UsageScope::Private).await?,
+            public: self.usage(UsageScope::Public).await?,
+            dms: self.usage(UsageScope::Dms).await?,
+            tenants
         })
     }
 
+    //Counts only, no record or key is ever part of the answer
+    pub async fn admin(&self, signed: SignedObject<AdminRequest>) -> Result<DwnResponse, Error> {
+        match signed.verify(&*self.did_resolver, None).await {
+            Ok(Verifier::Left(did)) if did == self.com_key.public.did || self.admins.contains(&did) => {},
+            _ => return Ok(DwnResponse::InvalidAuth(ErrorContext::new("Not Admin")))
+        }
+        Ok(DwnResponse::Admin(match signed.unwrap() {
+            AdminRequest::Stats => AdminResponse::Stats(self.stats().await?),
+            AdminRequest::TenantDetail(tenant) => AdminResponse::TenantDetail(self.tenant_stats(tenant).await?)
+        }))
+    }
+
+    //Counters start empty, a Dwn with items stored before they were kept recounts once
+    pub async fn recount_usage(&self) -> Result<(), Error> {
+        for key in self.usage_database.keys().await? {
+            self.usage_database.delete(&key).await?;
+        }
+        for item in self.private_database.get_all::<DwnItem>().await? {
+            self.account(vec![UsageScope::Private], None, Some(item.payload.len())).await?;
+        }
+        for item in self.public_database.get_all::<PublicDwnItem>().await? {
+            self.account_public(None, Some(&item)).await?;
+        }
+        //Rows still under the full key are left out, they are counted once moved over
+        let stored = self.dms_database.query::<StoredDM>(&Filters::new(vec![]), Some(SortOptions::new("recipient"))).await?.0;
+        for dm in stored {
+            self.account_dm(&dm, false).await?;
+        }
+        Ok(())
+    }
+
+    //A proof is remembered until it expires or for READ_SESSION_TTL, whichever comes first
+    async fn read_session(&self, read: &SessionRead) -> bool {
+        let now = (self.clock)();
+        let session = SessionRead::session(&read.token);
+        let key = (read.discover.to_vec(), session.clone());
+        if let Some(proof) = &read.proof {
+            let verified = proof.verify(&*self.did_resolver, Some(&Verifier::Right(read.discover.clone()))).await.is_ok();
+            let challenge = proof.inner();
+            if !verified || challenge.dwn != self.com_key.public.did || challenge.session != session || challenge.expires <= now {
+                return false;
+            }
+            let expires = challenge.expires.min(now+READ_SESSION_TTL);
+            let mut sessions = self.read_sessions.lock().unwrap();
+            sessions.retain(|_, expires| *expires > now);
+            sessions.insert(key, expires);
+            return true;
+        }
+        self.read_sessions.lock().unwrap().get(&key).is_some_and(|expires| *expires > now)
+    }
+
+    //The discover key a private request touches, who else signed it and the request type
+    fn accessed(request: &DwnRequest) -> Option<(PublicKey, Option<String>, &'static str)> {
+        let fingerprint = |verifier: &Verifier| match verifier {
+            Verifier::Left(did) => did.to_string(),
+            Verifier::Right(key) => key.thumbprint()
+        };
+        match request {
+            DwnRequest::CreatePrivate(signed) => Some((signed.inner().discover.clone(), None, "CreatePrivate")),
+            DwnRequest::ReadPrivate(signed) => match signed.signer() {
+                Verifier::Right(discover) => Some((discover.clone(), None, "ReadPrivate")),
+                _ => None
+            },
+            DwnRequest::ReadPrivateSession(read) => Some((read.discover.clone(), None, "ReadPrivate")),
+            DwnRequest::UpdatePrivate(signed) | DwnRequest::GuardedUpdatePrivate(signed, _) => Some((
+                signed.inner().inner().discover.clone(), Some(fingerprint(signed.signer())), "UpdatePrivate"
+            )),
+            DwnRequest::DeletePrivate(signed) => Some((
+                signed.inner().clone(), Some(fingerprint(signed.signer())), "DeletePrivate"
+            )),
+            _ => None
+        }
+    }
+
+    async fn access_entries(&self, tenant: &Did) -> Result<Vec<AccessLogEntry>, Error> {
+        let filters = Filters::new(vec![("tenant", Filter::equal(tenant.to_string()))]);
+        let mut entries = self.access_database.query::<AccessLogEntry>(&filters, None).await?.0;
+        entries.sort_by_key(|e| e.timestamp);
+        Ok(entries)
+    }
+
+    async fn log_request(&self, discover: &PublicKey, requester: Option<String>, request: &str) -> Result<(), Error> {
+        let fingerprint = discover.thumbprint();
+        if let Some(audited) = self.audit_database.get::<AuditedKey>(fingerprint.as_bytes()).await? {
+            let entries = self.access_entries(&audited.tenant).await?;
+            self.access_database.set(&AccessLogEntry{
+                id: Uuid::new_v4(),
+                tenant: audited.tenant,
+                discover: fingerprint,
+                timestamp: (self.clock)(),
+                requester,
+                request: request.to_string()
+            }).await?;
+            let excess = (entries.len()+1).saturating_sub(self.access_log);
+            for old in entries.into_iter().take(excess) {
+                self.access_database.delete(&old.primary_key()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    //An exact resend is acknowledged without storing a second row
+    async fn store_dm(&self, item: DwnItem) -> Result<(), Error> {
+        let dm = StoredDM{item, stored: Some((self.clock)())};
+        if self.dms_database.get::<StoredDM>(&dm.primary_key()).await?.is_none() {
+            self.dms_database.set(&dm).await?;
+            self.account_dm(&dm, false).await?;
+            let _ = self.dm_arrivals.send(StoredDM::fingerprint(&dm.item.discover));
+        }
+        Ok(())
+    }
+
+    //DMs stored under the full key are moved over as their recipient reads them, ones the cursor
+    //already passed stay behind since moving restamps them and would hand them out again
+    async fn migrate_dms(&self, key: &PublicKey, cursor: &DmCursor) -> Result<(), Error> {
+        let filters = Filters::new(vec![
+            ("timestamp_stored", Filter::cmp(CmpType::GT, cursor.since)),
+            ("discover", Filter::equal(key.to_vec()))
+        ]);
+        let sort = SortOptions::new("timestamp_stored");
+        let legacy = self.dms_database.query::<UuidKeyed<DwnItem>>(&filters, Some(sort)).await?.0;
+        let start = match cursor.after {
+            Some(after) => legacy.iter().position(|dm| dm.primary_key() == after.as_bytes()).map(|p| p+1).unwrap_or(legacy.len()),
+            None => 0
+        };
+        for dm in legacy.into_iter().skip(start) {
+            self.dms_database.delete(&dm.primary_key()).await?;
+            self.store_dm(dm.inner()).await?;
+        }
+        Ok(())
+    }
+
+    //Rows still stored under the full key are removed along with the fingerprinted ones. Stored
+    //times are kept to the second, DMs stored in the second of before are kept
+    async fn delete_dms(&self, key: &PublicKey, before: DateTime<Utc>) -> Result<(), Error> {
+        let stored = Filter::cmp(CmpType::LT, before);
+        let filters = Filters::new(vec![
+            ("timestamp_stored", stored.clone()),
+            ("recipient", Filter::equal(StoredDM::fingerprint(key)))
+        ]);
+        for dm in self.dms_database.query::<StoredDM>(&filters, None).await?.0 {
+            self.dms_database.delete(&dm.primary_key()).await?;
+            self.account_dm(&dm, true).await?;
+        }
+        let filters = Filters::new(vec![
+            ("timestamp_stored", stored),
+            ("discover", Filter::equal(key.to_vec()))
+        ]);
+        for dm in self.dms_database.query::<UuidKeyed<DwnItem>>(&filters, None).await?.0 {
+            self.dms_database.delete(&dm.primary_key()).await?;
+        }
+        Ok(())
+    }
+
+    //Sorted by stored time so the cursor stays valid as new DMs arrive. The database resumes after
+    //the stored bytes of the cursor's DM. A cursor whose DM was deleted ends the read like
+    //read_public, deletes take every DM stored up to it so the next read starts after since again
+    async fn read_dms(&self, key: PublicKey, cursor: DmCursor, limit: usize) -> Result<(Vec<DwnItem>, DmPage), Error> {
+        self.migrate_dms(&key, &cursor).await?;
+        let resume = match cursor.after {
+            Some(after) => match self.dms_database.get_raw(after.as_bytes()).await? {
+                Some(raw) if serde_json::from_slice::<StoredDM>(&raw).is_ok() => Some(raw),
+                //A legacy row the cursor passed stays behind, every DM under the fingerprint comes after it
+                Some(_) => None,
+                None => {
+                    let next = DmCursor{since: cursor.since, after: None};
+                    return Ok((Vec::new(), DmPage{next, remaining: 0, stored: Vec::new()}));
+                }
+            },
+            None => None
+        };
+        let filters = Filters::new(vec![
+            ("timestamp_stored", Filter::cmp(CmpType::GT, cursor.since)),
+            ("recipient", Filter::equal(StoredDM::fingerprint(&key)))
+        ]);
+        //The database's own limit indexes past the end on the last page, the rest is counted here
+        let sort = SortOptions::new("timestamp_stored").with_page(None, resume)?;
+        let mut dms = self.dms_database.query::<StoredDM>(&filters, Some(sort)).await?.0;
+        let remaining = dms.len().saturating_sub(limit.min(MAX_DM_PAGE));
+        dms.truncate(dms.len()-remaining);
+        let after = dms.last().map(|dm| dm.id()).or(cursor.after);
+        let (items, stored) = dms.into_iter().map(|dm| (dm.item, dm.stored)).unzip();
+        Ok((items, DmPage{next: DmCursor{since: cursor.since, after}, remaining, stored}))
+    }
+
+    //Listens before the first read so a DM stored in between still wakes it. A lagged receiver
+    //reads again since what it missed may have been for this key
+    async fn subscribe_dms(&self, key: PublicKey, cursor: DmCursor, limit: usize) -> Result<(Vec<DwnItem>, DmPage), Error> {
+        let mut arrivals = self.dm_arrivals.subscribe();
+        let fingerprint = StoredDM::fingerprint(&key);
+        let deadline = tokio::time::Instant::now() + self.dm_wait;
+        loop {
+            let (items, page) = {
+                let _guard = self.requests.lock().await;
+                self.read_dms(key.clone(), cursor.clone(), limit).await?
+            };
+            if !items.is_empty() {return Ok((items, page));}
+            loop {
+                match tokio::time::timeout_at(deadline, arrivals.recv()).await {
+                    Err(_) => return Ok((items, page)),
+                    Ok(Ok(recipient)) if recipient != fingerprint => continue,
+                    Ok(_) => break
+                }
+            }
+        }
+    }
+
+    async fn update_private(
+        &self, del_signed: SignedObject<SignedObject<DwnItem>>, guard: Option<Vec<u8>>
+    ) -> Result<DwnResponse, Error> {
+        let context = ErrorContext::new("Signature").with_discover(&del_signed.inner().inner().discover);
+        Ok(if let Ok(Verifier::Right(key)) = del_signed.verify(&*self.did_resolver, None).await {
+            let dis_signed = del_signed.unwrap();
+            let discover = &dis_signed.inner().discover;
+            if dis_signed.verify(&*self.did_resolver, Some(&Verifier::Right(discover.clone()))).await.is_ok() {
+                let item = dis_signed.unwrap();
+                let old_item = self.private_database.get::<DwnItem>(&item.discover.to_vec()).await?;
+                if let Some(old_item) = &old_item {
+                    if old_item.delete != Some(key) {
+                        let context = ErrorContext::new("Delete").with_discover(&item.discover);
+                        return Ok(DwnResponse::InvalidAuth(context));
+                    }
+                }
+                if let Some(guard) = guard {
+                    match old_item {
+                        Some(old_item) if old_item.hash_bytes() != guard => {
+                            let context = ErrorContext::new("Guard").with_discover(&item.discover);
+                            return Ok(DwnResponse::Conflict(old_item, context));
+                        },
+                        None => {
+                            let context = ErrorContext::new("Guarded Record Missing").with_discover(&item.discover);
+                            return Ok(DwnResponse::InvalidAuth(context));
+                        },
+                        _ => {}
+                    }
+                }
+                self.private_database.set(&item).await?;
+                self.account(vec![UsageScope::Private], old_item.map(|i| i.payload.len()), Some(item.payload.len())).await?;
+                DwnResponse::Empty
+            } else {DwnResponse::InvalidAuth(context)}
+        } else {DwnResponse::InvalidAuth(context)})
+    }
+
+    async fn update_public(&self, item: PublicDwnItem, generation: Option<u64>) -> Result<DwnResponse, Error> {
+        let id = item.0.inner().uuid;
+        if PublicRecord::validate_index(&item.0.inner().index).is_err() {
+            return Ok(DwnResponse::BadRequest(ErrorContext::new("Reserved Index").with_id(id)));
+        }
+        if let Err(e) = self.limits.check(item.0.inner()) {
+            return Ok(DwnResponse::InvalidAuth(ErrorContext::new(&e.to_string()).with_id(id)));
+        }
+        Ok(if let Ok(verifier) = item.0.verify(&*self.did_resolver, None).await {
+            let oitem = self.public_database.get::<PublicDwnItem>(&item.primary_key()).await?;
+            if let Some(oitem) = &oitem {
+                if verifier != *oitem.0.signer() {
+                    return Ok(DwnResponse::InvalidAuth(ErrorContext::new("Not Record Signer").with_id(id)));
+                }
+            }
+            if let Some(generation) = generation {
+                match oitem {
+                    Some(oitem) if oitem.0.inner().generation() != generation => {
+                        return Ok(DwnResponse::PublicConflict(oitem, ErrorContext::new("Generation").with_id(id)));
+                    },
+                    None if generation != 0 => {
+                        return Ok(DwnResponse::InvalidAuth(ErrorContext::new("Guarded Record Missing").with_id(id)));
+                    },
+                    _ => {}
+                }
+            }
+            self.public_database.set(&item).await?;
+            self.store_version(&item).await?;
+            self.account_public(oitem.as_ref(), Some(&item)).await?;
+            DwnResponse::Empty
+        } else {DwnResponse::InvalidAuth(ErrorContext::new("Signature").with_id(id))})
+    }
+
+    async fn versions(&self, uuid: &Uuid) -> Result<Vec<PublicVersion>, Error> {
+        let filters = Filters::new(vec![("uuid", Filter::equal(uuid.as_bytes().to_vec()))]);
+        let mut versions = self.history_database.query::<PublicVersion>(&filters, None).await?.0;
+        versions.sort_by_key(|v| v.version);
+        Ok(versions)
+    }
+
+    async fn store_version(&self, item: &PublicDwnItem) -> Result<(), Error> {
+        let record = item.0.inner();
+        if let Some(retention) = self.history.get(&record.protocol.uuid()) {
+            let versions = self.versions(&record.uuid).await?;
+            let version = versions.last().map(|v| v.version+1).unwrap_or_default();
+            self.history_database.set(&PublicVersion{
+                uuid: record.uuid, version, stored: (self.clock)(), item: Some(item.clone())
+            }).await?;
+            let excess = (versions.len()+1).saturating_sub(*retention);
+            for old in versions.into_iter().take(excess) {
+                self.history_database.delete(&old.primary_key()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    //The owner took the record down, so no instant reads it back. Only a tombstone is left, it
+    //keeps the version count going should the uuid be written again
+    async fn delete_versions(&self, uuid: &Uuid) -> Result<(), Error> {
+        let versions = self.versions(uuid).await?;
+        let Some(version) = versions.last().map(|v| v.version+1) else {return Ok(());};
+        for old in versions {
+            self.history_database.delete(&old.primary_key()).await?;
+        }
+        self.history_database.set(&PublicVersion{uuid: *uuid, version, stored: (self.clock)(), item: None}).await?;
+        Ok(())
+    }
+
+    //Paged by primary key like DMs, the database resumes after the stored bytes of the cursor's
+    //record and the limit is applied here since the database's own indexes past the end on the
+    //last page. A cursor whose record was deleted or no longer matches ends the read instead of
+    //starting over
+    async fn read_public(
+        &self, filters: &FilterExpr, sort_options: Option<SortOptions>
+    ) -> Result<(Vec<PublicDwnItem>, Option<Vec<u8>>), Error> {
+        let Some(sort_options) = sort_options else {
+            return Ok((self.query_public(filters, None).await?, None));
+        };
+        let (limit, cursor) = sort_options.page()?;
+        let resume = match cursor {
+            Some(cursor) => match self.public_database.get_raw(&cursor).await? {
+                Some(raw) if serde_json::from_slice::<PublicDwnItem>(&raw).is_ok_and(|item|
+                    filters.filter(&Self::public_index(&item))
+                ) => Some(raw),
+                _ => return Ok((Vec::new(), None))
+            },
+            None => None
+        };
+        let mut items = self.query_public(filters, Some(sort_options.with_page(None, resume)?)).await?;
+        let end = limit.map(|limit| items.len().min(limit)).unwrap_or(items.len());
+        let cursor = (end > 0 && end < items.len()).then(|| items[end-1].primary_key());
+        items.truncate(end);
+        Ok((items, cursor))
+    }
+
+    //The database only takes Filters, it narrows by the planned ones and the whole expression is
+    //checked here, also for Filters since the database inverts Filter::All. Unsafe keys are only
+    //checked here, the database would name a partition after them
+    async fn query_public(&self, filters: &FilterExpr, sort_options: Option<SortOptions>) -> Result<Vec<PublicDwnItem>, Error> {
+        let mut planned = filters.planned();
+        planned.0.retain(|key, _| PublicLimits::is_safe_key(key));
+        let items = self.public_database.query::<PublicDwnItem>(&planned, sort_options).await?.0;
+        Ok(items.into_iter().filter(|item| filters.filter(&Self::public_index(item))).collect())
+    }
+
+    fn public_index(item: &PublicDwnItem) -> Index {
+        let mut index = item.secondary_keys();
+        index.insert(PublicDwnItem::PRIMARY_KEY.to_string(), item.primary_key().into());
+        index
+    }
+
+    //Records are found by their versions matching the planned filters, as query_public does, then
+    //the version each was at the instant is looked up by uuid and checked against the whole
+    //expression. A tombstone reads as absent. Paged by uuid with the limit applied here
+    async fn read_public_at(
+        &self, filters: &FilterExpr, at: DateTime<Utc>, sort_options: Option<SortOptions>
+    ) -> Result<(Vec<PublicDwnItem>, Option<Vec<u8>>), Error> {
+        let (limit, cursor) = sort_options.map(|s| s.page()).transpose()?.unwrap_or_default();
+        let mut planned = filters.planned();
+        planned.0.retain(|key, _| PublicLimits::is_safe_key(key));
+        let uuids = self.history_database.query::<PublicVersion>(&planned, None).await?.0.into_iter()
+            .map(|version| version.uuid)
+            .filter(|uuid| cursor.as_ref().is_none_or(|cursor| uuid.as_bytes().as_slice() > cursor.as_slice()))
+            .collect::<BTreeSet<_>>();
+        let mut items = Vec::new();
+        for uuid in uuids {
+            if limit.is_some_and(|limit| items.len() > limit) {break;}
+            let version = self.versions(&uuid).await?.into_iter().rev().find(|v| v.stored <= at);
+            if let Some(item) = version.and_then(|v| v.item).filter(|item| filters.filter(&Self::public_index(item))) {
+                items.push(item);
+            }
+        }
+        let end = limit.map(|limit| items.len().min(limit)).unwrap_or(items.len());
+        let cursor = (end > 0 && end < items.len()).then(|| items[end-1].primary_key());
+        items.truncate(end);
+        Ok((items, cursor))
+    }
+
     pub async fn debug(&self) -> Result<String, Error> {
         Ok(
             self.com_key.public.did.to_string()+"\n"+
             &self.private_database.debug().await?+
             &self.public_database.debug().await?+
-            &self.dms_database.debug().await?
+            &self.dms_database.debug().await?+
+            &self.history_database.debug().await?+
+            &self.audit_database.debug().await?+
+            &self.access_database.debug().await?+
+            &self.usage_database.debug().await?
         )
     }
 }
@@ -228,6 +1034,9 @@ impl std::fmt::Debug for Dwn {
         .field("private_database", &self.private_database)
         .field("public_database", &self.public_database)
         .field("dms", &self.dms_database)
+        .field("history", &self.history_database)
+        .field("audit", &self.audit_database)
+        .field("access", &self.access_database)
         .finish()
     }
 }
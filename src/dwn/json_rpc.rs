@@ -1,13 +1,14 @@
 use super::Error;
 
-use super::structs::{DwnResponse, Packet};
+use super::structs::{DwnResponse, AdminRequest, Packet};
 use super::traits::{Server, Client};
+use super::router::RouterConfig;
 use crate::dids::Did;
+use crate::dids::signing::SignedObject;
 
 use super::Dwn;
 
 use jsonrpc_v2::{Data, Params, Server as JsonServer};
-use tokio::sync::Mutex;
 use uuid::Uuid;
 use url::Url;
 
@@ -15,6 +16,7 @@ use url::Url;
 trait Method {
     async fn process_packet(&self, recipient: Did, payload: Vec<u8>) -> Vec<(Uuid, DwnResponse)>;
     async fn debug(&self) -> String;
+    async fn admin(&self, request: SignedObject<AdminRequest>) -> DwnResponse;
 }
 
 #[jsonrpc_client::implement(Method)]
@@ -24,10 +26,27 @@ struct JsonClient {
     base_url: Url,
 }
 
+//Clones share the same connection pool
 #[derive(Debug, Clone)]
-pub struct JsonRpcClient {}
+pub struct JsonRpcClient {
+    inner: reqwest::Client
+}
 
 impl JsonRpcClient {
+    pub fn new(config: &RouterConfig) -> Result<Self, Error> {
+        let mut builder = reqwest::Client::builder()
+            .pool_max_idle_per_host(config.pool_max_idle)
+            .pool_idle_timeout(config.idle_timeout);
+        if config.http2 {builder = builder.http2_prior_knowledge();}
+        Ok(JsonRpcClient{inner: builder.build()?})
+    }
+
+    //Answered outside of packets, the request is signed but not encrypted
+    pub async fn admin(&self, url: Url, request: SignedObject<AdminRequest>) -> Result<DwnResponse, Error> {
+        let client = JsonClient{inner: self.inner.clone(), base_url: url};
+        client.admin(request).await.map_err(|e| Error::json_rpc(&e.to_string()))
+    }
+
     #[cfg(test)]
     pub async fn client_debug(url: &str) -> String {
         let client = JsonClient{inner: reqwest::Client::new(), base_url: Url::parse(url).unwrap()};
@@ -39,7 +58,7 @@ impl JsonRpcClient {
 impl Client for JsonRpcClient {
     async fn send_request(&self, body: String, url: Url) -> Result<String, Error> {
         let p = serde_json::from_str::<Packet>(&body)?;
-        let client = JsonClient{inner: reqwest::Client::new(), base_url: url};
+        let client = JsonClient{inner: self.inner.clone(), base_url: url};
         Ok(serde_json::to_string(&client.process_packet(p.recipient, p.payload).await.map_err(|e|
             Error::json_rpc(&e.to_string())
         )?)?)
@@ -48,30 +67,40 @@ impl Client for JsonRpcClient {
 
 impl jsonrpc_v2::ErrorLike for Error {}
 
+#[derive(serde::Deserialize)]
+struct AdminParams {
+    request: SignedObject<AdminRequest>
+}
+
 #[derive(Debug, Clone)]
 pub struct JsonRpcServer {}
 
 impl JsonRpcServer {
     async fn process_packet(
-        data: Data<Mutex<Dwn>>, Params(params): Params<Packet>
+        data: Data<Dwn>, Params(params): Params<Packet>
     ) -> Result<Vec<(Uuid, DwnResponse)>, Error> {
-        data.lock().await.process_packet(params).await
+        data.process_packet(params).await
     }
 
-    async fn debug(data: Data<Mutex<Dwn>>) -> Result<String, Error> {
-        data.lock().await.debug().await
+    async fn debug(data: Data<Dwn>) -> Result<String, Error> {
+        data.debug().await
     }
-}
 
-#[async_trait::async_trait]
-impl Server for JsonRpcServer {
-    async fn start_server(
-        &self, dwn: Dwn, port: u32
+    async fn admin(
+        data: Data<Dwn>, Params(params): Params<AdminParams>
+    ) -> Result<DwnResponse, Error> {
+        data.admin(params.request).await
+    }
+
+    //Serves on a listener that is already bound, e.g. to port 0 so the OS picks a free port
+    pub fn listen(
+        &self, dwn: Dwn, listener: std::net::TcpListener
     ) -> Result<actix_web::dev::Server, Error> {
         let rpc = JsonServer::new()
-            .with_data(Data::new(Mutex::new(dwn)))
+            .with_data(Data::new(dwn))
             .with_method("process_packet", Self::process_packet)
             .with_method("debug", Self::debug)
+            .with_method("admin", Self::admin)
             .finish();
         let server = actix_web::HttpServer::new(move || {
             actix_web::App::new().service(
@@ -80,6 +109,15 @@ impl Server for JsonRpcServer {
                     .finish(rpc.clone().into_web_service()),
             )
         });
-        Ok(server.bind(&format!("0.0.0.0:{}", port))?.run())
+        Ok(server.listen(listener)?.run())
+    }
+}
+
+#[async_trait::async_trait]
+impl Server for JsonRpcServer {
+    async fn start_server(
+        &self, dwn: Dwn, port: u32
+    ) -> Result<actix_web::dev::Server, Error> {
+        self.listen(dwn, std::net::TcpListener::bind(format!("0.0.0.0:{}", port))?)
     }
 }
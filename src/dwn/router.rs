@@ -3,20 +3,87 @@ use super::Error;
 use super::traits::Client;
 use super::structs::{DwnResponse, DwnRequest, Packet};
 
-use crate::dids::{DidResolver, Endpoint};
+use crate::dids::{DidResolver, Endpoint, Did};
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
 
 use futures::future;
 use uuid::Uuid;
 use url::Url;
 
-use crate::agent::TypeDebug;
+use crate::common::TypeDebug;
+use crate::common::backoff::{self, Backoff, Jitter};
+
+//Kinds of send failure a RouterConfig can retry on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusClass {
+    Transport,//Refused, reset or timed out connections
+    Malformed,//An answer that is not a list of responses
+}
+
+impl StatusClass {
+    pub fn of(error: &Error) -> Option<Self> {
+        match error.code() {
+            "TRANSPORT" => Some(StatusClass::Transport),
+            "PARSE" => Some(StatusClass::Malformed),
+            _ => None
+        }
+    }
+}
+
+//Connection settings for the http pool shared by every clone of a client, and how reads are retried
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouterConfig {
+    pub pool_max_idle: usize,//Per endpoint
+    pub idle_timeout: Option<Duration>,//None keeps idle connections forever
+    pub http2: bool,//Requires endpoints that accept http2 without upgrade
+    pub max_retries: usize,//Rounds over the endpoints of a did after the first, batches of reads only
+    pub base_delay: Duration,//Before the first retry, doubled for every one after up to the max, with full jitter
+    pub max_delay: Duration,
+    pub retry_on: Vec<StatusClass>,
+    pub max_batch: usize,//Requests per packet, more to one endpoint are sent as several packets
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        RouterConfig{
+            pool_max_idle: 8, idle_timeout: Some(Duration::from_secs(90)), http2: false,
+            max_retries: 2, base_delay: Duration::from_millis(100), max_delay: Duration::from_secs(5), retry_on: vec![StatusClass::Transport],
+            max_batch: 32
+        }
+    }
+}
+
+//Consecutive transport failures after which an endpoint is passed over for a healthy sibling
+const FAILOVER_THRESHOLD: usize = 1;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EndpointHealth {
+    pub failures: usize,//Consecutive
+    pub last_success: Option<DateTime<Utc>>
+}
+
+impl EndpointHealth {
+    pub fn is_healthy(&self) -> bool {self.failures < FAILOVER_THRESHOLD}
+}
+
+pub type HealthTable = BTreeMap<Endpoint, EndpointHealth>;
+
+//What came back for the requests sent to one endpoint, errors name the endpoint and are shared by
+//every request of the packet that failed
+pub type EndpointResponses = BTreeMap<Uuid, Result<DwnResponse, Arc<Error>>>;
 
 #[derive(Clone)]
 pub struct Router {
     did_resolver: Box<dyn DidResolver>,
-    client: Box<dyn Client>
+    client: Box<dyn Client>,
+    health: Arc<Mutex<HealthTable>>,
+    config: RouterConfig
 }
 
 impl Router {
@@ -24,7 +91,44 @@ impl Router {
         did_resolver: Box<dyn DidResolver>,
         client: Box<dyn Client>,
     ) -> Self {
-        Router{did_resolver, client}
+        Router{did_resolver, client, health: Arc::new(Mutex::new(HealthTable::new())), config: RouterConfig::default()}
+    }
+
+    pub fn with_config(mut self, config: RouterConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn health(&self) -> HealthTable {self.health.lock().unwrap().clone()}
+
+    //Forgets what is known about the endpoints of a did, or every endpoint when None
+    pub fn reset_health(&self, did: Option<&Did>) {
+        self.health.lock().unwrap().retain(|ep, _| did.map(|did| ep.0 != *did).unwrap_or(false));
+    }
+
+    fn record(&self, ep: &Endpoint, success: bool) {
+        let mut health = self.health.lock().unwrap();
+        let entry = health.entry(ep.clone()).or_default();
+        if success {
+            entry.failures = 0;
+            entry.last_success = Some(Utc::now());
+        } else {
+            entry.failures += 1;
+        }
+    }
+
+    //The endpoint followed by the other endpoints of its did not already part of the send,
+    //healthy endpoints before failing ones. Endpoints no longer listed by the document are forgotten
+    async fn candidates(&self, ep: &Endpoint, taken: &BTreeSet<Endpoint>) -> Vec<Endpoint> {
+        let listed = self.did_resolver.get_endpoints(std::slice::from_ref(&ep.0)).await.unwrap_or_default();
+        let mut health = self.health.lock().unwrap();
+        if !listed.is_empty() {
+            health.retain(|known, _| known.0 != ep.0 || listed.contains(known) || known == ep);
+        }
+        let mut candidates = vec![ep.clone()];
+        candidates.extend(listed.into_iter().filter(|sibling| sibling != ep && !taken.contains(sibling)));
+        candidates.sort_by_key(|c| health.get(c).map(|h| h.failures).unwrap_or(0));
+        candidates
     }
 
     async fn send_packet(
@@ -37,15 +141,77 @@ impl Router {
     }
 
     //Order in which the server recieves and processes the requests is important,
-    //The order in which we get back the responses is irrelevant
+    //The order in which we get back the responses is irrelevant.
+    //Transport failures fail over to the other endpoints of the same did,
+    //responses are still keyed by the endpoint they were sent for
     pub async fn send(
         &self,
         requests: BTreeMap<Endpoint, Vec<(Uuid, Box<DwnRequest>)>>,
-    ) -> Result<BTreeMap<Endpoint, BTreeMap<Uuid, DwnResponse>>, Error> {
-        Ok(BTreeMap::from_iter(future::try_join_all(requests.into_iter().map(|(ep, request)| async move {
-            println!("EPREQUEST BATCH: {:?}, {:#?}", ep.1.to_string(), request.iter().map(|(h, v)| format!("{:?}", (h, v.debug(50)))).collect::<Vec<_>>());
-            let ser_reqs = serde_json::to_vec(&request)?;
-            Ok::<_, Error>((ep.clone(), BTreeMap::from_iter(self.send_packet(&Packet::new(&*self.did_resolver, ep.0, &ser_reqs).await?, ep.1).await?)))
-        })).await?))
+    ) -> BTreeMap<Endpoint, EndpointResponses> {
+        let taken = BTreeSet::from_iter(requests.keys().cloned());
+        let taken = &taken;
+        BTreeMap::from_iter(future::join_all(requests.into_iter().map(|(ep, request)| async move {
+            let responses = self.send_endpoint(&ep, request, taken).await;
+            (ep, responses)
+        })).await)
+    }
+
+    //Packets to one endpoint are sent one after the other so the server still sees the requests in order,
+    //a failed packet does not stop the ones after it
+    async fn send_endpoint(
+        &self, ep: &Endpoint, request: Vec<(Uuid, Box<DwnRequest>)>, taken: &BTreeSet<Endpoint>
+    ) -> EndpointResponses {
+        let mut responses = EndpointResponses::new();
+        for chunk in request.chunks(self.config.max_batch.max(1)) {
+            match self.send_chunk(ep, chunk, taken).await {
+                Ok(chunk) => responses.extend(chunk.into_iter().map(|(uuid, r)| (uuid, Ok(r)))),
+                Err(e) => {
+                    let error = Arc::new(Error::at_endpoint(&ep.0.to_string(), ep.1.as_str(), Arc::new(e)));
+                    responses.extend(chunk.iter().map(|(uuid, _)| (*uuid, Err(error.clone()))));
+                }
+            }
+        }
+        responses
+    }
+
+    //Only reads are sent again, after every endpoint of the did failed in a way the config retries on.
+    //Endpoints that could not be reached are told apart from ones that answered with an error
+    async fn send_chunk(
+        &self, ep: &Endpoint, request: &[(Uuid, Box<DwnRequest>)], taken: &BTreeSet<Endpoint>
+    ) -> Result<BTreeMap<Uuid, DwnResponse>, Error> {
+        println!("EPREQUEST BATCH: {:?}, {:#?}", ep.1.to_string(), request.iter().map(|(h, v)| format!("{:?}", (h, v.debug(50)))).collect::<Vec<_>>());
+        let retries = match request.iter().all(|(_, r)| r.is_idempotent()) {
+            true => self.config.max_retries,
+            false => 0
+        };
+        let ser_reqs = serde_json::to_vec(request)?;
+        let packet = Packet::new(&*self.did_resolver, ep.0.clone(), &ser_reqs).await?;
+        let attempts = AtomicUsize::new(0);
+        let delays = Backoff::exponential(self.config.base_delay, self.config.max_delay)
+            .with_jitter(Jitter::Full).take(retries);
+        let is_retryable = |e: &Error| StatusClass::of(e).map(|c| self.config.retry_on.contains(&c)).unwrap_or(false);
+        backoff::retry(delays, is_retryable, None, || async {
+            let mut error = None;
+            for candidate in self.candidates(ep, taken).await {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                match self.send_packet(&packet, candidate.1.clone()).await {
+                    Ok(responses) => {
+                        self.record(&candidate, true);
+                        return Ok(BTreeMap::from_iter(responses));
+                    },
+                    Err(e) => {
+                        log::warn!("Endpoint {:?} failed: {}", candidate, e);
+                        self.record(&candidate, false);
+                        error = Some(e);
+                    }
+                }
+            }
+            Err(error.unwrap())
+        }).await.map_err(|error| match StatusClass::of(&error) {
+            Some(StatusClass::Transport) => Error::unreachable(
+                &format!("{} after {} attempts: {}", ep.1, attempts.load(Ordering::SeqCst), error)
+            ),
+            _ => error
+        })
     }
 }
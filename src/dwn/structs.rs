@@ -1,27 +1,74 @@
 use super::Error;
+use crate::ErrorJson;
 
-use crate::dids::signing::{SignedObject, Signer};
+use crate::dids::signing::{SignedObject, Signer, Verifier};
 use crate::dids::{DidResolver, Did};
 
+use std::collections::BTreeSet;
+
 use simple_crypto::{Hashable, SecretKey, PublicKey};
-use simple_database::database::{IndexBuilder, Index, Filters, SortOptions};
+use simple_database::database::{IndexBuilder, Index, SortOptions, Value};
+use crate::common::FilterExpr;
 use simple_database::Indexable;
 
+use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
 //TODO: Fix circular dependency
-use crate::agent::Protocol;
+use crate::model::protocol::{Protocol, SystemProtocols};
+use crate::model::structs::RecordState;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct ErrorContext {
+    pub id: Option<Uuid>,
+    pub discover: Option<String>,
+    pub message: String
+}
+
+impl ErrorContext {
+    pub fn new(message: &str) -> Self {
+        ErrorContext{id: None, discover: None, message: message.to_string()}
+    }
+
+    pub fn with_id(mut self, id: Uuid) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn with_discover(mut self, discover: &PublicKey) -> Self {
+        self.discover = Some(discover.thumbprint());
+        self
+    }
+}
+
+impl std::fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(id) = &self.id {write!(f, " id: {}", id)?;}
+        if let Some(discover) = &self.discover {write!(f, " discover: {}", discover)?;}
+        Ok(())
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
 pub enum DwnResponse {
     ReadPrivate(Option<DwnItem>),
-    ReadPublic(Vec<PublicDwnItem>),
-    ReadDM(Vec<DwnItem>),
-    InvalidAuth(String),
-    PublicConflict(PublicDwnItem),
-    Conflict(DwnItem),
+    //Cursor of the next page when the query had a limit and more records remain
+    ReadPublic(Vec<PublicDwnItem>, Option<Vec<u8>>),
+    Count(usize),
+    ReadDM(Vec<DwnItem>, DmPage),
+    ReadAccessLog(Vec<AccessLogEntry>),
+    Capabilities(SignedObject<DwnCapabilities>),
+    Admin(AdminResponse),
+    //In place of Empty for a write sent with a receipt requested
+    Receipt(SignedObject<Receipt>),
+    InvalidAuth(ErrorContext),
+    //The request is malformed whoever signed it, sending it again will not change the answer
+    BadRequest(ErrorContext),
+    PublicConflict(PublicDwnItem, ErrorContext),
+    Conflict(DwnItem, ErrorContext),
     #[default]
     Empty,
 }
@@ -31,6 +78,31 @@ impl DwnResponse {
         matches!(self, Self::InvalidAuth(_))
     }
 
+    //Error responses in the same code space as Error::code
+    pub fn to_error_json(&self) -> Option<ErrorJson> {
+        let (code, context) = match self {
+            Self::InvalidAuth(c) => ("INVALID_AUTH", c),
+            Self::BadRequest(c) => ("BAD_REQUEST", c),
+            Self::Conflict(_, c) | Self::PublicConflict(_, c) => ("CONFLICT", c),
+            _ => return None
+        };
+        let mut json = ErrorJson::new(code, &context.message);
+        if let Some(id) = &context.id {json = json.with_context("id", &id.to_string());}
+        if let Some(discover) = &context.discover {json = json.with_context("discover", discover);}
+        Some(json)
+    }
+
+    //Requests ids are only known to the agent, attach them to any error context on the way back
+    pub fn with_id(self, id: Uuid) -> Self {
+        match self {
+            Self::InvalidAuth(c) if c.id.is_none() => Self::InvalidAuth(c.with_id(id)),
+            Self::BadRequest(c) if c.id.is_none() => Self::BadRequest(c.with_id(id)),
+            Self::Conflict(i, c) if c.id.is_none() => Self::Conflict(i, c.with_id(id)),
+            Self::PublicConflict(i, c) if c.id.is_none() => Self::PublicConflict(i, c.with_id(id)),
+            other => other
+        }
+    }
+
     pub fn into_read_private(self) -> Result<Option<DwnItem>, Error> {
         match self {
             Self::ReadPrivate(pr) => Ok(pr),
@@ -38,7 +110,7 @@ impl DwnResponse {
         }
     }
 
-    pub fn into_invalid_auth(self) -> Result<String, Error> {
+    pub fn into_invalid_auth(self) -> Result<ErrorContext, Error> {
         match self {
             Self::InvalidAuth(i) => Ok(i),
             other => Err(Error::bad_response(&format!("Expected InvalidAuth(_) Got {:?}", other)))
@@ -46,20 +118,40 @@ impl DwnResponse {
     }
 
     pub fn into_empty(self) -> Result<(), Error> {
+        self.into_receipt().map(|_| ())
+    }
+
+    //A Dwn without receipts answers Empty even when one was requested
+    pub fn into_receipt(self) -> Result<Option<SignedObject<Receipt>>, Error> {
         match self {
-            Self::Empty => Ok(()),
+            Self::Empty => Ok(None),
+            Self::Receipt(receipt) => Ok(Some(receipt)),
+            Self::InvalidAuth(c) => Err(Error::invalid_auth(&c.to_string())),
+            Self::BadRequest(c) => Err(Error::bad_request(&c.to_string())),
+            Self::Conflict(_, c) | Self::PublicConflict(_, c) =>
+                Err(Error::conflict(&c.to_string())),
             other => Err(Error::bad_response(&format!("Expected Empty Got {:?}", other)))
         }
     }
 
+    pub fn into_admin(self) -> Result<AdminResponse, Error> {
+        match self {
+            Self::Admin(response) => Ok(response),
+            Self::InvalidAuth(c) => Err(Error::invalid_auth(&c.to_string())),
+            other => Err(Error::bad_response(&format!("Expected Admin(_) Got {:?}", other)))
+        }
+    }
+
     pub fn into_conflict(self) -> Result<DwnItem, Error> {
         match self {
-            Self::Conflict(item) => Ok(item),
+            Self::Conflict(item, _) => Ok(item),
             other => Err(Error::bad_response(&format!("Expected Conflict(_) Got {:?}", other)))
         }
     }
 }
 
+//A batch of requests encrypted to the recipient's Dwn key, the recipient must be the did of the
+//endpoint the packet is posted to or the Dwn can not read it
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Packet {
     pub recipient: Did,
@@ -82,7 +174,16 @@ impl Packet {
 pub struct DwnItem {
     pub discover: PublicKey,
     pub delete: Option<PublicKey>,
-    pub payload: Vec<u8>
+    pub payload: Vec<u8>,
+    //Not served once passed and swept by the Dwn's garbage collection
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub expires: Option<DateTime<Utc>>
+}
+
+impl DwnItem {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires.map(|expires| expires <= now).unwrap_or(false)
+    }
 }
 
 impl Hashable for DwnItem {}
@@ -90,9 +191,43 @@ impl Indexable for DwnItem {
     const PRIMARY_KEY: &'static str = "discover";
     fn primary_key(&self) -> Vec<u8> {self.discover.to_vec()}
     fn secondary_keys(&self) -> Index {
-        IndexBuilder::build(vec![
-            ("delete", self.delete.as_ref().map(|d| d.to_vec()).unwrap_or_default())
-        ]).unwrap()
+        let mut keys = vec![
+            ("delete", Value::from(self.delete.as_ref().map(|d| d.to_vec()).unwrap_or_default()))
+        ];
+        if let Some(expires) = self.expires {
+            keys.push(("expires", Value::from(expires)));
+        }
+        IndexBuilder::build(keys).unwrap()
+    }
+}
+
+//A DM as the Dwn stores it, indexed by a fingerprint of the recipient key and keyed by that
+//fingerprint and the payload hash so an exact resend is the same row. Rows stored before the
+//time was kept have none
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StoredDM {
+    #[serde(flatten)]
+    pub item: DwnItem,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub stored: Option<DateTime<Utc>>
+}
+
+impl StoredDM {
+    pub fn fingerprint(key: &PublicKey) -> Vec<u8> {
+        key.to_vec().hash_bytes()[..16].to_vec()
+    }
+
+    pub fn id(&self) -> Uuid {
+        let key = [Self::fingerprint(&self.item.discover), self.item.payload.hash_bytes()].concat();
+        Uuid::new_v5(&Uuid::NAMESPACE_OID, &key)
+    }
+}
+
+impl Indexable for StoredDM {
+    const PRIMARY_KEY: &'static str = "id";
+    fn primary_key(&self) -> Vec<u8> {self.id().as_bytes().to_vec()}
+    fn secondary_keys(&self) -> Index {
+        IndexBuilder::build(vec![("recipient", Self::fingerprint(&self.item.discover))]).unwrap()
     }
 }
 
@@ -102,18 +237,133 @@ pub struct PublicRecord {
     pub protocol: Protocol,
     pub payload: Vec<u8>,
     pub index: Index,
+    //Set by the agent on read, never signed or stored
+    #[serde(skip)]
+    pub state: RecordState
+}
+
+//Indexes set by the Dwn or the underlying database, "timestamp_stored" is overwritten on every set
+pub const RESERVED_INDEXES: [&str; 5] = ["signer", "protocol", "payload", "uuid", "timestamp_stored"];
+pub const RESERVED_PREFIX: &str = "__sys.";
+
+//Bounds a Dwn puts on public records, index keys and values are measured as json
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PublicLimits {
+    pub payload: usize,
+    pub index_fields: usize,
+    pub index_key: usize,
+    pub index_value: usize
+}
+
+pub const DEFAULT_PUBLIC_LIMITS: PublicLimits = PublicLimits{
+    payload: 64*1024, index_fields: 32, index_key: 64, index_value: 256
+};
+
+impl Default for PublicLimits {
+    fn default() -> Self {DEFAULT_PUBLIC_LIMITS}
+}
+
+impl PublicLimits {
+    pub fn check(&self, record: &PublicRecord) -> Result<(), Error> {
+        if record.payload.len() > self.payload {
+            return Err(Error::validation(&format!(
+                "Payload of {} bytes exceeds the limit of {}", record.payload.len(), self.payload
+            )));
+        }
+        if record.index.len() > self.index_fields {
+            return Err(Error::validation(&format!(
+                "Index has {} fields, the limit is {}", record.index.len(), self.index_fields
+            )));
+        }
+        for (key, value) in &record.index {
+            if key.len() > self.index_key {
+                return Err(Error::validation(&format!(
+                    "Index key '{}' is {} bytes, the limit is {}", key, key.len(), self.index_key
+                )));
+            }
+            //Records stored before this check keep their keys and are still read, an update has to
+            //move the field to a safe name
+            if !Self::is_safe_key(key) {
+                return Err(Error::validation(&format!(
+                    "Index key '{}' may only use letters, digits, '_', '-' and '.' and may not start with '.'", key
+                )));
+            }
+            let length = serde_json::to_vec(value)?.len();
+            if length > self.index_value {
+                return Err(Error::validation(&format!(
+                    "Value of index '{}' is {} bytes, the limit is {}", key, length, self.index_value
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    //The database names a partition after every index key, a key that could leave its directory or
+    //collide with another once a store normalizes the name never reaches it
+    pub fn is_safe_key(key: &str) -> bool {
+        !key.is_empty() && !key.starts_with('.') &&
+        key.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    }
+}
+
+//Optional requests, a Dwn without one is sent the older shape where there is one
+pub const FEATURE_GUARDED_UPDATE: &str = "guarded_update";
+pub const FEATURE_ACCESS_LOG: &str = "access_log";
+pub const FEATURE_RECEIPT: &str = "receipt";
+pub const FEATURE_SUBSCRIBE_DM: &str = "subscribe_dm";
+pub const FEATURE_READ_SESSION: &str = "read_session";
+pub const FEATURE_COUNT: &str = "count";
+pub const FEATURES: [&str; 6] = [
+    FEATURE_GUARDED_UPDATE, FEATURE_ACCESS_LOG, FEATURE_RECEIPT, FEATURE_SUBSCRIBE_DM, FEATURE_READ_SESSION,
+    FEATURE_COUNT
+];
+
+//Longest a Dwn remembers a discover key proven in a read session
+pub const READ_SESSION_TTL: std::time::Duration = std::time::Duration::from_secs(300);
+
+//What a Dwn accepts, answered to anyone who asks and signed by its com key
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DwnCapabilities {
+    pub version: String,
+    pub features: BTreeSet<String>,
+    pub public: PublicLimits,
+    pub dm_page: usize
+}
+
+impl DwnCapabilities {
+    pub fn supports(&self, feature: &str) -> bool {self.features.contains(feature)}
 }
 
 impl PublicRecord {
+    //Checked against the default limits, use bounded for a Dwn that accepts more
     pub fn new(uuid: Option<Uuid>, protocol: Protocol, payload: &[u8], index: Option<Index>) -> Result<Self, Error> {
+        Self::bounded(uuid, protocol, payload, index, &DEFAULT_PUBLIC_LIMITS)
+    }
+
+    pub fn bounded(
+        uuid: Option<Uuid>, protocol: Protocol, payload: &[u8], index: Option<Index>, limits: &PublicLimits
+    ) -> Result<Self, Error> {
         let uuid = uuid.unwrap_or(Uuid::new_v4());
         let index = index.unwrap_or_default();
-        if index.contains_key("signer") ||
-           index.contains_key("protocol") ||
-           index.contains_key("payload") ||
-           index.contains_key("uuid") {
-            Err(Error::bad_request("'signer', 'protocol', 'payload', and 'uuid' are reserved indexes"))
-        } else {Ok(PublicRecord{uuid, protocol, payload: payload.to_vec(), index})}
+        Self::validate_index(&index)?;
+        let record = PublicRecord{uuid, protocol, payload: payload.to_vec(), index, state: RecordState::Valid};
+        limits.check(&record)?;
+        Ok(record)
+    }
+
+    pub fn validate_index(index: &Index) -> Result<(), Error> {
+        if let Some(key) = index.keys().find(|k|
+            RESERVED_INDEXES.contains(&k.as_str()) || k.starts_with(RESERVED_PREFIX)
+        ) {
+            Err(Error::bad_request(&format!(
+                "'{}' is a reserved index, reserved: {:?} and '{}*'", key, RESERVED_INDEXES, RESERVED_PREFIX
+            )))
+        } else {Ok(())}
+    }
+
+    //Bumped by writers that guard on it, records without one are at generation 0
+    pub fn generation(&self) -> u64 {
+        self.index.get("generation").and_then(|g| g.as_u64()).copied().unwrap_or_default()
     }
 
     pub fn into_item(self, signer: Signer) -> Result<PublicDwnItem, Error> {
@@ -129,33 +379,311 @@ impl Indexable for PublicDwnItem {
     const PRIMARY_KEY: &'static str = "uuid";
     fn primary_key(&self) -> Vec<u8> {self.0.inner().uuid.as_bytes().to_vec()}
     fn secondary_keys(&self) -> Index {
-        let mut index = IndexBuilder::build(vec![
+        let mut index = self.0.inner().index.clone();
+        index.extend(IndexBuilder::build(vec![
             ("signer", self.0.signer().to_string()),
             ("protocol", self.0.inner().protocol.uuid().to_string()),
             ("payload", self.0.inner().payload.hash().to_string()),
-        ]).unwrap();
-        index.extend(self.0.inner().index.clone());
+        ]).unwrap());
+        index
+    }
+}
+
+//A prior state of a public record, kept for protocols the Dwn keeps history for. Indexed like
+//the item so history is queried by the same filters, a tombstone only by its uuid
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PublicVersion {
+    pub uuid: Uuid,
+    pub version: u64,
+    pub stored: DateTime<Utc>,
+    //None is the tombstone of a delete
+    pub item: Option<PublicDwnItem>
+}
+
+impl Indexable for PublicVersion {
+    const PRIMARY_KEY: &'static str = "uuid_version";
+    fn primary_key(&self) -> Vec<u8> {
+        [self.uuid.as_bytes().to_vec(), self.version.to_be_bytes().to_vec()].concat()
+    }
+    fn secondary_keys(&self) -> Index {
+        let mut index = self.item.as_ref().map(|item| item.secondary_keys()).unwrap_or_default();
+        index.extend(IndexBuilder::build(vec![
+            ("uuid", self.uuid.as_bytes().to_vec()),
+        ]).unwrap());
         index
     }
 }
 
+//A discover key a tenant audits reads of, the key itself is never stored
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditedKey {
+    pub fingerprint: String,
+    pub tenant: Did
+}
+
+impl Indexable for AuditedKey {
+    const PRIMARY_KEY: &'static str = "fingerprint";
+    fn primary_key(&self) -> Vec<u8> {self.fingerprint.as_bytes().to_vec()}
+    fn secondary_keys(&self) -> Index {
+        IndexBuilder::build(vec![
+            ("tenant", self.tenant.to_string()),
+        ]).unwrap()
+    }
+}
+
+//Where a DM read resumes, items stored after since that come after the item after in stored order
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct DmCursor {
+    pub since: DateTime<Utc>,
+    pub after: Option<Uuid>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DmPage {
+    pub next: DmCursor,
+    //Items past next when the page was read
+    pub remaining: usize,
+    //When each DM of the page was stored, in order. Empty from Dwns that do not say
+    #[serde(default)]
+    pub stored: Vec<Option<DateTime<Utc>>>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AccessLogEntry {
+    pub id: Uuid,
+    pub tenant: Did,
+    //Fingerprint of the discover key
+    pub discover: String,
+    pub timestamp: DateTime<Utc>,
+    //Fingerprint of whoever signed besides the discover key, reads are only signed by it
+    pub requester: Option<String>,
+    pub request: String
+}
+
+impl Indexable for AccessLogEntry {
+    const PRIMARY_KEY: &'static str = "id";
+    fn primary_key(&self) -> Vec<u8> {self.id.as_bytes().to_vec()}
+    fn secondary_keys(&self) -> Index {
+        IndexBuilder::build(vec![
+            ("tenant", self.tenant.to_string()),
+        ]).unwrap()
+    }
+}
+
+//Payload of a public abuse_report record, anyone may file one against a public record a tenant signed
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AbuseReport {
+    pub tenant: Did,
+    pub record: Uuid,
+    pub reason: String
+}
+
+impl AbuseReport {
+    pub fn new(tenant: Did, record: Uuid, reason: &str) -> Self {
+        AbuseReport{tenant, record, reason: reason.to_string()}
+    }
+
+    pub fn into_record(self) -> Result<PublicRecord, Error> {
+        PublicRecord::new(None, SystemProtocols::abuse_report(), &serde_json::to_vec(&self)?, None)
+    }
+}
+
+//Left by the operator in place of a public record it took down, signed by the Dwn so the
+//tenant can tell moderation apart from their own deletes. Also sent to the tenant as a DM
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Takedown {
+    pub report: Uuid,
+    pub record: Uuid,
+    pub tenant: Did,
+    pub reason: String,
+    pub timestamp: DateTime<Utc>
+}
+
+//Kept up as items are written so stats are read without a scan, bytes are payload bytes as in
+//DwnRequest::stored_size
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub items: u64,
+    pub bytes: u64,
+    pub last_activity: Option<DateTime<Utc>>
+}
+
+impl Usage {
+    //The sizes of the item replaced and the one stored, None where there is none
+    pub fn apply(&mut self, old: Option<usize>, new: Option<usize>, at: DateTime<Utc>) {
+        self.items = (self.items + new.is_some() as u64).saturating_sub(old.is_some() as u64);
+        self.bytes = (self.bytes + new.unwrap_or_default() as u64).saturating_sub(old.unwrap_or_default() as u64);
+        self.last_activity = Some(at);
+    }
+}
+
+//What a Usage counts, DMs are counted by a fingerprint of the recipient key as they are stored
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum UsageScope {
+    Private,
+    Public,
+    Dms,
+    Tenant(Did),
+    Recipient(Vec<u8>)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UsageCounter {
+    pub scope: UsageScope,
+    pub usage: Usage
+}
+
+impl Indexable for UsageCounter {
+    const PRIMARY_KEY: &'static str = "scope";
+    fn primary_key(&self) -> Vec<u8> {serde_json::to_vec(&self.scope).unwrap()}
+}
+
+//A tenant's public records and the DMs sent to its com key
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TenantStats {
+    pub tenant: Did,
+    pub public: Usage,
+    pub dms: Usage
+}
+
+//Private items are signed by record keys and can not be told apart by tenant, only their totals are kept.
+//Tenants are the signers of public records
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DwnStats {
+    pub private: Usage,
+    pub public: Usage,
+    pub dms: Usage,
+    pub tenants: Vec<TenantStats>
+}
+
+//Signed by the Dwn itself or one of its admins, answered with counts only
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AdminRequest {
+    Stats,
+    TenantDetail(Did)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AdminResponse {
+    Stats(DwnStats),
+    TenantDetail(TenantStats)
+}
+
+//Signed once by a discover key so later reads of it in the session need no signature. The session is
+//the hash of a token only the agent and the Dwn see, packets being encrypted to the Dwn
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ReadChallenge {
+    pub dwn: Did,
+    pub session: Vec<u8>,
+    pub expires: DateTime<Utc>
+}
+
+//The proof is only sent on the first read of the key in the session or once the Dwn forgot it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SessionRead {
+    pub discover: PublicKey,
+    pub token: Vec<u8>,
+    pub proof: Option<SignedObject<ReadChallenge>>
+}
+
+impl SessionRead {
+    pub fn session(token: &[u8]) -> Vec<u8> {token.hash_bytes()}
+}
+
+//Signed by the com key of a Dwn for a write it accepted, the hash is of the request as it was sent
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Receipt {
+    pub request_id: Uuid,
+    pub payload_hash: Vec<u8>,
+    pub timestamp: DateTime<Utc>
+}
+
+impl Receipt {
+    //Checks the signature against the did document of the Dwn and that it covers the request
+    pub async fn verify(
+        signed: &SignedObject<Receipt>, did_resolver: &dyn DidResolver, dwn: &Did, request: &DwnRequest
+    ) -> Result<Receipt, Error> {
+        signed.verify(did_resolver, Some(&Verifier::Left(dwn.clone()))).await?;
+        if signed.inner().payload_hash != request.hash_bytes() {
+            return Err(Error::validation("Receipt covers another request"));
+        }
+        Ok(signed.inner().clone())
+    }
+}
+
+//Private requests are signed by the keys of the record's permissions, the discover key signs reads
+//and creates and the delete key signs updates and deletes. A request signed by any other key is
+//answered with InvalidAuth
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum DwnRequest{
     CreatePrivate(SignedObject<DwnItem>),
     ReadPrivate(SignedObject<String>),
+    //A ReadPrivate whose discover key was proven earlier in the session
+    ReadPrivateSession(SessionRead),
     UpdatePrivate(SignedObject<SignedObject<DwnItem>>),
+    //Only applied when the stored item still hashes to the guard
+    GuardedUpdatePrivate(SignedObject<SignedObject<DwnItem>>, Vec<u8>),
     DeletePrivate(SignedObject<PublicKey>),//Delete Signed Some(Discover)
 
     CreatePublic(PublicDwnItem),
-    ReadPublic(Filters, Option<SortOptions>),
+    ReadPublic(FilterExpr, Option<SortOptions>),
+    //Records as they were at the given instant, only covers protocols with kept history. Paged by
+    //uuid, the sort property of the options is not applied
+    ReadPublicAt(FilterExpr, DateTime<Utc>, Option<SortOptions>),
+    //Number of public records the filters match, taken on trust since no record comes back to check
+    CountPublic(FilterExpr),
     UpdatePublic(PublicDwnItem),
+    //Only applied when the stored record is still at the generation
+    GuardedUpdatePublic(PublicDwnItem, u64),
     DeletePublic(SignedObject<Uuid>),
 
     CreateDM(DwnItem),
-    ReadDM(SignedObject<DateTime<Utc>>)
+    //Signed by the com key, at most limit items from the cursor
+    ReadDM(SignedObject<(DmCursor, usize)>),
+    //As ReadDM but held open until a DM arrives after the cursor or the Dwn's wait runs out
+    SubscribeDM(SignedObject<(DmCursor, usize)>),
+    //Signed by the com key, DMs stored before the instant are removed. The instant may not be in
+    //the future so a signed request seen by others can not later remove newer DMs
+    DeleteDM(SignedObject<DateTime<Utc>>),
+
+    //Signed by the tenant, replaces the audited set. Each key proves itself by signing the tenant's did
+    AuditAccess(SignedObject<Vec<SignedObject<Did>>>),
+    //Signed by the tenant, entries logged after the instant
+    ReadAccessLog(SignedObject<DateTime<Utc>>),
+
+    //Signed by the Dwn itself, takes down the record an abuse report names with the reason given
+    Takedown(SignedObject<(Uuid, String)>),
+
+    //A write the Dwn answers with a Receipt once it is accepted
+    WithReceipt(Box<DwnRequest>),
+
+    Capabilities
 }
 
 impl DwnRequest {
+    //Safe to send again when no answer came back
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Self::WithReceipt(request) => request.is_idempotent(),
+            other => matches!(other,
+                Self::ReadPrivate(_) | Self::ReadPrivateSession(_) | Self::ReadPublic(..) | Self::ReadPublicAt(..) |
+                Self::CountPublic(_) | Self::ReadDM(_) | Self::SubscribeDM(_) | Self::ReadAccessLog(_) | Self::Capabilities
+            )
+        }
+    }
+
+    //Bytes of the item a write stores, private payloads are ciphertext
+    pub fn stored_size(&self) -> Option<usize> {
+        match self {
+            Self::CreatePrivate(item) => Some(item.inner().payload.len()),
+            Self::UpdatePrivate(item) | Self::GuardedUpdatePrivate(item, _) => Some(item.inner().inner().payload.len()),
+            Self::CreatePublic(item) | Self::UpdatePublic(item) | Self::GuardedUpdatePublic(item, _) =>
+                Some(item.0.inner().payload.len()),
+            Self::WithReceipt(request) => request.stored_size(),
+            _ => None
+        }
+    }
+
     pub fn read_private(discover: &SecretKey) -> Result<DwnRequest, Error> {
         let payload = SignedObject::from_key(discover, String::new())?;
         Ok(DwnRequest::ReadPrivate(payload))
@@ -166,3 +694,4 @@ impl DwnRequest {
         Ok(DwnRequest::DeletePrivate(payload))
     }
 }
+impl Hashable for DwnRequest {}
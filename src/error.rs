@@ -1,10 +1,12 @@
 use snafu::Snafu;
+use serde::{Serialize, Deserialize};
+
+use std::collections::BTreeMap;
 
 fn get_backtrace() -> snafu::Backtrace {
     snafu::Backtrace::capture()
 }
 
-#[derive(serde_with::SerializeDisplay)]
 #[derive(Debug, Snafu)]
 #[snafu(module)]
 pub enum Error {
@@ -50,6 +52,9 @@ pub enum Error {
     },
     #[snafu(display("Validation Error: {message}"))]
     Validation{message: String, backtrace: snafu::Backtrace},
+    //Pointer into the payload that does not match the protocol schema
+    #[snafu(display("Schema Validation Error at '{pointer}': expected {expected}, got {got}"))]
+    SchemaValidation{pointer: String, expected: String, got: String, backtrace: snafu::Backtrace},
 
     #[snafu(display("Could not parse type ({message}) from: {message1}"))]
     Parse{message: String, message1: String, backtrace: snafu::Backtrace},
@@ -61,10 +66,30 @@ pub enum Error {
     BadRequest{message: String, backtrace: snafu::Backtrace},
     #[snafu(display("Could Not Find: {message}"))]
     NotFound{message: String, backtrace: snafu::Backtrace},
+    #[snafu(display("Update Rejected: {message}"))]
+    UpdateRejected{message: String, backtrace: snafu::Backtrace},
+    #[snafu(display("Wrong Key Domain: {message}"))]
+    WrongDomain{message: String, backtrace: snafu::Backtrace},
+    #[snafu(display("Conflict: {message}"))]
+    Conflict{message: String, backtrace: snafu::Backtrace},
+    #[snafu(display("Bootstrap Race: {message}"))]
+    BootstrapRace{message: String, backtrace: snafu::Backtrace},
     #[snafu(display("JsonRpc: {message}"))]
     JsonRpc{message: String, backtrace: snafu::Backtrace},
+    #[snafu(display("Unreachable: {message}"))]
+    Unreachable{message: String, backtrace: snafu::Backtrace},
+    #[snafu(display("Cancelled: {message}"))]
+    Cancelled{message: String, backtrace: snafu::Backtrace},
+    #[snafu(display("Pinned: {message}"))]
+    Pinned{message: String, backtrace: snafu::Backtrace},
+    //Everything sent to one endpoint failed with the source
+    #[snafu(display("{did} at {url}: {source}"))]
+    AtEndpoint{did: String, url: String, source: std::sync::Arc<Error>},
+    //The record at the path is of another protocol than the one cached for it or written over it
+    #[snafu(display("Protocol Mismatch at {path}: expected {cached}, found {found}"))]
+    ProtocolMismatch{path: String, cached: String, found: String, backtrace: snafu::Backtrace},
 
-    #[snafu(display("Multi: {errors:?}"))]
+    #[snafu(display("Multi: [{}]", errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(", ")))]
     Multi{errors: Vec<Error>},
 
     #[snafu(display("InsufficentPermission"))]
@@ -94,6 +119,39 @@ impl Error {
     pub fn json_rpc(msg: &str) -> Self {
         Error::JsonRpc{message: msg.to_string(), backtrace: get_backtrace()}
     }
+    pub fn unreachable(msg: &str) -> Self {
+        Error::Unreachable{message: msg.to_string(), backtrace: get_backtrace()}
+    }
+    pub fn schema_validation(pointer: &str, expected: &str, got: &str) -> Self {
+        Error::SchemaValidation{
+            pointer: pointer.to_string(), expected: expected.to_string(), got: got.to_string(),
+            backtrace: get_backtrace()
+        }
+    }
+    pub fn protocol_mismatch(path: &str, cached: &str, found: &str) -> Self {
+        Error::ProtocolMismatch{
+            path: path.to_string(), cached: cached.to_string(), found: found.to_string(),
+            backtrace: get_backtrace()
+        }
+    }
+    pub fn cancelled(msg: &str) -> Self {
+        Error::Cancelled{message: msg.to_string(), backtrace: get_backtrace()}
+    }
+    pub fn pinned(msg: &str) -> Self {
+        Error::Pinned{message: msg.to_string(), backtrace: get_backtrace()}
+    }
+    pub fn update_rejected(msg: &str) -> Self {
+        Error::UpdateRejected{message: msg.to_string(), backtrace: get_backtrace()}
+    }
+    pub fn wrong_domain(msg: &str) -> Self {
+        Error::WrongDomain{message: msg.to_string(), backtrace: get_backtrace()}
+    }
+    pub fn conflict(msg: &str) -> Self {
+        Error::Conflict{message: msg.to_string(), backtrace: get_backtrace()}
+    }
+    pub fn bootstrap_race(msg: &str) -> Self {
+        Error::BootstrapRace{message: msg.to_string(), backtrace: get_backtrace()}
+    }
     pub fn validation(msg: &str) -> Self {
         Error::Validation{message: msg.to_string(), backtrace: get_backtrace()}
     }
@@ -113,6 +171,88 @@ impl Error {
     pub fn arc(err: std::sync::Arc<Error>) -> Self {
         Error::Arc{source: err}
     }
+
+    pub fn at_endpoint(did: &str, url: &str, err: std::sync::Arc<Error>) -> Self {
+        Error::AtEndpoint{did: did.to_string(), url: url.to_string(), source: err}
+    }
+
+    //Stable across releases, the code of an existing variant is never changed
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Hex{..} |
+            Error::Base64Decode{..} |
+            Error::TryFromSlice{..} |
+            Error::Zbase32{..} |
+            Error::FromStringUtf8{..} |
+            Error::UrlParse{..} |
+            Error::SerdeJson{..} |
+            Error::SerdeBencode{..} |
+            Error::Regex{..} |
+            Error::Parse{..} => "PARSE",
+            Error::Ed25519{..} | Error::SimpleCrypto{..} => "CRYPTO",
+            Error::SimpleDns{..} | Error::Reqwest{..} | Error::JsonRpc{..} => "TRANSPORT",
+            Error::SimpleDatabase{..} | Error::Io{..} => "STORAGE",
+            Error::SystemTime{..} | Error::FailedDowncast{..} => "INTERNAL",
+            Error::Arc{source} | Error::AtEndpoint{source, ..} => source.code(),
+            Error::Validation{..} | Error::SchemaValidation{..} => "VALIDATION",
+            Error::InvalidAuth{..} => "INVALID_AUTH",
+            Error::BadResponse{..} => "BAD_RESPONSE",
+            Error::BadRequest{..} => "BAD_REQUEST",
+            Error::NotFound{..} => "NOT_FOUND",
+            Error::UpdateRejected{..} => "UPDATE_REJECTED",
+            Error::WrongDomain{..} => "WRONG_DOMAIN",
+            Error::Conflict{..} => "CONFLICT",
+            Error::BootstrapRace{..} => "BOOTSTRAP_RACE",
+            Error::Unreachable{..} => "UNREACHABLE",
+            Error::Cancelled{..} => "CANCELLED",
+            Error::Pinned{..} => "PINNED",
+            Error::ProtocolMismatch{..} => "PROTOCOL_MISMATCH",
+            Error::Multi{..} => "MULTI",
+            Error::InsufficentPermission{..} => "INSUFFICIENT_PERMISSION",
+            Error::Custom{..} => "CUSTOM",
+        }
+    }
+
+    pub fn to_json(&self) -> ErrorJson {
+        match self {
+            Error::Arc{source} => source.to_json(),
+            Error::AtEndpoint{did, url, source} =>
+                source.to_json().with_context("did", did).with_context("url", url),
+            Error::Multi{errors} => ErrorJson{
+                causes: errors.iter().map(|e| e.to_json()).collect(),
+                ..ErrorJson::new(self.code(), &self.to_string())
+            },
+            _ => ErrorJson::new(self.code(), &self.to_string())
+        }
+    }
+}
+
+//What an application forwards to its own clients, the same shape for agent and Dwn errors
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ErrorJson {
+    pub code: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if="BTreeMap::is_empty")]
+    pub context: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub causes: Vec<ErrorJson>
+}
+
+impl ErrorJson {
+    pub fn new(code: &str, message: &str) -> Self {
+        ErrorJson{code: code.to_string(), message: message.to_string(), context: BTreeMap::new(), causes: Vec::new()}
+    }
+
+    pub fn with_context(mut self, key: &str, value: &str) -> Self {
+        self.context.insert(key.to_string(), value.to_string());
+        self
+    }
+}
+
+impl Serialize for Error {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_json().serialize(serializer)
+    }
 }
 
 impl From<Vec<Box<std::sync::Arc<Error>>>> for Error {
@@ -125,6 +265,7 @@ impl From<Vec<Box<std::sync::Arc<Error>>>> for Error {
     }
 }
 
+#[cfg(feature = "agent")]
 impl From<Box<dyn crate::agent::Response>> for Error {
     fn from(r: Box<dyn crate::agent::Response>) -> Error {
         Error::FailedDowncast{
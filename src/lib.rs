@@ -1,12 +1,15 @@
 mod error;
-pub use error::Error;
+pub use error::{Error, ErrorJson};
 
 mod common;
+pub use common::{SortPaging, FilterExpr, FilterLogic, backoff};
 mod ed25519;
 pub mod dids;
 
-#[cfg(not(feature = "dwn"))]
-mod dwn;
+//Records and protocols as both the Dwn and the agent understand them, most of it is only used by the agent
+#[cfg(feature = "dwn")]
+#[cfg_attr(not(feature = "agent"), allow(dead_code))]
+mod model;
 
 #[cfg(feature = "dwn")]
 pub mod dwn;
@@ -14,8 +17,15 @@ pub mod dwn;
 #[cfg(feature = "agent")]
 pub mod agent;
 
+pub mod prelude;
+
+//In process Dwns and agents for tests and the examples
+#[cfg(any(feature = "test-utils", all(test, feature = "agent")))]
+pub mod test_utils;
+
+//Prefer the Filters and Filter in the prelude, the whole crate is kept for existing users
 pub extern crate simple_database;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "agent"))]
 mod tests;
 
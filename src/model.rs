@@ -0,0 +1,5 @@
+use super::error::Error;
+
+pub mod permission;
+pub mod protocol;
+pub mod structs;
@@ -133,9 +133,21 @@ impl PermissionSet {
         .ok_or(Error::invalid_auth("Create Child"))
     }
 
+    pub fn options(&self) -> PermissionOptions {
+        PermissionOptions{
+            can_create: !self.create.is_public(),
+            can_read: !self.read.is_public(),
+            can_delete: self.delete.as_ref().map(|d| !d.is_public()).unwrap_or(false),
+            channel: self.channel.as_ref().map(|c| ChannelPermissionOptions{
+                can_create: !c.create.is_public(),
+                can_read: !c.read.is_public()
+            })
+        }
+    }
+
     pub fn pointer(&self, index: usize) -> Result<Self, Error> {
         Ok(PermissionSet::new(
-            RecordPath::new(&[]),
+            RecordPath::root(),
             self.discover_child()?.derive_usize(index)?,
             self.channel()?.create.clone(),
             self.channel()?.read.clone(),
@@ -143,7 +155,8 @@ impl PermissionSet {
         ))
     }
 
-    pub fn subset(self, options: &PermissionOptions) -> Result<Self, Error> {
+    //The checks of subset without building the subset
+    pub fn covers(&self, options: &PermissionOptions) -> Result<(), Error> {
         let error = |r: &str| Err(Error::bad_request(r));
         if options.can_create && self.create.is_public() {return error("Missing create permission");}
         if options.can_read && self.read.is_public() {return error("Missing read permission");}
@@ -155,6 +168,11 @@ impl PermissionSet {
                 if options_channel.can_read && channel.discover.is_public() || channel.read.is_public() {return error("Missing read child permission");}
             }
         }
+        Ok(())
+    }
+
+    pub fn subset(self, options: &PermissionOptions) -> Result<Self, Error> {
+        self.covers(options)?;
         Ok(PermissionSet{
             path: self.path,
             discover: self.discover,
@@ -0,0 +1,528 @@
+use super::Error;
+
+use super::permission::{
+    ChannelPermissionOptions,
+    PermissionOptions,
+    PermissionSet,
+};
+use super::structs::{SharedPointer, ShareEnvelope, RedactedView, ShareGroup, Subscribers, Placement, CapabilityGrant, RecordPath};
+use super::structs::{PendingShareUpgrade, ShareAuditEntry, BlobManifest, BlobChunk};
+use crate::dwn::structs::{DmCursor, AbuseReport, Takedown};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use simple_crypto::{PublicKey, Hashable};
+
+use simple_database::Indexable;
+
+use schemars::{JsonSchema, schema_for};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+
+use jsonschema::{JSONSchema, ValidationError};
+use jsonschema::error::{TypeKind, ValidationErrorKind};
+
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ChannelProtocol {
+    pub child_protocols: Option<Vec<Uuid>>, //None for any child empty for no children
+}
+impl ChannelProtocol {
+    pub fn new(child_protocols: Option<Vec<&Protocol>>) -> Self {
+        ChannelProtocol{child_protocols: child_protocols.map(|cp| cp.into_iter().map(|p| p.uuid()).collect())}
+    }
+}
+
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Protocol {
+    pub name: String,
+    pub delete: bool,//Weather record can be deleted
+    pub permissions: PermissionOptions,
+    pub schema: Option<String>,
+    pub channel: Option<ChannelProtocol>,
+    //Skipped when empty so protocols without one keep their hash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_payload: Option<Vec<u8>>
+}
+impl Hashable for Protocol {}
+impl Indexable for Protocol {
+    fn primary_key(&self) -> Vec<u8> {self.hash_bytes()}
+}
+impl Protocol {
+    pub fn new(
+        name: &str,
+        delete: bool,//Weather record can be deleted
+        permissions: PermissionOptions,
+        schema: Option<String>,
+        channel: Option<ChannelProtocol>
+    ) -> Result<Self, Error> {
+        let protocol = Protocol{name: name.to_string(), delete, permissions, schema, channel, default_payload: None};
+        protocol.validate()?;
+        Ok(protocol)
+    }
+
+    //The payload records of this protocol start with, part of the protocol's hash
+    pub fn with_default_payload(mut self, payload: Vec<u8>) -> Result<Self, Error> {
+        self.default_payload = Some(payload);
+        self.validate()?;
+        Ok(self)
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        Uuid::new_v5(&Uuid::NAMESPACE_OID, &self.hash_bytes())
+    }
+
+    //Name and short hash for errors and logs
+    pub fn label(&self) -> String {
+        format!("{} ({})", self.name, ProtocolRegistry::short(&self.uuid()))
+    }
+
+    pub fn trim_permission(&self, mut permission: PermissionSet) -> PermissionSet {
+        if !self.delete {permission.delete = None;}
+        if self.channel.is_none() {permission.channel = None;}
+        permission
+    }
+
+    pub fn subset_permission(
+        &self, permission: PermissionSet, permission_options: Option<&PermissionOptions>
+    ) -> Result<PermissionSet, Error> {
+        let options = permission_options.unwrap_or(&self.permissions);
+        let perms = self.trim_permission(permission).subset(options)?;
+        self.validate_permission(&perms)?;
+        Ok(perms)
+    }
+
+    pub fn validate_child(&self, child_protocol: &Protocol) -> Result<(), Error> {
+        if let Some(channel) = &self.channel {
+            if let Some(cps) = &channel.child_protocols {
+                if !cps.contains(&child_protocol.uuid()) {
+                    return Err(Error::validation(&format!(
+                        "Invalid Child Protocol {} for {}", child_protocol.label(), self.label()
+                    )));
+                }
+            }
+            Ok(())
+        } else {Err(Error::validation(&format!("No Channel For Protocol {}", self.label())))}
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.channel.is_some() != self.permissions.channel.is_some() {
+            return Err(Error::validation("Channel Permission Without Channel Protocol"));
+        }
+        if !self.delete && self.permissions.can_delete {
+            return Err(Error::validation("Deletes Permission Without Deletes Enabled"));
+        }
+        if let Some(payload) = &self.default_payload {
+            self.validate_payload(payload)?;
+        }
+        Ok(())
+    }
+
+    pub fn validate_payload(&self, payload: &[u8]) -> Result<(), Error> {
+        if let Some(schema) = self.schema.as_ref() {
+            let payload = serde_json::from_slice(payload)?;
+            JSONSchema::compile(&serde_json::from_str(schema)?)
+            .map_err(|_| Error::validation("Invalid Schema"))?
+            .validate(&payload)
+            .map_err(|mut errors| Self::schema_error(errors.next().unwrap()))
+        } else if !payload.is_empty() {
+            Err(Error::validation(&format!("Invalid Payload for {}", self.label())))
+        } else {Ok(())}
+    }
+
+    //Type mismatches name the expected and found types, other failures the schema keyword and the value
+    fn schema_error(error: ValidationError) -> Error {
+        fn type_of(value: &serde_json::Value) -> &'static str {
+            match value {
+                serde_json::Value::Null => "null",
+                serde_json::Value::Bool(_) => "boolean",
+                serde_json::Value::Number(_) => "number",
+                serde_json::Value::String(_) => "string",
+                serde_json::Value::Array(_) => "array",
+                serde_json::Value::Object(_) => "object",
+            }
+        }
+        let (expected, got) = match &error.kind {
+            ValidationErrorKind::Type{kind: TypeKind::Single(t)} => (t.to_string(), type_of(&error.instance).to_string()),
+            ValidationErrorKind::Type{kind: TypeKind::Multiple(ts)} => (
+                ts.into_iter().map(|t| t.to_string()).collect::<Vec<_>>().join(" or "),
+                type_of(&error.instance).to_string()
+            ),
+            _ => (error.schema_path.to_string(), error.instance.to_string())
+        };
+        Error::schema_validation(&error.instance_path.to_string(), &expected, &got)
+    }
+
+    //Runs on every read, so it checks what trim_permission would drop instead of cloning the keys to compare
+    pub fn validate_permission(&self, perms: &PermissionSet) -> Result<(), Error> {
+        if (!self.delete && perms.delete.is_some()) || (self.channel.is_none() && perms.channel.is_some()) {
+            return Err(Error::validation(&format!("Protocol Restrictions Mismatch for {}", self.label())));
+        }
+        perms.covers(&self.permissions).or(Err(Error::validation("Insuffcient Permission")))?;
+        Ok(())
+    }
+
+    //Sorted keys, only used to show what changed, the hash stays over the serialized form
+    pub fn canonical(&self) -> String {
+        fn sort(value: serde_json::Value) -> serde_json::Value {
+            match value {
+                serde_json::Value::Object(map) => serde_json::Value::Object(
+                    BTreeMap::from_iter(map.into_iter().map(|(k, v)| (k, sort(v)))).into_iter().collect()
+                ),
+                serde_json::Value::Array(values) => serde_json::Value::Array(values.into_iter().map(sort).collect()),
+                value => value
+            }
+        }
+        sort(serde_json::to_value(self).unwrap()).to_string()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LockEntry {
+    pub hash: String,
+    pub canonical: String
+}
+
+//Protocol name to the hash it had when locked, meant to be checked in
+pub type LockFile = BTreeMap<String, LockEntry>;
+
+const SYSTEM_LOCK: &str = include_str!("system_protocols.lock");
+
+//Names of the protocols an agent knows, records and requests only ever carry the hash
+#[derive(Debug, Clone, Default)]
+pub struct ProtocolRegistry {
+    names: BTreeMap<Uuid, String>
+}
+
+impl ProtocolRegistry {
+    pub fn new(protocols: &[Protocol]) -> Self {
+        let mut registry = ProtocolRegistry::default();
+        protocols.iter().for_each(|p| registry.register(p));
+        registry
+    }
+
+    pub fn register(&mut self, protocol: &Protocol) {
+        self.names.insert(protocol.uuid(), protocol.name.clone());
+    }
+
+    pub fn name_of(&self, protocol: &Uuid) -> Option<&str> {
+        self.names.get(protocol).map(|n| n.as_str())
+    }
+
+    pub fn short(protocol: &Uuid) -> String {
+        protocol.simple().to_string()[..8].to_string()
+    }
+
+    //Unknown protocols are only shown by their short hash
+    pub fn label(&self, protocol: &Uuid) -> String {
+        match self.name_of(protocol) {
+            Some(name) => format!("{} ({})", name, Self::short(protocol)),
+            None => Self::short(protocol)
+        }
+    }
+
+    pub fn dump(&self) -> Vec<(Uuid, String)> {
+        self.names.iter().map(|(u, n)| (*u, n.clone())).collect()
+    }
+}
+
+//A protocols hash changing orphans every record stored under it
+#[derive(Debug, Clone)]
+pub struct ProtocolLock {
+    pub protocols: Vec<Protocol>,
+    pub lock: LockFile,
+    pub allow_drift: bool
+}
+
+impl ProtocolLock {
+    pub fn new(protocols: Vec<Protocol>, lock: LockFile) -> Self {
+        ProtocolLock{protocols, lock, allow_drift: false}
+    }
+
+    //Only logs drift instead of refusing to start
+    pub fn allow_drift(mut self) -> Self {
+        self.allow_drift = true;
+        self
+    }
+
+    pub fn generate(protocols: &[Protocol]) -> LockFile {
+        BTreeMap::from_iter(protocols.iter().map(|p|
+            (p.name.clone(), LockEntry{hash: p.hash().to_string(), canonical: p.canonical()})
+        ))
+    }
+
+    pub fn verify(protocols: &[Protocol], lock: &LockFile) -> Result<(), Error> {
+        let mut drift = Vec::new();
+        for (name, current) in Self::generate(protocols) {
+            match lock.get(&name) {
+                Some(locked) if locked.hash == current.hash => {},
+                Some(locked) => drift.push(format!(
+                    "{} changed\n- {}\n+ {}", name, locked.canonical, current.canonical
+                )),
+                None => drift.push(format!("{} is not locked\n+ {}", name, current.canonical))
+            }
+        }
+        if drift.is_empty() {Ok(())} else {Err(Error::validation(&drift.join("\n")))}
+    }
+
+    pub fn system() -> Result<LockFile, Error> {
+        Ok(serde_json::from_str(SYSTEM_LOCK)?)
+    }
+
+    pub fn check(&self) -> Result<(), Error> {
+        let result = Self::verify(&SystemProtocols::all(), &Self::system()?).and_then(|_|
+            Self::verify(&self.protocols, &self.lock)
+        );
+        match result {
+            Err(e) if self.allow_drift => {
+                log::warn!("Protocol drift allowed: {}", e);
+                Ok(())
+            },
+            result => result
+        }
+    }
+}
+
+pub struct SystemProtocols{}
+impl SystemProtocols {
+    pub fn all() -> Vec<Protocol> {
+        vec![
+            Self::root(), Self::dms_channel(), Self::agent_keys(), Self::usize(),
+            Self::perm_pointer(), Self::pointer(), Self::shared_pointer(), Self::redacted_views(),
+            Self::share_group(), Self::subscribers(), Self::placement(), Self::capability(),
+            Self::dm_cursor(), Self::abuse_report(), Self::takedown(), Self::share_upgrade(),
+            Self::share_audit(), Self::pins(), Self::blob(), Self::blob_chunk()
+        ]
+    }
+
+    pub fn root() -> Protocol {
+        Protocol::new(
+            "root",
+            false,
+            PermissionOptions::new(true, true, false, Some(
+                ChannelPermissionOptions::new(true, true)
+            )),
+            None,
+            Some(ChannelProtocol::new(None))
+        ).unwrap()
+    }
+
+  //pub fn protocol_folder(protocol: Uuid) -> Protocol {
+  //    Protocol::new(
+  //        &format!("protocol_folder: {}", hex::encode(protocol.as_bytes())),
+  //        false,
+  //        PermissionOptions::new(false, false, false, Some(
+  //            ChannelPermissionOptions::new(false, false)
+  //        )),
+  //        None,
+  //        Some(ChannelProtocol{child_protocols: Some(vec![protocol])})
+  //    ).unwrap()
+  //}
+
+    pub fn dms_channel() -> Protocol {
+        Protocol::new(
+            "dms_channel",
+            true,
+            PermissionOptions::new(true, true, true, Some(
+                ChannelPermissionOptions::new(true, true)
+            )),
+            None,
+            Some(ChannelProtocol::new(None))
+        ).unwrap()
+    }
+
+    pub fn agent_keys() -> Protocol {
+        Protocol::new(
+            "agent_keys",
+            true,
+            PermissionOptions::new(true, true, true, None),
+            Some(serde_json::to_string(&schema_for!(BTreeMap<RecordPath, PublicKey>)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+    pub fn usize() -> Protocol {
+        Protocol::new(
+            "date_time",
+            true,
+            PermissionOptions::new(true, true, true, None),
+            Some(serde_json::to_string(&schema_for!(usize)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+  //pub fn channel_item() -> Protocol {
+  //    Protocol::new(
+  //        "channel_item",
+  //        false,
+  //        PermissionOptions::new(false, false, false, None),
+  //        Some(serde_json::to_string(&Schemas::any()).unwrap()),
+  //        None
+  //    ).unwrap()
+  //}
+
+    pub fn perm_pointer() -> Protocol {
+        Protocol::new(
+            "perm_pointer",
+            false,
+            PermissionOptions::new(true, true, false, None),
+            Some(serde_json::to_string(&schema_for!(PermissionSet)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+    pub fn pointer() -> Protocol {
+        Protocol::new(
+            "pointer",
+            true,
+            PermissionOptions::new(true, true, true, None),
+            Some(serde_json::to_string(&schema_for!(SharedPointer)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+    pub fn shared_pointer() -> Protocol {
+        Protocol::new(
+            "shared_pointer",
+            true,
+            PermissionOptions::new(true, true, true, None),
+            Some(serde_json::to_string(&schema_for!(ShareEnvelope)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+    pub fn redacted_views() -> Protocol {
+        Protocol::new(
+            "redacted_views",
+            false,
+            PermissionOptions::new(true, true, false, None),
+            Some(serde_json::to_string(&schema_for!(Vec<RedactedView>)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+    pub fn share_group() -> Protocol {
+        Protocol::new(
+            "share_group",
+            true,
+            PermissionOptions::new(true, true, true, None),
+            Some(serde_json::to_string(&schema_for!(ShareGroup)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+    pub fn subscribers() -> Protocol {
+        Protocol::new(
+            "subscribers",
+            false,
+            PermissionOptions::new(true, true, false, None),
+            Some(serde_json::to_string(&schema_for!(Subscribers)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+    pub fn placement() -> Protocol {
+        Protocol::new(
+            "placement",
+            false,
+            PermissionOptions::new(true, true, false, None),
+            Some(serde_json::to_string(&schema_for!(Placement)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+    pub fn capability() -> Protocol {
+        Protocol::new(
+            "capability",
+            true,
+            PermissionOptions::new(true, true, true, None),
+            Some(serde_json::to_string(&schema_for!(CapabilityGrant)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+    pub fn dm_cursor() -> Protocol {
+        Protocol::new(
+            "dm_cursor",
+            true,
+            PermissionOptions::new(true, true, true, None),
+            Some(serde_json::to_string(&schema_for!(DmCursor)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+    //Public, filed by anyone and only ever read by the operator of the Dwn
+    pub fn abuse_report() -> Protocol {
+        Protocol::new(
+            "abuse_report",
+            false,
+            PermissionOptions::new(true, true, false, None),
+            Some(serde_json::to_string(&schema_for!(AbuseReport)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+    //Tombstone of a public record the operator took down
+    pub fn takedown() -> Protocol {
+        Protocol::new(
+            "takedown",
+            false,
+            PermissionOptions::new(true, true, false, None),
+            Some(serde_json::to_string(&schema_for!(Takedown)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+    //Deleted once the sharer answers
+    pub fn share_upgrade() -> Protocol {
+        Protocol::new(
+            "share_upgrade",
+            true,
+            PermissionOptions::new(true, true, true, None),
+            Some(serde_json::to_string(&schema_for!(PendingShareUpgrade)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+    pub fn share_audit() -> Protocol {
+        Protocol::new(
+            "share_audit",
+            false,
+            PermissionOptions::new(true, true, false, None),
+            Some(serde_json::to_string(&schema_for!(ShareAuditEntry)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+    pub fn pins() -> Protocol {
+        Protocol::new(
+            "pins",
+            true,
+            PermissionOptions::new(true, true, true, None),
+            Some(serde_json::to_string(&schema_for!(BTreeSet<RecordPath>)).unwrap()),
+            None
+        ).unwrap()
+    }
+
+    pub fn blob() -> Protocol {
+        Protocol::new(
+            "blob",
+            true,
+            PermissionOptions::new(true, true, true, Some(
+                ChannelPermissionOptions::new(true, true)
+            )),
+            Some(serde_json::to_string(&schema_for!(BlobManifest)).unwrap()),
+            Some(ChannelProtocol::new(Some(vec![&Self::blob_chunk()])))
+        ).unwrap()
+    }
+
+    pub fn blob_chunk() -> Protocol {
+        Protocol::new(
+            "blob_chunk",
+            true,
+            PermissionOptions::new(true, true, true, None),
+            Some(serde_json::to_string(&schema_for!(BlobChunk)).unwrap()),
+            None
+        ).unwrap()
+    }
+}
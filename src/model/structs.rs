@@ -0,0 +1,834 @@
+use super::Error;
+
+use super::permission::{
+    ChannelPermissionSet,
+    PermissionOptions,
+    PermissionSet
+};
+use super::protocol::{SystemProtocols, Protocol, ProtocolRegistry};
+
+use crate::dids::signing::{SignedObject, Verifier};
+use crate::dids::Did;
+
+use crate::dwn::structs::{DwnItem, Takedown};
+use crate::common::{Convert, Compression};
+
+use simple_crypto::{Hashable, SecretKey, PublicKey, Key};
+
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use uuid::Uuid;
+
+const INDEX_UUID: Uuid = Uuid::max();
+const RESERVED_SEGMENTS: [Uuid; 1] = [INDEX_UUID];
+//Deepest path a record can have, every segment costs a key derivation whenever the path is touched
+pub const MAX_PATH_DEPTH: usize = 64;
+
+//The root path "/" names no stored record. Reading it yields a synthetic record under
+//SystemProtocols::root, children can be created under it but it is never created, updated or deleted
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[derive(serde_with::SerializeDisplay)]
+pub struct RecordPath {
+    inner: Vec<Uuid>
+}
+
+//Serialized through Display so the schema has to be a string
+impl JsonSchema for RecordPath {
+    fn schema_name() -> String {"RecordPath".to_string()}
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        String::json_schema(gen)
+    }
+}
+
+impl std::fmt::Display for RecordPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "/{}", self.inner.iter().map(|id| id.to_string()).collect::<Vec<_>>().join("/"))
+    }
+}
+
+impl std::str::FromStr for RecordPath {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let path = Self::parse(s).map_err(|_| Error::parse("RecordPath", s))?;
+        Self::new(path.as_slice())
+    }
+}
+
+//Stored records and permissions may carry system segments, only FromStr and new are guarded
+impl<'de> Deserialize<'de> for RecordPath {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Self::parse(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl RecordPath {
+    pub fn new(path: &[Uuid]) -> Result<Self, Error> {
+        if let Some(segment) = path.iter().find(|s| RESERVED_SEGMENTS.contains(s)) {
+            return Err(Error::validation(&format!("{} is a reserved path segment", segment)));
+        }
+        Self::check_depth(path)?;
+        Ok(Self::from_segments(path))
+    }
+
+    //Reserved segments do not count, the index of the deepest record is still derivable
+    pub fn check_depth(path: &[Uuid]) -> Result<(), Error> {
+        let depth = path.iter().filter(|s| !RESERVED_SEGMENTS.contains(s)).count();
+        if depth > MAX_PATH_DEPTH {
+            return Err(Error::validation(&format!("Path of {} segments is deeper than {}", depth, MAX_PATH_DEPTH)));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn from_segments(path: &[Uuid]) -> Self {
+        RecordPath{inner: path.to_vec()}
+    }
+
+    fn parse(s: &str) -> Result<Self, uuid::Error> {
+        Ok(RecordPath{inner:
+            s.get(1..).unwrap_or_default().split("/").collect::<Vec<_>>()
+            .into_iter().filter_map(|id|
+                if id.is_empty() {None} else {Some(Uuid::parse_str(id))}
+            ).collect::<Result<Vec<Uuid>, uuid::Error>>()?
+        })
+    }
+
+    pub fn parent_of(&self, path: &RecordPath) -> bool {
+        path.as_slice().strip_prefix(self.as_slice()).is_some()
+    }
+
+    pub fn root() -> Self {
+        RecordPath{inner: Vec::new()}
+    }
+
+    pub fn last(&self) -> Uuid {
+        self.inner.last().copied().unwrap_or(Uuid::nil())
+    }
+
+    pub fn is_empty(&self) -> bool {self.inner.is_empty()}
+
+    //Op is what was attempted, as in "Cannot delete the root record"
+    pub fn check_writable(&self, op: &str) -> Result<(), Error> {
+        if self.is_empty() {
+            return Err(Error::bad_request(&format!("Cannot {} the root record, only its children", op)));
+        }
+        Ok(())
+    }
+
+    pub fn as_slice(&self) -> &[Uuid] {
+        self.inner.as_slice()
+    }
+
+    pub fn parent(&self) -> Result<Self, Error> {
+        match self.inner.split_last() {
+            Some(p) => Ok(RecordPath::from_segments(p.1)),
+            None => {Err(Error::bad_request("Cannot Get Parent Of Root"))}
+        }
+    }
+
+    pub fn index(&self) -> Self {
+        RecordPath::from_segments(&[&self.inner, &[INDEX_UUID][..]].concat())
+    }
+
+    pub fn extend(&self, path: &[Uuid]) -> Result<Self, Error> {
+        RecordPath::new(&[&self.inner, path].concat())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub path: RecordPath,
+    pub protocol: Protocol,
+    pub payload: Vec<u8>,
+    //Private records only, read back as missing once passed
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if="RecordState::is_valid")]
+    pub state: RecordState
+}
+
+impl Record {
+    pub fn new(path: RecordPath, protocol: Protocol, payload: &[u8]) -> Self {
+        Record{path, protocol, payload: payload.to_vec(), expires: None, state: RecordState::Valid}
+    }
+
+    //Empty payload when the protocol has no default
+    pub fn from_defaults(path: RecordPath, protocol: Protocol) -> Self {
+        let payload = protocol.default_payload.clone().unwrap_or_default();
+        Record{path, protocol, payload, expires: None, state: RecordState::Valid}
+    }
+
+    pub fn new_typed<T: Serialize>(path: RecordPath, protocol: Protocol, value: &T) -> Result<Self, Error> {
+        Ok(Record::new(path, protocol, &serde_json::to_vec(value)?))
+    }
+
+    pub fn expiring(mut self, expires: DateTime<Utc>) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    //Names the protocol and shows the start of the payload when it does not decode
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        const SHOWN: usize = 64;
+        serde_json::from_slice::<T>(&self.payload).map_err(|e| {
+            let shown = String::from_utf8_lossy(&self.payload[..self.payload.len().min(SHOWN)]).to_string();
+            let more = if self.payload.len() > SHOWN {"..."} else {""};
+            Error::validation(&format!(
+                "Could not decode {} payload {}{}: {}", self.protocol.label(), shown, more, e
+            ))
+        })
+    }
+}
+
+impl Hashable for Record {}
+
+//A record whose payload is known to decode to T and to pass the protocol schema
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TypedRecord<T> {
+    record: Record,
+    value: T
+}
+
+impl<T: Serialize + DeserializeOwned> TypedRecord<T> {
+    pub fn new(path: RecordPath, protocol: Protocol, value: T) -> Result<Self, Error> {
+        let record = Record::new_typed(path, protocol, &value)?;
+        record.protocol.validate_payload(&record.payload)?;
+        Ok(TypedRecord{record, value})
+    }
+
+    pub fn from_record(record: Record) -> Result<Self, Error> {
+        record.protocol.validate_payload(&record.payload)?;
+        let value = record.decode::<T>()?;
+        Ok(TypedRecord{record, value})
+    }
+
+    pub fn value(&self) -> &T {&self.value}
+    pub fn protocol(&self) -> &Protocol {&self.record.protocol}
+    pub fn path(&self) -> &RecordPath {&self.record.path}
+    pub fn record(&self) -> &Record {&self.record}
+    pub fn into_record(self) -> Record {self.record}
+    pub fn into_value(self) -> T {self.value}
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ValidationIssue(pub String);
+
+impl ValidationIssue {
+    pub fn new(issue: &str) -> Self {ValidationIssue(issue.to_string())}
+}
+
+impl std::fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+pub type MigratorId = Uuid;
+
+//What a read does with the readers own records that fail validation, foreign records are always rejected
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum OnInvalid {
+    #[default]
+    Reject,
+    //Return the record as stored with RecordState::Invalid so the app can repair it
+    SurfaceRaw,
+    //Rewrite the payload with a registered PayloadMigrator, the stored record is left untouched
+    Migrate(MigratorId)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum RecordState {
+    #[default]
+    Valid,
+    //Payload was migrated on read, updating the record persists it
+    Migrated,
+    Invalid{issues: Vec<ValidationIssue>, payload: Vec<u8>}
+}
+
+impl RecordState {
+    pub fn is_valid(&self) -> bool {matches!(self, Self::Valid)}
+}
+
+//Payload of a DM, the permissions being handed over and the protocol they are meant for
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SharedPermissions {
+    pub protocol: Uuid,
+    pub perms: PermissionSet
+}
+
+impl SharedPermissions {
+    pub fn new(protocol: Uuid, perms: PermissionSet) -> Self {
+        SharedPermissions{protocol, perms}
+    }
+
+    //A record under another protocol could trim the permissions differently than the sharer intended
+    pub fn verify(&self, protocol: &Protocol, protocols: &ProtocolRegistry) -> Result<(), Error> {
+        if protocol.uuid() != self.protocol {
+            return Err(Error::invalid_auth(&format!(
+                "Shared for protocol {} but the record uses {}", protocols.label(&self.protocol), protocol.label()
+            )));
+        }
+        Ok(())
+    }
+}
+
+//Sent to subscribers after a shared record changes, only hashes leave the sharer
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RecordUpdated {
+    pub path: Uuid,
+    pub payload: String,
+    pub timestamp: DateTime<Utc>
+}
+
+impl RecordUpdated {
+    pub fn new(path: &RecordPath, payload: &[u8]) -> Self {
+        RecordUpdated{path: Self::path_hash(path), payload: payload.hash().to_string(), timestamp: Utc::now()}
+    }
+
+    pub fn path_hash(path: &RecordPath) -> Uuid {
+        Uuid::new_v5(&Uuid::NAMESPACE_OID, path.to_string().as_bytes())
+    }
+}
+
+//Untagged so share DMs keep the format they had before notifications existed
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum DmMessage {
+    Share(Box<SharedPermissions>),
+    RecordUpdated(RecordUpdated),
+    //Sent by a Dwn after its operator took down one of our public records
+    Takedown(Takedown),
+    //Recipient to sharer and back, after the older shapes so those still parse first
+    ShareUpgradeRequest(ShareUpgradeRequest),
+    ShareResponse(ShareResponse)
+}
+
+//Asks the sharer of a record for a different subset of its permissions than was shared
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ShareUpgradeRequest {
+    pub path: RecordPath,
+    pub wanted: PermissionOptions
+}
+
+//None when denied, a denial is sent without reading the record so it tells nothing about it.
+//An approval is followed by a fresh share holding the granted subset
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ShareResponse {
+    pub path: RecordPath,
+    pub granted: Option<PermissionOptions>
+}
+
+//Stored by ScanDM on the sharers com tree until the request is approved or denied
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PendingShareUpgrade {
+    pub requester: Did,
+    pub request: ShareUpgradeRequest,
+    pub received_at: DateTime<Utc>
+}
+
+impl PendingShareUpgrade {
+    pub fn path(requester: &Did, path: &RecordPath) -> Result<RecordPath, Error> {
+        Ok(RecordPath::from_segments(&[Uuid::new_v5(
+            &Uuid::NAMESPACE_OID, &serde_json::to_vec(&("share_upgrade", requester, path))?
+        )]))
+    }
+}
+
+//Stored by ScanDM on the recipients com tree for every answer, granted is exactly what was accepted
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ShareAuditEntry {
+    pub sharer: Did,
+    pub path: RecordPath,
+    pub granted: Option<PermissionOptions>,
+    pub received_at: DateTime<Utc>
+}
+
+impl ShareAuditEntry {
+    pub fn new(sharer: Did, response: ShareResponse) -> Self {
+        ShareAuditEntry{sharer, path: response.path, granted: response.granted, received_at: Utc::now()}
+    }
+
+    //An answer repeated by a retried DM lands on the same entry
+    pub fn record_path(&self) -> Result<RecordPath, Error> {
+        Ok(RecordPath::from_segments(&[Uuid::new_v5(
+            &Uuid::NAMESPACE_OID, &serde_json::to_vec(&("share_audit", &self.sharer, &self.path, &self.granted))?
+        )]))
+    }
+}
+
+//Sidecar of a record listing who gets a RecordUpdated DM when it changes
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct Subscribers {
+    pub members: Vec<Did>
+}
+
+impl Subscribers {
+    pub fn path(record: &RecordPath) -> RecordPath {
+        RecordPath::from_segments(&[Uuid::new_v5(
+            &Uuid::NAMESPACE_OID, format!("SUBSCRIBERS:{}", record).as_bytes()
+        )])
+    }
+
+    pub fn from_record(record: Option<Box<PrivateRecord>>) -> Result<Self, Error> {
+        Ok(match record {
+            Some(record) => serde_json::from_slice::<Subscribers>(&record.payload)?,
+            None => Subscribers::default()
+        })
+    }
+
+    pub fn into_record(self, record: &RecordPath) -> Result<Record, Error> {
+        Ok(Record::new(Self::path(record), SystemProtocols::subscribers(), &serde_json::to_vec(&self)?))
+    }
+}
+
+//Sidecar of a record moved off the tenants Dwns, reads fall back to the did it was moved to
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Placement {
+    pub did: Did
+}
+
+impl Placement {
+    pub fn new(did: Did) -> Self {Placement{did}}
+
+    pub fn path(record: &RecordPath) -> RecordPath {
+        RecordPath::from_segments(&[Uuid::new_v5(
+            &Uuid::NAMESPACE_OID, format!("PLACEMENT:{}", record).as_bytes()
+        )])
+    }
+
+    pub fn from_record(record: Option<Box<PrivateRecord>>) -> Result<Option<Self>, Error> {
+        Ok(record.map(|record| serde_json::from_slice::<Placement>(&record.payload)).transpose()?)
+    }
+
+    pub fn into_record(self, record: &RecordPath) -> Result<Record, Error> {
+        Ok(Record::new(Self::path(record), SystemProtocols::placement(), &serde_json::to_vec(&self)?))
+    }
+}
+
+//Payload of a blob's root record. The chunks are its children, the manifest lists them in order
+//with the hash a reader checks each one against
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct BlobManifest {
+    pub size: u64,
+    pub chunks: Vec<BlobChunkRef>,
+    //Set once the writer finished, a blob still being written is not read
+    pub complete: bool
+}
+
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlobChunkRef {
+    pub id: Uuid,
+    pub size: usize,
+    pub hash: String
+}
+
+//Payloads are json, a chunk carries its bytes as base64
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlobChunk {
+    pub data: String
+}
+
+impl BlobChunk {
+    pub fn into_record(bytes: &[u8], blob: &RecordPath) -> Result<(Record, BlobChunkRef), Error> {
+        let chunk = BlobChunkRef{id: Uuid::new_v4(), size: bytes.len(), hash: bytes.hash().to_string()};
+        let payload = serde_json::to_vec(&BlobChunk{data: Convert::Base64UrlUnpadded.encode(bytes)})?;
+        Ok((Record::new(blob.extend(&[chunk.id])?, SystemProtocols::blob_chunk(), &payload), chunk))
+    }
+
+    //The bytes of a chunk read back, checked against the manifest
+    pub fn from_record(record: &PrivateRecord, chunk: &BlobChunkRef) -> Result<Vec<u8>, Error> {
+        if record.protocol != SystemProtocols::blob_chunk() {
+            return Err(Error::bad_response("Blob chunk is not of the blob_chunk protocol"));
+        }
+        let bytes = Convert::Base64UrlUnpadded.decode(&serde_json::from_slice::<BlobChunk>(&record.payload)?.data)?;
+        if bytes.len() != chunk.size || bytes.hash().to_string() != chunk.hash {
+            return Err(Error::bad_response(&format!("Blob chunk {} does not match the manifest", chunk.id)));
+        }
+        Ok(bytes)
+    }
+}
+
+//Payload of a capability record, what a token holder may read and until when
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CapabilityGrant {
+    pub perms: PermissionSet,
+    pub expires: Option<DateTime<Utc>>
+}
+
+impl CapabilityGrant {
+    pub fn new(perms: PermissionSet, expires: Option<DateTime<Utc>>) -> Result<Self, Error> {
+        CapabilityToken::read_only(&perms)?;
+        Ok(CapabilityGrant{perms, expires})
+    }
+
+    //Every capability gets its own record so each can be revoked on its own
+    pub fn path() -> RecordPath {
+        Self::path_for(Uuid::new_v4())
+    }
+
+    pub fn path_for(id: Uuid) -> RecordPath {
+        RecordPath::from_segments(&[id])
+    }
+
+    pub fn check(&self, now: DateTime<Utc>) -> Result<(), Error> {
+        match self.expires {
+            Some(expires) if expires <= now => Err(Error::invalid_auth(&format!("Capability expired at {}", expires))),
+            _ => Ok(())
+        }
+    }
+}
+
+//Read access to a capability record and the did whose Dwns hold it, encoded as a url safe token
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CapabilityToken {
+    pub owner: Did,
+    pub perms: PermissionSet
+}
+
+impl CapabilityToken {
+    pub fn new(owner: Did, perms: PermissionSet) -> Result<Self, Error> {
+        Self::read_only(&perms)?;
+        Ok(CapabilityToken{owner, perms})
+    }
+
+    fn read_only(perms: &PermissionSet) -> Result<(), Error> {
+        let options = perms.options();
+        if options.can_create || options.can_delete || options.channel.map(|c| c.can_create).unwrap_or(false) {
+            return Err(Error::bad_request("Capabilities only carry read and discover permissions"));
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Result<String, Error> {
+        Ok(Convert::Base64UrlUnpadded.encode(&serde_json::to_vec(self)?))
+    }
+
+    pub fn decode(token: &str) -> Result<Self, Error> {
+        let token = serde_json::from_slice::<Self>(&Convert::Base64UrlUnpadded.decode(token)?)?;
+        Self::read_only(&token.perms)?;
+        Ok(token)
+    }
+}
+
+//Stored by ScanDM under the per sender path of the com tree for every received DM
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SharedPointer {
+    pub sharer: Verifier,
+    pub protocol: Uuid,
+    pub perms: PermissionSet,
+    pub received_at: DateTime<Utc>
+}
+
+impl SharedPointer {
+    //received_at is when the Dwn stored the DM, a rescan leaves it unchanged
+    pub fn new(sharer: Verifier, shared: SharedPermissions, received_at: DateTime<Utc>) -> Self {
+        SharedPointer{sharer, protocol: shared.protocol, perms: shared.perms, received_at}
+    }
+
+    pub fn path(sharer: &Verifier, path: &RecordPath) -> Result<RecordPath, Error> {
+        Ok(RecordPath::from_segments(&[Uuid::new_v5(
+            &Uuid::NAMESPACE_OID, &serde_json::to_vec(&(sharer, path))?
+        )]))
+    }
+
+    //Where pointers were kept, one per sharer holding only the shared PermissionSet
+    pub fn legacy_path(sharer: &Verifier) -> RecordPath {
+        RecordPath::from_segments(&[Uuid::new_v5(&Uuid::NAMESPACE_OID, sharer.to_string().as_bytes())])
+    }
+
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SharedRecordInfo {
+    pub sharer: Did,
+    pub path: RecordPath,
+    pub protocol: Uuid,
+    pub capabilities: PermissionOptions,
+    //When the channel it was shared on arrived, None for a channel still under its legacy pointer
+    pub received_at: Option<DateTime<Utc>>
+}
+
+//Payload of a shared_pointer record, the shared permissions encrypted to each recipient agent key
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ShareEnvelope {
+    pub path: RecordPath,
+    pub protocol: Protocol,
+    pub p_opts: Option<PermissionOptions>,
+    pub ciphertexts: Vec<(PublicKey, Vec<u8>)>
+}
+
+impl ShareEnvelope {
+    pub fn new(path: RecordPath, protocol: Protocol, p_opts: Option<PermissionOptions>) -> Self {
+        ShareEnvelope{path, protocol, p_opts, ciphertexts: Vec::new()}
+    }
+
+    //Appends ciphertexts for keys not yet sealed to, returns weather any were added
+    pub fn seal(&mut self, keys: Vec<PublicKey>, perms: &PermissionSet) -> Result<bool, Error> {
+        let payload = serde_json::to_vec(perms)?;
+        let mut sealed = false;
+        for key in keys {
+            if !self.ciphertexts.iter().any(|(k, _)| *k == key) {
+                self.ciphertexts.push((key.clone(), key.encrypt(&payload)?));
+                sealed = true;
+            }
+        }
+        Ok(sealed)
+    }
+
+    pub fn open(&self, key: &SecretKey) -> Option<PermissionSet> {
+        let public = key.public_key();
+        let (_, ciphertext) = self.ciphertexts.iter().find(|(k, _)| *k == public)?;
+        serde_json::from_slice(&key.decrypt(ciphertext).ok()?).ok()
+    }
+}
+
+//Named set of DIDs and the paths shared with all of them, kept as a private record
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ShareGroup {
+    pub name: String,
+    pub members: Vec<Did>,
+    pub paths: Vec<(RecordPath, Option<PermissionOptions>)>
+}
+
+impl ShareGroup {
+    pub fn new(name: &str, members: Vec<Did>) -> Self {
+        ShareGroup{name: name.to_string(), members, paths: Vec::new()}
+    }
+
+    pub fn path(name: &str) -> RecordPath {
+        RecordPath::from_segments(&[Uuid::new_v5(&Uuid::NAMESPACE_OID, format!("SHARE_GROUP:{}", name).as_bytes())])
+    }
+
+    pub fn from_record(record: Option<Box<PrivateRecord>>) -> Result<Self, Error> {
+        let record = record.ok_or(Error::not_found("Share Group"))?;
+        Ok(serde_json::from_slice::<ShareGroup>(&record.payload)?)
+    }
+
+    pub fn into_record(self) -> Result<Record, Error> {
+        Ok(Record::new(Self::path(&self.name), SystemProtocols::share_group(), &serde_json::to_vec(&self)?))
+    }
+}
+
+//JSON pointers stripped from a payload before it is shared, the view may use its own protocol
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RedactionSpec {
+    pub pointers: Vec<String>,
+    pub view_protocol: Option<Protocol>
+}
+
+impl RedactionSpec {
+    pub fn new(pointers: Vec<&str>, view_protocol: Option<Protocol>) -> Self {
+        RedactionSpec{pointers: pointers.into_iter().map(|p| p.to_string()).collect(), view_protocol}
+    }
+
+    //Pointers that do not resolve are skipped so the spec survives payload changes. Each one is
+    //resolved against the original payload, then removed highest index first so no removal shifts
+    //an array index another pointer names
+    pub fn apply(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut value = serde_json::from_slice::<serde_json::Value>(payload)?;
+        let mut targets = Vec::new();
+        for pointer in &self.pointers {
+            let (parent, key) = match pointer.rsplit_once('/') {
+                Some(split) => split,
+                None => {return Err(Error::parse("JSON Pointer", pointer));}
+            };
+            if value.pointer(pointer).is_none() {continue;}
+            let tokens = pointer.split('/').skip(1).map(|token| {
+                let token = token.replace("~1", "/").replace("~0", "~");
+                (token.parse::<usize>().ok(), token)
+            }).collect::<Vec<_>>();
+            targets.push((tokens, parent, key.replace("~1", "/").replace("~0", "~")));
+        }
+        targets.sort();
+        targets.dedup();
+        for (_, parent, key) in targets.into_iter().rev() {
+            match value.pointer_mut(parent) {
+                Some(serde_json::Value::Object(map)) => {map.remove(&key);},
+                Some(serde_json::Value::Array(items)) => {
+                    if let Some(i) = key.parse::<usize>().ok().filter(|i| *i < items.len()) {
+                        items.remove(i);
+                    }
+                },
+                _ => {}
+            }
+        }
+        Ok(serde_json::to_vec(&value)?)
+    }
+}
+
+//Links a derived record to the original it was redacted from, kept only by the sharer
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RedactedView {
+    pub original: RecordPath,
+    pub view: RecordPath,
+    pub spec: RedactionSpec
+}
+
+impl RedactedView {
+    pub fn new(original: RecordPath, spec: RedactionSpec) -> Result<Self, Error> {
+        let view = RecordPath::from_segments(&[Uuid::new_v5(
+            &Uuid::NAMESPACE_OID, &serde_json::to_vec(&(&original, &spec))?
+        )]);
+        Ok(RedactedView{original, view, spec})
+    }
+
+    pub fn registry_path() -> RecordPath {
+        RecordPath::from_segments(&[Uuid::new_v5(&Uuid::NAMESPACE_OID, b"REDACTED_VIEWS")])
+    }
+
+    pub fn derive(&self, original: &Record) -> Result<Record, Error> {
+        let protocol = self.spec.view_protocol.clone().unwrap_or(original.protocol.clone());
+        Ok(Record::new(self.view.clone(), protocol, &self.spec.apply(&original.payload)?))
+    }
+}
+
+impl std::fmt::Debug for PrivateRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.payload)
+    }
+}
+
+#[derive(JsonSchema, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PrivateRecord {
+    pub perms: PermissionSet,
+    pub protocol: Protocol,
+    pub payload: Vec<u8>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+    //Set on read, never signed or stored
+    #[serde(skip)]
+    pub state: RecordState
+}
+
+impl PrivateRecord {
+    pub fn new(perms: PermissionSet, protocol: Protocol, payload: Vec<u8>) -> Self {
+        PrivateRecord{perms, protocol, payload, expires: None, state: RecordState::Valid}
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires.map(|expires| expires <= Utc::now()).unwrap_or(false)
+    }
+
+    pub fn into_record(self) -> Record {
+        Record{path: self.perms.path, protocol: self.protocol, payload: self.payload, expires: self.expires, state: self.state}
+    }
+
+    pub fn into_item(self, create: Option<&SecretKey>) -> Result<DwnItem, Error> {
+        let discover = self.perms.discover.public_key();
+        let delete = self.perms.delete.clone().map(|d| d.public_key());
+        let read = self.perms.read.public_key();
+        let create = match create {
+            Some(create) => {
+                if create.public_key() != self.perms.create.public_key() {
+                    return Err(Error::invalid_auth("Create"));
+                }
+                create
+            },
+            None => &self.perms.create.secret_key().ok_or(Error::invalid_auth("Create"))?
+        };
+        let expires = self.expires;
+        let signed = SignedObject::from_key(create, self)?;
+        let payload = read.encrypt(&Compression::encode(serde_json::to_vec(&signed)?))?;
+
+        Ok(DwnItem{discover, delete, payload, expires})
+    }
+}
+
+//Drops the permissions, only the path is kept
+impl From<PrivateRecord> for Record {
+    fn from(record: PrivateRecord) -> Self {record.into_record()}
+}
+
+//The permissions must be for the records path, the protocol trims them as it would on create
+impl TryFrom<(Record, PermissionSet)> for PrivateRecord {
+    type Error = Error;
+    fn try_from((record, perms): (Record, PermissionSet)) -> Result<Self, Error> {
+        if perms.path != record.path {
+            return Err(Error::bad_request(&format!(
+                "Permissions for {} given for a record at {}", perms.path, record.path
+            )));
+        }
+        let perms = record.protocol.trim_permission(perms);
+        Ok(PrivateRecord{perms, protocol: record.protocol, payload: record.payload, expires: record.expires, state: record.state})
+    }
+}
+
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PathedKey {
+    pub key: SecretKey,
+    pub path: RecordPath
+}
+
+impl PathedKey {
+    pub fn new(key: SecretKey, path: RecordPath) -> Self {
+        PathedKey{key, path}
+    }
+
+    pub fn new_root(key: SecretKey) -> Self {
+        PathedKey{key, path: RecordPath::root()}
+    }
+
+    pub fn derive_path(&self, path: &[Uuid]) -> Result<Self, Error> {
+        RecordPath::check_depth(path)?;
+        if let Some(striped_path) = path.strip_prefix(self.path.as_slice()) {
+            let mut key = self.key.clone();
+            for uuid in striped_path {
+                key = key.derive_bytes(uuid.as_bytes())?;
+            }
+            Ok(PathedKey::new(key, RecordPath::from_segments(path)))
+        } else {Err(Error::insufficent_permission())}
+    }
+
+    pub fn to_permission(&self) -> Result<PermissionSet, Error> {
+        self.to_roles(None)
+    }
+
+    pub fn discover(&self) -> Result<SecretKey, Error> {
+        Ok(self.key.derive_usize(Self::DISCOVER)?)
+    }
+
+    pub fn create(&self) -> Result<SecretKey, Error> {
+        Ok(self.key.derive_usize(Self::CREATE)?)
+    }
+
+    pub fn to_roles(&self, protocol: Option<&Protocol>) -> Result<PermissionSet, Error> {
+        Self::roles_with(self.path.clone(), protocol, |role| Ok(self.key.derive_usize(role)?))
+    }
+
+    pub const DISCOVER: usize = 0;
+    pub const CREATE: usize = 1;
+
+    //Role keys are asked of the closure by index, the delete and channel keys only when the protocol keeps them
+    pub fn roles_with(
+        path: RecordPath, protocol: Option<&Protocol>, mut role: impl FnMut(usize) -> Result<SecretKey, Error>
+    ) -> Result<PermissionSet, Error> {
+        let delete = protocol.map(|p| p.delete).unwrap_or(true);
+        let channel = protocol.map(|p| p.channel.is_some()).unwrap_or(true);
+        Ok(PermissionSet::new(
+            path,
+            role(Self::DISCOVER)?,
+            Key::new_secret(role(Self::CREATE)?),
+            Key::new_secret(role(2)?),
+            if delete {Some(Key::new_secret(role(3)?))} else {None},
+            if channel {Some(ChannelPermissionSet::new(
+                Key::new_secret(role(4)?),
+                Key::new_secret(role(5)?),
+                Key::new_secret(role(6)?),
+            ))} else {None}
+        ))
+    }
+
+    pub fn get_perms(&self, path: &RecordPath, protocol: Option<&Protocol>) -> Result<PermissionSet, Error> {
+        self.get_perms_from_slice(path.as_slice(), protocol)
+    }
+
+    pub fn get_perms_from_slice(&self, path: &[Uuid], protocol: Option<&Protocol>) -> Result<PermissionSet, Error> {
+        let perms = self.derive_path(path)?.to_roles(protocol)?;
+        if let Some(protocol) = protocol {
+            Ok(protocol.trim_permission(perms))
+        } else {Ok(perms)}
+    }
+}
@@ -0,0 +1,21 @@
+//The supported surface, anything reached through a deeper path may move between releases
+pub use crate::error::{Error, ErrorJson};
+pub use crate::dids::{Did, DidResolver, DidDocument, DhtDocument};
+pub use simple_database::database::{Filters, Filter};
+pub use crate::common::{FilterExpr, FilterLogic};
+
+#[cfg(feature = "dwn")]
+pub use crate::dwn::Dwn;
+#[cfg(feature = "dwn")]
+pub use crate::model::structs::{Record, RecordPath, TypedRecord};
+#[cfg(feature = "dwn")]
+pub use crate::model::protocol::{Protocol, ChannelProtocol};
+#[cfg(feature = "dwn")]
+pub use crate::model::permission::{PermissionOptions, ChannelPermissionOptions};
+
+#[cfg(feature = "agent")]
+pub use crate::agent::{Agent, Wallet, Identity};
+#[cfg(feature = "agent")]
+pub use crate::agent::CompilerCache;
+#[cfg(feature = "agent")]
+pub use crate::agent::scripts;
@@ -0,0 +1,156 @@
+use crate::prelude::{Error, Did, DidResolver, DidDocument, DhtDocument, Dwn};
+use crate::prelude::{Agent, Wallet, Identity, CompilerCache};
+use crate::dwn::DwnIdentity;
+use crate::dwn::structs::{DwnRequest, DwnResponse, Packet};
+use crate::dwn::traits::Client;
+
+use simple_database::MemoryStore;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use uuid::Uuid;
+use url::Url;
+
+pub type Docs = BTreeMap<Did, Box<dyn DidDocument>>;
+
+#[derive(Clone, Default)]
+pub struct MemoryDidResolver {
+    pub docs: Docs
+}
+
+impl MemoryDidResolver {
+    pub fn new() -> Self {MemoryDidResolver{docs: Docs::default()}}
+    pub fn store(&mut self, doc: Box<dyn DidDocument>) {
+        self.docs.insert(doc.did(), doc);
+    }
+}
+
+#[async_trait::async_trait]
+impl DidResolver for MemoryDidResolver {
+    async fn resolve(&self, did: &Did) -> Result<Option<Box<dyn DidDocument>>, Error> {
+        Ok(self.docs.get(did).cloned())
+    }
+}
+
+impl std::fmt::Debug for MemoryDidResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryDidResolver")
+        .field("dids", &self.docs.keys().map(|did| did.to_string()).collect::<Vec<String>>())
+        .finish()
+    }
+}
+
+//Serves packets from in process Dwns by url, keeping every request it decrypts.
+//Stands in for the http client under a Router, so agents run their commands without binding a port
+#[derive(Debug, Clone)]
+pub struct LocalDwns {
+    pub(crate) dwns: Arc<BTreeMap<Url, Dwn>>,
+    pub(crate) requests: Arc<Mutex<Vec<(Url, DwnRequest)>>>,
+    pub(crate) responses: Arc<Mutex<Vec<(Url, DwnResponse)>>>,
+    pub(crate) faults: Arc<Mutex<BTreeMap<Url, String>>>
+}
+
+impl LocalDwns {
+    pub async fn new(resolver: &(dyn DidResolver + 'static), servers: Vec<(DwnIdentity, DhtDocument)>) -> Result<Self, Error> {
+        let mut dwns = BTreeMap::new();
+        for (id, doc) in servers {
+            let url = resolver.get_endpoints(&[doc.did()]).await?.remove(0).1;
+            dwns.insert(url, Dwn::scratch::<MemoryStore>(id, Some(dyn_clone::clone_box(resolver))).await?);
+        }
+        Ok(Self::from_dwns(dwns))
+    }
+
+    //For Dwns set up by hand, with features or limits of their own
+    pub fn from_dwns(dwns: BTreeMap<Url, Dwn>) -> Self {
+        LocalDwns{dwns: Arc::new(dwns), requests: Default::default(), responses: Default::default(), faults: Default::default()}
+    }
+
+    pub fn url(&self, dwn: &Did) -> Option<Url> {
+        self.dwns.iter().find(|(_, d)| d.com_key.public.did == *dwn).map(|(url, _)| url.clone())
+    }
+
+    //Every request in the order it arrived, with the url it was sent to
+    pub fn requests(&self) -> Vec<(Url, DwnRequest)> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    //Packets to the url fail with the message as if the Dwn was unreachable, until it recovers
+    pub fn fail(&self, url: &Url, message: &str) {
+        self.faults.lock().unwrap().insert(url.clone(), message.to_string());
+    }
+
+    pub fn recover(&self, url: &Url) {
+        self.faults.lock().unwrap().remove(url);
+    }
+
+    pub fn sent(&self, url: &Url) -> Vec<DwnRequest> {
+        self.requests.lock().unwrap().iter().filter(|(u, _)| u == url).map(|(_, r)| r.clone()).collect()
+    }
+
+    pub fn received(&self, url: &Url) -> Vec<DwnResponse> {
+        self.responses.lock().unwrap().iter().filter(|(u, _)| u == url).map(|(_, r)| r.clone()).collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for LocalDwns {
+    async fn send_request(&self, body: String, url: Url) -> Result<String, Error> {
+        if let Some(fault) = self.faults.lock().unwrap().get(&url) {return Err(Error::json_rpc(fault));}
+        let dwn = self.dwns.get(&url).ok_or(Error::json_rpc("Connection refused"))?;
+        let packet = serde_json::from_str::<Packet>(&body)?;
+        let requests = serde_json::from_slice::<Vec<(Uuid, DwnRequest)>>(&dwn.com_key.secret.decrypt(&packet.payload)?)?;
+        self.requests.lock().unwrap().extend(requests.into_iter().map(|(_, r)| (url.clone(), r)));
+        let responses = dwn.process_packet(packet).await?;
+        self.responses.lock().unwrap().extend(responses.iter().map(|(_, r)| (url.clone(), r.clone())));
+        Ok(serde_json::to_string(&responses)?)
+    }
+}
+
+//A user of a TestNet with an agent already connected to the user's Dwn
+pub struct TestUser {
+    pub did: Did,
+    pub agent: Agent,
+    pub cache: CompilerCache,
+}
+
+//Gives every user their own in process Dwn, the endpoints are only names so nothing binds a port
+#[derive(Debug, Clone)]
+pub struct TestNet {
+    users: usize,
+}
+
+impl Default for TestNet {
+    fn default() -> Self {TestNet{users: 2}}
+}
+
+impl TestNet {
+    pub fn new() -> Self {Self::default()}
+
+    pub fn users(mut self, users: usize) -> Self {
+        self.users = users;
+        self
+    }
+
+    pub async fn build(self) -> Result<(Vec<TestUser>, LocalDwns), Error> {
+        let mut resolver = MemoryDidResolver::new();
+        let mut servers = Vec::new();
+        let mut users = Vec::new();
+        for i in 0..self.users {
+            let (server, server_doc) = DwnIdentity::new(vec![format!("http://dwn{}.test", i)])?;
+            let (user, user_doc) = Identity::new(vec![server_doc.did().to_string()])?;
+            resolver.store(Box::new(server_doc.clone()));
+            resolver.store(Box::new(user_doc.clone()));
+            servers.push((server, server_doc));
+            users.push((user, user_doc.did()));
+        }
+        let resolver: Box<dyn DidResolver> = Box::new(resolver);
+        let dwns = LocalDwns::new(&*resolver, servers).await?;
+        let mut test_users = Vec::new();
+        for (user, did) in users {
+            let agent = Agent::with_client(Wallet::new(user).root(), resolver.clone(), Box::new(dwns.clone()), None).await?;
+            test_users.push(TestUser{did, agent, cache: CompilerCache::default()});
+        }
+        Ok((test_users, dwns))
+    }
+}
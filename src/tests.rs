@@ -8,91 +8,121 @@
 //      assert!(false);
 //      Ok(())
 //  }
-use crate::error::Error;
+use crate::prelude::{Error, ErrorJson};
 
 use simple_database::MemoryStore;
-use simple_crypto::Hashable;
+use simple_crypto::{Hashable, SecretKey};
 
-use crate::dids::{DidResolver, DidDocument};
-use crate::dids::Did;
-use crate::dids::DhtDocument;
+use crate::prelude::{Did, DidResolver, DidDocument, DhtDocument, Filters};
 
 use crate::dwn::json_rpc::{JsonRpcClient, JsonRpcServer};
-use crate::dwn::traits::Server;
+use crate::dwn::router::{Router, RouterConfig};
+use crate::backoff::{self, Backoff, CancelToken, Jitter};
+use crate::dwn::structs::{DwnRequest, DwnResponse};
+use crate::dwn::traits::Client;
 //use crate::dwn::structs::PublicRecord;
-use crate::dwn::{Dwn, DwnIdentity};
+use crate::prelude::Dwn;
+use crate::dwn::DwnIdentity;
 
-use crate::agent::{Wallet, Agent, Identity};
-use crate::agent::{RecordPath, Record};
-use crate::agent::{ChannelPermissionOptions, PermissionOptions};
-use crate::agent::{ChannelProtocol, Protocol};
-use crate::agent::CompilerCache;
+use crate::prelude::{Wallet, Agent, Identity};
+use crate::prelude::{RecordPath, Record, TypedRecord};
+use crate::prelude::{ChannelPermissionOptions, PermissionOptions};
+use crate::prelude::{ChannelProtocol, Protocol, CompilerCache};
+use crate::agent::{ProtocolLock, SystemProtocols, CacheStats};
+use crate::agent::{PayloadValidator, ValidationIssue, Validators};
+use crate::agent::{PayloadMerger, ConflictStrategy, ConflictStrategies};
+use crate::agent::{PayloadMigrator, OnInvalid, RecordState};
+use crate::agent::RedactionSpec;
+use crate::agent::CommandJournal;
+use crate::agent::{ShareGroup, RecordUpdated, SharedPermissions, SharesNeedingRefresh};
+use crate::agent::{KeyDomain, PathedKey, Placement, Subscribers, CapabilityGrant, CapabilityToken, ChildSlot};
+use crate::agent::{AgentTelemetry, TelemetryAggregator, Outcome};
+use crate::prelude::scripts;
 
 use crate::common::Schemas;
 
 
 //use crate::agent::scripts::*;
+#[allow(deprecated)]
 use crate::agent::commands;
 use crate::agent::PermissionSet;
+use crate::test_utils::{MemoryDidResolver, LocalDwns};
 
 use std::path::PathBuf;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 use uuid::Uuid;
 
 
-pub type Docs = BTreeMap<Did, Box<dyn DidDocument>>;
+fn get_user(servers: Vec<Did>) -> Result<(Identity, DhtDocument), Error> {
+    Identity::new(servers.iter().map(|d| d.to_string()).collect())
+}
 
-#[derive(Clone)]
-pub struct MemoryDidResolver {
-    pub docs: Docs
+//In process Dwns bind nothing, an endpoint only needs a name no other Dwn in the test has
+fn get_server(endpoints: usize) -> Result<(DwnIdentity, DhtDocument), Error> {
+    DwnIdentity::new((0..endpoints).map(|_| format!("http://{}.test", Uuid::new_v4())).collect())
 }
 
-impl MemoryDidResolver {
-    fn new() -> Self {MemoryDidResolver{docs: Docs::default()}}
-    pub fn store(&mut self, doc: Box<dyn DidDocument>) {
-        self.docs.insert(doc.did(), doc);
-    }
+//Users each with an in process Dwn of their own, urls[i] is the Dwn of users[i]
+struct LocalNet {
+    resolver: Box<dyn DidResolver>,
+    users: Vec<(Identity, Did)>,
+    urls: Vec<url::Url>,
+    dwns: LocalDwns
 }
 
-#[async_trait::async_trait]
-impl DidResolver for MemoryDidResolver {
-    async fn resolve(&self, did: &Did) -> Result<Option<Box<dyn DidDocument>>, Error> {
-        Ok(self.docs.get(did).cloned())
+impl LocalNet {
+    async fn new(users: usize) -> Result<Self, Error> {
+        Self::with_dwns(users, |dwn| dwn).await
     }
-}
 
-impl std::fmt::Debug for MemoryDidResolver {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("MemoryDidResolver")
-        .field("dids", &self.docs.keys().map(|did| did.to_string()).collect::<Vec<String>>())
-        .finish()
+    //Each Dwn goes through setup before it is served, for limits or features of its own
+    async fn with_dwns(users: usize, setup: impl Fn(Dwn) -> Dwn) -> Result<Self, Error> {
+        let mut resolver = MemoryDidResolver::new();
+        let mut servers = Vec::new();
+        let mut identities = Vec::new();
+        for _ in 0..users {
+            let (server, server_doc) = get_server(1)?;
+            let (user, user_doc) = get_user(vec![server_doc.did()])?;
+            resolver.store(Box::new(server_doc));
+            resolver.store(Box::new(user_doc.clone()));
+            servers.push(server);
+            identities.push((user, user_doc.did()));
+        }
+        let resolver: Box<dyn DidResolver> = Box::new(resolver);
+        let mut dwns = BTreeMap::new();
+        let mut urls = Vec::new();
+        for (server, (_, did)) in servers.into_iter().zip(&identities) {
+            let url = resolver.get_endpoints(std::slice::from_ref(did)).await?.remove(0).1;
+            dwns.insert(url.clone(), setup(Dwn::scratch::<MemoryStore>(server, Some(resolver.clone())).await?));
+            urls.push(url);
+        }
+        Ok(LocalNet{resolver, users: identities, urls, dwns: LocalDwns::from_dwns(dwns)})
     }
-}
 
-fn get_user(servers: Vec<Did>) -> Result<(Identity, DhtDocument), Error> {
-    Identity::new(servers.iter().map(|d| d.to_string()).collect())
-}
+    fn did(&self, user: usize) -> Did {self.users[user].1.clone()}
+
+    fn dwn(&self, user: usize) -> &Dwn {&self.dwns.dwns[&self.urls[user]]}
 
-fn get_server(ports: Vec<u32>) -> Result<(DwnIdentity, DhtDocument), Error> {
-    DwnIdentity::new(ports.iter().map(|p| format!("http://localhost:{}", p)).collect())
+    async fn agent(&self, user: usize) -> Result<Agent, Error> {
+        Agent::with_client(
+            Wallet::new(self.users[user].0.clone()).root(), self.resolver.clone(), Box::new(self.dwns.clone()), None
+        ).await
+    }
 }
 
 async fn run_test() -> Result<(), Error> {
     let mut did_resolver = MemoryDidResolver::new();
 
-    let ard_port = 3000;
-    let (ard_id, ard_doc) = get_server(vec![ard_port])?;
+    let (ard_id, ard_doc) = get_server(1)?;
     let ard_did = ard_doc.did();
     did_resolver.store(Box::new(ard_doc.clone()));
 
-    let brd_port = 3001;
-    let (brd_id, brd_doc) = get_server(vec![brd_port])?;
+    let (brd_id, brd_doc) = get_server(1)?;
     let brd_did = brd_doc.did();
     did_resolver.store(Box::new(brd_doc.clone()));
 
-    let crd_port = 3002;
-    let (crd_id, crd_doc) = get_server(vec![crd_port])?;
+    let (crd_id, crd_doc) = get_server(1)?;
     let crd_did = crd_doc.did();
     did_resolver.store(Box::new(crd_doc.clone()));
 
@@ -112,21 +142,10 @@ async fn run_test() -> Result<(), Error> {
     let did_resolver: Box<dyn DidResolver> = Box::new(did_resolver.clone());
 
 
-    //RemoteDwns
-    let ard = Dwn::new::<MemoryStore>(
-        ard_id, Some(PathBuf::from("servera")), Some(did_resolver.clone())
-    ).await?;
-    let ard = tokio::spawn(JsonRpcServer{}.start_server(ard, ard_port).await?);
-
-    let brd = Dwn::new::<MemoryStore>(
-        brd_id, Some(PathBuf::from("serverb")), Some(did_resolver.clone())
-    ).await?;
-    let brd = tokio::spawn(JsonRpcServer{}.start_server(brd, brd_port).await?);
-
-    let crd = Dwn::new::<MemoryStore>(
-        crd_id, Some(PathBuf::from("serverc")), Some(did_resolver.clone())
-    ).await?;
-    let crd = tokio::spawn(JsonRpcServer{}.start_server(crd, crd_port).await?);
+    let dwns = LocalDwns::new(&*did_resolver, vec![(ard_id, ard_doc), (brd_id, brd_doc), (crd_id, crd_doc)]).await?;
+    let ard = dwns.url(&ard_did).unwrap();
+    let brd = dwns.url(&brd_did).unwrap();
+    let crd = dwns.url(&crd_did).unwrap();
 
 
     let messages_protocol = Protocol::new(
@@ -151,28 +170,29 @@ async fn run_test() -> Result<(), Error> {
     )?;
     println!("room_protocol: {}", rooms_protocol.hash());
 
-    let protocols = vec![rooms_protocol.clone(), messages_protocol.clone()];
-    let client = Box::new(JsonRpcClient{});
-
     //Wallet
     let a_wallet = Wallet::new(a_id);
     let b_wallet = Wallet::new(b_id);
 
     //Agent
-    let alice_agent = Agent::new(
+    let alice_agent = Agent::with_client(
         a_wallet.root(),
         did_resolver.clone(),
+        Box::new(dwns.clone()),
+        None,
     ).await?;
 
-    let bob_agent = Agent::new(
+    let bob_agent = Agent::with_client(
         b_wallet.root(),
         did_resolver.clone(),
+        Box::new(dwns.clone()),
+        None,
     ).await?;
 
     let mut a_cache = CompilerCache::default();
-    let mut b_cache = CompilerCache::default();
+    let b_cache = CompilerCache::default();
 
-    let path = RecordPath::new(&[Uuid::new_v4()]);
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
 
   //alice_agent.process_commands(&mut a_cache, vec![
   //    Box::new(commands::Init::new(vec![RecordPath::root()]))
@@ -186,7 +206,7 @@ async fn run_test() -> Result<(), Error> {
     println!("INIT////////////////////////////////////");
     alice_agent.process_commands(&mut a_cache, vec![
         Box::new(commands::CreatePrivate::new(record.clone(), None)),
-        Box::new(commands::Send::new(commands::CreatePrivate::new(record, None), vec![a_did]))
+        Box::new(commands::Send::new(commands::CreatePrivate::new(record, None), vec![a_did.clone()]))
     ]).await?.remove(0).downcast::<()>()?;
 //  println!("two");
 //  let record = Record::new(path.extend(&[Uuid::new_v4()]), messages_protocol.clone(), b"\"2\"");
@@ -233,7 +253,7 @@ async fn run_test() -> Result<(), Error> {
   //    Some(vec![a_did.clone()])
   //).await?;
 
-  //let path = RecordPath::new(&[Uuid::new_v4()]);
+  //let path = RecordPath::new(&[Uuid::new_v4()])?;
   //compiler.add_command(DeletePrivate::new(path.clone()), None).await?;
   //let record = Record::new(path.clone(), rooms_protocol.uuid(), b"\"1\"");
   //compiler.add_command(CreatePrivate::new(record.clone(), None), None).await?;
@@ -304,9 +324,17 @@ async fn run_test() -> Result<(), Error> {
   //println!("R: {:#?}", res);
 
 
-    println!("ARD: {}", JsonRpcClient::client_debug("http://localhost:3000").await);
-    println!("BRD: {}", JsonRpcClient::client_debug("http://localhost:3001").await);
-    assert!(false);
+    //Only alices Dwn was written to, bobs only saw his agent start and carol has none
+    let writes = dwns.requests().into_iter().filter(|(_, r)| matches!(r, DwnRequest::CreatePrivate(_))).collect::<Vec<_>>();
+    assert!(!writes.is_empty() && writes.iter().all(|(url, _)| *url == ard));
+    assert!(!dwns.sent(&brd).is_empty() && dwns.sent(&crd).is_empty());
+
+    //An unreachable Dwn fails the commands sent to it until it recovers
+    dwns.fail(&ard, "Connection refused");
+    let record = Record::new(RecordPath::new(&[Uuid::new_v4()])?, rooms_protocol.clone(), b"\"3\"");
+    assert!(alice_agent.run::<()>(&mut a_cache, scripts::CreatePrivate::new(record.clone(), None)).await.is_err());
+    dwns.recover(&ard);
+    alice_agent.run::<()>(&mut a_cache, scripts::CreatePrivate::new(record, None)).await?;
     Ok(())
 }
 
@@ -319,3 +347,4611 @@ async fn group_messaging() {
         assert!(false);
     }
 }
+
+struct ItemsValidator {}
+impl PayloadValidator for ItemsValidator {
+    fn validate(&self, payload: &[u8]) -> Result<(), ValidationIssue> {
+        let value: serde_json::Value = serde_json::from_slice(payload)
+            .map_err(|_| ValidationIssue::new("Not Json"))?;
+        let count = value.get("count").and_then(|c| c.as_u64());
+        let items = value.get("items").and_then(|i| i.as_array()).map(|i| i.len() as u64);
+        if count.is_some() && count == items {Ok(())} else {
+            Err(ValidationIssue::new("Count does not match items"))
+        }
+    }
+}
+
+#[test]
+fn payload_validators() {
+    let protocol = Protocol::new(
+        "Items",
+        false,
+        PermissionOptions::new(true, true, false, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true)).unwrap()),
+        None
+    ).unwrap();
+    let mut validators = Validators::default();
+    validators.register(&protocol, ItemsValidator{}, false).unwrap();
+    assert!(validators.register(&protocol, ItemsValidator{}, true).is_err());
+
+    let valid = br#"{"count": 2, "items": [1, 2]}"#;
+    let invalid = br#"{"count": 3, "items": [1, 2]}"#;
+    assert!(validators.validate(&protocol, valid, false).is_ok());
+    assert!(validators.validate(&protocol, invalid, false).is_err());
+    //validate_on_read was not set
+    assert!(validators.validate(&protocol, invalid, true).is_ok());
+}
+
+#[tokio::test]
+async fn payload_validators_pipeline() -> Result<(), Error> {
+    use crate::dwn::structs::PublicRecord;
+    use simple_database::database::Filter;
+
+    let net = LocalNet::new(1).await?;
+    let protocol = Protocol::new(
+        "Items", false, PermissionOptions::new(true, true, false, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let valid = br#"{"count": 1, "items": [1]}"#;
+    let invalid = br#"{"count": 3, "items": [1]}"#;
+    //Both agents are the same user, only the reader knows the invariant
+    let writer = net.agent(0).await?;
+    let mut reader = net.agent(0).await?;
+    reader.register_validator(&protocol, ItemsValidator{}, true)?;
+    let (mut w_cache, mut r_cache) = (CompilerCache::default(), CompilerCache::default());
+
+    let record = |payload: &[u8]| Record::new(RecordPath::new(&[Uuid::new_v4()]).unwrap(), protocol.clone(), payload);
+    let error = reader.run::<()>(&mut r_cache, scripts::CreatePrivate::new(record(invalid), None)).await.unwrap_err();
+    assert_eq!(error.code(), "VALIDATION");
+    reader.run::<()>(&mut r_cache, scripts::CreatePrivate::new(record(valid), None)).await?;
+
+    //Written past the validator, an invalid private record reads as missing unless read strictly
+    let stored = record(invalid);
+    writer.run::<()>(&mut w_cache, scripts::CreatePrivate::new(stored.clone(), None)).await?;
+    assert!(reader.run::<Option<Record>>(&mut r_cache, scripts::ReadPrivate::new(stored.path.clone())).await?.is_none());
+    let strict = commands::ReadPrivate::strict(stored.path, protocol.uuid());
+    let error = reader.process_commands(&mut r_cache, vec![Box::new(strict)]).await.unwrap_err();
+    assert_eq!(error.code(), "VALIDATION");
+
+    //and invalid public records are dropped from reads
+    writer.run_all::<()>(&mut w_cache, vec![
+        scripts::CreatePublic::new(PublicRecord::new(None, protocol.clone(), valid, None)?, None),
+        scripts::CreatePublic::new(PublicRecord::new(None, protocol.clone(), invalid, None)?, None)
+    ]).await?;
+    let filters = Filters::new(vec![("protocol", Filter::equal(protocol.uuid().to_string()))]);
+    let (records, _) = reader.run::<(Vec<PublicRecord>, Option<Vec<u8>>)>(
+        &mut r_cache, scripts::ReadPublic::new(filters.clone(), None)
+    ).await?;
+    assert!(matches!(&records[..], [record] if record.payload == valid.to_vec()));
+    let (records, _) = writer.run::<(Vec<PublicRecord>, Option<Vec<u8>>)>(
+        &mut w_cache, scripts::ReadPublic::new(filters, None)
+    ).await?;
+    assert_eq!(records.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn reserved_path_segments() {
+    use std::str::FromStr;
+    let id = Uuid::new_v4();
+    assert!(RecordPath::from_str(&format!("/{}", id)).is_ok());
+    assert!(RecordPath::from_str(&format!("/{}/{}", id, Uuid::max())).is_err());
+    assert!(RecordPath::new(&[id, Uuid::max()]).is_err());
+
+    //Internal index paths still round trip through storage
+    let index = RecordPath::new(&[id]).unwrap().index();
+    let json = serde_json::to_string(&index).unwrap();
+    assert_eq!(serde_json::from_str::<RecordPath>(&json).unwrap(), index);
+}
+
+struct UnionMerger {}
+impl PayloadMerger for UnionMerger {
+    fn merge(&self, base: &[u8], ours: &[u8], theirs: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut merged = serde_json::from_slice::<Vec<u64>>(base)?;
+        for item in [ours, theirs].into_iter().map(serde_json::from_slice::<Vec<u64>>) {
+            for i in item? {
+                if !merged.contains(&i) {merged.push(i);}
+            }
+        }
+        Ok(serde_json::to_vec(&merged)?)
+    }
+}
+
+#[test]
+fn conflict_strategies() {
+    let protocol = Protocol::new(
+        "List",
+        false,
+        PermissionOptions::new(true, true, false, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true)).unwrap()),
+        None
+    ).unwrap();
+    let union = Uuid::new_v4();
+    let mut conflicts = ConflictStrategies::default();
+    assert_eq!(conflicts.get(&protocol), ConflictStrategy::LastWriterWins);
+    assert!(conflicts.set(&protocol, ConflictStrategy::Merge(union)).is_err());
+    conflicts.register_merger(union, UnionMerger{}).unwrap();
+    conflicts.set(&protocol, ConflictStrategy::Merge(union)).unwrap();
+
+    //Two appenders started from the same base, the second one merges onto the first
+    let base = b"[1]";
+    let first = b"[1,2]";
+    let second = b"[1,3]";
+    let merged = conflicts.merge(&union, base, second, first).unwrap();
+    assert_eq!(serde_json::from_slice::<Vec<u64>>(&merged).unwrap(), vec![1, 3, 2]);
+}
+
+#[tokio::test]
+async fn conflict_strategies_pipeline() -> Result<(), Error> {
+    let net = LocalNet::new(1).await?;
+    let list = |name: &str| Protocol::new(
+        name, true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true)).unwrap()), None
+    );
+    let (merged, overwritten) = (list("Merged")?, list("Overwritten")?);
+    let union = Uuid::new_v4();
+    let mut agent = net.agent(0).await?;
+    agent.register_merger(union, UnionMerger{})?;
+    agent.set_conflict_strategy(&merged, ConflictStrategy::Merge(union))?;
+    let mut cache = CompilerCache::default();
+
+    //Both appends read the same base and race their updates in one batch
+    let mut finals = Vec::new();
+    for protocol in [merged, overwritten] {
+        let path = RecordPath::new(&[Uuid::new_v4()])?;
+        agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(path.clone(), protocol.clone(), b"[1]"), None)).await?;
+        agent.run_all::<()>(&mut cache, vec![
+            scripts::UpdatePrivate::new(Record::new(path.clone(), protocol.clone(), b"[1,2]"), None),
+            scripts::UpdatePrivate::new(Record::new(path.clone(), protocol, b"[1,3]"), None)
+        ]).await?;
+        let record = agent.run::<Option<Record>>(&mut cache, scripts::ReadPrivate::new(path)).await?.unwrap();
+        let mut payload = serde_json::from_slice::<Vec<u64>>(&record.payload)?;
+        payload.sort();
+        finals.push(payload);
+    }
+    assert_eq!(finals[0], vec![1, 2, 3]);
+    //Without a strategy one append is lost
+    assert_eq!(finals[1].len(), 2);
+    Ok(())
+}
+
+#[test]
+fn redaction_spec() {
+    let card = br#"{"name": "Alice", "phone": "555", "tags": ["a", "b"], "a/b": 1}"#;
+    let spec = RedactionSpec::new(vec!["/phone", "/tags/0", "/a~1b", "/missing"], None);
+    let redacted = serde_json::from_slice::<serde_json::Value>(&spec.apply(card).unwrap()).unwrap();
+    assert_eq!(redacted, serde_json::json!({"name": "Alice", "tags": ["b"]}));
+    assert!(RedactionSpec::new(vec!["phone"], None).apply(card).is_err());
+    //Indices name the original array whatever order they are listed in
+    let list = br#"{"tags": ["a", "b", "c", "d"], "nested": [{"x": 1, "y": 2}, {"x": 3}]}"#;
+    let spec = RedactionSpec::new(vec!["/tags/0", "/tags/1", "/nested/0", "/nested/1/x", "/tags/3"], None);
+    let redacted = serde_json::from_slice::<serde_json::Value>(&spec.apply(list).unwrap()).unwrap();
+    assert_eq!(redacted, serde_json::json!({"tags": ["c"], "nested": [{}]}));
+}
+
+#[tokio::test]
+async fn redacted_share() -> Result<(), Error> {
+    use crate::agent::structs::RedactedView;
+
+    let net = LocalNet::new(2).await?;
+    let (alice, bob) = (net.agent(0).await?, net.agent(1).await?);
+    let (mut a_cache, mut b_cache) = (CompilerCache::default(), CompilerCache::default());
+    let cards = Protocol::new(
+        "Card", true, PermissionOptions::new(false, true, false, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let spec = RedactionSpec::new(vec!["/phone"], None);
+    let view = RedactedView::new(path.clone(), spec.clone())?.view;
+    alice.run::<()>(&mut a_cache, scripts::CreatePrivate::new(
+        Record::new(path.clone(), cards.clone(), br#"{"name": "Alice", "phone": "555"}"#), None
+    )).await?;
+    alice.run::<()>(&mut a_cache, scripts::Share::redacted(path.clone(), None, net.did(1), spec)).await?;
+
+    bob.run::<Vec<(crate::dids::signing::Verifier, RecordUpdated)>>(&mut b_cache, scripts::ScanDM::new()).await?;
+    let (shares, _) = bob.run::<(Vec<SharedPermissions>, SharesNeedingRefresh)>(
+        &mut b_cache, scripts::ProcessShares::new(net.did(0))
+    ).await?;
+    assert_eq!(shares.len(), 1);
+    let read = || scripts::ReadPrivate::shared_from(shares[0].clone(), net.did(0));
+
+    //Only the view is shared, without the stripped field
+    let record = bob.run::<Option<Record>>(&mut b_cache, read()).await?.unwrap();
+    assert_eq!(record.path, view);
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&record.payload)?, serde_json::json!({"name": "Alice"}));
+
+    //An update to the original reaches the view once it is refreshed
+    alice.run::<()>(&mut a_cache, scripts::UpdatePrivate::new(
+        Record::new(path.clone(), cards, br#"{"name": "Alicia", "phone": "556"}"#), None
+    )).await?;
+    alice.run::<()>(&mut a_cache, scripts::RefreshRedactedViews::new(Some(path))).await?;
+    let record = bob.run::<Option<Record>>(&mut CompilerCache::default(), read()).await?.unwrap();
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&record.payload)?, serde_json::json!({"name": "Alicia"}));
+    Ok(())
+}
+
+#[tokio::test]
+async fn command_journal() -> Result<(), Error> {
+    let journal = CommandJournal::scratch::<MemoryStore>().await?;
+    let protocol = Protocol::new(
+        "Note",
+        false,
+        PermissionOptions::new(true, true, false, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true)).unwrap()),
+        None
+    )?;
+    let record = Record::new(RecordPath::new(&[Uuid::new_v4()])?, protocol, b"1");
+
+    journal.dispatch("completed", &[scripts::CreatePrivate::new(record.clone(), None)]).await?;
+    journal.complete("completed").await?;
+    //Dispatched but never completed, as if the process died mid flight
+    journal.dispatch("in_doubt", &[
+        scripts::CreatePrivate::new(record.clone(), None),
+        scripts::DeletePrivate::new(record.path.clone())
+    ]).await?;
+    assert!(journal.dispatch("in_doubt", &[]).await.is_err());
+
+    let in_doubt = journal.in_doubt().await?;
+    assert_eq!(in_doubt.len(), 1);
+    assert_eq!(in_doubt[0].token, "in_doubt");
+    assert_eq!(in_doubt[0].commands.len(), 2);
+    //DeletePrivate cannot be checked so the batch needs the application to reconcile
+    assert!(in_doubt[0].records.is_none());
+
+    journal.acknowledge("in_doubt").await?;
+    assert!(journal.in_doubt().await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn journal_recover() -> Result<(), Error> {
+    let net = LocalNet::new(1).await?;
+    let mut agent = net.agent(0).await?;
+    agent.set_journal(CommandJournal::scratch::<MemoryStore>().await?);
+    let mut cache = CompilerCache::default();
+    let protocol = Protocol::new(
+        "Note", false, PermissionOptions::new(true, true, false, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let record = |payload: &[u8]| Record::new(RecordPath::new(&[Uuid::new_v4()]).unwrap(), protocol.clone(), payload);
+    let create = |record: &Record| scripts::CreatePrivate::new(record.clone(), None);
+
+    let written = record(b"1");
+    agent.process_commands_journaled(&mut cache, "written", vec![create(&written)]).await?;
+    assert!(agent.recover().await?.is_empty());
+
+    //The Dwn goes down between dispatch and completion, the failed batch stays in doubt
+    let (first, second) = (record(b"2"), record(b"3"));
+    net.dwns.fail(&net.urls[0], "down");
+    assert!(agent.process_commands_journaled(&mut cache, "cut", vec![create(&first), create(&second)]).await.is_err());
+    net.dwns.recover(&net.urls[0]);
+    //Only part of it reached the Dwn
+    agent.run::<()>(&mut cache, create(&first)).await?;
+    let in_doubt = agent.recover().await?;
+    assert_eq!(in_doubt.len(), 1);
+    assert_eq!(in_doubt[0].token, "cut");
+    assert_eq!(in_doubt[0].records, Some(vec![first.clone(), second.clone()]));
+
+    //Once every record is on the Dwn unchanged the batch resolves itself
+    agent.run::<()>(&mut cache, create(&second)).await?;
+    assert!(agent.recover().await?.is_empty());
+    assert!(agent.recover().await?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn share_group_record() -> Result<(), Error> {
+    let mut group = ShareGroup::new("team", Vec::new());
+    group.paths.push((RecordPath::new(&[Uuid::new_v4()])?, None));
+    let record = group.clone().into_record()?;
+    assert_eq!(record.path, ShareGroup::path("team"));
+    assert_ne!(ShareGroup::path("team"), ShareGroup::path("other"));
+    assert_eq!(serde_json::from_slice::<ShareGroup>(&record.payload)?, group);
+    record.protocol.validate_payload(&record.payload)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn share_group_membership() -> Result<(), Error> {
+    use crate::agent::ShareFailures;
+
+    let net = LocalNet::new(5).await?;
+    let alice = net.agent(0).await?;
+    let mut a_cache = CompilerCache::default();
+    let mut agents = Vec::new();
+    for user in 1..5 {agents.push(net.agent(user).await?);}
+
+    let protocol = Protocol::new("Shared", true, PermissionOptions::new(false, true, false, None), None, None)?;
+    let paths = [RecordPath::new(&[Uuid::new_v4()])?, RecordPath::new(&[Uuid::new_v4()])?];
+    alice.run::<()>(&mut a_cache, scripts::CreateShareGroup::new("team", (1..4).map(|u| net.did(u)).collect())).await?;
+    for path in &paths {
+        alice.run::<()>(&mut a_cache, scripts::CreatePrivate::new(Record::new(path.clone(), protocol.clone(), &[]), None)).await?;
+        let failures = alice.run::<ShareFailures>(&mut a_cache, scripts::ShareWithGroup::new(path.clone(), None, "team")).await?;
+        assert!(failures.is_empty());
+    }
+
+    //Joining the group is the only action taken for the new member
+    let failures = alice.run::<ShareFailures>(&mut a_cache, scripts::AddGroupMember::new("team", net.did(4))).await?;
+    assert!(failures.is_empty());
+
+    let newcomer = &agents[3];
+    let mut n_cache = CompilerCache::default();
+    newcomer.run::<Vec<(crate::dids::signing::Verifier, RecordUpdated)>>(&mut n_cache, scripts::ScanDM::new()).await?;
+    let (shares, _) = newcomer.run::<(Vec<SharedPermissions>, SharesNeedingRefresh)>(
+        &mut n_cache, scripts::ProcessShares::new(net.did(0))
+    ).await?;
+    let mut read = Vec::new();
+    for share in shares {
+        let record = newcomer.run::<Option<Record>>(&mut n_cache, scripts::ReadPrivate::shared_from(share, net.did(0))).await?;
+        read.extend(record.map(|r| r.path));
+    }
+    read.sort();
+    let mut expected = paths.to_vec();
+    expected.sort();
+    assert_eq!(read, expected);
+    Ok(())
+}
+
+#[test]
+fn schema_pointer() -> Result<(), Error> {
+    let schema = serde_json::json!({
+        "$defs": {"message": {"type": "object", "properties": {"author": {"type": "string"}}}},
+        "type": "object",
+        "properties": {"messages": {"type": "array", "items": {"$ref": "#/$defs/message"}}}
+    });
+    let chat = Protocol::new(
+        "chat", false, PermissionOptions::new(true, true, false, None),
+        Some(serde_json::to_string(&schema)?), None
+    )?;
+    chat.validate_payload(br#"{"messages": [{"author": "alice"}]}"#)?;
+    match chat.validate_payload(br#"{"messages": [{"author": 5}]}"#) {
+        Err(Error::SchemaValidation{pointer, expected, got, ..}) => {
+            assert_eq!(pointer, "/messages/0/author");
+            assert_eq!((expected.as_str(), got.as_str()), ("string", "number"));
+        },
+        other => panic!("Expected a schema error, got {:?}", other)
+    }
+    SystemProtocols::usize().validate_payload(b"5")?;
+    assert_eq!(SystemProtocols::usize().validate_payload(b"\"five\"").unwrap_err().code(), "VALIDATION");
+    Ok(())
+}
+
+#[test]
+fn record_updated() -> Result<(), Error> {
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let first = RecordUpdated::new(&path, b"1");
+    let second = RecordUpdated::new(&path, b"2");
+    assert_eq!(first.path, second.path);
+    assert_eq!(first.path, RecordUpdated::path_hash(&path));
+    assert_ne!(first.payload, second.payload);
+    assert_eq!(RecordUpdated::new(&path, b"1").payload, first.payload);
+    Ok(())
+}
+
+#[tokio::test]
+async fn record_updated_notification() -> Result<(), Error> {
+    use crate::dids::signing::Verifier;
+
+    let net = LocalNet::new(2).await?;
+    let (alice, bob) = (net.agent(0).await?, net.agent(1).await?);
+    let (mut a_cache, mut b_cache) = (CompilerCache::default(), CompilerCache::default());
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let record = |payload: &[u8]| Record::new(path.clone(), SystemProtocols::usize(), payload);
+    alice.run::<()>(&mut a_cache, scripts::CreatePrivate::new(record(b"1"), None)).await?;
+    alice.run::<()>(&mut a_cache, scripts::Share::notify(path.clone(), None, net.did(1))).await?;
+
+    //Bob refreshes his copy only when a notification names a payload other than the one he holds
+    let mut cached = None::<Record>;
+    let mut refreshes = 0;
+    for payload in [b"2", b"3", b"3"] {
+        alice.run::<()>(&mut a_cache, scripts::UpdatePrivate::new(record(payload), None)).await?;
+        let updates = bob.run::<Vec<(Verifier, RecordUpdated)>>(&mut b_cache, scripts::ScanDM::new()).await?;
+        assert_eq!(updates.len(), 1);
+        for (sender, update) in updates {
+            assert_eq!((sender, &update.path), (Verifier::Left(net.did(0)), &RecordUpdated::path_hash(&path)));
+            assert_eq!(update.payload, RecordUpdated::new(&path, payload).payload);
+            if cached.as_ref().map(|c| RecordUpdated::new(&path, &c.payload).payload) == Some(update.payload) {continue;}
+            let (mut shares, _) = bob.run::<(Vec<SharedPermissions>, SharesNeedingRefresh)>(&mut b_cache, scripts::ProcessShares::new(net.did(0))).await?;
+            cached = bob.run::<Option<Record>>(&mut b_cache, scripts::ReadPrivate::shared_from(shares.remove(0), net.did(0))).await?;
+            refreshes += 1;
+        }
+        assert_eq!(cached.as_ref().map(|c| c.payload.as_slice()), Some(&payload[..]));
+    }
+    assert_eq!(refreshes, 2);
+
+    //Two updates between scans are two notifications, a scanned notification is not surfaced again
+    alice.run::<()>(&mut a_cache, scripts::UpdatePrivate::new(record(b"4"), None)).await?;
+    alice.run::<()>(&mut a_cache, scripts::UpdatePrivate::new(record(b"5"), None)).await?;
+    let updates = bob.run::<Vec<(Verifier, RecordUpdated)>>(&mut b_cache, scripts::ScanDM::new()).await?;
+    let payloads = updates.into_iter().map(|(_, u)| u.payload).collect::<Vec<_>>();
+    assert_eq!(payloads, vec![RecordUpdated::new(&path, b"4").payload, RecordUpdated::new(&path, b"5").payload]);
+    assert!(bob.run::<Vec<(Verifier, RecordUpdated)>>(&mut b_cache, scripts::ScanDM::new()).await?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn key_domains() -> Result<(), Error> {
+    let enc_key = PathedKey::new_root(SecretKey::new());
+    let com_key = PathedKey::new_root(SecretKey::new());
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let discover = com_key.get_perms(&path, None)?.discover().public_key();
+    assert_eq!(KeyDomain::of(&path, &discover, &enc_key, &com_key)?, Some(KeyDomain::Com));
+    assert_ne!(KeyDomain::of(&path, &discover, &enc_key, &com_key)?, Some(KeyDomain::Enc));
+    let foreign = PathedKey::new_root(SecretKey::new()).get_perms(&path, None)?.discover().public_key();
+    assert_eq!(KeyDomain::of(&path, &foreign, &enc_key, &com_key)?, None);
+    Ok(())
+}
+
+//A write from one tree sent under a header of the other is refused before it reaches a Dwn
+#[cfg(feature = "unstable-internals")]
+#[tokio::test]
+async fn cross_domain_write() -> Result<(), Error> {
+    use crate::agent::custom_commands::{Command, Header, CompilerMemory};
+    use crate::agent::structs::{MutableAgentRequest, Task, Tasks};
+
+    #[derive(serde::Serialize, Debug, Clone)]
+    struct WriteFrom(bool, RecordPath);
+    #[async_trait::async_trait]
+    impl Command for WriteFrom {
+        async fn process<'a>(
+            self: Box<Self>, uuid: Uuid, header: Header,
+            memory: &mut CompilerMemory<'a>, _: &mut CompilerCache
+        ) -> Result<Tasks, Error> {
+            let protocol = SystemProtocols::usize();
+            let perms = memory.get_perms(self.0, &self.1, Some(&protocol))?;
+            let request = MutableAgentRequest::create_private(perms, None, protocol, b"1".to_vec(), None)?;
+            Ok(vec![(uuid, Task::MutableRequest(header.clone(), request, header.order))])
+        }
+    }
+
+    let net = LocalNet::new(1).await?;
+    let agent = net.agent(0).await?;
+    let mut cache = CompilerCache::default();
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let error = agent.run::<()>(&mut cache, Box::new(WriteFrom(false, path.clone()))).await.unwrap_err();
+    assert_eq!(error.code(), "WRONG_DOMAIN");
+    assert!(!net.dwns.requests().iter().any(|(_, r)| matches!(r, DwnRequest::CreatePrivate(_))));
+
+    //From its own tree the same write goes through
+    agent.run::<DwnResponse>(&mut cache, Box::new(WriteFrom(true, path.clone()))).await?;
+    assert!(agent.run::<Option<Record>>(&mut cache, scripts::ReadPrivate::new(path)).await?.is_some());
+    Ok(())
+}
+
+#[test]
+fn shared_protocol() -> Result<(), Error> {
+    let intended = Protocol::new(
+        "ReadOnly", false, PermissionOptions::new(true, false, false, None), None, None
+    )?;
+    let broader = Protocol::new(
+        "ReadWrite", true, PermissionOptions::new(true, true, true, None), None, None
+    )?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let perms = PathedKey::new_root(SecretKey::new()).get_perms(&path, Some(&intended))?;
+    let shared = SharedPermissions::new(intended.uuid(), perms);
+    shared.verify(&intended, &Default::default())?;
+    assert!(shared.verify(&broader, &Default::default()).is_err());
+    Ok(())
+}
+
+#[test]
+fn telemetry_aggregator() {
+    use std::time::Duration;
+    let aggregator = TelemetryAggregator::default();
+    let telemetry: Box<dyn AgentTelemetry> = Box::new(aggregator.clone());
+    telemetry.record("CreatePrivate", Outcome::Success, Duration::from_millis(10), 1);
+    telemetry.record("CreatePrivate", Outcome::Failure, Duration::from_millis(30), 2);
+    telemetry.record("ReadPrivate", Outcome::Success, Duration::from_millis(20), 1);
+    let snapshot = aggregator.snapshot();
+    let create = snapshot.get("CreatePrivate").unwrap();
+    assert_eq!((create.successes, create.failures, create.endpoints), (1, 1, 3));
+    assert_eq!(create.percentile(0.5), Some(Duration::from_millis(10)));
+    assert_eq!(create.percentile(1.0), Some(Duration::from_millis(30)));
+    assert_eq!(snapshot.get("ReadPrivate").unwrap().successes, 1);
+    assert_eq!((*scripts::ReadPrivate::new(RecordPath::root())).get_op(), "ReadPrivate");
+
+    //Latencies are bucketed, the slowest past the last bucket is still reported
+    for ms in 0..10_000 {telemetry.record("ReadPublic", Outcome::Success, Duration::from_millis(ms % 100), 1);}
+    telemetry.record("ReadPublic", Outcome::Success, Duration::from_secs(60), 1);
+    let read = aggregator.snapshot().remove("ReadPublic").unwrap();
+    assert_eq!(read.latencies.iter().sum::<usize>(), 10_001);
+    assert_eq!(read.percentile(0.5), Some(Duration::from_millis(50)));
+    assert_eq!(read.percentile(1.0), Some(Duration::from_secs(60)));
+}
+
+//The compiler reports each submitted command once with its outcome, whatever it ran underneath
+#[tokio::test]
+async fn telemetry_hook() -> Result<(), Error> {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(&'static str, Outcome)>>);
+    impl AgentTelemetry for Recorder {
+        fn record(&self, op: &'static str, outcome: Outcome, _: Duration, _: usize) {
+            self.0.lock().unwrap().push((op, outcome));
+        }
+    }
+
+    let net = LocalNet::new(1).await?;
+    let mut agent = net.agent(0).await?;
+    let recorder = Arc::new(Recorder::default());
+    agent.set_telemetry(recorder.clone());
+    let mut cache = CompilerCache::default();
+    let record = |payload: &[u8]| Ok::<_, Error>(Record::new(RecordPath::new(&[Uuid::new_v4()])?, SystemProtocols::usize(), payload));
+    let results = agent.process_commands_keyed(&mut cache, vec![
+        (0, scripts::CreatePrivate::new(record(b"1")?, None)),
+        (1, scripts::CreatePrivate::new(record(b"\"one\"")?, None)),
+        (2, scripts::ReadPrivate::new(RecordPath::new(&[Uuid::new_v4()])?)),
+        (3, scripts::CreatePrivate::new(record(b"[]")?, None))
+    ]).await?;
+    let failed = results.values().map(|r| r.is_err()).collect::<Vec<_>>();
+    assert_eq!(failed, vec![false, true, false, true]);
+
+    let mut recorded = recorder.0.lock().unwrap().clone();
+    recorded.sort_by_key(|(op, outcome)| (*op, *outcome == Outcome::Success));
+    assert_eq!(recorded, vec![
+        ("CreatePrivate", Outcome::Failure), ("CreatePrivate", Outcome::Failure),
+        ("CreatePrivate", Outcome::Success), ("ReadPrivate", Outcome::Success)
+    ]);
+    Ok(())
+}
+
+//Refuses every request sent to the dead url and answers the rest with no responses
+#[derive(Debug, Clone)]
+struct DeadEndpointClient {
+    dead: url::Url,
+    dead_calls: std::sync::Arc<std::sync::atomic::AtomicUsize>
+}
+
+#[async_trait::async_trait]
+impl Client for DeadEndpointClient {
+    async fn send_request(&self, _: String, url: url::Url) -> Result<String, Error> {
+        if url == self.dead {
+            self.dead_calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            return Err(Error::json_rpc("Connection refused"));
+        }
+        Ok("[]".to_string())
+    }
+}
+
+#[tokio::test]
+async fn endpoint_failover() -> Result<(), Error> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let mut did_resolver = MemoryDidResolver::new();
+    let (_, doc) = get_server(2)?;
+    let did = doc.did();
+    did_resolver.store(Box::new(doc));
+    let did_resolver: Box<dyn DidResolver> = Box::new(did_resolver);
+    let endpoints = did_resolver.get_endpoints(std::slice::from_ref(&did)).await?;
+    let (dead, alive) = (endpoints[0].clone(), endpoints[1].clone());
+    let dead_calls = std::sync::Arc::new(AtomicUsize::new(0));
+    let client = DeadEndpointClient{dead: dead.1.clone(), dead_calls: dead_calls.clone()};
+    let router = Router::new(did_resolver, Box::new(client));
+
+    let batch = || BTreeMap::from([(dead.clone(), vec![(
+        Uuid::new_v4(), Box::new(DwnRequest::ReadPublic(Filters::new(vec![]).into(), None))
+    )])]);
+    assert!(router.send(batch()).await.get(&dead).unwrap().values().all(|r| r.is_ok()));
+    let health = router.health();
+    assert_eq!(health.get(&dead).unwrap().failures, 1);
+    assert!(health.get(&alive).unwrap().last_success.is_some());
+
+    router.send(batch()).await;
+    assert_eq!(dead_calls.load(Ordering::SeqCst), 1);
+
+    router.reset_health(Some(&did));
+    assert!(router.health().is_empty());
+    Ok(())
+}
+
+//Refuses the first few requests and answers the rest with no responses
+#[derive(Debug, Clone)]
+struct FlakyClient {
+    failures: usize,
+    calls: std::sync::Arc<std::sync::atomic::AtomicUsize>
+}
+
+#[async_trait::async_trait]
+impl Client for FlakyClient {
+    async fn send_request(&self, _: String, _: url::Url) -> Result<String, Error> {
+        if self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) < self.failures {
+            return Err(Error::json_rpc("Connection refused"));
+        }
+        Ok("[]".to_string())
+    }
+}
+
+#[tokio::test]
+async fn backoff_policy() -> Result<(), Error> {
+    let ms = std::time::Duration::from_millis;
+    let delays = Backoff::exponential(ms(10), ms(50)).take(5).collect::<Vec<_>>();
+    assert_eq!(delays, vec![ms(10), ms(20), ms(40), ms(50), ms(50)]);
+    assert_eq!(Backoff::exponential(ms(10), ms(50)).nth(200), Some(ms(50)));
+
+    for (delay, cap) in Backoff::exponential(ms(10), ms(50)).with_jitter(Jitter::Full).zip(delays.clone()).take(100) {
+        assert!(delay <= cap);
+    }
+    for (delay, cap) in Backoff::exponential(ms(10), ms(50)).with_jitter(Jitter::Equal).zip(delays).take(100) {
+        assert!(delay >= cap / 2 && delay <= cap);
+    }
+
+    //Only retryable errors are tried again, until the policy runs out
+    let calls = std::sync::atomic::AtomicUsize::new(0);
+    let attempt = || async {
+        calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Err::<(), _>(Error::unreachable("down"))
+    };
+    let error = backoff::retry(Backoff::exponential(ms(1), ms(1)).take(3), |_| true, None, attempt).await.unwrap_err();
+    assert_eq!((error.code(), calls.swap(0, std::sync::atomic::Ordering::SeqCst)), ("UNREACHABLE", 4));
+    backoff::retry(Backoff::exponential(ms(1), ms(1)).take(3), |e| e.code() != "UNREACHABLE", None, attempt).await.unwrap_err();
+    assert_eq!(calls.swap(0, std::sync::atomic::Ordering::SeqCst), 1);
+
+    //A cancel ends the wait without another attempt
+    let token = CancelToken::new();
+    let canceller = token.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(ms(20)).await;
+        canceller.cancel();
+    });
+    let start = std::time::Instant::now();
+    let error = backoff::retry(Backoff::exponential(ms(10_000), ms(10_000)), |_| true, Some(&token), attempt).await.unwrap_err();
+    assert_eq!(error.code(), "CANCELLED");
+    assert!(start.elapsed() < ms(5_000));
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn router_retry() -> Result<(), Error> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let mut did_resolver = MemoryDidResolver::new();
+    let (_, doc) = get_server(1)?;
+    let did = doc.did();
+    did_resolver.store(Box::new(doc));
+    let endpoint = did_resolver.get_endpoints(std::slice::from_ref(&did)).await?.remove(0);
+    let config = RouterConfig{base_delay: std::time::Duration::from_millis(1), ..RouterConfig::default()};
+    let router = |failures: usize| {
+        let calls = std::sync::Arc::new(AtomicUsize::new(0));
+        let client = FlakyClient{failures, calls: calls.clone()};
+        (Router::new(Box::new(did_resolver.clone()), Box::new(client)).with_config(config.clone()), calls)
+    };
+    let batch = || BTreeMap::from([(endpoint.clone(), vec![(
+        Uuid::new_v4(), Box::new(DwnRequest::ReadPublic(Filters::new(vec![]).into(), None))
+    )])]);
+
+    let (flaky, calls) = router(2);
+    assert!(flaky.send(batch()).await.remove(&endpoint).unwrap().values().all(|r| r.is_ok()));
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    let (down, calls) = router(5);
+    let error = down.send(batch()).await.remove(&endpoint).unwrap().into_values().next().unwrap().unwrap_err();
+    assert_eq!(error.code(), "UNREACHABLE");
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+    Ok(())
+}
+
+//Answers every packet with no responses except the one at the given call, which gets an unreadable answer
+#[derive(Debug, Clone)]
+struct PacketCounter {
+    malformed: usize,
+    calls: std::sync::Arc<std::sync::atomic::AtomicUsize>
+}
+
+#[async_trait::async_trait]
+impl Client for PacketCounter {
+    async fn send_request(&self, _: String, _: url::Url) -> Result<String, Error> {
+        match self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == self.malformed {
+            true => Ok("{".to_string()),
+            false => Ok("[]".to_string())
+        }
+    }
+}
+
+#[tokio::test]
+async fn router_batches() -> Result<(), Error> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let mut did_resolver = MemoryDidResolver::new();
+    let (_, doc) = get_server(1)?;
+    let did = doc.did();
+    did_resolver.store(Box::new(doc));
+    let endpoint = did_resolver.get_endpoints(std::slice::from_ref(&did)).await?.remove(0);
+    let calls = std::sync::Arc::new(AtomicUsize::new(0));
+    let client = PacketCounter{malformed: 1, calls: calls.clone()};
+    let router = Router::new(Box::new(did_resolver), Box::new(client));
+
+    let reads = (0..100).map(|_| (Uuid::new_v4(), Box::new(DwnRequest::ReadPublic(Filters::new(vec![]).into(), None))))
+        .collect::<Vec<_>>();
+    let second = reads[32..64].iter().map(|(uuid, _)| *uuid).collect::<Vec<_>>();
+    let responses = router.send(BTreeMap::from([(endpoint.clone(), reads)])).await.remove(&endpoint).unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 100usize.div_ceil(RouterConfig::default().max_batch));
+    //Only the packet with the unreadable answer failed
+    let failed = responses.iter().filter(|(_, r)| r.is_err()).map(|(uuid, _)| *uuid).collect::<Vec<_>>();
+    assert_eq!(failed.len(), 32);
+    assert!(failed.iter().all(|uuid| second.contains(uuid)));
+    Ok(())
+}
+
+#[test]
+fn protocol_default_payload() -> Result<(), Error> {
+    let schema = serde_json::to_string(&schemars::schema_for!(Vec<u64>)).unwrap();
+    let settings = |default: &[u8]| Protocol::new(
+        "Settings", false, PermissionOptions::new(true, true, false, None), Some(schema.clone()), None
+    )?.with_default_payload(default.to_vec());
+    let protocol = settings(b"[]")?;
+    assert!(settings(br#"{"theme": "dark"}"#).is_err());
+    let without = Protocol::new(
+        "Settings", false, PermissionOptions::new(true, true, false, None), Some(schema.clone()), None
+    )?;
+    assert_ne!(protocol.uuid(), without.uuid());
+    assert!(!serde_json::to_string(&without)?.contains("default_payload"));
+    let record = Record::from_defaults(RecordPath::new(&[Uuid::new_v4()])?, protocol);
+    assert_eq!(record.payload, b"[]");
+    Ok(())
+}
+
+#[tokio::test]
+async fn get_or_create() -> Result<(), Error> {
+    use crate::dwn::structs::DwnResponse;
+    let net = LocalNet::new(1).await?;
+    let protocol = Protocol::new(
+        "Items", false, PermissionOptions::new(true, true, false, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?.with_default_payload(br#"{"count": 0, "items": []}"#.to_vec())?;
+    let (first, second) = (net.agent(0).await?, net.agent(0).await?);
+    let (mut f_cache, mut s_cache) = (CompilerCache::default(), CompilerCache::default());
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    //Both agents have their keys before the race
+    first.run::<Option<Record>>(&mut f_cache, scripts::ReadPrivate::new(path.clone())).await?;
+    second.run::<Option<Record>>(&mut s_cache, scripts::ReadPrivate::new(path.clone())).await?;
+
+    let (sent, received) = (net.dwns.sent(&net.urls[0]).len(), net.dwns.received(&net.urls[0]).len());
+    let (a, b) = tokio::join!(
+        first.run::<Record>(&mut f_cache, scripts::GetOrCreate::new(path.clone(), protocol.clone())),
+        second.run::<Record>(&mut s_cache, scripts::GetOrCreate::new(path.clone(), protocol.clone()))
+    );
+    let (a, b) = (a?, b?);
+    assert_eq!(a, b);
+    assert_eq!(a.payload, protocol.default_payload.clone().unwrap());
+    //Each item was created once, every create of it sent after that met the stored one
+    let creates = net.dwns.sent(&net.urls[0])[sent..].iter().filter_map(|r| match r {
+        DwnRequest::CreatePrivate(item) => Some(item.inner().discover.clone()),
+        _ => None
+    }).collect::<Vec<_>>();
+    let conflicts = net.dwns.received(&net.urls[0])[received..].iter().filter(|r| matches!(r, DwnResponse::Conflict(..))).count();
+    assert_eq!(creates.len() - conflicts, creates.iter().collect::<BTreeSet<_>>().len());
+
+    //A record there that this agent can not read is an error, not a create that conflicts forever
+    let mut reader = net.agent(0).await?;
+    reader.register_validator(&protocol, ItemsValidator{}, true)?;
+    let invalid = Record::new(RecordPath::new(&[Uuid::new_v4()])?, protocol.clone(), br#"{"count": 3, "items": [1]}"#);
+    first.run::<()>(&mut f_cache, scripts::CreatePrivate::new(invalid.clone(), None)).await?;
+    let error = reader.run::<Record>(
+        &mut CompilerCache::default(), scripts::GetOrCreate::new(invalid.path, protocol.clone())
+    ).await.unwrap_err();
+    assert_eq!(error.code(), Error::insufficent_permission().code());
+    Ok(())
+}
+
+#[test]
+fn placement_record() -> Result<(), Error> {
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let (_, doc) = get_server(1)?;
+    let record = Placement::new(doc.did()).into_record(&path)?;
+    assert_eq!(record.path, Placement::path(&path));
+    assert_ne!(Placement::path(&path), Subscribers::path(&path));
+    record.protocol.validate_payload(&record.payload)?;
+    assert_eq!(serde_json::from_slice::<Placement>(&record.payload)?.did, doc.did());
+    Ok(())
+}
+
+#[test]
+fn protocol_lock() -> Result<(), Error> {
+    //Golden lockfile, regenerate only when a system protocol is changed on purpose
+    ProtocolLock::verify(&SystemProtocols::all(), &ProtocolLock::system()?)?;
+
+    let protocol = Protocol::new(
+        "Note", false, PermissionOptions::new(true, true, false, None), None, None
+    )?;
+    let lock = ProtocolLock::generate(std::slice::from_ref(&protocol));
+    ProtocolLock::verify(std::slice::from_ref(&protocol), &lock)?;
+    ProtocolLock::new(vec![protocol.clone()], lock.clone()).check()?;
+
+    //A refactor reordering fields changes the serialized form and with it the hash
+    let mut reordered = lock.clone();
+    reordered.get_mut("Note").unwrap().hash = protocol.canonical().as_bytes().hash().to_string();
+    let drift = ProtocolLock::verify(std::slice::from_ref(&protocol), &reordered).unwrap_err().to_string();
+    assert!(drift.contains("Note changed"));
+    assert!(ProtocolLock::new(vec![protocol.clone()], reordered.clone()).check().is_err());
+    ProtocolLock::new(vec![protocol], reordered).allow_drift().check()?;
+    Ok(())
+}
+
+static HISTORY_NOW: std::sync::atomic::AtomicI64 = std::sync::atomic::AtomicI64::new(0);
+fn history_clock() -> chrono::DateTime<chrono::Utc> {
+    chrono::DateTime::from_timestamp(HISTORY_NOW.load(std::sync::atomic::Ordering::SeqCst), 0).unwrap()
+}
+
+#[tokio::test]
+async fn public_history() -> Result<(), Error> {
+    use crate::dids::signing::{Signer, SignedObject};
+    use crate::SortPaging;
+    use simple_database::database::SortOptions;
+    use crate::dwn::structs::{DwnResponse, PublicRecord};
+    use std::sync::atomic::Ordering;
+    let at = |s: i64| chrono::DateTime::from_timestamp(s, 0).unwrap();
+
+    let (id, _) = get_server(1)?;
+    let resolver: Box<dyn DidResolver> = Box::new(MemoryDidResolver::new());
+    let mut dwn = Dwn::scratch::<MemoryStore>(id, Some(resolver)).await?
+        .with_clock(history_clock);
+    let protocol = Protocol::new(
+        "Profile", false, PermissionOptions::new(true, true, false, None), None, None
+    )?;
+    dwn.keep_history(&protocol, 3);
+
+    let key = SecretKey::new();
+    let uuid = Uuid::new_v4();
+    let write = |time: i64, payload: &str| {
+        HISTORY_NOW.store(time, Ordering::SeqCst);
+        let item = PublicRecord::new(Some(uuid), protocol.clone(), payload.as_bytes(), None)?
+            .into_item(Signer::Right(key.clone()))?;
+        Ok::<_, Error>(if time == 100 {DwnRequest::CreatePublic(item)} else {DwnRequest::UpdatePublic(item)})
+    };
+    for (time, payload) in [(100, "a"), (200, "b"), (300, "c")] {
+        dwn.process_request(write(time, payload)?).await?.into_empty()?;
+    }
+    let read = |time: i64| {
+        let dwn = dwn.clone();
+        async move {
+            match dwn.process_request(DwnRequest::ReadPublicAt(Filters::new(vec![]).into(), at(time), None)).await? {
+                DwnResponse::ReadPublic(items, _) => Ok::<_, Error>(
+                    items.into_iter().map(|i| i.0.unwrap().payload).collect::<Vec<_>>()
+                ),
+                other => Err(Error::bad_response(&format!("{:?}", other)))
+            }
+        }
+    };
+    assert!(read(99).await?.is_empty());
+    assert_eq!(read(100).await?, vec![b"a".to_vec()]);
+    assert_eq!(read(199).await?, vec![b"a".to_vec()]);
+    assert_eq!(read(200).await?, vec![b"b".to_vec()]);
+    assert_eq!(read(300).await?, vec![b"c".to_vec()]);
+
+    //Retention of three drops the first version
+    dwn.process_request(write(400, "d")?).await?.into_empty()?;
+    assert!(read(100).await?.is_empty());
+    assert_eq!(read(400).await?, vec![b"d".to_vec()]);
+
+    //Paged by uuid
+    HISTORY_NOW.store(450, Ordering::SeqCst);
+    let other = PublicRecord::new(None, protocol.clone(), b"e", None)?.into_item(Signer::Right(key.clone()))?;
+    dwn.process_request(DwnRequest::CreatePublic(other)).await?.into_empty()?;
+    let page = |cursor: Option<Vec<u8>>| {
+        let dwn = dwn.clone();
+        async move {
+            let sort_options = SortOptions::new("uuid").with_page(Some(1), cursor)?;
+            let req = DwnRequest::ReadPublicAt(Filters::new(vec![]).into(), at(450), Some(sort_options));
+            match dwn.process_request(req).await? {
+                DwnResponse::ReadPublic(items, cursor) => Ok::<_, Error>((items, cursor)),
+                other => Err(Error::bad_response(&format!("{:?}", other)))
+            }
+        }
+    };
+    let (first, cursor) = page(None).await?;
+    assert_eq!(first.len(), 1);
+    let (second, end) = page(cursor).await?;
+    assert_eq!(second.len(), 1);
+    assert!(end.is_none());
+    assert!(first[0].0.inner().uuid < second[0].0.inner().uuid);
+
+    //A delete leaves the record absent at every instant, the other record is untouched
+    HISTORY_NOW.store(500, Ordering::SeqCst);
+    let delete = SignedObject::new(Signer::Right(key.clone()), uuid)?;
+    dwn.process_request(DwnRequest::DeletePublic(delete)).await?.into_empty()?;
+    assert!(read(400).await?.is_empty());
+    assert_eq!(read(500).await?, vec![b"e".to_vec()]);
+    Ok(())
+}
+
+struct CountMigrator {}
+impl PayloadMigrator for CountMigrator {
+    fn migrate(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut value: serde_json::Value = serde_json::from_slice(payload)?;
+        let count = value.get("items").and_then(|i| i.as_array()).map(|i| i.len()).unwrap_or_default();
+        value["count"] = count.into();
+        Ok(serde_json::to_vec(&value)?)
+    }
+}
+
+#[test]
+fn on_invalid_read_policy() -> Result<(), Error> {
+    let protocol = Protocol::new(
+        "Items", false, PermissionOptions::new(true, true, false, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true)).unwrap()), None
+    )?;
+    //Written before the count rule existed
+    let stored = br#"{"count": 3, "items": [1, 2]}"#;
+    let mut validators = Validators::default();
+    assert_eq!(validators.validate_read(&protocol, stored, true)?, (stored.to_vec(), RecordState::Valid));
+
+    validators.register(&protocol, ItemsValidator{}, true)?;
+    assert!(validators.validate_read(&protocol, stored, true).is_err());
+
+    validators.set_on_invalid(&protocol, OnInvalid::SurfaceRaw)?;
+    let (payload, state) = validators.validate_read(&protocol, stored, true)?;
+    assert_eq!(payload, stored);
+    assert!(matches!(state, RecordState::Invalid{issues, payload} if issues.len() == 1 && payload == stored));
+    assert!(validators.validate_read(&protocol, stored, false).is_err());
+
+    let id = Uuid::new_v4();
+    assert!(validators.set_on_invalid(&protocol, OnInvalid::Migrate(id)).is_err());
+    validators.register_migrator(id, CountMigrator{})?;
+    validators.set_on_invalid(&protocol, OnInvalid::Migrate(id))?;
+    let (payload, state) = validators.validate_read(&protocol, stored, true)?;
+    assert_eq!(state, RecordState::Migrated);
+    validators.validate(&protocol, &payload, true)?;
+    assert!(validators.validate_read(&protocol, stored, false).is_err());
+
+    //Read state is never part of what gets signed
+    let mut record = crate::dwn::structs::PublicRecord::new(None, protocol, stored, None)?;
+    let signed = serde_json::to_vec(&record)?;
+    record.state = state;
+    assert_eq!(serde_json::to_vec(&record)?, signed);
+    Ok(())
+}
+
+#[tokio::test]
+async fn verified_by_key_id() -> Result<(), Error> {
+    use crate::dids::{DidKey, DidKeyPair, DidKeyPurpose, DidMethod};
+    use crate::dids::signing::{SignedObject, Signer, Verifier};
+    use crate::dwn::structs::PublicRecord;
+
+    let id = crate::ed25519::SecretKey::new().public_key();
+    let did = Did::new(DidMethod::DHT, id.thumbprint());
+    let pair = |kid: &str, purposes: Vec<DidKeyPurpose>| {
+        let secret = SecretKey::new();
+        let key = DidKey::new(Some(kid.to_string()), did.clone(), secret.public_key(), purposes, None);
+        DidKeyPair::new(secret, key)
+    };
+    let sig = pair("sig", vec![DidKeyPurpose::Auth, DidKeyPurpose::Asm])?;
+    let agm = pair("agm", vec![DidKeyPurpose::Agm])?;
+    let keys = BTreeMap::from([
+        ("sig".to_string(), sig.public.clone()), ("agm".to_string(), agm.public.clone())
+    ]);
+    let mut resolver = MemoryDidResolver::new();
+    resolver.store(Box::new(DhtDocument::new(id, vec![], vec![], BTreeMap::new(), keys, vec![])));
+
+    let protocol = Protocol::new(
+        "Profile", false, PermissionOptions::new(true, true, false, None), None, None
+    )?;
+    let record = PublicRecord::new(None, protocol.clone(), &[], None)?;
+    let by_sig = SignedObject::new(Signer::Left(sig.clone()), record.clone())?.verify_by(&resolver, None).await?;
+    let by_agm = SignedObject::new(Signer::Left(agm), record.clone())?.verify_by(&resolver, None).await?;
+    assert_eq!(by_sig.key_id.as_deref(), Some("sig"));
+    assert_eq!(by_agm.key_id.as_deref(), Some("agm"));
+    assert!(by_agm.has_purpose(&DidKeyPurpose::Agm));
+    assert_eq!(Verifier::from(by_agm.clone()), Verifier::Left(did.clone()));
+
+    //Default key signatures keep the form they had before key ids were recorded
+    let signed = SignedObject::new(Signer::Left(sig), record)?;
+    assert!(!serde_json::to_string(&signed)?.contains("key_id"));
+
+    let mut validators = Validators::default();
+    validators.check_signer(&protocol, &by_agm)?;
+    validators.require_purpose(&protocol, DidKeyPurpose::Asm);
+    validators.check_signer(&protocol, &by_sig)?;
+    assert!(validators.check_signer(&protocol, &by_agm).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn reserved_indexes() -> Result<(), Error> {
+    use crate::dids::signing::Signer;
+    use crate::dwn::structs::{DwnResponse, PublicRecord};
+    use simple_database::database::IndexBuilder;
+
+    let (id, _) = get_server(1)?;
+    let resolver: Box<dyn DidResolver> = Box::new(MemoryDidResolver::new());
+    let dwn = Dwn::scratch::<MemoryStore>(id, Some(resolver)).await?;
+    let signer = SecretKey::new();
+    for key in ["__sys.owner", "timestamp_stored"] {
+        let mut index = IndexBuilder::build(vec![("type", "forged")])?;
+        index.insert(key.to_string(), "forged".to_string().into());
+        let error = PublicRecord::new(None, SystemProtocols::usize(), b"1", Some(index.clone())).unwrap_err();
+        assert_eq!(error.code(), "BAD_REQUEST");
+
+        //Records signed by a client that skipped the check are refused as malformed, not unauthorized
+        let mut record = PublicRecord::new(None, SystemProtocols::usize(), b"1", None)?;
+        record.index = index;
+        let item = record.into_item(Signer::Right(signer.clone()))?;
+        for request in [DwnRequest::CreatePublic(item.clone()), DwnRequest::UpdatePublic(item)] {
+            let response = dwn.process_request(request).await?;
+            assert!(matches!(response, DwnResponse::BadRequest(_)), "{:?}", response);
+            assert_eq!(response.to_error_json().unwrap().code, "BAD_REQUEST");
+            assert_eq!(response.into_empty().unwrap_err().code(), "BAD_REQUEST");
+        }
+    }
+    let stored = dwn.process_request(DwnRequest::CountPublic(Filters::new(vec![]).into())).await?;
+    assert!(matches!(stored, DwnResponse::Count(0)), "{:?}", stored);
+    Ok(())
+}
+
+#[tokio::test]
+async fn agent_keys_generation_guard() -> Result<(), Error> {
+    use crate::dids::signing::Signer;
+    use crate::dwn::structs::{DwnResponse, PublicRecord};
+    use simple_database::database::IndexBuilder;
+
+    let (id, _) = get_server(1)?;
+    let resolver: Box<dyn DidResolver> = Box::new(MemoryDidResolver::new());
+    let dwn = Dwn::scratch::<MemoryStore>(id, Some(resolver)).await?;
+    let signer = SecretKey::new();
+    let uuid = Uuid::new_v4();
+    let write = |keys: &BTreeMap<RecordPath, simple_crypto::PublicKey>, generation: u64| {
+        let index = IndexBuilder::build(vec![("type", "agent_keys")]).unwrap();
+        let mut record = PublicRecord::new(
+            Some(uuid), SystemProtocols::agent_keys(), &serde_json::to_vec(keys).unwrap(), Some(index)
+        ).unwrap();
+        record.index.insert("generation".to_string(), (generation+1).into());
+        DwnRequest::GuardedUpdatePublic(record.into_item(Signer::Right(signer.clone())).unwrap(), generation)
+    };
+    let device = || (RecordPath::new(&[Uuid::new_v4()]).unwrap(), SecretKey::new().public_key());
+    let (a, b) = (device(), device());
+
+    //Both devices saw no record, the second write loses and merges into the winner
+    let a_keys = BTreeMap::from([a.clone()]);
+    dwn.process_request(write(&a_keys, 0)).await?.into_empty()?;
+    let winner = match dwn.process_request(write(&BTreeMap::from([b.clone()]), 0)).await? {
+        DwnResponse::PublicConflict(item, _) => item.0.unwrap(),
+        other => panic!("Expected PublicConflict got {:?}", other)
+    };
+    assert_eq!(winner.generation(), 1);
+    let mut merged = serde_json::from_slice::<BTreeMap<RecordPath, simple_crypto::PublicKey>>(&winner.payload)?;
+    merged.insert(b.0.clone(), b.1.clone());
+    dwn.process_request(write(&merged, winner.generation())).await?.into_empty()?;
+
+    //A stale writer can no longer overwrite the merged record
+    assert!(matches!(dwn.process_request(write(&a_keys, 1)).await?, DwnResponse::PublicConflict(..)));
+    let stored = match dwn.process_request(DwnRequest::ReadPublic(Filters::new(vec![]).into(), None)).await? {
+        DwnResponse::ReadPublic(mut items, _) => items.remove(0).0.unwrap(),
+        other => panic!("Expected ReadPublic got {:?}", other)
+    };
+    assert_eq!(stored.generation(), 2);
+    assert_eq!(serde_json::from_slice::<BTreeMap<RecordPath, simple_crypto::PublicKey>>(&stored.payload)?, BTreeMap::from([a, b]));
+    Ok(())
+}
+
+#[test]
+fn compiler_cache_lru() -> Result<(), Error> {
+    use crate::dids::Endpoint;
+    let key = PathedKey::new_root(SecretKey::new());
+    let protocol = SystemProtocols::root();
+    let paths = (0..5).map(|_| RecordPath::new(&[Uuid::new_v4()])).collect::<Result<Vec<_>, Error>>()?;
+    let entry = |path: &RecordPath| Ok::<_, Error>((
+        (Endpoint::default(), true, path.clone()), (protocol.clone(), key.get_perms(path, None)?)
+    ));
+
+    let mut cache = CompilerCache::with_capacity(3);
+    for path in &paths[..3] {
+        let (k, info) = entry(path)?;
+        cache.insert_info(k, info);
+    }
+    //Touching the first path makes the second the least recently used
+    assert!(cache.get_info(&entry(&paths[0])?.0).is_some());
+    for path in &paths[3..] {
+        let (k, info) = entry(path)?;
+        cache.insert_info(k, info);
+    }
+    assert!(cache.get_info(&entry(&paths[1])?.0).is_none());
+    assert!(cache.get_info(&entry(&paths[2])?.0).is_none());
+    let (k, info) = entry(&paths[0])?;
+    assert_eq!(cache.get_info(&k), Some(info));
+
+    //A miss is refilled the same way ReadInfo does after reading the record
+    let (k, info) = entry(&paths[1])?;
+    cache.insert_info(k.clone(), info.clone());
+    assert_eq!(cache.get_info(&k), Some(info));
+
+    let stats = cache.stats();
+    assert_eq!(stats, CacheStats{hits: 3, misses: 2, evictions: 3, entries: 3});
+    assert_eq!(stats.entries + stats.evictions, 6);
+    Ok(())
+}
+
+#[test]
+fn read_capability_token() -> Result<(), Error> {
+    let key = PathedKey::new_root(SecretKey::new());
+    let (_, doc) = get_server(1)?;
+    let perms = key.get_perms(&RecordPath::new(&[Uuid::new_v4()])?, Some(&SystemProtocols::capability()))?;
+    assert!(CapabilityToken::new(doc.did(), perms.clone()).is_err());
+
+    let read = perms.subset(&PermissionOptions::new(false, true, false, None))?;
+    let token = CapabilityToken::new(doc.did(), read.clone())?.encode()?;
+    assert!(token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    let decoded = CapabilityToken::decode(&token)?;
+    assert_eq!(decoded.owner, doc.did());
+    assert_eq!(decoded.perms, read);
+    assert!(scripts::ReadPrivate::from_capability(&token).is_ok());
+    assert!(scripts::RevokeReadCapability::new(&token, RecordPath::new(&[Uuid::new_v4()])?).is_ok());
+    assert!(scripts::ReadPrivate::from_capability(&token[1..]).is_err());
+
+    let now = chrono::Utc::now();
+    let grant = CapabilityGrant::new(read.clone(), Some(now))?;
+    SystemProtocols::capability().validate_payload(&serde_json::to_vec(&grant)?)?;
+    grant.check(now - chrono::Duration::seconds(1))?;
+    assert!(grant.check(now).is_err());
+    CapabilityGrant::new(read, None)?.check(now)?;
+    assert_ne!(CapabilityGrant::path(), CapabilityGrant::path());
+    Ok(())
+}
+
+#[tokio::test]
+async fn revoke_read_capability() -> Result<(), Error> {
+    use crate::agent::structs::{PrivateRecord, Responses};
+    let net = LocalNet::new(2).await?;
+    let (owner, reader) = (net.agent(0).await?, net.agent(1).await?);
+    let (mut o_cache, mut r_cache) = (CompilerCache::default(), CompilerCache::default());
+    let protocol = Protocol::new(
+        "Note", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    owner.run::<()>(&mut o_cache, scripts::CreatePrivate::new(Record::new(path.clone(), protocol, b"{}"), None)).await?;
+    let token = owner.run::<String>(
+        &mut o_cache, scripts::PublishReadCapability::new(path.clone(), PermissionOptions::new(false, true, false, None))
+    ).await?;
+
+    //The reader has nothing but the token, and keeps the keys the grant hands it
+    let record = reader.run::<Option<Record>>(&mut r_cache, scripts::ReadPrivate::from_capability(&token)?).await?;
+    assert_eq!(record.map(|r| r.payload), Some(b"{}".to_vec()));
+    let remote_read = |perms: PermissionSet| commands::Send::new(commands::ReadPrivate::new(Box::new(perms), false), vec![net.did(0)]);
+    let read = |responses: Responses| responses.into_iter().find_map(|r|
+        r.downcast::<(Option<Box<PrivateRecord>>, bool)>().ok().and_then(|r| r.0)
+    );
+    let grant = read(reader.run::<Responses>(
+        &mut r_cache, Box::new(remote_read(CapabilityToken::decode(&token)?.perms))
+    ).await?).unwrap();
+    let kept = serde_json::from_slice::<CapabilityGrant>(&grant.payload)?.perms;
+    assert!(read(reader.run::<Responses>(&mut r_cache, Box::new(remote_read(kept.clone()))).await?).is_some());
+
+    let to = RecordPath::new(&[Uuid::new_v4()])?;
+    owner.run::<()>(&mut o_cache, scripts::RevokeReadCapability::new(&token, to.clone())?).await?;
+    assert!(reader.run::<Option<Record>>(&mut r_cache, scripts::ReadPrivate::from_capability(&token)?).await.is_err());
+    assert!(read(reader.run::<Responses>(&mut r_cache, Box::new(remote_read(kept))).await?).is_none());
+    //The owner reads it at its new path
+    let moved = owner.run::<Option<Record>>(&mut o_cache, scripts::ReadPrivate::new(to)).await?;
+    assert_eq!(moved.map(|r| r.payload), Some(b"{}".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn child_slots() -> Result<(), Error> {
+    let record = Record::new(RecordPath::root(), SystemProtocols::root(), b"hi");
+    let slot = ChildSlot::from((Some(Box::new(record.clone())), true));
+    assert_eq!(slot.clone().map(|r| r.payload).present(), Some(b"hi".to_vec()));
+    assert_eq!(ChildSlot::<Record>::from((None, true)), ChildSlot::Tombstoned);
+    assert!(ChildSlot::<Record>::from((None, false)).is_missing());
+    assert!(!slot.is_missing());
+    assert_eq!(ChildSlot::<Record>::Tombstoned.present(), None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn access_log() -> Result<(), Error> {
+    use crate::dids::{DidKey, DidKeyPair, DidKeyPurpose, DidMethod};
+    use crate::dids::signing::SignedObject;
+    use crate::dwn::structs::{DwnResponse, DwnItem};
+
+    let tenant = |resolver: &mut MemoryDidResolver| {
+        let id = crate::ed25519::SecretKey::new().public_key();
+        let did = Did::new(DidMethod::DHT, id.thumbprint());
+        let secret = SecretKey::new();
+        let key = DidKey::new(Some("sig".to_string()), did, secret.public_key(), vec![DidKeyPurpose::Auth], None);
+        let pair = DidKeyPair::new(secret, key)?;
+        let keys = BTreeMap::from([("sig".to_string(), pair.public.clone())]);
+        resolver.store(Box::new(DhtDocument::new(id, vec![], vec![], BTreeMap::new(), keys, vec![])));
+        Ok::<_, Error>(pair)
+    };
+    let mut resolver = MemoryDidResolver::new();
+    let owner = tenant(&mut resolver)?;
+    let other = tenant(&mut resolver)?;
+
+    let (id, _) = get_server(1)?;
+    let mut dwn = Dwn::scratch::<MemoryStore>(id, Some(Box::new(resolver))).await?;
+    let audited = SecretKey::new();
+    let plain = SecretKey::new();
+    let audit = |pair: &DidKeyPair, keys: Vec<SecretKey>| {
+        let proofs = keys.iter().map(|k| SignedObject::from_key(k, pair.public.did.clone())).collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, Error>(DwnRequest::AuditAccess(SignedObject::from_keypair(pair, proofs)?))
+    };
+
+    //Off unless the Dwn enables it
+    assert!(dwn.process_request(audit(&owner, vec![audited.clone()])?).await?.is_invalid_auth());
+    dwn.log_access(10);
+    let log = |pair: &DidKeyPair| {
+        let request = DwnRequest::ReadAccessLog(SignedObject::from_keypair(pair, chrono::DateTime::UNIX_EPOCH).unwrap());
+        let dwn = dwn.clone();
+        async move {
+            match dwn.process_request(request).await? {
+                DwnResponse::ReadAccessLog(entries) => Ok::<_, Error>(entries),
+                other => Err(Error::bad_response(&format!("{:?}", other)))
+            }
+        }
+    };
+    for key in [&audited, &plain] {
+        let item = DwnItem{discover: key.public_key(), delete: None, payload: vec![], expires: None};
+        dwn.process_request(DwnRequest::CreatePrivate(SignedObject::from_key(key, item)?)).await?.into_empty()?;
+    }
+    dwn.process_request(audit(&owner, vec![audited.clone()])?).await?.into_empty()?;
+    assert!(dwn.process_request(audit(&other, vec![audited.clone()])?).await?.is_invalid_auth());
+
+    dwn.process_request(DwnRequest::read_private(&audited)?).await?.into_read_private()?.unwrap();
+    dwn.process_request(DwnRequest::read_private(&plain)?).await?.into_read_private()?.unwrap();
+
+    let entries = log(&owner).await?;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].discover, audited.public_key().thumbprint());
+    assert_eq!(entries[0].request, "ReadPrivate");
+    assert_eq!(entries[0].requester, None);
+    assert!(log(&other).await?.is_empty());
+    Ok(())
+}
+
+//Codes are part of the public contract, this list only ever grows
+fn golden_code(error: &Error) -> &'static str {
+    match error {
+        Error::Hex{..} => "PARSE",
+        Error::Ed25519{..} => "CRYPTO",
+        Error::Base64Decode{..} => "PARSE",
+        Error::TryFromSlice{..} => "PARSE",
+        Error::Zbase32{..} => "PARSE",
+        Error::SimpleCrypto{..} => "CRYPTO",
+        Error::FromStringUtf8{..} => "PARSE",
+        Error::SimpleDns{..} => "TRANSPORT",
+        Error::UrlParse{..} => "PARSE",
+        Error::SerdeJson{..} => "PARSE",
+        Error::SimpleDatabase{..} => "STORAGE",
+        Error::Regex{..} => "PARSE",
+        Error::Reqwest{..} => "TRANSPORT",
+        Error::SystemTime{..} => "INTERNAL",
+        Error::SerdeBencode{..} => "PARSE",
+        Error::Io{..} => "STORAGE",
+        Error::Arc{source} => golden_code(source),
+        Error::FailedDowncast{..} => "INTERNAL",
+        Error::Validation{..} => "VALIDATION",
+        Error::Parse{..} => "PARSE",
+        Error::InvalidAuth{..} => "INVALID_AUTH",
+        Error::BadResponse{..} => "BAD_RESPONSE",
+        Error::BadRequest{..} => "BAD_REQUEST",
+        Error::NotFound{..} => "NOT_FOUND",
+        Error::UpdateRejected{..} => "UPDATE_REJECTED",
+        Error::WrongDomain{..} => "WRONG_DOMAIN",
+        Error::Conflict{..} => "CONFLICT",
+        Error::BootstrapRace{..} => "BOOTSTRAP_RACE",
+        Error::JsonRpc{..} => "TRANSPORT",
+        Error::Multi{..} => "MULTI",
+        Error::InsufficentPermission{..} => "INSUFFICIENT_PERMISSION",
+        Error::Custom{..} => "CUSTOM",
+        Error::Unreachable{..} => "UNREACHABLE",
+        Error::Cancelled{..} => "CANCELLED",
+        Error::Pinned{..} => "PINNED",
+        Error::SchemaValidation{..} => "VALIDATION",
+        Error::AtEndpoint{source, ..} => golden_code(source),
+        Error::ProtocolMismatch{..} => "PROTOCOL_MISMATCH",
+    }
+}
+
+#[test]
+fn error_codes() -> Result<(), Error> {
+    use crate::common::Convert;
+    use crate::dwn::structs::{DwnResponse, ErrorContext};
+
+    let errors: Vec<Error> = vec![
+        hex::decode("0").unwrap_err().into(),
+        ed25519_dalek::ed25519::Error::new().into(),
+        Convert::Base64UrlUnpadded.decode("!").unwrap_err(),
+        <[u8; 2]>::try_from(&[0u8][..]).unwrap_err().into(),
+        Convert::ZBase32.decode("!").unwrap_err(),
+        simple_crypto::Error::err("", "").into(),
+        String::from_utf8(vec![0xff]).unwrap_err().into(),
+        simple_dns::Packet::parse(&[]).unwrap_err().into(),
+        url::Url::parse("").unwrap_err().into(),
+        serde_json::from_str::<u8>("").unwrap_err().into(),
+        simple_database::Error::err("", "").into(),
+        regex::Regex::new(&String::from("(")).unwrap_err().into(),
+        reqwest::Client::new().get("").build().unwrap_err().into(),
+        std::time::UNIX_EPOCH.duration_since(std::time::SystemTime::now()).unwrap_err().into(),
+        serde_bencode::from_bytes::<String>(b"x").unwrap_err().into(),
+        std::io::Error::other("").into(),
+        Error::arc(std::sync::Arc::new(Error::not_found(""))),
+        Error::from(Box::new(0u8) as Box<dyn crate::agent::Response>),
+        Error::validation(""),
+        Error::parse("", ""),
+        Error::invalid_auth(""),
+        Error::bad_response(""),
+        Error::bad_request(""),
+        Error::not_found(""),
+        Error::update_rejected(""),
+        Error::wrong_domain(""),
+        Error::conflict(""),
+        Error::bootstrap_race(""),
+        Error::json_rpc(""),
+        Error::multi(vec![Box::new(std::sync::Arc::new(Error::custom("a"))), Box::new(std::sync::Arc::new(Error::not_found("b")))]),
+        Error::insufficent_permission(),
+        Error::custom(""),
+        Error::unreachable(""),
+        Error::at_endpoint("did", "url", std::sync::Arc::new(Error::unreachable(""))),
+        Error::cancelled(""),
+        Error::schema_validation("", "", ""),
+        Error::protocol_mismatch("", "", ""),
+        Error::pinned(""),
+    ];
+    for error in &errors {
+        assert_eq!(error.code(), golden_code(error), "{:?}", error);
+    }
+    assert_eq!(errors[16].code(), "NOT_FOUND");
+
+    let json = serde_json::to_value(&errors[29])?;
+    assert_eq!(json["code"], "MULTI");
+    assert_eq!(json["causes"][1]["code"], "NOT_FOUND");
+    assert_eq!(json["causes"][1]["message"], "Could Not Find: b");
+    assert_eq!(serde_json::from_value::<ErrorJson>(json)?, errors[29].to_json());
+
+    let id = Uuid::new_v4();
+    let conflict = DwnResponse::InvalidAuth(ErrorContext::new("Signature").with_id(id));
+    let json = conflict.to_error_json().unwrap();
+    assert_eq!(json.code, Error::invalid_auth("").code());
+    assert_eq!(json.context.get("id"), Some(&id.to_string()));
+    assert!(DwnResponse::Empty.to_error_json().is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn conflict_names_record() -> Result<(), Error> {
+    let net = LocalNet::new(1).await?;
+    let (first, second) = (net.agent(0).await?, net.agent(0).await?);
+    let folder = Protocol::new(
+        "Folder", true, PermissionOptions::new(true, true, true, Some(ChannelPermissionOptions::new(true, true))),
+        None, Some(ChannelProtocol::new(Some(vec![&SystemProtocols::usize()])))
+    )?;
+    let parent = RecordPath::new(&[Uuid::new_v4()])?;
+    let child = |payload: &[u8]| scripts::CreatePrivate::new(
+        Record::new(parent.extend(&[Uuid::new_v4()]).unwrap(), SystemProtocols::usize(), payload), None
+    );
+    let mut session = first.session(None);
+    session.run::<()>(scripts::CreatePrivate::new(Record::new(parent.clone(), folder, &[]), None)).await?;
+    session.run::<()>(child(b"0")).await?;
+
+    //The second agent takes the slot the session still holds as its next one
+    second.run::<()>(&mut CompilerCache::default(), child(b"1")).await?;
+    let error = session.run::<()>(child(b"2")).await.unwrap_err();
+    let slot = Wallet::new(net.users[0].0.clone()).root().enc_key.get_perms(&parent, None)?.pointer(1)?;
+    assert_eq!(error.code(), "CONFLICT");
+    let message = error.to_json().message;
+    assert!(message.contains("Record Exists"), "{}", message);
+    assert!(message.contains(&format!("discover: {}", slot.discover().public_key().thumbprint())), "{}", message);
+    Ok(())
+}
+
+//A fresh user with an agent on a single in process Dwn
+async fn local_agent() -> Result<(Agent, LocalDwns, url::Url), Error> {
+    let net = LocalNet::new(1).await?;
+    Ok((net.agent(0).await?, net.dwns.clone(), net.urls[0].clone()))
+}
+
+#[tokio::test]
+async fn fault_injection() -> Result<(), Error> {
+    let net = LocalNet::new(2).await?;
+    let (alice, bob) = (net.agent(0).await?, net.agent(1).await?);
+    let (mut a_cache, mut b_cache) = (CompilerCache::default(), CompilerCache::default());
+    let record = || Record::new(RecordPath::new(&[Uuid::new_v4()]).unwrap(), SystemProtocols::usize(), b"1");
+
+    //A failed Dwn refuses with the fault as an unreachable endpoint and records nothing it was sent
+    net.dwns.fail(&net.urls[0], "Connection reset");
+    let before = net.dwns.sent(&net.urls[0]).len();
+    let error = alice.run::<()>(&mut a_cache, scripts::CreatePrivate::new(record(), None)).await.unwrap_err();
+    assert_eq!(error.code(), "UNREACHABLE");
+    assert!(error.to_string().contains("Connection reset"));
+    assert_eq!(net.dwns.sent(&net.urls[0]).len(), before);
+
+    //Other Dwns are untouched by the fault
+    bob.run::<()>(&mut b_cache, scripts::CreatePrivate::new(record(), None)).await?;
+
+    //Once recovered the same Dwn serves and records requests again
+    net.dwns.recover(&net.urls[0]);
+    alice.run::<()>(&mut a_cache, scripts::CreatePrivate::new(record(), None)).await?;
+    assert!(net.dwns.sent(&net.urls[0]).split_off(before).iter().any(|r| matches!(r, DwnRequest::CreatePrivate(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn exists_path() -> Result<(), Error> {
+    use crate::agent::structs::PrivateRecord;
+
+    let (agent, dwns, url) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let absent = RecordPath::new(&[Uuid::new_v4()])?;
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(path.clone(), SystemProtocols::usize(), b"1"), None)).await?;
+    assert!(agent.run::<bool>(&mut cache, scripts::ExistsPath::new(path.clone())).await?);
+    assert!(!agent.run::<bool>(&mut cache, scripts::ExistsPath::new(absent)).await?);
+    assert!(agent.run::<bool>(&mut cache, scripts::ExistsPath::new(RecordPath::root())).await?);
+
+    //An exists check next to a full read of the same path rides on its request
+    let before = dwns.sent(&url).len();
+    let mut results = agent.process_commands_keyed(&mut cache, vec![
+        (0, scripts::ExistsPath::new(path.clone())),
+        (1, Box::new(commands::ReadPrivate::path(path.clone())))
+    ]).await?;
+    let (record, _) = *results.remove(&1).unwrap()?.remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?;
+    assert_eq!(record.map(|r| r.perms.path), Some(path));
+    assert!(*results.remove(&0).unwrap()?.remove(0).downcast::<bool>()?);
+    let reads = dwns.sent(&url).split_off(before).into_iter().filter(|r| matches!(r, DwnRequest::ReadPrivate(_))).count();
+    assert_eq!(reads, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn keyed_commands() -> Result<(), Error> {
+    let (agent, dwns, url) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let record = |payload: &[u8]| Record::new(RecordPath::new(&[Uuid::new_v4()]).unwrap(), SystemProtocols::usize(), payload);
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(path.clone(), SystemProtocols::usize(), b"1"), None)).await?;
+
+    //Keys need not be in order, each maps to its own command's result
+    let mut results = agent.process_commands_keyed(&mut cache, vec![
+        ("exists", scripts::ExistsPath::new(path)),
+        ("invalid", scripts::CreatePrivate::new(record(b"\"two\""), None)),
+        ("create", scripts::CreatePrivate::new(record(b"2"), None))
+    ]).await?;
+    assert!(*results.remove("exists").unwrap()?.remove(0).downcast::<bool>()?);
+    assert_eq!(results.remove("invalid").unwrap().unwrap_err().code(), "VALIDATION");
+    results.remove("create").unwrap()?.remove(0).downcast::<()>()?;
+
+    let before = dwns.sent(&url).len();
+    let error = agent.process_commands_keyed(&mut cache, vec![
+        (1, scripts::CreatePrivate::new(record(b"3"), None)),
+        (1, scripts::CreatePrivate::new(record(b"4"), None))
+    ]).await.unwrap_err();
+    assert_eq!(error.code(), "BAD_REQUEST");
+    assert_eq!(dwns.sent(&url).len(), before);
+    Ok(())
+}
+
+#[tokio::test]
+async fn typed_run() -> Result<(), Error> {
+    let (agent, _, _) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let absent = RecordPath::new(&[Uuid::new_v4()])?;
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(path.clone(), SystemProtocols::usize(), b"1"), None)).await?;
+
+    let error = agent.run::<usize>(&mut cache, scripts::ExistsPath::new(path.clone())).await.unwrap_err();
+    assert_eq!(error.code(), "BAD_RESPONSE");
+    assert!(error.to_string().contains("usize") && error.to_string().contains("bool"), "{}", error);
+
+    //Results are in the order of the commands, whichever finishes first
+    let exists = |path: &RecordPath| scripts::ExistsPath::new(path.clone());
+    assert_eq!(agent.run_all::<bool>(&mut cache, vec![exists(&absent), exists(&path), exists(&absent)]).await?, vec![false, true, false]);
+    let error = agent.run_all::<bool>(&mut cache, vec![
+        exists(&path), scripts::CreatePrivate::new(Record::new(absent, SystemProtocols::usize(), b"\"two\""), None)
+    ]).await.unwrap_err();
+    assert_eq!(error.code(), "VALIDATION");
+    Ok(())
+}
+
+#[tokio::test]
+async fn dm_sync_shared() -> Result<(), Error> {
+    let net = LocalNet::new(3).await?;
+    let (dwns, urls) = (&net.dwns, &net.urls);
+    let recipients = [net.did(1), net.did(2)];
+    let agent = net.agent(0).await?;
+    let before = dwns.sent(&urls[0]).len();
+
+    let mut cache = CompilerCache::default();
+    let results = agent.process_commands_keyed(&mut cache, vec![
+        (0, Box::new(commands::EstablishChannel::new(recipients[0].clone()))),
+        (1, Box::new(commands::EstablishChannel::new(recipients[1].clone())))
+    ]).await?;
+    assert!(results.values().all(|r| r.is_ok()));
+
+    let sent = dwns.sent(&urls[0]).split_off(before);
+    //The DM cursor and the com root index
+    let updates = sent.iter().filter(|r| matches!(r, DwnRequest::UpdatePrivate(_))).count();
+    let reads = sent.iter().filter(|r| matches!(r, DwnRequest::ReadDM(_))).count();
+    assert_eq!((updates, reads), (2, 1));
+    for url in &urls[1..] {
+        assert!(dwns.sent(url).iter().any(|r| matches!(r, DwnRequest::CreatePrivate(_))));
+    }
+
+    //Both channels exist, establishing them again creates nothing
+    let before = dwns.sent(&urls[0]).len();
+    let results = agent.process_commands_keyed(&mut cache, vec![
+        (0, Box::new(commands::EstablishChannel::new(recipients[0].clone()))),
+        (1, Box::new(commands::EstablishChannel::new(recipients[1].clone())))
+    ]).await?;
+    assert!(results.values().all(|r| r.is_ok()));
+    assert!(!dwns.sent(&urls[0]).split_off(before).iter().any(|r| matches!(r, DwnRequest::CreatePrivate(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn dm_pages() -> Result<(), Error> {
+    use crate::agent::structs::{MutableAgentRequest, DmMessage};
+    use crate::dids::signing::{Signer, Verifier};
+
+    let net = LocalNet::new(1).await?;
+    let (_, com_key) = net.resolver.resolve_dwn_keys(&net.did(0)).await?;
+    let sender = SecretKey::new();
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    //Seeding is quadratic in the stored DMs, a few hundred are enough to span compiles
+    for i in 0..600 {
+        let message = DmMessage::RecordUpdated(RecordUpdated::new(&path, i.to_string().as_bytes()));
+        let req = MutableAgentRequest::create_dm(Uuid::new_v4(), message, Signer::Right(sender.clone()), com_key.clone())?;
+        net.dwn(0).process_request(req.into_dwn_request()?).await?;
+    }
+
+    let agent = net.agent(0).await?;
+    let mut cache = CompilerCache::default();
+    let mut pending = Vec::new();
+    let mut payloads = std::collections::BTreeSet::new();
+    loop {
+        let mut results = agent.process_commands(&mut cache, vec![scripts::PendingDMs::new()]).await?;
+        pending.push(*results.remove(0).downcast::<usize>()?);
+        let mut results = agent.process_commands(&mut cache, vec![scripts::ScanDM::pages(2)]).await?;
+        let updates = *results.remove(0).downcast::<Vec<(Verifier, RecordUpdated)>>()?;
+        if updates.is_empty() {break;}
+        assert!(updates.len() <= 2*commands::DM_PAGE_SIZE);
+        payloads.extend(updates.into_iter().map(|(_, u)| u.payload));
+    }
+    assert_eq!(pending, vec![600, 400, 200, 0]);
+    assert_eq!(payloads.len(), 600);
+    assert!(net.dwns.received(&net.urls[0]).iter().all(|r| match r {
+        DwnResponse::ReadDM(items, _) => items.len() <= commands::DM_PAGE_SIZE,
+        _ => true
+    }));
+    Ok(())
+}
+
+#[test]
+fn protocol_names() -> Result<(), Error> {
+    use crate::agent::structs::MutableAgentRequest;
+    use crate::agent::ProtocolRegistry;
+    use crate::dids::signing::Signer;
+    use crate::dwn::structs::PublicRecord;
+
+    let pointer = SystemProtocols::pointer();
+    let registry = ProtocolRegistry::new(&SystemProtocols::all());
+    assert_eq!(registry.name_of(&pointer.uuid()), Some("pointer"));
+    let unknown = Uuid::new_v4();
+    assert_eq!(registry.name_of(&unknown), None);
+    assert_eq!(registry.label(&unknown), unknown.simple().to_string()[..8]);
+    assert_eq!(registry.label(&pointer.uuid()), pointer.label());
+
+    let rooms = Protocol::new(
+        "rooms_protocol", true,
+        PermissionOptions::new(true, true, true, Some(ChannelPermissionOptions::new(true, true))),
+        None, Some(ChannelProtocol::new(Some(vec![&SystemProtocols::usize()])))
+    )?;
+    let error = rooms.validate_child(&pointer).unwrap_err().to_string();
+    assert!(error.contains("pointer") && error.contains("rooms_protocol"));
+
+    let perms = PathedKey::new_root(SecretKey::new()).get_perms(&RecordPath::root(), None)?;
+    let shared = SharedPermissions::new(rooms.uuid(), perms);
+    let error = shared.verify(&pointer, &registry).unwrap_err().to_string();
+    assert!(error.contains(&rooms.uuid().simple().to_string()[..8]) && error.contains("pointer"));
+    assert!(!serde_json::to_string(&shared)?.contains("rooms_protocol"));
+
+    let record = PublicRecord::new(None, pointer.clone(), b"{}", None)?;
+    let req = MutableAgentRequest::CreatePublic(Box::new(record), Signer::Right(SecretKey::new()));
+    assert!(format!("{:?}", req).contains(&pointer.label()));
+    assert!(!serde_json::to_string(&req.into_dwn_request()?)?.contains(&pointer.label()));
+    Ok(())
+}
+
+#[test]
+fn record_conversions() -> Result<(), Error> {
+    use crate::agent::structs::PrivateRecord;
+
+    let key = PathedKey::new_root(SecretKey::new());
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let protocol = SystemProtocols::usize();
+    let record = Record::new(path.clone(), protocol.clone(), b"1");
+    let private = PrivateRecord::try_from((record.clone(), key.get_perms(&path, None)?))?;
+    assert_eq!(private.perms, key.get_perms(&path, Some(&protocol))?);
+    assert_eq!(Record::from(private), record);
+
+    let other = key.get_perms(&RecordPath::root(), None)?;
+    assert!(PrivateRecord::try_from((record, other)).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn public_limits() -> Result<(), Error> {
+    use crate::dwn::structs::{PublicRecord, PublicLimits, DEFAULT_PUBLIC_LIMITS};
+    use simple_database::database::IndexBuilder;
+
+    let protocol = SystemProtocols::usize();
+    let error = PublicRecord::new(None, protocol.clone(), &vec![0; 64*1024+1], None).unwrap_err();
+    assert_eq!(error.code(), "VALIDATION");
+    assert!(error.to_string().contains("Payload of 65537 bytes exceeds the limit of 65536"));
+    let fields = (0..33).map(|i| format!("f{}", i)).collect::<Vec<_>>();
+    let index = IndexBuilder::build(fields.iter().map(|f| (f.as_str(), 1u64)).collect())?;
+    assert!(PublicRecord::new(None, protocol.clone(), b"1", Some(index)).unwrap_err().to_string().contains("33 fields"));
+    let index = IndexBuilder::build(vec![("k".repeat(65).as_str(), 1u64)])?;
+    assert!(PublicRecord::new(None, protocol.clone(), b"1", Some(index)).unwrap_err().to_string().contains("Index key"));
+    let index = IndexBuilder::build(vec![("key", "v".repeat(300))])?;
+    assert!(PublicRecord::new(None, protocol.clone(), b"1", Some(index.clone())).is_err());
+    let roomy = PublicLimits{index_value: 1024, ..DEFAULT_PUBLIC_LIMITS};
+    assert!(PublicRecord::bounded(None, protocol.clone(), b"1", Some(index), &roomy).is_ok());
+
+    let tight = PublicLimits{payload: 256, ..DEFAULT_PUBLIC_LIMITS};
+    let net = LocalNet::with_dwns(1, |dwn| dwn.with_limits(tight)).await?;
+    let (dwns, url) = (&net.dwns, &net.urls[0]);
+    let mut agent = net.agent(0).await?;
+    let mut cache = CompilerCache::default();
+    let notes = Protocol::new(
+        "notes", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let record = PublicRecord::new(None, notes, &serde_json::to_vec(&"n".repeat(300))?, None)?;
+    let result = agent.process_commands(&mut cache, vec![Box::new(commands::CreatePublic::new(record.clone(), None))]).await;
+    //Sent under the default limits and rejected by the Dwn
+    assert!(result.unwrap_err().to_string().contains("Payload of 302 bytes exceeds the limit of 256"));
+
+    assert_eq!(agent.probe_limits(&mut cache).await?, tight);
+    let before = dwns.sent(url).len();
+    let result = agent.process_commands(&mut cache, vec![Box::new(commands::CreatePublic::new(record, None))]).await;
+    assert_eq!(result.unwrap_err().code(), "VALIDATION");
+    assert!(!dwns.sent(url)[before..].iter().any(|r| matches!(r, DwnRequest::CreatePublic(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn partition_safe_index_keys() -> Result<(), Error> {
+    use crate::dids::signing::Signer;
+    use crate::dwn::structs::{PublicRecord, PublicLimits, DEFAULT_PUBLIC_LIMITS};
+    use crate::prelude::Filter;
+    use simple_database::database::IndexBuilder;
+
+    //Index keys name database partitions, only names that are safe as they are get through
+    let protocol = SystemProtocols::usize();
+    let long = PublicLimits{index_key: 1024, ..DEFAULT_PUBLIC_LIMITS};
+    for key in ["../../etc", "a/b", "a\\b", ".hidden", ""] {
+        let index = IndexBuilder::build(vec![(key, 1u64)])?;
+        let error = PublicRecord::bounded(None, protocol.clone(), b"1", Some(index), &long).unwrap_err();
+        assert!(error.to_string().contains("may only use"), "{}", error);
+    }
+    let index = IndexBuilder::build(vec![("k".repeat(300).as_str(), 1u64)])?;
+    assert!(PublicRecord::new(None, protocol.clone(), b"1", Some(index.clone())).unwrap_err().to_string().contains("300 bytes"));
+    assert!(PublicRecord::bounded(None, protocol.clone(), b"1", Some(index), &long).is_ok());
+
+    //The Dwn refuses what a client that skipped the check signs
+    let net = LocalNet::new(1).await?;
+    let signer = SecretKey::new();
+    let mut record = PublicRecord::new(None, protocol.clone(), b"1", None)?;
+    record.index = IndexBuilder::build(vec![("../../etc", 1u64)])?;
+    let response = net.dwn(0).process_request(DwnRequest::CreatePublic(record.into_item(Signer::Right(signer.clone()))?)).await?;
+    assert!(response.is_invalid_auth(), "{:?}", response);
+
+    //Keys that differ in one character stay apart
+    let agent = net.agent(0).await?;
+    let mut cache = CompilerCache::default();
+    for key in ["a.b", "a-b", "a_b"] {
+        let record = PublicRecord::new(None, protocol.clone(), b"1", Some(IndexBuilder::build(vec![(key, key)])?))?;
+        agent.run::<()>(&mut cache, scripts::CreatePublic::new(record, None)).await?;
+    }
+    for key in ["a.b", "a-b", "a_b"] {
+        let filters = Filters::new(vec![(key, Filter::equal(key.to_string()))]);
+        let (records, _) = agent.run::<(Vec<PublicRecord>, Option<Vec<u8>>)>(&mut cache, scripts::ReadPublic::new(filters, None)).await?;
+        assert_eq!(records.len(), 1);
+        assert!(records[0].index.contains_key(key));
+    }
+
+    //Filtering on an unsafe key matches nothing rather than reaching the database
+    let filters = Filters::new(vec![("../../etc", Filter::equal(1u64))]);
+    let (records, _) = agent.run::<(Vec<PublicRecord>, Option<Vec<u8>>)>(&mut cache, scripts::ReadPublic::new(filters, None)).await?;
+    assert!(records.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn capability_fallback() -> Result<(), Error> {
+    use crate::dwn::structs::{DwnCapabilities, FEATURE_GUARDED_UPDATE};
+    use crate::dids::signing::Verifier;
+
+    let net = LocalNet::with_dwns(1, |dwn| dwn.with_features(&[])).await?;
+    let (dwns, url, did_resolver) = (&net.dwns, &net.urls[0], &net.resolver);
+
+    //Bootstrapping writes agent_keys guarded, which this Dwn would reject
+    let agent = net.agent(0).await?;
+    let sent = dwns.sent(url);
+    assert!(matches!(sent[0], DwnRequest::Capabilities));
+    assert!(sent.iter().any(|r| matches!(r, DwnRequest::UpdatePublic(_))));
+    assert!(!sent.iter().any(|r| matches!(r, DwnRequest::GuardedUpdatePublic(..))));
+
+    let signed = dwns.received(url).into_iter().find_map(|r| match r {
+        DwnResponse::Capabilities(signed) => Some(signed),
+        _ => None
+    }).unwrap();
+    signed.verify(&**did_resolver, Some(&Verifier::Left(net.dwn(0).com_key.public.did.clone()))).await?;
+    assert!(signed.verify(&**did_resolver, Some(&Verifier::Left(net.did(0)))).await.is_err());
+    assert!(!signed.inner().supports(FEATURE_GUARDED_UPDATE));
+
+    let mut cache = CompilerCache::default();
+    for _ in 0..2 {
+        let capabilities = *agent.process_commands(&mut cache, vec![Box::new(commands::ProbeCapabilities::new())]).await?
+            .remove(0).downcast::<DwnCapabilities>()?;
+        assert!(capabilities.features.is_empty());
+    }
+    assert_eq!(dwns.sent(url).iter().filter(|r| matches!(r, DwnRequest::Capabilities)).count(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn dm_dedup() -> Result<(), Error> {
+    use crate::agent::structs::{MutableAgentRequest, DmMessage};
+    use crate::dids::signing::{Signer, SignedObject};
+    use crate::dwn::structs::{DmCursor, DwnItem};
+    use simple_database::database::UuidKeyed;
+
+    let mut did_resolver = MemoryDidResolver::new();
+    let (id, doc) = get_server(1)?;
+    did_resolver.store(Box::new(doc.clone()));
+    let did_resolver: Box<dyn DidResolver> = Box::new(did_resolver);
+    let dwn = Dwn::scratch::<MemoryStore>(id, Some(did_resolver)).await?;
+
+    let recipient = SecretKey::new();
+    let sender = SecretKey::new();
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let dm = |i: usize| -> Result<DwnRequest, Error> {
+        let message = DmMessage::RecordUpdated(RecordUpdated::new(&path, i.to_string().as_bytes()));
+        MutableAgentRequest::create_dm(Uuid::new_v4(), message, Signer::Right(sender.clone()), recipient.public_key())?.into_dwn_request()
+    };
+    //Rows written before fingerprints, keyed by a random uuid and indexed by the full key
+    let DwnRequest::CreateDM(legacy) = dm(0)? else {panic!()};
+    dwn.dms_database.set(&UuidKeyed::new(legacy.clone())).await?;
+    let resend = dm(1)?;
+    dwn.process_request(resend.clone()).await?;
+    dwn.process_request(resend).await?;
+    dwn.process_request(dm(2)?).await?;
+    assert_eq!(dwn.dms_database.keys().await?.len(), 3);
+
+    let read = SignedObject::from_key(&recipient, (DmCursor::default(), 10))?;
+    let DwnResponse::ReadDM(items, page) = dwn.process_request(DwnRequest::ReadDM(read.clone())).await? else {panic!()};
+    assert_eq!(items.len(), 3);
+    //Moving it over restamped it after the others
+    assert_eq!(items[2], legacy);
+    assert_eq!(page.remaining, 0);
+    assert_eq!(dwn.dms_database.keys().await?.len(), 3);
+    //Migrated, so the legacy item resent is acknowledged without a row
+    dwn.process_request(DwnRequest::CreateDM(legacy)).await?;
+    let DwnResponse::ReadDM(again, _) = dwn.process_request(DwnRequest::ReadDM(read)).await? else {panic!()};
+    assert_eq!(again, items);
+    assert!(dwn.dms_database.get_all::<UuidKeyed<DwnItem>>().await.map(|r| r.is_empty()).unwrap_or(true));
+    Ok(())
+}
+
+#[tokio::test]
+async fn dm_deleted_cursor() -> Result<(), Error> {
+    use crate::agent::structs::{MutableAgentRequest, DmMessage};
+    use crate::dids::signing::{Signer, SignedObject};
+    use crate::dwn::structs::{DmCursor, DmPage, StoredDM};
+    use simple_database::Indexable;
+
+    let mut did_resolver = MemoryDidResolver::new();
+    let (id, doc) = get_server(1)?;
+    did_resolver.store(Box::new(doc.clone()));
+    let did_resolver: Box<dyn DidResolver> = Box::new(did_resolver);
+    let dwn = Dwn::scratch::<MemoryStore>(id, Some(did_resolver)).await?;
+
+    let recipient = SecretKey::new();
+    let sender = SecretKey::new();
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    for i in 0..3 {
+        let message = DmMessage::RecordUpdated(RecordUpdated::new(&path, i.to_string().as_bytes()));
+        let req = MutableAgentRequest::create_dm(Uuid::new_v4(), message, Signer::Right(sender.clone()), recipient.public_key())?;
+        dwn.process_request(req.into_dwn_request()?).await?;
+    }
+    let read = |cursor: DmCursor| {
+        let (dwn, read) = (&dwn, SignedObject::from_key(&recipient, (cursor, 2)));
+        async move {
+            let DwnResponse::ReadDM(items, page) = dwn.process_request(DwnRequest::ReadDM(read?)).await? else {panic!()};
+            Ok::<_, Error>((items, page))
+        }
+    };
+
+    let (first, page) = read(DmCursor::default()).await?;
+    assert_eq!((first.len(), page.remaining), (2, 1));
+    let (last, _) = read(page.next.clone()).await?;
+    assert_eq!(last.len(), 1);
+
+    //The page read is deleted, the read after it ends instead of starting over
+    for item in &first {
+        dwn.dms_database.delete(&StoredDM{item: item.clone(), stored: None}.primary_key()).await?;
+    }
+    let (items, ended) = read(page.next.clone()).await?;
+    assert!(items.is_empty());
+    assert_eq!(ended, DmPage{next: DmCursor{since: page.next.since, after: None}, remaining: 0, stored: Vec::new()});
+    //Only DMs stored after the deleted one are left to read from there
+    let (items, page) = read(ended.next).await?;
+    assert_eq!((items, page.remaining), (last, 0));
+    Ok(())
+}
+
+fn string_array(name: &str) -> Result<Protocol, Error> {
+    let schema = serde_json::json!({"type": "array", "items": {"type": "string", "pattern": "^[a-z]+$"}});
+    Protocol::new(name, false, PermissionOptions::new(true, true, false, None), Some(schema.to_string()), None)
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn blocking_validation() -> Result<(), Error> {
+    use crate::dids::signing::SignedObject;
+
+    let mut did_resolver = MemoryDidResolver::new();
+    let (id, doc) = get_server(1)?;
+    did_resolver.store(Box::new(doc.clone()));
+    let did_resolver: Box<dyn DidResolver> = Box::new(did_resolver);
+    let dwn = Dwn::scratch::<MemoryStore>(id, Some(did_resolver)).await?;
+    let read = SignedObject::from_key(&SecretKey::new(), String::new())?;
+
+    let protocol = string_array("big")?;
+    let payload = serde_json::to_vec(&vec!["abcdefghijklmnop"; 5*1024*1024/19])?;
+    assert!(payload.len() > 5_000_000);
+    let validators = Validators::default();
+    let start = std::time::Instant::now();
+    let big = tokio::spawn(async move {validators.validate_async(&protocol, &payload, false).await});
+    //With one executor thread an inline check would run to the end here before the read gets a turn
+    tokio::task::yield_now().await;
+    let small = tokio::time::timeout(std::time::Duration::from_millis(500), dwn.process_request(DwnRequest::ReadPrivate(read))).await;
+    assert!(matches!(small, Ok(Ok(DwnResponse::ReadPrivate(None)))));
+    assert!(start.elapsed() < std::time::Duration::from_millis(500));
+    assert!(!big.is_finished());
+    big.await.unwrap()?;
+    Ok(())
+}
+
+//Cost of the schema check by payload size, run with --ignored --nocapture. At 64 KiB a check holds
+//its thread for over 10ms in a debug build, below that the copy into the blocking pool is not worth it
+#[tokio::test]
+#[ignore]
+async fn validation_threshold() -> Result<(), Error> {
+    let protocol = string_array("bench")?;
+    let mut inline = Validators::default();
+    inline.set_blocking_threshold(usize::MAX);
+    let mut pooled = Validators::default();
+    pooled.set_blocking_threshold(0);
+    for kib in [1, 16, 64, 256, 1024] {
+        let payload = serde_json::to_vec(&vec!["abcdefghijklmnop"; kib*1024/19])?;
+        for (name, validators) in [("inline", &inline), ("pooled", &pooled)] {
+            let start = std::time::Instant::now();
+            for _ in 0..20 {validators.validate_async(&protocol, &payload, false).await?;}
+            println!("{:>5} KiB {}: {:?}", kib, name, start.elapsed()/20);
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn legacy_agent_keys() -> Result<(), Error> {
+    use crate::agent::structs::{MutableAgentRequest, PathedKey};
+    use crate::agent::AgentKeys;
+    use crate::dids::signing::Signer;
+    use crate::dids::DidKeyPair;
+    use crate::dwn::structs::PublicRecord;
+    use simple_database::database::IndexBuilder;
+    use simple_crypto::PublicKey;
+
+    let net = LocalNet::new(2).await?;
+    let (dwns, bob_url, bob_did) = (&net.dwns, &net.urls[1], net.did(1));
+
+    //Bob's agent_keys as written before agents were scoped to paths
+    let identity = serde_json::to_value(&net.users[1].0)?;
+    let sig_key = serde_json::from_value::<DidKeyPair>(identity["sig_key"].clone())?;
+    let enc_key = serde_json::from_value::<PathedKey>(identity["enc_key"].clone())?;
+    let legacy = Protocol::new(
+        "agent_keys", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema_for!(Vec<PublicKey>))?), None
+    )?;
+    let index = IndexBuilder::build(vec![("type", "agent_keys")])?;
+    let record = PublicRecord::new(None, legacy, &serde_json::to_vec(&vec![enc_key.key.public_key()])?, Some(index))?;
+    let req = MutableAgentRequest::create_public(record, Signer::Left(sig_key))?;
+    net.dwn(1).process_request(req.into_dwn_request()?).await?;
+
+    let agent = net.agent(0).await?;
+    let mut cache = CompilerCache::default();
+    let keys = *agent.process_commands(&mut cache, vec![Box::new(commands::ReadAgentKeys::new(bob_did.clone()))]).await?
+        .remove(0).downcast::<AgentKeys>()?;
+    assert!(keys.is_legacy());
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    assert_eq!(keys.keys_for(&path), vec![enc_key.key.public_key()]);
+
+    let protocol = SystemProtocols::usize();
+    let before = dwns.sent(bob_url).len();
+    agent.process_commands(&mut cache, vec![Box::new(commands::CreatePrivate::new(Record::new(path.clone(), protocol, b"1"), None))]).await?
+        .remove(0).downcast::<()>()?;
+    agent.process_commands(&mut cache, vec![scripts::Share::new(path, None, bob_did.clone())]).await?.remove(0).downcast::<()>()?;
+    assert!(dwns.sent(bob_url)[before..].iter().any(|r| matches!(r, DwnRequest::CreatePrivate(_))));
+
+    //Bob's next Init rewrites the record as V2
+    net.agent(1).await?;
+    let keys = *agent.process_commands(&mut cache, vec![Box::new(commands::ReadAgentKeys::new(bob_did))]).await?
+        .remove(0).downcast::<AgentKeys>()?;
+    assert_eq!(keys, AgentKeys::V2(BTreeMap::from([(RecordPath::root(), enc_key.key.public_key())])));
+    Ok(())
+}
+
+#[tokio::test]
+async fn seeded_ids() -> Result<(), Error> {
+    let (server, server_doc) = get_server(1)?;
+    let (alice, alice_doc) = get_user(vec![server_doc.did()])?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+
+    //Signatures and ciphertexts stay random, the keys each private request is discovered by do not
+    let run = |seed: u64| {
+        let (server, server_doc, alice, alice_doc, path) = (server.clone(), server_doc.clone(), alice.clone(), alice_doc.clone(), path.clone());
+        async move {
+            let mut did_resolver = MemoryDidResolver::new();
+            did_resolver.store(Box::new(server_doc.clone()));
+            did_resolver.store(Box::new(alice_doc));
+            let did_resolver: Box<dyn DidResolver> = Box::new(did_resolver);
+            let url = did_resolver.get_endpoints(&[server_doc.did()]).await?.remove(0).1;
+            let dwn = Dwn::scratch::<MemoryStore>(server, Some(did_resolver.clone())).await?;
+            let dwns = LocalDwns::from_dwns(BTreeMap::from([(url.clone(), dwn)]));
+            let agent = Agent::with_client(Wallet::new(alice).root(), did_resolver, Box::new(dwns.clone()), None).await?.with_rng_seed(seed);
+            let mut cache = CompilerCache::default();
+            let record = Record::new(path.clone(), SystemProtocols::usize(), b"1");
+            agent.process_commands(&mut cache, vec![Box::new(commands::CreatePrivate::new(record, None))]).await?;
+            agent.process_commands(&mut cache, vec![
+                scripts::PublishReadCapability::new(path, PermissionOptions::new(false, true, false, None))
+            ]).await?;
+            Ok::<_, Error>(dwns.sent(&url).into_iter().flat_map(|r| match r {
+                DwnRequest::CreatePrivate(s) => Some(s.inner().discover.clone()),
+                DwnRequest::UpdatePrivate(s) | DwnRequest::GuardedUpdatePrivate(s, _) => Some(s.inner().inner().discover.clone()),
+                _ => None
+            }).collect::<Vec<_>>())
+        }
+    };
+    let first = run(7).await?;
+    assert!(first.len() > 2);
+    assert_eq!(run(7).await?, first);
+    assert_ne!(run(8).await?, first);
+    Ok(())
+}
+
+#[tokio::test]
+async fn delete_private_child() -> Result<(), Error> {
+    let net = LocalNet::new(1).await?;
+    let agent = net.agent(0).await?;
+    let mut cache = CompilerCache::default();
+
+    let rooms = Protocol::new(
+        "rooms_protocol", true,
+        PermissionOptions::new(true, true, true, Some(ChannelPermissionOptions::new(true, true))),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?),
+        Some(ChannelProtocol::new(Some(vec![&SystemProtocols::usize()])))
+    )?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    agent.run::<()>(&mut cache, Box::new(commands::CreatePrivate::new(Record::new(path.clone(), rooms, b"{}"), None))).await?;
+    let children = (0..5).map(|i| {
+        let record = Record::new(path.extend(&[Uuid::new_v4()])?, SystemProtocols::usize(), i.to_string().as_bytes());
+        Ok(scripts::CreatePrivate::new(record, None))
+    }).collect::<Result<Vec<_>, Error>>()?;
+    agent.run_all::<()>(&mut cache, children).await?;
+
+    agent.run::<()>(&mut cache, scripts::DeletePrivateChild::new(path.clone(), 2)).await?;
+    let records = agent.run::<Vec<Record>>(&mut cache, scripts::Scan::new(path.clone(), 0)).await?;
+    assert_eq!(records.len(), 4);
+    assert!(!records.iter().any(|r| r.payload == b"2"));
+    let slots = agent.run::<BTreeMap<usize, ChildSlot>>(&mut cache, scripts::ScanSlots::new(path, 0)).await?;
+    assert_eq!(slots[&2], ChildSlot::Tombstoned);
+    Ok(())
+}
+
+//A slot written while AwaitChildren polls shows up before the timeout, the way a message that has
+//not replicated yet arrives after a reconnect
+#[cfg(feature = "unstable-internals")]
+#[tokio::test]
+async fn await_children() -> Result<(), Error> {
+    use crate::agent::custom_commands::{Command, Header, CompilerMemory};
+    use crate::agent::structs::{Callback, MutableAgentRequest, Task, Tasks};
+    use std::time::{Duration, Instant};
+
+    //Writes a child and its pointer at the index given rather than the next one
+    #[derive(serde::Serialize, Debug, Clone)]
+    struct CreateChildAt(Record, usize);
+    #[async_trait::async_trait]
+    impl Command for CreateChildAt {
+        async fn process<'a>(
+            self: Box<Self>, uuid: Uuid, header: Header,
+            memory: &mut CompilerMemory<'a>, _: &mut CompilerCache
+        ) -> Result<Tasks, Error> {
+            let CreateChildAt(record, index) = *self;
+            let parent = memory.get_perms(header.enc, &record.path.parent()?, None)?;
+            let perms = memory.get_perms(header.enc, &record.path, Some(&record.protocol))?;
+            let pointer = MutableAgentRequest::create_private_child(&parent, &record.protocol.subset_permission(perms.clone(), None)?, index)?;
+            let create = MutableAgentRequest::create_private(perms, None, record.protocol, record.payload, None)?;
+            Task::waiting(uuid, header.clone(), Callback::new(commands::EnsureEmpty::new), vec![
+                Task::MutableRequest(header.clone(), create, 0),
+                Task::MutableRequest(header, pointer, 0)
+            ])
+        }
+    }
+
+    let net = LocalNet::new(1).await?;
+    let agent = net.agent(0).await?;
+    let mut cache = CompilerCache::default();
+    let chat = Protocol::new(
+        "chat", true,
+        PermissionOptions::new(true, true, true, Some(ChannelPermissionOptions::new(true, true))),
+        None, Some(ChannelProtocol::new(Some(vec![&SystemProtocols::usize()])))
+    )?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(path.clone(), chat, &[]), None)).await?;
+    let write = |index: usize| Ok::<_, Error>(Box::new(CreateChildAt(
+        Record::new(path.extend(&[Uuid::new_v4()])?, SystemProtocols::usize(), index.to_string().as_bytes()), index
+    )));
+    agent.run_all::<()>(&mut cache, vec![write(0)?, write(1)?, write(3)?]).await?;
+
+    let slots = agent.run::<BTreeMap<usize, ChildSlot>>(&mut cache, scripts::ScanSlots::new(path.clone(), 0)).await?;
+    let payload = |slot: &ChildSlot| match slot {ChildSlot::Present(r) => Some(r.payload.clone()), _ => None};
+    assert_eq!([0, 1, 3].map(|i| payload(&slots[&i])), [Some(b"0".to_vec()), Some(b"1".to_vec()), Some(b"3".to_vec())]);
+    assert_eq!(slots[&2], ChildSlot::Missing);
+
+    let timeout = Duration::from_secs(10);
+    let started = Instant::now();
+    let mut writer = CompilerCache::default();
+    let (slots, written) = tokio::join!(
+        agent.run::<BTreeMap<usize, ChildSlot>>(&mut cache, scripts::AwaitChildren::new(path.clone(), vec![2, 3], timeout)),
+        async {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            agent.run::<()>(&mut writer, write(2)?).await
+        }
+    );
+    written?;
+    let slots = slots?;
+    assert!(started.elapsed() < timeout);
+    assert_eq!([2, 3].map(|i| payload(&slots[&i])), [Some(b"2".to_vec()), Some(b"3".to_vec())]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn usage_report() -> Result<(), Error> {
+    use crate::agent::{UsageGroup, UsageTotal};
+    use crate::dwn::structs::PublicRecord;
+
+    let net = LocalNet::new(1).await?;
+    let agent = net.agent(0).await?;
+    let mut cache = CompilerCache::default();
+
+    let notes = Protocol::new(
+        "notes", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let mut uuids = Vec::new();
+    for size in [10, 100, 1000] {
+        let record = PublicRecord::new(None, notes.clone(), &serde_json::to_vec(&"n".repeat(size-2))?, None)?;
+        uuids.push(record.uuid);
+        agent.process_commands(&mut cache, vec![scripts::CreatePublic::new(record, None)]).await?;
+    }
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    agent.process_commands(&mut cache, vec![
+        Box::new(commands::CreatePrivate::new(Record::new(path, SystemProtocols::usize(), b"1"), None))
+    ]).await?.remove(0).downcast::<()>()?;
+
+    let by_protocol = agent.run::<BTreeMap<String, UsageTotal>>(&mut cache, scripts::UsageReport::new(UsageGroup::Protocol)).await?;
+    assert_eq!(by_protocol[&notes.label()], UsageTotal{records: 3, bytes: 1110});
+    //Private sizes are of the ciphertext
+    let private = by_protocol[&SystemProtocols::usize().label()];
+    assert!(private.records >= 1 && private.bytes > private.records);
+    let by_path = agent.run::<BTreeMap<String, UsageTotal>>(&mut cache, scripts::UsageReport::new(UsageGroup::PathDepth(0))).await?;
+    assert_eq!(by_path["public"].records, 3);
+    let error = agent.run::<Vec<Record>>(&mut cache, scripts::UsageReport::new(UsageGroup::Protocol)).await.unwrap_err();
+    assert_eq!(error.code(), "BAD_RESPONSE");
+    assert!(error.to_string().contains("Vec<web5_rust::model::structs::Record>") && error.to_string().contains("UsageTotal"));
+
+    agent.process_commands(&mut cache, vec![scripts::DeletePublic::new(uuids[2], None)]).await?;
+    let by_protocol = agent.run::<BTreeMap<String, UsageTotal>>(&mut cache, scripts::UsageReport::new(UsageGroup::Protocol)).await?;
+    assert_eq!(by_protocol[&notes.label()], UsageTotal{records: 2, bytes: 110});
+    Ok(())
+}
+
+#[tokio::test]
+async fn scan_until() -> Result<(), Error> {
+    use crate::agent::{ScanPredicate, ScanStop};
+
+    let net = LocalNet::new(1).await?;
+    let (dwns, url) = (&net.dwns, &net.urls[0]);
+    let agent = net.agent(0).await?;
+    let mut cache = CompilerCache::default();
+
+    let rooms = Protocol::new(
+        "rooms_protocol", true,
+        PermissionOptions::new(true, true, true, Some(ChannelPermissionOptions::new(true, true))),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?),
+        Some(ChannelProtocol::new(Some(vec![&SystemProtocols::usize()])))
+    )?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    agent.process_commands(&mut cache, vec![
+        Box::new(commands::CreatePrivate::new(Record::new(path.clone(), rooms, b"{}"), None))
+    ]).await?.remove(0).downcast::<()>()?;
+    //A scan that kept going would read all of them and a batch past the end
+    for i in 0..40 {
+        let record = Record::new(path.extend(&[Uuid::new_v4()])?, SystemProtocols::usize(), i.to_string().as_bytes());
+        agent.process_commands(&mut cache, vec![Box::new(commands::CreatePrivate::new(record, None))]).await?;
+    }
+
+    let before = dwns.sent(url).len();
+    let predicate = ScanPredicate::PayloadHash(b"12".to_vec().hash().to_string());
+    let (records, stop) = *agent.process_commands(&mut cache, vec![scripts::Scan::until(path.clone(), predicate)]).await?
+        .remove(0).downcast::<(Vec<Record>, ScanStop)>()?;
+    assert_eq!(stop, ScanStop::Matched);
+    assert_eq!(records.len(), 13);
+    assert_eq!(records[12].payload, b"12");
+    //Every child read is a pointer and a record read
+    let reads = dwns.sent(url)[before..].iter().filter(|r| matches!(r, DwnRequest::ReadPrivate(_))).count();
+    assert!(reads < 2*32, "{} reads", reads);
+    Ok(())
+}
+
+#[tokio::test]
+async fn root_path() -> Result<(), Error> {
+    use crate::agent::structs::PrivateRecord;
+
+    let (agent, _, _) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let root = RecordPath::root();
+
+    let (record, exists) = agent.run::<(Option<Box<PrivateRecord>>, bool)>(
+        &mut cache, Box::new(commands::ReadPrivate::path(root.clone()))
+    ).await?;
+    assert!(exists && record.unwrap().protocol == SystemProtocols::root());
+
+    let record = Record::new(root.clone(), SystemProtocols::usize(), b"1");
+    for (command, op) in [
+        (scripts::CreatePrivate::new(record.clone(), None), "create"),
+        (scripts::UpdatePrivate::new(record, None), "update"),
+        (scripts::DeletePrivate::new(root), "delete"),
+    ] {
+        let error = agent.run::<()>(&mut cache, command).await.unwrap_err();
+        assert_eq!(error.code(), "BAD_REQUEST");
+        assert!(error.to_string().contains(&format!("Cannot {} the root record", op)));
+    }
+
+    let child = Record::new(RecordPath::new(&[Uuid::new_v4()])?, SystemProtocols::usize(), b"1");
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(child, None)).await?;
+    assert_eq!(agent.run::<Vec<Record>>(&mut cache, scripts::Scan::new(RecordPath::root(), 0)).await?.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn public_pages() -> Result<(), Error> {
+    use crate::dwn::structs::PublicRecord;
+    use crate::SortPaging;
+    use simple_database::database::{IndexBuilder, SortOptions, Filter};
+
+    let (agent, _, _) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let notes = Protocol::new(
+        "notes", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let creates = (0..25u64).map(|n| {
+        let index = IndexBuilder::build(vec![("n", n)])?;
+        Ok(scripts::CreatePublic::new(PublicRecord::new(None, notes.clone(), b"{}", Some(index))?, None))
+    }).collect::<Result<Vec<_>, Error>>()?;
+    agent.run_all::<()>(&mut cache, creates).await?;
+
+    let filters = Filters::new(vec![("protocol", Filter::equal(notes.uuid().to_string()))]);
+    let mut seen = Vec::new();
+    let mut pages = Vec::new();
+    let mut cursor = None;
+    loop {
+        let sort = SortOptions::new("n").with_page(Some(10), cursor)?;
+        let (records, next) = agent.run::<(Vec<PublicRecord>, Option<Vec<u8>>)>(
+            &mut cache, scripts::ReadPublic::new(filters.clone(), Some(sort))
+        ).await?;
+        pages.push(records.len());
+        seen.extend(records.iter().map(|r| r.index["n"].clone()));
+        cursor = next;
+        if cursor.is_none() {break;}
+    }
+    assert_eq!(pages, vec![10, 10, 5]);
+    assert_eq!(seen, (0..25u64).map(|n| n.into()).collect::<Vec<simple_database::database::Value>>());
+    Ok(())
+}
+
+//Counts resolutions of one did, each signature check by that did resolves it
+#[derive(Debug, Clone)]
+struct CountingResolver(MemoryDidResolver, Did, std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+#[async_trait::async_trait]
+impl DidResolver for CountingResolver {
+    async fn resolve(&self, did: &Did) -> Result<Option<Box<dyn DidDocument>>, Error> {
+        if *did == self.1 {self.2.fetch_add(1, std::sync::atomic::Ordering::SeqCst);}
+        self.0.resolve(did).await
+    }
+}
+
+#[tokio::test]
+async fn public_limit_verification() -> Result<(), Error> {
+    use crate::agent::structs::MutableAgentRequest;
+    use crate::dids::signing::Signer;
+    use crate::dids::DidKeyPair;
+    use crate::dwn::structs::PublicRecord;
+    use crate::SortPaging;
+    use simple_database::database::{IndexBuilder, SortOptions};
+
+    let (server, server_doc) = get_server(1)?;
+    let (user, user_doc) = get_user(vec![server_doc.did()])?;
+    let mut resolver = MemoryDidResolver::new();
+    resolver.store(Box::new(server_doc.clone()));
+    resolver.store(Box::new(user_doc.clone()));
+    let resolutions = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let did_resolver: Box<dyn DidResolver> = Box::new(CountingResolver(resolver, user_doc.did(), resolutions.clone()));
+    let url = did_resolver.get_endpoints(&[server_doc.did()]).await?.remove(0).1;
+    let dwns = LocalDwns::new(&*did_resolver, vec![(server, server_doc)]).await?;
+
+    let sig_key = serde_json::from_value::<DidKeyPair>(serde_json::to_value(&user)?["sig_key"].clone())?;
+    let notes = Protocol::new(
+        "notes", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    for n in 0..500u64 {
+        let record = PublicRecord::new(None, notes.clone(), b"{}", Some(IndexBuilder::build(vec![("n", n)])?))?;
+        let req = MutableAgentRequest::create_public(record, Signer::Left(sig_key.clone()))?;
+        dwns.dwns[&url].process_request(req.into_dwn_request()?).await?.into_empty()?;
+    }
+
+    let agent = Agent::with_client(Wallet::new(user).root(), did_resolver, Box::new(dwns), None).await?;
+    let mut cache = CompilerCache::default();
+    let before = resolutions.load(std::sync::atomic::Ordering::SeqCst);
+    let sort = SortOptions::new("n").with_page(Some(10), None)?;
+    let (records, cursor) = agent.run::<(Vec<PublicRecord>, Option<Vec<u8>>)>(
+        &mut cache, scripts::ReadPublic::new(Filters::new(vec![]), Some(sort))
+    ).await?;
+    assert_eq!(records.iter().map(|r| r.index["n"].clone()).collect::<Vec<_>>(), (0..10u64).map(|n| n.into()).collect::<Vec<_>>());
+    assert_eq!(cursor, Some(records[9].uuid.as_bytes().to_vec()));
+    assert!(resolutions.load(std::sync::atomic::Ordering::SeqCst) - before < 50);
+    Ok(())
+}
+
+#[tokio::test]
+async fn did_cache() -> Result<(), Error> {
+    use crate::dids::{DefaultDidResolver, DidKeyPair};
+    use crate::dids::signing::SignedObject;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    let (user, user_doc) = get_user(vec![])?;
+    let mut source = MemoryDidResolver::new();
+    source.store(Box::new(user_doc.clone()));
+    let resolutions = std::sync::Arc::new(AtomicUsize::new(0));
+    let source = Box::new(CountingResolver(source, user_doc.did(), resolutions.clone()));
+    let path = std::env::temp_dir().join(format!("web5-did-cache-{}", Uuid::new_v4()));
+    let resolver = DefaultDidResolver::new::<MemoryStore>(Some(path)).await?.with_source(source.clone());
+
+    let sig_key = serde_json::from_value::<DidKeyPair>(serde_json::to_value(&user)?["sig_key"].clone())?;
+    let signed = (0..50u64).map(|n| SignedObject::from_keypair(&sig_key, n)).collect::<Result<Vec<_>, Error>>()?;
+    for result in futures::future::join_all(signed.iter().map(|s| s.verify(&resolver, None))).await {result?;}
+    assert_eq!(resolutions.load(Ordering::SeqCst), 1);
+
+    resolver.invalidate(&user_doc.did()).await?;
+    signed[0].verify(&resolver, None).await?;
+    assert_eq!(resolutions.load(Ordering::SeqCst), 2);
+
+    //Expired documents are served while the refresh runs
+    let path = std::env::temp_dir().join(format!("web5-did-cache-{}", Uuid::new_v4()));
+    let resolver = DefaultDidResolver::new::<MemoryStore>(Some(path)).await?
+        .with_source(source).with_ttl(Duration::ZERO).with_stale_while_revalidate(true);
+    resolver.resolve(&user_doc.did()).await?;
+    assert_eq!(resolutions.load(Ordering::SeqCst), 3);
+    assert!(resolver.resolve(&user_doc.did()).await?.is_some());
+    assert_eq!(resolutions.load(Ordering::SeqCst), 3);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(resolutions.load(Ordering::SeqCst), 4);
+    Ok(())
+}
+
+#[tokio::test]
+async fn filter_expr() -> Result<(), Error> {
+    use crate::dwn::structs::PublicRecord;
+    use crate::prelude::{FilterExpr, Filter};
+    use simple_database::database::IndexBuilder;
+
+    let (agent, dwns, url) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let posts = Protocol::new(
+        "posts", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    for (kind, author) in [("room", "x"), ("room", "y"), ("channel", "x"), ("channel", "y"), ("note", "x")] {
+        let index = IndexBuilder::build(vec![("kind", kind), ("author", author)])?;
+        let record = PublicRecord::new(None, posts.clone(), b"{}", Some(index))?;
+        agent.run::<()>(&mut cache, scripts::CreatePublic::new(record, None)).await?;
+    }
+    let kinds = |records: Vec<PublicRecord>| {
+        let mut kinds = records.iter().map(|r| format!("{:?}/{:?}", r.index["kind"], r.index["author"])).collect::<Vec<_>>();
+        kinds.sort();
+        kinds
+    };
+    let protocol = FilterExpr::prop("protocol", Filter::equal(posts.uuid().to_string()));
+
+    //kind == room OR (kind == channel AND author == x)
+    let expr = FilterExpr::And(vec![protocol.clone(), FilterExpr::Or(vec![
+        FilterExpr::prop("kind", Filter::equal("room")),
+        FilterExpr::And(vec![FilterExpr::prop("kind", Filter::equal("channel")), FilterExpr::prop("author", Filter::equal("x"))])
+    ])]);
+    assert_eq!(expr.planned(), Filters::new(vec![("protocol", Filter::equal(posts.uuid().to_string()))]));
+    let (records, _) = agent.run::<(Vec<PublicRecord>, Option<Vec<u8>>)>(&mut cache, scripts::ReadPublic::new(expr.clone(), None)).await?;
+    assert_eq!(kinds(records), vec!["channel/x", "room/x", "room/y"]);
+    let DwnResponse::ReadPublic(items, _) = dwns.dwns[&url].process_request(DwnRequest::ReadPublic(expr.clone(), None)).await? else {
+        panic!("Expected ReadPublic")
+    };
+    assert_eq!(items.len(), 3);
+
+    let not_notes = FilterExpr::And(vec![protocol, FilterExpr::new_not(FilterExpr::prop("kind", Filter::equal("note")))]);
+    let (records, _) = agent.run::<(Vec<PublicRecord>, Option<Vec<u8>>)>(&mut cache, scripts::ReadPublic::new(not_notes, None)).await?;
+    assert_eq!(records.len(), 4);
+
+    //Filters keep their wire shape both ways
+    let filters = Filters::new(vec![("kind", Filter::equal("room")), ("author", Filter::equal("x"))]);
+    assert_eq!(serde_json::to_value(FilterExpr::from(filters.clone()))?, serde_json::to_value(&filters)?);
+    assert_eq!(serde_json::from_value::<FilterExpr>(serde_json::to_value(&filters)?)?, FilterExpr::from(filters));
+    let json = serde_json::to_value(&expr)?;
+    assert_eq!(serde_json::to_value(serde_json::from_value::<FilterExpr>(json.clone())?)?, json);
+    Ok(())
+}
+
+#[tokio::test]
+async fn dwn_admin_stats() -> Result<(), Error> {
+    use crate::agent::structs::MutableAgentRequest;
+    use crate::dids::signing::{Signer, SignedObject};
+    use crate::dids::DidKeyPair;
+    use crate::dwn::structs::{AdminRequest, AdminResponse, DwnItem, PublicRecord};
+    use crate::dwn::router::RouterConfig;
+
+    let (server, server_doc) = get_server(1)?;
+    let mut resolver = MemoryDidResolver::new();
+    resolver.store(Box::new(server_doc.clone()));
+    let mut users = Vec::new();
+    for _ in 0..3 {
+        let (user, doc) = get_user(vec![server_doc.did()])?;
+        resolver.store(Box::new(doc.clone()));
+        let sig_key = serde_json::from_value::<DidKeyPair>(serde_json::to_value(&user)?["sig_key"].clone())?;
+        users.push((doc.did(), sig_key));
+    }
+    let [(a, a_key), (b, b_key), (admin, admin_key)] = <[_; 3]>::try_from(users).unwrap();
+    let resolver: Box<dyn DidResolver> = Box::new(resolver);
+    let dwn = Dwn::scratch::<MemoryStore>(server, Some(resolver.clone())).await?.with_admins(&[admin]);
+
+    let notes = Protocol::new(
+        "notes", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let mut a_records = Vec::new();
+    for (key, payload) in [(&a_key, &b"{}"[..]), (&a_key, b"{\"a\":1}"), (&b_key, b"{}")] {
+        let record = PublicRecord::new(None, notes.clone(), payload, None)?;
+        if key == &a_key {a_records.push(record.uuid);}
+        let req = MutableAgentRequest::create_public(record, Signer::Left(key.clone()))?;
+        dwn.process_request(req.into_dwn_request()?).await?.into_empty()?;
+    }
+    let (_, a_com) = resolver.resolve_dwn_keys(&a).await?;
+    for payload in [vec![0; 10], vec![1; 5]] {
+        dwn.process_request(DwnRequest::CreateDM(DwnItem{discover: a_com.clone(), delete: None, payload, expires: None})).await?;
+    }
+    let keys = (0..3).map(|_| SecretKey::new()).collect::<Vec<_>>();
+    for key in &keys {
+        let item = DwnItem{discover: key.public_key(), delete: Some(key.public_key()), payload: vec![0; 4], expires: None};
+        dwn.process_request(DwnRequest::CreatePrivate(SignedObject::from_key(key, item)?)).await?.into_empty()?;
+    }
+    dwn.process_request(DwnRequest::delete_private(keys[0].public_key(), &keys[0])?).await?.into_empty()?;
+    let req = MutableAgentRequest::delete_public(a_records[1], Signer::Left(a_key.clone()))?;
+    dwn.process_request(req.into_dwn_request()?).await?.into_empty()?;
+
+    let AdminResponse::Stats(stats) = dwn.admin(SignedObject::from_keypair(&admin_key, AdminRequest::Stats)?).await?.into_admin()? else {
+        panic!("Expected Stats")
+    };
+    let counts = |usage: &crate::dwn::structs::Usage| (usage.items, usage.bytes);
+    assert_eq!((counts(&stats.private), counts(&stats.public), counts(&stats.dms)), ((2, 8), (2, 4), (2, 15)));
+    let tenants = stats.tenants.iter().map(|t| (t.tenant.clone(), (counts(&t.public), counts(&t.dms)))).collect::<BTreeMap<_, _>>();
+    assert_eq!(tenants, BTreeMap::from([(a.clone(), ((1, 2), (2, 15))), (b.clone(), ((1, 2), (0, 0)))]));
+    assert!(stats.tenants.iter().all(|t| t.public.last_activity.is_some()));
+
+    //Only the Dwn and its admins, over json rpc as well
+    assert!(dwn.admin(SignedObject::from_keypair(&b_key, AdminRequest::Stats)?).await?.is_invalid_auth());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let url = url::Url::parse(&format!("http://{}", listener.local_addr()?))?;
+    let _server = tokio::spawn(JsonRpcServer{}.listen(dwn.clone(), listener)?);
+    let client = JsonRpcClient::new(&RouterConfig::default())?;
+    let signed = SignedObject::from_keypair(&dwn.com_key, AdminRequest::TenantDetail(a.clone()))?;
+    let AdminResponse::TenantDetail(detail) = client.admin(url.clone(), signed).await?.into_admin()? else {
+        panic!("Expected TenantDetail")
+    };
+    assert_eq!((detail.tenant, counts(&detail.public), counts(&detail.dms)), (a, (1, 2), (2, 15)));
+    let signed = SignedObject::from_keypair(&a_key, AdminRequest::TenantDetail(b))?;
+    assert!(client.admin(url, signed).await?.is_invalid_auth());
+    Ok(())
+}
+
+#[tokio::test]
+async fn subscribe_dm_over_json_rpc() -> Result<(), Error> {
+    use crate::dids::signing::{Signer, SignedObject};
+    use crate::dwn::structs::{DmCursor, DwnItem, Packet};
+    use crate::dwn::router::RouterConfig;
+    use crate::dwn::traits::Client;
+
+    let (server, _) = get_server(1)?;
+    let dwn = Dwn::scratch::<MemoryStore>(server, None).await?.with_dm_wait(std::time::Duration::from_secs(10));
+    let packet = |request: DwnRequest| -> Result<String, Error> {
+        let payload = serde_json::to_vec(&vec![(Uuid::new_v4(), request)])?;
+        Ok(serde_json::to_string(&Packet{
+            recipient: dwn.com_key.public.did.clone(), payload: dwn.com_key.public.public_key.encrypt(&payload)?
+        })?)
+    };
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let url = url::Url::parse(&format!("http://{}", listener.local_addr()?))?;
+    let _server = tokio::spawn(JsonRpcServer{}.listen(dwn.clone(), listener)?);
+    let client = JsonRpcClient::new(&RouterConfig::default())?;
+
+    let key = SecretKey::new();
+    let subscribe = packet(DwnRequest::SubscribeDM(SignedObject::new(Signer::Right(key.clone()), (DmCursor::default(), 10))?))?;
+    let item = DwnItem{discover: key.public_key(), delete: None, payload: vec![1], expires: None};
+    let create = packet(DwnRequest::CreateDM(item))?;
+
+    //The DM is stored while the subscription waits, and wakes it well short of the Dwn's wait
+    let start = std::time::Instant::now();
+    let (subscribed, created) = tokio::join!(client.send_request(subscribe, url.clone()), async {
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        let created = client.send_request(create, url.clone()).await;
+        (created, start.elapsed())
+    });
+    let (created, stored_after) = created;
+    assert!(stored_after < std::time::Duration::from_secs(5));
+    let created = serde_json::from_str::<Vec<(Uuid, DwnResponse)>>(&created?)?;
+    assert!(matches!(&created[..], [(_, DwnResponse::Empty)]));
+    let subscribed = serde_json::from_str::<Vec<(Uuid, DwnResponse)>>(&subscribed?)?;
+    assert!(matches!(&subscribed[..], [(_, DwnResponse::ReadDM(items, _))] if items.len() == 1));
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    Ok(())
+}
+
+#[tokio::test]
+async fn pooled_connections() -> Result<(), Error> {
+    use crate::dwn::structs::Packet;
+    use crate::dwn::router::RouterConfig;
+    use crate::dwn::traits::Client;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let (server, _) = get_server(1)?;
+    let dwn = Dwn::scratch::<MemoryStore>(server, None).await?;
+    let payload = serde_json::to_vec(&vec![(Uuid::new_v4(), DwnRequest::Capabilities)])?;
+    let packet = serde_json::to_string(&Packet{
+        recipient: dwn.com_key.public.did.clone(), payload: dwn.com_key.public.public_key.encrypt(&payload)?
+    })?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let _server = tokio::spawn(JsonRpcServer{}.listen(dwn, listener)?);
+
+    //Counts the connections the server is handed, each one is piped through as is
+    let proxy = TcpListener::bind("127.0.0.1:0")?;
+    let url = url::Url::parse(&format!("http://{}", proxy.local_addr()?))?;
+    let connections = std::sync::Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    std::thread::spawn(move || for client in proxy.incoming().flatten() {
+        accepted.fetch_add(1, Ordering::SeqCst);
+        let server = TcpStream::connect(addr).unwrap();
+        let (mut c_read, mut s_write) = (client.try_clone().unwrap(), server.try_clone().unwrap());
+        std::thread::spawn(move || std::io::copy(&mut c_read, &mut s_write));
+        let (mut s_read, mut c_write) = (server, client);
+        std::thread::spawn(move || std::io::copy(&mut s_read, &mut c_write));
+    });
+
+    let send = |config: RouterConfig| {
+        let (packet, url, connections) = (packet.clone(), url.clone(), connections.clone());
+        async move {
+            let client = JsonRpcClient::new(&config)?;
+            let before = connections.load(Ordering::SeqCst);
+            for _ in 0..4 {client.send_request(packet.clone(), url.clone()).await?;}
+            Ok::<_, Error>(connections.load(Ordering::SeqCst) - before)
+        }
+    };
+    assert_eq!(send(RouterConfig::default()).await?, 1);
+    //Clones share the pool of the client they came from
+    let client = JsonRpcClient::new(&RouterConfig::default())?;
+    let before = connections.load(Ordering::SeqCst);
+    client.send_request(packet.clone(), url.clone()).await?;
+    client.clone().send_request(packet.clone(), url.clone()).await?;
+    assert_eq!(connections.load(Ordering::SeqCst) - before, 1);
+    //Without idle connections kept every packet connects again
+    assert_eq!(send(RouterConfig{pool_max_idle: 0, ..RouterConfig::default()}).await?, 4);
+    Ok(())
+}
+
+#[tokio::test]
+async fn filter_logic() -> Result<(), Error> {
+    use crate::agent::structs::MutableAgentRequest;
+    use crate::dids::signing::Signer;
+    use crate::dids::DidKeyPair;
+    use crate::dwn::structs::PublicRecord;
+    use crate::prelude::{Filter, FilterLogic};
+    use simple_database::database::{CmpType, IndexBuilder, Value};
+    use rand::{Rng, SeedableRng};
+
+    //Strings and numbers mixed so some comparisons can not be made
+    fn value(rng: &mut rand::rngs::StdRng) -> Value {
+        match rng.gen_range(0..2) {
+            0 => Value::from(rng.gen_range(0..10u64)),
+            _ => Value::from(["a", "ab", "b"][rng.gen_range(0..3)])
+        }
+    }
+    fn filter(rng: &mut rand::rngs::StdRng, depth: usize) -> Filter {
+        let cmps = [CmpType::GT, CmpType::GTE, CmpType::E, CmpType::LT, CmpType::LTE];
+        match rng.gen_range(0..if depth == 0 {3} else {6}) {
+            0 => Filter::cmp(cmps[rng.gen_range(0..5)].clone(), value(rng)),
+            1 => Filter::contains(value(rng)),
+            2 => Filter::StartsWith(value(rng)),
+            3 => Filter::All((0..rng.gen_range(1..3)).map(|_| filter(rng, depth-1)).collect()),
+            4 => Filter::Any((0..rng.gen_range(1..3)).map(|_| filter(rng, depth-1)).collect()),
+            _ => Filter::new_not(filter(rng, depth-1))
+        }
+    }
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1521);
+    for _ in 0..2000 {
+        let (v, a, b) = (value(&mut rng), filter(&mut rng, 2), filter(&mut rng, 2));
+        let both = a.matches(&v).zip(b.matches(&v));
+        assert_eq!(Filter::All(vec![a.clone(), b.clone()]).matches(&v), both.map(|(a, b)| a && b), "{:?} {:?} {:?}", v, a, b);
+        assert_eq!(Filter::Any(vec![a.clone(), b.clone()]).matches(&v), both.map(|(a, b)| a || b), "{:?} {:?} {:?}", v, a, b);
+        assert_eq!(Filter::new_not(a.clone()).matches(&v), a.matches(&v).map(|a| !a));
+        let (low, high) = (rng.gen_range(0..10u64), rng.gen_range(0..10u64));
+        let n = rng.gen_range(0..10u64);
+        assert_eq!(Filter::range(low, high).matches(&Value::from(n)), Some(low <= n && n <= high));
+    }
+
+    //A range read from the Dwn returns the records in it
+    let (server, server_doc) = get_server(1)?;
+    let (user, user_doc) = get_user(vec![server_doc.did()])?;
+    let mut resolver = MemoryDidResolver::new();
+    resolver.store(Box::new(server_doc.clone()));
+    resolver.store(Box::new(user_doc));
+    let dwn = Dwn::scratch::<MemoryStore>(server, Some(Box::new(resolver))).await?;
+    let sig_key = serde_json::from_value::<DidKeyPair>(serde_json::to_value(&user)?["sig_key"].clone())?;
+    let notes = Protocol::new(
+        "notes", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    for n in 0..10u64 {
+        let record = PublicRecord::new(None, notes.clone(), b"{}", Some(IndexBuilder::build(vec![("n", n)])?))?;
+        let req = MutableAgentRequest::create_public(record, Signer::Left(sig_key.clone()))?;
+        dwn.process_request(req.into_dwn_request()?).await?.into_empty()?;
+    }
+    let filters = Filters::new(vec![("protocol", Filter::equal(notes.uuid().to_string())), ("n", Filter::range(3u64, 5u64))]);
+    let DwnResponse::ReadPublic(items, _) = dwn.process_request(DwnRequest::ReadPublic(filters.into(), None)).await? else {
+        panic!("Expected ReadPublic")
+    };
+    let mut found = items.iter().map(|item| item.0.inner().index["n"].clone()).collect::<Vec<_>>();
+    found.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(found, vec![Value::from(3u64), Value::from(4u64), Value::from(5u64)]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn public_read_paging() -> Result<(), Error> {
+    use crate::agent::structs::MutableAgentRequest;
+    use crate::dids::signing::Signer;
+    use crate::dids::DidKeyPair;
+    use crate::dwn::structs::PublicRecord;
+    use crate::SortPaging;
+    use crate::prelude::Filter;
+    use simple_database::database::{IndexBuilder, SortOptions, SortDirection, Value};
+
+    let (server, server_doc) = get_server(1)?;
+    let (user, user_doc) = get_user(vec![server_doc.did()])?;
+    let mut resolver = MemoryDidResolver::new();
+    resolver.store(Box::new(server_doc.clone()));
+    resolver.store(Box::new(user_doc));
+    let dwn = Dwn::scratch::<MemoryStore>(server, Some(Box::new(resolver))).await?;
+    let sig_key = serde_json::from_value::<DidKeyPair>(serde_json::to_value(&user)?["sig_key"].clone())?;
+    let notes = Protocol::new(
+        "notes", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let mut uuids = Vec::new();
+    for n in 0..20u64 {
+        let record = PublicRecord::new(None, notes.clone(), b"{}", Some(IndexBuilder::build(vec![("n", n)])?))?;
+        uuids.push(record.uuid);
+        let req = MutableAgentRequest::create_public(record, Signer::Left(sig_key.clone()))?;
+        dwn.process_request(req.into_dwn_request()?).await?.into_empty()?;
+    }
+    let filters = Filters::new(vec![("protocol", Filter::equal(notes.uuid().to_string()))]);
+    let page = |sort: SortOptions| {
+        let request = DwnRequest::ReadPublic(filters.clone().into(), Some(sort));
+        let dwn = &dwn;
+        async move {
+            let DwnResponse::ReadPublic(items, cursor) = dwn.process_request(request).await? else {
+                return Err(Error::bad_response("Expected ReadPublic"));
+            };
+            Ok::<_, Error>((items.iter().map(|item| item.0.inner().index["n"].clone()).collect::<Vec<_>>(), cursor))
+        }
+    };
+    let n = |range: &mut dyn Iterator<Item = u64>| range.map(Value::from).collect::<Vec<_>>();
+
+    //The limit lands on the last record, that page has no cursor
+    let (first, cursor) = page(SortOptions::new("n").with_page(Some(10), None)?).await?;
+    assert_eq!(first, n(&mut (0..10)));
+    assert_eq!(cursor, Some(uuids[9].as_bytes().to_vec()));
+    let (second, last) = page(SortOptions::new("n").with_page(Some(10), cursor.clone())?).await?;
+    assert_eq!(second, n(&mut (10..20)));
+    assert_eq!(last, None);
+    let (past, last) = page(SortOptions::new("n").with_page(Some(30), cursor.clone())?).await?;
+    assert_eq!((past.len(), last), (10, None));
+
+    //Descending pages run the other way
+    let mut descending = serde_json::to_value(SortOptions::new("n"))?;
+    descending["direction"] = serde_json::to_value(SortDirection::Descending)?;
+    let descending = serde_json::from_value::<SortOptions>(descending)?;
+    let (first, cursor) = page(descending.clone().with_page(Some(15), None)?).await?;
+    assert_eq!(first, n(&mut (5..20).rev()));
+    let (second, last) = page(descending.with_page(Some(15), cursor)?).await?;
+    assert_eq!((second, last), (n(&mut (0..5).rev()), None));
+
+    //The cursor's record is deleted between pages
+    let req = MutableAgentRequest::delete_public(uuids[9], Signer::Left(sig_key.clone()))?;
+    dwn.process_request(req.into_dwn_request()?).await?.into_empty()?;
+    let (gone, last) = page(SortOptions::new("n").with_page(Some(10), Some(uuids[9].as_bytes().to_vec()))?).await?;
+    assert_eq!((gone, last), (vec![], None));
+    Ok(())
+}
+
+#[tokio::test]
+async fn relocate_record() -> Result<(), Error> {
+    let (agent, _, _) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let messages = Protocol::new(
+        "messages", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let rooms = Protocol::new(
+        "rooms_protocol", true,
+        PermissionOptions::new(true, true, true, Some(ChannelPermissionOptions::new(true, true))),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?),
+        Some(ChannelProtocol::new(Some(vec![&messages])))
+    )?;
+    let from = RecordPath::new(&[Uuid::new_v4()])?;
+    let to = RecordPath::new(&[Uuid::new_v4()])?;
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(from.clone(), rooms.clone(), b"{}"), None)).await?;
+    let children = (0..3).map(|i| {
+        let record = Record::new(from.extend(&[Uuid::new_v4()])?, messages.clone(), i.to_string().as_bytes());
+        Ok(scripts::CreatePrivate::new(record, None))
+    }).collect::<Result<Vec<_>, Error>>()?;
+    agent.run_all::<()>(&mut cache, children).await?;
+
+    let error = agent.run::<()>(&mut cache, scripts::RelocateRecord::new(from.clone(), from.extend(&[Uuid::new_v4()])?)).await.unwrap_err();
+    assert_eq!(error.code(), "BAD_REQUEST");
+
+    agent.run::<()>(&mut cache, scripts::RelocateRecord::new(from.clone(), to.clone())).await?;
+    let mut payloads = agent.run::<Vec<Record>>(&mut cache, scripts::Scan::new(to.clone(), 0)).await?
+        .into_iter().map(|r| r.payload).collect::<Vec<_>>();
+    payloads.sort();
+    assert_eq!(payloads, vec![b"0".to_vec(), b"1".to_vec(), b"2".to_vec()]);
+    //The delete dropped the cached info of the old path, nothing is left to scan there
+    let error = agent.run::<Vec<Record>>(&mut cache, scripts::Scan::new(from.clone(), 0)).await.unwrap_err();
+    assert_eq!(error.to_json().causes[0].code, "NOT_FOUND");
+
+    let other = RecordPath::new(&[Uuid::new_v4()])?;
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(other.clone(), rooms, b"{\"name\":1}"), None)).await?;
+    let error = agent.run::<()>(&mut cache, scripts::RelocateRecord::new(to, other)).await.unwrap_err();
+    assert_eq!(error.code(), "CONFLICT");
+    Ok(())
+}
+
+#[tokio::test]
+async fn move_record() -> Result<(), Error> {
+    let net = LocalNet::new(2).await?;
+    let (dwns, urls) = (&net.dwns, &net.urls);
+    let agent = net.agent(0).await?;
+    let mut cache = CompilerCache::default();
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let record = Record::new(path.clone(), SystemProtocols::usize(), b"1");
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(record.clone(), None)).await?;
+    let read = || {
+        let (agent, path) = (&agent, path.clone());
+        async move {agent.run::<Option<Record>>(&mut CompilerCache::default(), scripts::ReadPrivate::new(path)).await}
+    };
+    assert_eq!(read().await?.map(|r| r.payload), Some(b"1".to_vec()));
+
+    //The destination is unreachable, the source copy stays readable
+    dwns.fail(&urls[1], "Connection refused");
+    assert!(agent.run::<()>(&mut cache, scripts::MoveRecord::new(path.clone(), net.did(0), net.did(1))).await.is_err());
+    dwns.recover(&urls[1]);
+    assert_eq!(read().await?.map(|r| r.payload), Some(b"1".to_vec()));
+
+    agent.run::<()>(&mut cache, scripts::MoveRecord::new(path.clone(), net.did(0), net.did(1))).await?;
+    let before = dwns.sent(&urls[1]).len();
+    assert_eq!(read().await?.map(|r| r.payload), Some(b"1".to_vec()));
+    assert!(dwns.sent(&urls[1]).split_off(before).iter().any(|r| matches!(r, DwnRequest::ReadPrivate(_))));
+
+    //Only the destination holds the record now, the source is not fallen back on
+    dwns.fail(&urls[1], "Connection refused");
+    assert_eq!(read().await.unwrap_err().code(), "UNREACHABLE");
+    Ok(())
+}
+
+#[tokio::test]
+async fn abuse_report() -> Result<(), Error> {
+    use crate::dids::signing::SignedObject;
+    use crate::dwn::structs::{AbuseReport, PublicRecord, Takedown};
+    use crate::agent::structs::DmMessage;
+    use simple_database::database::Filter;
+
+    let (agent, dwns, url) = local_agent().await?;
+    let dwn = dwns.dwns[&url].clone();
+    let mut cache = CompilerCache::default();
+    let notes = Protocol::new(
+        "notes", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let record = PublicRecord::new(None, notes, b"{}", None)?;
+    let uuid = record.uuid;
+    agent.run::<()>(&mut cache, scripts::CreatePublic::new(record, None)).await?;
+
+    //Reporters need no did, any key will do
+    let report = AbuseReport::new(agent.tenant().clone(), uuid, "spam").into_record()?;
+    let report_id = report.uuid;
+    dwn.process_request(DwnRequest::CreatePublic(report.into_item(either::Either::Right(SecretKey::new()))?)).await?.into_empty()?;
+    let open = dwn.open_reports().await?;
+    assert_eq!(open.len(), 1);
+    assert_eq!(open[0].0, report_id);
+
+    let forged = SignedObject::from_key(&SecretKey::new(), (report_id, "forged".to_string()))?;
+    assert!(dwn.process_request(DwnRequest::Takedown(forged)).await?.is_invalid_auth());
+    assert_eq!(dwn.open_reports().await?.len(), 1);
+
+    dwn.takedown(report_id, "Illegal content").await?;
+    assert!(dwn.open_reports().await?.is_empty());
+
+    let filters = Filters::new(vec![("protocol", Filter::equal(SystemProtocols::takedown().uuid().to_string()))]);
+    let (records, _) = agent.run::<(Vec<PublicRecord>, Option<Vec<u8>>)>(
+        &mut cache, scripts::ReadPublic::new(filters, None)
+    ).await?;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].uuid, uuid);
+    let tombstone = serde_json::from_slice::<Takedown>(&records[0].payload)?;
+    assert_eq!((tombstone.report, tombstone.reason.as_str()), (report_id, "Illegal content"));
+
+    let (messages, _) = agent.run::<(Vec<(crate::dids::signing::VerifiedBy, DmMessage)>, crate::dwn::structs::DmPage)>(
+        &mut cache, Box::new(commands::ReadDM::new(None, 10))
+    ).await?;
+    assert!(messages.iter().any(|(by, m)| by.did.as_ref() == Some(&dwn.com_key.public.did) && *m == DmMessage::Takedown(tombstone.clone())));
+    Ok(())
+}
+
+#[tokio::test]
+async fn public_batch_order() -> Result<(), Error> {
+    use crate::dwn::structs::PublicRecord;
+    use simple_database::database::Filter;
+
+    let (agent, _, _) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let notes = Protocol::new(
+        "notes", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let note = |uuid: Uuid, payload: &[u8]| PublicRecord::new(Some(uuid), notes.clone(), payload, None);
+    let (updated, created, recreated) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    agent.run_all::<()>(&mut cache, vec![
+        scripts::CreatePublic::new(note(updated, b"1")?, None),
+        scripts::CreatePublic::new(note(recreated, b"1")?, None),
+    ]).await?;
+
+    agent.run_all::<()>(&mut cache, vec![
+        scripts::UpdatePublic::new(note(updated, b"2")?, None),
+        scripts::DeletePublic::new(updated, None),
+        scripts::CreatePublic::new(note(created, b"2")?, None),
+        scripts::DeletePublic::new(created, None),
+        scripts::DeletePublic::new(recreated, None),
+        scripts::CreatePublic::new(note(recreated, b"2")?, None),
+    ]).await?;
+
+    let filters = Filters::new(vec![("protocol", Filter::equal(notes.uuid().to_string()))]);
+    let (records, _) = agent.run::<(Vec<PublicRecord>, Option<Vec<u8>>)>(
+        &mut cache, scripts::ReadPublic::new(filters, None)
+    ).await?;
+    assert_eq!(records.into_iter().map(|r| (r.uuid, r.payload)).collect::<Vec<_>>(), vec![(recreated, b"2".to_vec())]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn delete_dms() -> Result<(), Error> {
+    use crate::agent::structs::{BoxCommand, DmMessage, Responses};
+    use crate::dids::signing::VerifiedBy;
+    use crate::dwn::structs::DmPage;
+
+    let (agent, _, _) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let me = agent.tenant().clone();
+    let notify = |payload: &[u8]| -> BoxCommand {Box::new(commands::CreateDM::new(
+        DmMessage::RecordUpdated(RecordUpdated::new(&RecordPath::root(), payload)), me.clone()
+    ))};
+    type Inbox = (Vec<(VerifiedBy, DmMessage)>, DmPage);
+    let read = || scripts::ReadDM::new(None, 10);
+    agent.run_all::<Responses>(&mut cache, vec![notify(b"1"), notify(b"2")]).await?;
+    assert_eq!(agent.run::<Inbox>(&mut cache, read()).await?.0.len(), 2);
+
+    //The Dwn keeps stored times to the second
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let before = chrono::Utc::now();
+    agent.run::<Responses>(&mut cache, notify(b"3")).await?;
+
+    //A signed instant ahead of the Dwn's clock could be replayed to remove DMs yet to arrive
+    let future = before + chrono::Duration::hours(1);
+    let error = agent.run::<()>(&mut cache, scripts::DeleteDM::new(future)).await.unwrap_err();
+    assert_eq!(error.code(), "INVALID_AUTH");
+
+    agent.run::<()>(&mut cache, scripts::DeleteDM::new(before)).await?;
+    let (messages, _) = agent.run::<Inbox>(&mut cache, read()).await?;
+    assert_eq!(messages.len(), 1);
+    assert!(matches!(&messages[0].1, DmMessage::RecordUpdated(u) if u.payload == b"3".to_vec().hash().to_string()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn write_receipt() -> Result<(), Error> {
+    use crate::dids::signing::SignedObject;
+    use crate::dwn::structs::{PublicRecord, Receipt};
+    use simple_database::database::Filter;
+
+    let (agent, dwns, url) = local_agent().await?;
+    let dwn = dwns.dwns[&url].clone();
+    let mut cache = CompilerCache::default();
+    let notices = Protocol::new(
+        "notices", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let record = PublicRecord::new(None, notices.clone(), b"{}", None)?;
+    let receipts = agent.run::<Vec<SignedObject<Receipt>>>(
+        &mut cache, scripts::CreatePublic::with_receipt(record, None)
+    ).await?;
+    assert_eq!(receipts.len(), 1);
+
+    let filters = Filters::new(vec![("protocol", Filter::equal(notices.uuid().to_string()))]);
+    let DwnResponse::ReadPublic(mut stored, _) = dwn.process_request(DwnRequest::ReadPublic(filters.into(), None)).await? else {
+        panic!("Expected ReadPublic")
+    };
+    let submitted = DwnRequest::CreatePublic(stored.remove(0));
+    let receipt = Receipt::verify(&receipts[0], &*dwn.did_resolver, &dwn.com_key.public.did, &submitted).await?;
+    assert!(receipt.timestamp <= chrono::Utc::now());
+    assert!(Receipt::verify(&receipts[0], &*dwn.did_resolver, agent.tenant(), &submitted).await.is_err());
+
+    let other = PublicRecord::new(None, notices, b"{}", None)?;
+    let plain = DwnRequest::CreatePublic(other.into_item(either::Either::Right(SecretKey::new()))?);
+    assert!(Receipt::verify(&receipts[0], &*dwn.did_resolver, &dwn.com_key.public.did, &plain).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn store_isolation() -> Result<(), Error> {
+    use crate::dwn::structs::PublicRecord;
+
+    let resolver: Box<dyn DidResolver> = Box::new(MemoryDidResolver::new());
+    let (a_id, _) = get_server(1)?;
+    let (b_id, _) = get_server(1)?;
+    let (a, b) = futures::future::try_join(
+        Dwn::scratch::<MemoryStore>(a_id, Some(resolver.clone())),
+        Dwn::scratch::<MemoryStore>(b_id, Some(resolver.clone()))
+    ).await?;
+    assert_ne!(a.data_path(), b.data_path());
+
+    let notes = Protocol::new(
+        "notes", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let record = PublicRecord::new(None, notes, b"{}", None)?;
+    a.process_request(DwnRequest::CreatePublic(record.into_item(either::Either::Right(SecretKey::new()))?)).await?.into_empty()?;
+    let count = |dwn: Dwn| async move {
+        match dwn.process_request(DwnRequest::ReadPublic(Filters::new(vec![]).into(), None)).await? {
+            DwnResponse::ReadPublic(items, _) => Ok::<_, Error>(items.len()),
+            other => Err(Error::bad_response(&format!("{:?}", other)))
+        }
+    };
+    assert_eq!(count(a.clone()).await?, 1);
+    assert_eq!(count(b.clone()).await?, 0);
+    a.reset().await?;
+    assert_eq!(count(a.clone()).await?, 0);
+
+    let scratch = a.data_path().to_path_buf();
+    drop(a);
+    assert!(!scratch.exists());
+    Ok(())
+}
+
+#[tokio::test]
+async fn store_lock() -> Result<(), Error> {
+    let resolver: Box<dyn DidResolver> = Box::new(MemoryDidResolver::new());
+    let path = std::env::temp_dir().join(format!("web5-store-lock-{}", std::process::id()));
+    let (id, _) = get_server(1)?;
+    let dwn = Dwn::new::<MemoryStore>(id.clone(), Some(path.clone()), Some(resolver.clone())).await?;
+    let contended = Dwn::new::<MemoryStore>(id.clone(), Some(path.clone()), Some(resolver.clone())).await;
+    assert_eq!(contended.err().map(|e| e.code()), Some("CONFLICT"));
+    assert!(CommandJournal::new::<MemoryStore>(Some(path.clone())).await.is_err());
+
+    drop(dwn);
+    Dwn::new::<MemoryStore>(id, Some(path.clone()), Some(resolver)).await?;
+    std::fs::remove_dir_all(PathBuf::from(&path))?;
+    Ok(())
+}
+
+//In process Dwns where the one at the down url refuses every request
+#[derive(Debug, Clone)]
+struct PartialDwns {
+    dwns: LocalDwns,
+    down: std::sync::Arc<std::sync::Mutex<Option<url::Url>>>
+}
+
+#[async_trait::async_trait]
+impl Client for PartialDwns {
+    async fn send_request(&self, body: String, url: url::Url) -> Result<String, Error> {
+        if self.down.lock().unwrap().as_ref() == Some(&url) {
+            return Err(Error::json_rpc("Connection refused"));
+        }
+        self.dwns.send_request(body, url).await
+    }
+}
+
+#[tokio::test]
+async fn partial_failure() -> Result<(), Error> {
+    use crate::dwn::structs::DwnItem;
+
+    let mut did_resolver = MemoryDidResolver::new();
+    let servers = (0..3).map(|_| get_server(1)).collect::<Result<Vec<_>, Error>>()?;
+    let (alice, alice_doc) = get_user(servers.iter().map(|(_, doc)| doc.did()).collect())?;
+    servers.iter().for_each(|(_, doc)| did_resolver.store(Box::new(doc.clone())));
+    did_resolver.store(Box::new(alice_doc));
+    let did_resolver: Box<dyn DidResolver> = Box::new(did_resolver);
+    let endpoints = did_resolver.get_endpoints(&servers.iter().map(|(_, doc)| doc.did()).collect::<Vec<_>>()).await?;
+    let dwns = LocalDwns::new(&*did_resolver, servers).await?;
+    let client = PartialDwns{dwns: dwns.clone(), down: Default::default()};
+    let agent = Agent::with_client(Wallet::new(alice).root(), did_resolver, Box::new(client.clone()), None).await?;
+    let mut cache = CompilerCache::default();
+
+    let (down, up) = (endpoints[1].clone(), [endpoints[0].1.clone(), endpoints[2].1.clone()]);
+    *client.down.lock().unwrap() = Some(down.1.clone());
+    let record = Record::new(RecordPath::new(&[Uuid::new_v4()])?, SystemProtocols::usize(), b"1");
+    let error = agent.run::<()>(&mut cache, scripts::CreatePrivate::new(record, None)).await.unwrap_err();
+    fn urls(json: &ErrorJson) -> Vec<String> {
+        json.context.get("url").cloned().into_iter().chain(json.causes.iter().flat_map(urls)).collect()
+    }
+    let failed = urls(&error.to_json());
+    assert!(!failed.is_empty());
+    assert!(failed.iter().all(|url| *url == down.1.to_string()));
+    assert_eq!(error.code(), "UNREACHABLE");
+
+    for url in up {
+        let discover = dwns.sent(&url).into_iter().rev().find_map(|r| match r {
+            DwnRequest::CreatePrivate(s) => Some(s.inner().discover.clone()),
+            _ => None
+        }).unwrap();
+        assert!(dwns.dwns[&url].private_database.get::<DwnItem>(&discover.to_vec()).await?.is_some());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn strict_read() -> Result<(), Error> {
+    use crate::agent::structs::PrivateRecord;
+    use crate::dwn::structs::DwnItem;
+
+    let (agent, dwns, url) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let messages = Protocol::new(
+        "messages", false, PermissionOptions::new(true, true, false, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let rooms = Protocol::new(
+        "rooms_protocol", false, PermissionOptions::new(true, true, false, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(path.clone(), messages.clone(), b"{}"), None)).await?;
+    let read = |command: commands::ReadPrivate| {
+        let agent = &agent;
+        async move {agent.process_commands(&mut CompilerCache::default(), vec![Box::new(command)]).await}
+    };
+
+    let found = *read(commands::ReadPrivate::strict(path.clone(), messages.uuid())).await?
+        .remove(0).downcast::<(Option<Box<PrivateRecord>>, bool)>()?;
+    assert_eq!(found.0.map(|r| r.protocol), Some(messages.clone()));
+    let error = read(commands::ReadPrivate::strict(path.clone(), rooms.uuid())).await.unwrap_err();
+    assert_eq!(error.code(), "BAD_RESPONSE");
+    assert!(error.to_string().contains(&messages.uuid().to_string()) && error.to_string().contains(&rooms.uuid().to_string()));
+
+    //A corrupted item reads as missing unless read strictly
+    let dwn = &dwns.dwns[&url];
+    for mut item in dwn.private_database.query::<DwnItem>(&Filters::new(vec![]), None).await?.0 {
+        item.payload[10] ^= 1;
+        dwn.private_database.set(&item).await?;
+    }
+    assert!(agent.run::<Option<Record>>(&mut cache, scripts::ReadPrivate::new(path.clone())).await?.is_none());
+    assert!(read(commands::ReadPrivate::strict(path, messages.uuid())).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn record_expiry() -> Result<(), Error> {
+    use crate::agent::structs::{MutableAgentRequest, PrivateRecord};
+    use crate::dwn::structs::DwnItem;
+
+    let (agent, dwns, url) = local_agent().await?;
+    let dwn = &dwns.dwns[&url];
+    let mut cache = CompilerCache::default();
+    let protocol = SystemProtocols::usize();
+    let past = chrono::Utc::now() - chrono::Duration::hours(1);
+    let session = RecordPath::new(&[Uuid::new_v4()])?;
+    let kept = RecordPath::new(&[Uuid::new_v4()])?;
+    let later = Record::new(kept.clone(), protocol.clone(), b"2").expiring(chrono::Utc::now() + chrono::Duration::hours(1));
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(session.clone(), protocol.clone(), b"1").expiring(past), None)).await?;
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(later.clone(), None)).await?;
+    assert!(agent.run::<Option<Record>>(&mut cache, scripts::ReadPrivate::new(session)).await?.is_none());
+    assert_eq!(agent.run::<Option<Record>>(&mut cache, scripts::ReadPrivate::new(kept.clone())).await?, Some(later));
+
+    //Expired items stay stored until swept, only the delete key can update them meanwhile
+    let perms = PathedKey::new_root(SecretKey::new()).derive_path(&[Uuid::new_v4()])?.to_permission()?;
+    let create = MutableAgentRequest::create_private(perms.clone(), None, protocol.clone(), b"1".to_vec(), Some(past))?;
+    dwn.process_request(create.into_dwn_request()?).await?.into_empty()?;
+    let read = DwnRequest::read_private(&perms.discover())?;
+    assert!(dwn.process_request(read.clone()).await?.into_read_private()?.is_none());
+    let update = |delete: SecretKey| {
+        let record = PrivateRecord{expires: Some(past), ..PrivateRecord::new(perms.clone(), protocol.clone(), b"2".to_vec())};
+        MutableAgentRequest::UpdatePrivate(Box::new(record), perms.discover(), perms.create()?, delete).into_dwn_request()
+    };
+    assert!(dwn.process_request(update(SecretKey::new())?).await?.is_invalid_auth());
+    dwn.process_request(update(perms.delete()?)?).await?.into_empty()?;
+
+    assert_eq!(dwn.collect_garbage().await?, 2);
+    assert_eq!(dwn.collect_garbage().await?, 0);
+    assert!(dwn.private_database.get::<DwnItem>(&perms.discover().public_key().to_vec()).await?.is_none());
+    assert!(agent.run::<Option<Record>>(&mut cache, scripts::ReadPrivate::new(kept)).await?.is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn persistent_cache() -> Result<(), Error> {
+    let net = LocalNet::new(1).await?;
+    let agent = || net.agent(0);
+    let reads = || net.dwns.sent(&net.urls[0]).into_iter().filter(|r| matches!(r, DwnRequest::ReadPrivate(_))).count();
+    let path = PathBuf::from(format!("persistent_cache_{}", Uuid::new_v4()));
+
+    let messages = Protocol::new(
+        "messages", false, PermissionOptions::new(true, true, false, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let rooms = Protocol::new(
+        "rooms_protocol", true,
+        PermissionOptions::new(true, true, true, Some(ChannelPermissionOptions::new(true, true))),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?),
+        Some(ChannelProtocol::new(Some(vec![&messages])))
+    )?;
+    let room = RecordPath::new(&[Uuid::new_v4()])?;
+    let first = agent().await?;
+    let mut cache = first.load_cache::<MemoryStore>(path.clone()).await?;
+    let message = || Ok::<_, Error>(scripts::CreatePrivate::new(
+        Record::new(room.extend(&[Uuid::new_v4()])?, messages.clone(), b"{}"), None
+    ));
+    first.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(room.clone(), rooms.clone(), b"{}"), None)).await?;
+    first.run::<()>(&mut cache, message()?).await?;
+    cache.flush().await?;
+
+    let cold = agent().await?;
+    let before = reads();
+    cold.run::<()>(&mut CompilerCache::default(), message()?).await?;
+    let uncached = reads() - before;
+
+    let warm = agent().await?;
+    let mut cache = warm.load_cache::<MemoryStore>(path.clone()).await?;
+    let before = reads();
+    warm.run::<()>(&mut cache, message()?).await?;
+    assert!(reads() - before < uncached);
+
+    //An update replaces the record, its info is read again next time
+    let endpoint = net.resolver.get_endpoints(&[net.did(0)]).await?.remove(0);
+    let key = (endpoint, true, room.clone());
+    assert!(cache.get_info(&key).is_some());
+    let update = commands::UpdatePrivate::new(Record::new(room, rooms, b"[]"), None);
+    warm.run::<()>(&mut cache, Box::new(update)).await?;
+    assert!(cache.get_info(&key).is_none());
+    //Only the agent that flushed the cache can read it
+    assert!(CompilerCache::load::<MemoryStore>(path, &SecretKey::new()).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn typed_records() -> Result<(), Error> {
+    #[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+    struct Note {title: String, pinned: bool}
+
+    let (agent, _, _) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let schema = serde_json::json!({
+        "type": "object", "required": ["title", "pinned"],
+        "properties": {"title": {"type": "string"}, "pinned": {"type": "boolean"}}
+    });
+    let notes = Protocol::new(
+        "notes", false, PermissionOptions::new(true, true, false, None), Some(schema.to_string()), None
+    )?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let note = TypedRecord::new(path.clone(), notes.clone(), Note{title: "Groceries".to_string(), pinned: true})?;
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(note.clone().into_record(), None)).await?;
+    let read = scripts::ReadPrivate::read_typed::<Note>(&agent, &mut cache, path).await?.unwrap();
+    assert_eq!(read, note);
+    assert!(scripts::ReadPrivate::read_typed::<Note>(&agent, &mut cache, RecordPath::new(&[Uuid::new_v4()])?).await?.is_none());
+
+    let invalid = TypedRecord::new(RecordPath::new(&[Uuid::new_v4()])?, notes.clone(), serde_json::json!({"title": 1}));
+    assert_eq!(invalid.unwrap_err().code(), "VALIDATION");
+    let long = Record::new_typed(RecordPath::new(&[Uuid::new_v4()])?, notes.clone(), &"x".repeat(100))?;
+    let error = long.decode::<Note>().unwrap_err().to_string();
+    assert!(error.contains(&notes.label()) && error.contains(&format!("\"{}...", "x".repeat(63))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn watch_dms() -> Result<(), Error> {
+    use crate::agent::structs::{DmMessage, Responses};
+    use crate::dwn::structs::DmCursor;
+    use futures::StreamExt;
+
+    let (agent, dwns, url) = local_agent().await?;
+    let me = agent.tenant().clone();
+    let notify = |payload: &[u8]| -> commands::CreateDM {commands::CreateDM::new(
+        DmMessage::RecordUpdated(RecordUpdated::new(&RecordPath::root(), payload)), me.clone()
+    )};
+    let pause = std::time::Duration::from_millis(50);
+    let mut watch = Box::pin(agent.watch_dms(DmCursor::default(), pause));
+
+    //The subscription is held open until the DM lands, well short of the Dwn's wait
+    let start = std::time::Instant::now();
+    let (page, _) = tokio::join!(watch.next(), async {
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        agent.run::<Responses>(&mut CompilerCache::default(), Box::new(notify(b"1"))).await
+    });
+    let (messages, cursor) = page.unwrap()?;
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    assert_eq!(messages.len(), 1);
+    assert!(dwns.sent(&url).iter().any(|r| matches!(r, DwnRequest::SubscribeDM(_))));
+    drop(watch);
+
+    //DMs sent while disconnected are picked up from the last cursor, none twice
+    agent.run_all::<Responses>(&mut CompilerCache::default(), vec![Box::new(notify(b"2")), Box::new(notify(b"3"))]).await?;
+    let mut watch = Box::pin(agent.watch_dms(cursor, pause));
+    let (messages, _) = watch.next().await.unwrap()?;
+    let payloads = messages.into_iter().map(|(_, m)| match m {
+        DmMessage::RecordUpdated(u) => u.payload,
+        other => panic!("{:?}", other)
+    }).collect::<std::collections::BTreeSet<_>>();
+    assert_eq!(payloads, [b"2", b"3"].iter().map(|p| p.to_vec().hash().to_string()).collect());
+    Ok(())
+}
+
+#[cfg(feature = "import")]
+#[tokio::test]
+async fn file_import() -> Result<(), Error> {
+    use crate::agent::{FileImporter, ImportedFile};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn protocol_map(file: &std::path::Path, mime: &mime::Mime) -> Option<(RecordPath, Protocol)> {
+        let name = match mime.type_() {
+            mime::IMAGE => "photos",
+            mime::TEXT => "notes",
+            _ => return None
+        };
+        let protocol = Protocol::new(
+            name, false, PermissionOptions::new(true, true, false, None), Some(ImportedFile::schema()), None
+        ).unwrap();
+        let id = Uuid::new_v5(&Uuid::NAMESPACE_OID, file.file_name()?.as_encoded_bytes());
+        Some((RecordPath::new(&[id]).ok()?, protocol))
+    }
+
+    let (agent, _, _) = local_agent().await?;
+    let dir = std::env::temp_dir().join(format!("web5-import-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(dir.join("nested"))?;
+    std::fs::write(dir.join("photo.png"), b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR")?;
+    std::fs::write(dir.join("nested").join("note.md"), b"# Groceries")?;
+    std::fs::write(dir.join("blob.bin"), [0xfe, 0xff, 0x00, 0x80])?;
+    std::fs::write(dir.join("large.txt"), vec![b'a'; 64])?;
+
+    let progress = std::sync::Arc::new(AtomicUsize::new(0));
+    let counted = progress.clone();
+    let mut importer = FileImporter::scratch::<MemoryStore>(&agent, protocol_map).await?
+        .with_max_size(32).with_concurrency(2)
+        .on_progress(move |_, total, _| {assert_eq!(total, 4); counted.fetch_add(1, Ordering::SeqCst);});
+    let summary = importer.import(&dir).await?;
+    assert_eq!(summary.imported, vec![dir.join("nested").join("note.md"), dir.join("photo.png")]);
+    assert_eq!(summary.skipped, vec![dir.join("blob.bin")]);
+    assert_eq!(summary.failed.iter().map(|f| &f.0).collect::<Vec<_>>(), vec![&dir.join("large.txt")]);
+    assert_eq!(progress.load(Ordering::SeqCst), 4);
+
+    let (path, photos) = protocol_map(&dir.join("photo.png"), &"image/png".parse().unwrap()).unwrap();
+    let record = agent.run::<Option<Record>>(&mut CompilerCache::default(), scripts::ReadPrivate::new(path)).await?.unwrap();
+    assert_eq!(record.protocol, photos);
+    let (file, data) = ImportedFile::from_payload(&record.payload)?;
+    assert_eq!((file.name.as_str(), file.mime.as_str()), ("photo.png", "image/png"));
+    assert_eq!(data, std::fs::read(dir.join("photo.png"))?);
+
+    //The ledger skips what the first run imported
+    let summary = importer.import(&dir).await?;
+    assert!(summary.imported.is_empty());
+    assert_eq!(summary.skipped.len(), 3);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+//The public api from rustdoc's json output: every path reachable from the crate root with its signature,
+//fields, variants, methods and trait impls. The default features are documented so unstable internals
+//are left out. Regenerate with WEB5_UPDATE_PUBLIC_API=1 when a change is intended
+#[test]
+fn public_api() -> Result<(), Error> {
+    use serde_json::Value;
+    fn last(path: &Value) -> String {
+        path.as_str().unwrap_or_default().rsplit("::").next().unwrap_or_default().to_string()
+    }
+    fn join(values: &Value, f: fn(&Value) -> String, sep: &str) -> String {
+        values.as_array().map(|v| v.iter().map(f).collect::<Vec<_>>().join(sep)).unwrap_or_default()
+    }
+    fn args(args: &Value) -> String {
+        if let Some(a) = args.get("angle_bracketed") {
+            let mut parts = a["args"].as_array().cloned().unwrap_or_default().iter().map(|arg| match arg {
+                Value::Object(o) if o.contains_key("type") => ty(&arg["type"]),
+                Value::Object(o) if o.contains_key("lifetime") => arg["lifetime"].as_str().unwrap_or_default().to_string(),
+                Value::Object(o) if o.contains_key("const") => arg["const"]["expr"].as_str().unwrap_or_default().to_string(),
+                _ => "_".to_string()
+            }).collect::<Vec<_>>();
+            parts.extend(a["constraints"].as_array().cloned().unwrap_or_default().iter().map(|c| match c["binding"].get("equality") {
+                Some(eq) => format!("{} = {}", c["name"].as_str().unwrap_or_default(), eq.get("type").map(ty).unwrap_or_default()),
+                None => format!("{}: {}", c["name"].as_str().unwrap_or_default(), join(&c["binding"]["constraint"], bound, " + "))
+            }));
+            if parts.is_empty() {String::new()} else {format!("<{}>", parts.join(", "))}
+        } else if let Some(p) = args.get("parenthesized") {
+            let output = if p["output"].is_null() {String::new()} else {format!(" -> {}", ty(&p["output"]))};
+            format!("({}){}", join(&p["inputs"], ty, ", "), output)
+        } else {String::new()}
+    }
+    fn named(p: &Value) -> String {format!("{}{}", last(&p["path"]), args(&p["args"]))}
+    fn bound(b: &Value) -> String {
+        if let Some(t) = b.get("trait_bound") {
+            format!("{}{}", if t["modifier"] == "maybe" {"?"} else {""}, named(&t["trait"]))
+        } else {b.get("outlives").and_then(|l| l.as_str()).unwrap_or("use<..>").to_string()}
+    }
+    fn ty(t: &Value) -> String {
+        let Some((kind, v)) = t.as_object().and_then(|o| o.iter().next()) else {return t.as_str().unwrap_or("_").to_string()};
+        match kind.as_str() {
+            "resolved_path" => named(v),
+            "generic" | "primitive" => v.as_str().unwrap_or_default().to_string(),
+            "tuple" => format!("({})", join(v, ty, ", ")),
+            "slice" => format!("[{}]", ty(v)),
+            "array" => format!("[{}; {}]", ty(&v["type"]), v["len"].as_str().unwrap_or_default()),
+            "impl_trait" => format!("impl {}", join(v, bound, " + ")),
+            "dyn_trait" => {
+                let traits = join(&v["traits"], |p| named(&p["trait"]), " + ");
+                match v["lifetime"].as_str() {Some(l) => format!("dyn {} + {}", traits, l), None => format!("dyn {}", traits)}
+            },
+            "borrowed_ref" => format!("&{}{}{}", v["lifetime"].as_str().map(|l| format!("{} ", l)).unwrap_or_default(), if v["is_mutable"] == true {"mut "} else {""}, ty(&v["type"])),
+            "raw_pointer" => format!("*{} {}", if v["is_mutable"] == true {"mut"} else {"const"}, ty(&v["type"])),
+            "qualified_path" => match v["trait"].is_null() {
+                true => format!("{}::{}", ty(&v["self_type"]), v["name"].as_str().unwrap_or_default()),
+                false => format!("<{} as {}>::{}", ty(&v["self_type"]), named(&v["trait"]), v["name"].as_str().unwrap_or_default())
+            },
+            "function_pointer" => format!("fn({}){}", join(&v["sig"]["inputs"], |i| ty(&i[1]), ", "), if v["sig"]["output"].is_null() {String::new()} else {format!(" -> {}", ty(&v["sig"]["output"]))}),
+            _ => "_".to_string()
+        }
+    }
+    fn generics(g: &Value) -> (String, String) {
+        let params = g["params"].as_array().cloned().unwrap_or_default().iter().filter(|p| p["kind"]["type"]["is_synthetic"] != true).map(|p| {
+            let name = p["name"].as_str().unwrap_or_default();
+            match p["kind"].as_object().and_then(|o| o.iter().next()) {
+                Some((k, v)) if k == "type" && v["bounds"].as_array().is_some_and(|b| !b.is_empty()) => format!("{}: {}", name, join(&v["bounds"], bound, " + ")),
+                Some((k, v)) if k == "const" => format!("const {}: {}", name, ty(&v["type"])),
+                _ => name.to_string()
+            }
+        }).collect::<Vec<_>>();
+        let wheres = g["where_predicates"].as_array().cloned().unwrap_or_default().iter().map(|w| match w.get("bound_predicate") {
+            Some(b) => format!("{}: {}", ty(&b["type"]), join(&b["bounds"], bound, " + ")),
+            None => w.to_string()
+        }).collect::<Vec<_>>();
+        (
+            if params.is_empty() {String::new()} else {format!("<{}>", params.join(", "))},
+            if wheres.is_empty() {String::new()} else {format!(" where {}", wheres.join(", "))}
+        )
+    }
+    fn function(name: &str, f: &Value) -> String {
+        let header = &f["header"];
+        let inputs = f["sig"]["inputs"].as_array().cloned().unwrap_or_default().iter().map(|i| {
+            let (n, t) = (i[0].as_str().unwrap_or_default(), ty(&i[1]));
+            match t.strip_suffix("Self") {
+                Some(r) if n == "self" && (r.is_empty() || r.ends_with('&') || r.ends_with(' ')) => format!("{}self", r),
+                _ => format!("{}: {}", n, t)
+            }
+        }).collect::<Vec<_>>();
+        let output = if f["sig"]["output"].is_null() {String::new()} else {format!(" -> {}", ty(&f["sig"]["output"]))};
+        let (params, wheres) = generics(&f["generics"]);
+        format!(
+            "{}{}{}fn {}{}({}){}{}", if header["is_const"] == true {"const "} else {""}, if header["is_async"] == true {"async "} else {""},
+            if header["is_unsafe"] == true {"unsafe "} else {""}, name, params, inputs.join(", "), output, wheres
+        )
+    }
+
+    //Every path from the crate root to an item
+    fn walk(index: &Value, module: &Value, prefix: &str, paths: &mut Vec<(String, Value, bool)>, seen: &mut BTreeSet<(String, String)>) {
+        if !seen.insert((prefix.to_string(), module.to_string())) {return;}
+        let items = index[module.to_string()]["inner"]["module"]["items"].as_array().cloned().unwrap_or_default();
+        for id in items {
+            let item = &index[id.to_string()];
+            let deprecated = !item["deprecation"].is_null();
+            if let Some(u) = item["inner"].get("use") {
+                let local = index.get(u["id"].to_string()).filter(|t| t["crate_id"] == 0);
+                let name = format!("{}::{}", prefix, u["name"].as_str().unwrap_or_default());
+                match (local, u["is_glob"] == true) {
+                    (Some(target), true) if target["inner"].get("module").is_some() => walk(index, &u["id"], prefix, paths, seen),
+                    (Some(_), false) => {
+                        paths.push((name.clone(), u["id"].clone(), deprecated));
+                        if index[u["id"].to_string()]["inner"].get("module").is_some() {walk(index, &u["id"], &name, paths, seen);}
+                    },
+                    _ => paths.push((name, Value::String(u["source"].as_str().unwrap_or_default().to_string()), deprecated))
+                }
+                continue;
+            }
+            let name = format!("{}::{}", prefix, item["name"].as_str().unwrap_or_default());
+            paths.push((name.clone(), id.clone(), deprecated));
+            //A deprecated module is only a shim for an old path, what it holds is described where it lives now
+            if item["inner"].get("module").is_some() && !deprecated {walk(index, &id, &name, paths, seen);}
+        }
+    }
+    fn describe(index: &Value, path: &str, id: &Value, lines: &mut Vec<String>) {
+        let item = &index[id.to_string()];
+        let Some((kind, inner)) = item["inner"].as_object().and_then(|o| o.iter().next()) else {return};
+        let field = |f: &Value| ty(&index[f.to_string()]["inner"]["struct_field"]);
+        match kind.as_str() {
+            "module" => lines.push(format!("mod {}", path)),
+            "extern_crate" => lines.push(format!("extern crate {}", path)),
+            "function" => lines.push(function(path, inner)),
+            "constant" => lines.push(format!("const {}: {}", path, ty(&inner["type"]))),
+            "static" => lines.push(format!("static {}: {}", path, ty(&inner["type"]))),
+            "type_alias" => lines.push(format!("type {}{} = {}", path, generics(&inner["generics"]).0, ty(&inner["type"]))),
+            "macro" | "proc_macro" => lines.push(format!("macro {}", path)),
+            "trait" => {
+                let (params, wheres) = generics(&inner["generics"]);
+                let bounds = join(&inner["bounds"], bound, " + ");
+                lines.push(format!("trait {}{}{}{}", path, params, if bounds.is_empty() {String::new()} else {format!(": {}", bounds)}, wheres));
+                for member in inner["items"].as_array().cloned().unwrap_or_default() {
+                    let member = &index[member.to_string()];
+                    let name = format!("{}::{}", path, member["name"].as_str().unwrap_or_default());
+                    match member["inner"].as_object().and_then(|o| o.iter().next()) {
+                        Some((k, v)) if k == "function" => lines.push(function(&name, v)),
+                        Some((k, v)) if k == "assoc_const" => lines.push(format!("const {}: {}", name, ty(&v["type"]))),
+                        _ => lines.push(format!("type {}", name))
+                    }
+                }
+            },
+            "struct" | "enum" | "union" => {
+                let (params, wheres) = generics(&inner["generics"]);
+                lines.push(format!("{} {}{}{}", kind, path, params, wheres));
+                let kind = &inner["kind"];
+                for (n, f) in kind["tuple"].as_array().cloned().unwrap_or_default().iter().enumerate() {
+                    if !f.is_null() {lines.push(format!("{}.{}: {}", path, n, field(f)));}
+                }
+                for f in kind["plain"]["fields"].as_array().cloned().unwrap_or_default().iter().chain(inner["fields"].as_array().unwrap_or(&Vec::new())) {
+                    lines.push(format!("{}.{}: {}", path, index[f.to_string()]["name"].as_str().unwrap_or_default(), field(f)));
+                }
+                for v in inner["variants"].as_array().cloned().unwrap_or_default() {
+                    let variant = &index[v.to_string()];
+                    let vkind = &variant["inner"]["variant"]["kind"];
+                    let fields = if let Some(t) = vkind.get("tuple") {
+                        format!("({})", t.as_array().cloned().unwrap_or_default().iter().map(|f| if f.is_null() {"_".to_string()} else {field(f)}).collect::<Vec<_>>().join(", "))
+                    } else if let Some(s) = vkind.get("struct") {
+                        format!(" {{{}}}", s["fields"].as_array().cloned().unwrap_or_default().iter().map(|f| format!("{}: {}", index[f.to_string()]["name"].as_str().unwrap_or_default(), field(f))).collect::<Vec<_>>().join(", "))
+                    } else {String::new()};
+                    lines.push(format!("{}::{}{}", path, variant["name"].as_str().unwrap_or_default(), fields));
+                }
+                //Auto traits and blanket impls follow from the rest
+                for i in inner["impls"].as_array().cloned().unwrap_or_default() {
+                    let imp = &index[i.to_string()]["inner"]["impl"];
+                    if imp["is_synthetic"] == true || !imp["blanket_impl"].is_null() {continue;}
+                    if !imp["trait"].is_null() {
+                        lines.push(format!("impl {}{} for {}", if imp["is_negative"] == true {"!"} else {""}, named(&imp["trait"]), ty(&imp["for"])));
+                        continue;
+                    }
+                    for member in imp["items"].as_array().cloned().unwrap_or_default() {
+                        let member = &index[member.to_string()];
+                        if member["visibility"] != "public" {continue;}
+                        let name = format!("{}::{}", path, member["name"].as_str().unwrap_or_default());
+                        match member["inner"].as_object().and_then(|o| o.iter().next()) {
+                            Some((k, v)) if k == "function" => lines.push(function(&name, v)),
+                            Some((k, v)) if k == "assoc_const" => lines.push(format!("const {}: {}", name, ty(&v["type"]))),
+                            _ => {}
+                        }
+                    }
+                }
+            },
+            _ => lines.push(format!("{} {}", kind, path))
+        }
+    }
+
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let target = dir.join("target").join("public-api");
+    let status = std::process::Command::new(std::env::var("CARGO").unwrap_or("cargo".to_string()))
+        .args(["rustdoc", "--lib", "--quiet", "--manifest-path"]).arg(dir.join("Cargo.toml"))
+        .arg("--target-dir").arg(&target)
+        .args(["--", "-Z", "unstable-options", "--output-format", "json"])
+        //The json output is unstable, this lets a stable rustdoc write it
+        .env("RUSTC_BOOTSTRAP", "1")
+        .status()?;
+    assert!(status.success(), "rustdoc could not write the json output");
+    let doc = serde_json::from_str::<Value>(&std::fs::read_to_string(target.join("doc").join("web5_rust.json"))?)?;
+    let index = &doc["index"];
+
+    let mut paths = Vec::new();
+    walk(index, &doc["root"], "web5_rust", &mut paths, &mut BTreeSet::new());
+    //An item re-exported under several paths is described at the shortest, the others point to it
+    let mut primary = BTreeMap::<String, String>::new();
+    for (path, id, _) in paths.iter().filter(|(_, id, _)| !id.is_string()) {
+        let shorter = |p: &String| (p.len(), p) > (path.len(), path);
+        if primary.get(&id.to_string()).is_none_or(shorter) {primary.insert(id.to_string(), path.clone());}
+    }
+    let mut lines = Vec::new();
+    for (path, id, deprecated) in paths {
+        let mark = if deprecated {"#[deprecated] "} else {""};
+        match id.as_str().or(primary.get(&id.to_string()).map(|p| p.as_str())) {
+            Some(to) if *to != path => lines.push(format!("{}use {} = {}", mark, path, to)),
+            _ => {
+                let mut item = Vec::new();
+                describe(index, &path, &id, &mut item);
+                lines.extend(item.into_iter().map(|l| format!("{}{}", mark, l)));
+            }
+        }
+    }
+    lines.sort();
+    lines.dedup();
+
+    let current = lines.join("\n") + "\n";
+    let lock = dir.join("src").join("public_api.lock");
+    if std::env::var("WEB5_UPDATE_PUBLIC_API").is_ok() {
+        std::fs::write(&lock, &current)?;
+    }
+    let locked = std::fs::read_to_string(&lock)?;
+    let added = lines.iter().filter(|l| !locked.lines().any(|k| k == *l)).collect::<Vec<_>>();
+    let removed = locked.lines().filter(|k| !lines.iter().any(|l| l == k)).collect::<Vec<_>>();
+    assert!(added.is_empty() && removed.is_empty(), "Public api changed\nadded: {:#?}\nremoved: {:#?}", added, removed);
+    Ok(())
+}
+
+#[tokio::test]
+async fn list_shared() -> Result<(), Error> {
+    use crate::agent::{SharedFilter, SharedRecordInfo};
+    use crate::dids::signing::{Signer, SignedObject, Verifier};
+    use crate::dids::DidKeyPair;
+    use crate::dwn::structs::DwnItem;
+    use crate::model::permission::ChannelPermissionSet;
+    use simple_crypto::Key;
+
+    let net = LocalNet::new(4).await?;
+    let (alice_did, bob_did, carol_did, dave_did) = (net.did(0), net.did(1), net.did(2), net.did(3));
+    let (alice, bob, carol) = (net.agent(0).await?, net.agent(1).await?, net.agent(2).await?);
+    let mut b_cache = CompilerCache::default();
+
+    let start = chrono::Utc::now();
+    let read_only = PermissionOptions::new(false, true, false, None);
+    let notes = Protocol::new("Notes", true, read_only.clone(), None, None)?;
+    let share = |sharer: &Agent, count: usize| {
+        let (sharer, notes, read_only, bob_did) = (sharer.clone(), notes.clone(), read_only.clone(), bob_did.clone());
+        async move {
+            let mut cache = CompilerCache::default();
+            let mut paths = Vec::new();
+            for _ in 0..count {
+                let path = RecordPath::new(&[Uuid::new_v4()])?;
+                sharer.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(path.clone(), notes.clone(), &[]), None)).await?;
+                sharer.run::<()>(&mut cache, scripts::Share::new(path.clone(), Some(read_only.clone()), bob_did.clone())).await?;
+                paths.push(path);
+            }
+            Ok::<_, Error>(paths)
+        }
+    };
+    let mut paths = share(&alice, 2).await?;
+    paths.extend(share(&carol, 1).await?);
+
+    //Sent before the protocol was part of share DMs, a bare PermissionSet of a channel left uncompressed
+    let channel = RecordPath::from_segments(&[Uuid::new_v5(&Uuid::NAMESPACE_OID, bob_did.to_string().as_bytes())]);
+    let key = || Key::new_secret(SecretKey::new());
+    let perms = PermissionSet::new(channel.clone(), SecretKey::new(), key(), key(), None, Some(ChannelPermissionSet::new(key(), key(), key())));
+    let dave_key = serde_json::from_value::<DidKeyPair>(serde_json::to_value(&net.users[3].0)?["sig_key"].clone())?;
+    let (_, bob_com) = net.resolver.resolve_dwn_keys(&bob_did).await?;
+    let payload = bob_com.encrypt(&serde_json::to_vec(&SignedObject::new(Signer::Left(dave_key), perms.clone())?)?)?;
+    net.dwn(1).process_request(DwnRequest::CreateDM(DwnItem{discover: bob_com, delete: None, payload, expires: None})).await?;
+
+    //Pointers keep when the DMs were stored, not when they were scanned
+    let sent = chrono::Utc::now();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    bob.run::<Vec<(Verifier, RecordUpdated)>>(&mut b_cache, scripts::ScanDM::new()).await?;
+    let list = |filter: Option<SharedFilter>| scripts::ListShared::new(filter);
+    let before = net.dwns.requests().len();
+    let mut shared = bob.run::<Vec<SharedRecordInfo>>(&mut b_cache, list(None)).await?;
+    let requests = net.dwns.requests()[before..].to_vec();
+    shared.sort_by_key(|info| (info.sharer == carol_did, paths.iter().position(|p| *p == info.path)));
+    assert_eq!(shared.iter().map(|info| &info.path).collect::<Vec<_>>(), paths.iter().collect::<Vec<_>>());
+    assert_eq!(shared.iter().filter(|info| info.sharer == alice_did).count(), 2);
+    assert!(shared.iter().all(|info| info.protocol == notes.uuid() && info.capabilities == read_only));
+    assert!(shared.iter().all(|info| info.received_at.is_some_and(|at| start <= at && at <= sent)));
+
+    //Only pointers were read, never the shared records
+    let mut discovers = BTreeSet::new();
+    for sharer in [&alice_did, &carol_did] {
+        let (shares, _) = bob.run::<(Vec<SharedPermissions>, SharesNeedingRefresh)>(
+            &mut b_cache, scripts::ProcessShares::new(sharer.clone())
+        ).await?;
+        discovers.extend(shares.into_iter().map(|s| s.perms.discover().public_key()));
+    }
+    assert_eq!(discovers.len(), 3);
+    assert!(requests.iter().all(|(_, request)| match request {
+        DwnRequest::ReadPrivate(signed) => !matches!(signed.signer(), Verifier::Right(key) if discovers.contains(key)),
+        DwnRequest::ReadPrivateSession(read) => !discovers.contains(&read.discover),
+        _ => true
+    }));
+
+    let from_alice = bob.run::<Vec<SharedRecordInfo>>(&mut b_cache, list(Some(SharedFilter::Sharer(alice_did.clone())))).await?;
+    assert_eq!(from_alice.len(), 2);
+    assert!(from_alice.iter().all(|info| info.sharer == alice_did));
+    assert_eq!(bob.run::<Vec<SharedRecordInfo>>(&mut b_cache, list(Some(SharedFilter::Protocol(notes.uuid())))).await?.len(), 3);
+    let dms_channel = SystemProtocols::dms_channel().uuid();
+    assert!(bob.run::<Vec<SharedRecordInfo>>(&mut b_cache, list(Some(SharedFilter::Protocol(dms_channel)))).await?.is_empty());
+
+    //The legacy share is found where ProcessShares looks for the channel, nothing was shared on it
+    let (shares, _) = bob.run::<(Vec<SharedPermissions>, SharesNeedingRefresh)>(&mut b_cache, scripts::ProcessShares::new(dave_did.clone())).await?;
+    assert!(shares.is_empty());
+    assert!(bob.run::<Vec<SharedRecordInfo>>(&mut b_cache, list(Some(SharedFilter::Sharer(dave_did)))).await?.is_empty());
+    Ok(())
+}
+
+//Pointers written before they moved are only left on the com tree, the script api can not put one there
+#[cfg(feature = "unstable-internals")]
+#[tokio::test]
+async fn legacy_share_pointer() -> Result<(), Error> {
+    use crate::agent::custom_commands::{Command, Header, CompilerMemory};
+    use crate::agent::structs::{Callback, SharedPointer, Task, Tasks};
+    use crate::dids::signing::Verifier;
+    use crate::model::permission::ChannelPermissionSet;
+    use simple_crypto::Key;
+
+    #[derive(serde::Serialize, Debug, Clone)]
+    struct WriteCom(Record);
+    #[async_trait::async_trait]
+    impl Command for WriteCom {
+        async fn process<'a>(
+            self: Box<Self>, uuid: Uuid, header: Header,
+            _: &mut CompilerMemory<'a>, _: &mut CompilerCache
+        ) -> Result<Tasks, Error> {
+            Task::waiting(uuid, header.clone(), Callback::new(commands::EnsureEmpty::new), vec![
+                Task::ready(header.com(), commands::UpdatePrivate::new(self.0, None))
+            ])
+        }
+    }
+
+    let net = LocalNet::new(2).await?;
+    let (alice_did, bob_did) = (net.did(0), net.did(1));
+    let bob = net.agent(1).await?;
+    let mut cache = CompilerCache::default();
+    let process = || scripts::ProcessShares::new(alice_did.clone());
+    let error = bob.run::<(Vec<SharedPermissions>, SharesNeedingRefresh)>(&mut cache, process()).await.unwrap_err();
+    assert_eq!(error.code(), "NOT_FOUND");
+
+    let channel = RecordPath::from_segments(&[Uuid::new_v5(&Uuid::NAMESPACE_OID, bob_did.to_string().as_bytes())]);
+    let key = || Key::new_secret(SecretKey::new());
+    let perms = PermissionSet::new(channel, SecretKey::new(), key(), key(), None, Some(ChannelPermissionSet::new(key(), key(), key())));
+    let pointer = Protocol::new(
+        "pointer", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema_for!(PermissionSet))?), None
+    )?;
+    let path = SharedPointer::legacy_path(&Verifier::Left(alice_did.clone()));
+    bob.run::<()>(&mut cache, Box::new(WriteCom(Record::new(path, pointer, &serde_json::to_vec(&perms)?)))).await?;
+    let (shares, _) = bob.run::<(Vec<SharedPermissions>, SharesNeedingRefresh)>(&mut cache, process()).await?;
+    assert!(shares.is_empty());
+    //Listing falls back to it for the sharer named, it is no pointer of the current format
+    let list = |filter| scripts::ListShared::new(filter);
+    let filter = crate::agent::SharedFilter::Sharer(alice_did.clone());
+    assert!(bob.run::<Vec<crate::agent::SharedRecordInfo>>(&mut cache, list(Some(filter))).await?.is_empty());
+    assert!(bob.run::<Vec<crate::agent::SharedRecordInfo>>(&mut cache, list(None)).await?.is_empty());
+    Ok(())
+}
+
+//Stands in for a Dwn, answering every public read with its records re-signed by mallory and indexed
+//as the real signer's. With claim the signatures also name the real signer
+#[derive(Debug, Clone)]
+struct HostileDwn {
+    inner: LocalDwns,
+    url: url::Url,
+    mallory: crate::dids::DidKeyPair,
+    claim: bool
+}
+
+impl HostileDwn {
+    fn forge(&self, item: &crate::dwn::structs::PublicDwnItem) -> Result<crate::dwn::structs::PublicDwnItem, Error> {
+        use crate::dids::signing::{Signer, SignedObject};
+        let mut record = item.0.inner().clone();
+        record.index.insert("signer".to_string(), item.0.signer().to_string().into());
+        let mut forged = serde_json::to_value(SignedObject::new(Signer::Left(self.mallory.clone()), record)?)?;
+        if self.claim {forged["signature"]["signer"] = serde_json::to_value(item.0.signer())?;}
+        Ok(crate::dwn::structs::PublicDwnItem(serde_json::from_value(forged)?))
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for HostileDwn {
+    async fn send_request(&self, body: String, url: url::Url) -> Result<String, Error> {
+        let response = self.inner.send_request(body, url.clone()).await?;
+        if url != self.url {return Ok(response);}
+        let mut responses = serde_json::from_str::<Vec<(Uuid, DwnResponse)>>(&response)?;
+        for (_, response) in &mut responses {
+            if let DwnResponse::ReadPublic(items, _) = response {
+                *items = items.iter().map(|item| self.forge(item)).collect::<Result<_, Error>>()?;
+            }
+        }
+        Ok(serde_json::to_string(&responses)?)
+    }
+}
+
+#[tokio::test]
+async fn share_rejects_forged_agent_keys() -> Result<(), Error> {
+    use crate::dids::DidKeyPair;
+
+    let net = LocalNet::new(3).await?;
+    let bob_did = net.did(1);
+    net.agent(1).await?;
+    let protocol = Protocol::new("Shared", true, PermissionOptions::new(false, true, false, None), None, None)?;
+    let mallory = serde_json::from_value::<DidKeyPair>(serde_json::to_value(&net.users[2].0)?["sig_key"].clone())?;
+
+    //Bob's agent keys as his Dwn serves them are shared to
+    let alice = net.agent(0).await?;
+    let mut cache = CompilerCache::default();
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    alice.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(path.clone(), protocol.clone(), &[]), None)).await?;
+    alice.run::<()>(&mut cache, scripts::Share::new(path, None, bob_did.clone())).await?;
+
+    for claim in [false, true] {
+        let hostile = HostileDwn{inner: net.dwns.clone(), url: net.urls[1].clone(), mallory: mallory.clone(), claim};
+        let alice = Agent::with_client(
+            Wallet::new(net.users[0].0.clone()).root(), net.resolver.clone(), Box::new(hostile), None
+        ).await?;
+        let mut cache = CompilerCache::default();
+        let path = RecordPath::new(&[Uuid::new_v4()])?;
+        alice.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(path.clone(), protocol.clone(), &[]), None)).await?;
+        let error = alice.run::<()>(&mut cache, scripts::Share::new(path, None, bob_did.clone())).await.unwrap_err();
+        assert_eq!(error.to_string(), "Bad Request: Recipient has no active agents", "claim: {}", claim);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn refresh_shares_to_new_device() -> Result<(), Error> {
+    let net = LocalNet::new(2).await?;
+    let (alice_did, bob_did) = (net.did(0), net.did(1));
+    let alice = net.agent(0).await?;
+    net.agent(1).await?;
+    let mut a_cache = CompilerCache::default();
+
+    let folder = Uuid::new_v4();
+    let path = RecordPath::new(&[folder, Uuid::new_v4()])?;
+    let protocol = Protocol::new("Shared", true, PermissionOptions::new(false, true, false, None), None, None)?;
+    let folder_protocol = Protocol::new(
+        "Folder", true, PermissionOptions::new(false, true, false, Some(ChannelPermissionOptions::new(true, true))),
+        None, Some(ChannelProtocol::new(Some(vec![&protocol])))
+    )?;
+    alice.run::<()>(&mut a_cache, scripts::CreatePrivate::new(Record::new(RecordPath::new(&[folder])?, folder_protocol, &[]), None)).await?;
+    alice.run::<()>(&mut a_cache, scripts::CreatePrivate::new(Record::new(path.clone(), protocol, &[]), None)).await?;
+    alice.run::<()>(&mut a_cache, scripts::Share::new(path.clone(), None, bob_did.clone())).await?;
+
+    //Enrolled after the share, the envelope was only sealed to bob's root agent
+    let device_key = Wallet::new(net.users[1].0.clone()).get_agent_key(RecordPath::new(&[folder])?)?;
+    let device = Agent::with_client(device_key, net.resolver.clone(), Box::new(net.dwns.clone()), None).await?;
+    let mut d_cache = CompilerCache::default();
+    device.run::<Vec<(crate::dids::signing::Verifier, RecordUpdated)>>(&mut d_cache, scripts::ScanDM::new()).await?;
+    let process = || scripts::ProcessShares::new(alice_did.clone());
+    let (shares, refresh) = device.run::<(Vec<SharedPermissions>, SharesNeedingRefresh)>(&mut d_cache, process()).await?;
+    assert!(shares.is_empty());
+    assert_eq!((&refresh.sharer, &refresh.paths), (&alice_did, &vec![path.clone()]));
+
+    alice.run::<()>(&mut a_cache, scripts::RefreshSharesTo::new(bob_did)).await?;
+    let (mut shares, refresh) = device.run::<(Vec<SharedPermissions>, SharesNeedingRefresh)>(&mut d_cache, process()).await?;
+    assert!(refresh.paths.is_empty());
+    assert_eq!(shares.len(), 1);
+    let record = device.run::<Option<Record>>(&mut d_cache, scripts::ReadPrivate::shared_from(shares.remove(0), alice_did)).await?;
+    assert_eq!(record.map(|r| r.path), Some(path));
+    Ok(())
+}
+
+#[tokio::test]
+async fn share_upgrade() -> Result<(), Error> {
+    use crate::agent::{PendingShareUpgrade, ShareAuditEntry};
+    use crate::agent::structs::Responses;
+
+    let net = LocalNet::new(2).await?;
+    let (alice_did, bob_did) = (net.did(0), net.did(1));
+    let (alice, bob) = (net.agent(0).await?, net.agent(1).await?);
+    let (mut a_cache, mut b_cache) = (CompilerCache::default(), CompilerCache::default());
+
+    //Shares are at least read only, delete is there to be asked for
+    let read_only = PermissionOptions::new(false, true, false, None);
+    let protocol = Protocol::new("Deletable", true, read_only.clone(), None, None)?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    alice.process_commands(&mut a_cache, vec![
+        scripts::CreatePrivate::new(Record::new(path.clone(), protocol, &[]), None)
+    ]).await?.remove(0).downcast::<()>()?;
+    alice.process_commands(&mut a_cache, vec![
+        scripts::Share::new(path.clone(), Some(read_only), bob_did.clone())
+    ]).await?.remove(0).downcast::<()>()?;
+
+    bob.process_commands(&mut b_cache, vec![scripts::ScanDM::new()]).await?;
+    let (shares, _) = *bob.process_commands(&mut b_cache, vec![scripts::ProcessShares::new(alice_did.clone())]).await?
+        .remove(0).downcast::<(Vec<SharedPermissions>, SharesNeedingRefresh)>()?;
+    assert!(shares.iter().all(|s| !s.perms.options().can_delete));
+    let wanted = PermissionOptions::new(false, true, true, None);
+    //Both are sent to the endpoints of the sharer, one response each
+    commands::EnsureEmpty::is_empty(bob.run::<Responses>(&mut b_cache,
+        scripts::RequestShareUpgrade::new(alice_did.clone(), path.clone(), wanted.clone())
+    ).await?)?;
+
+    alice.process_commands(&mut a_cache, vec![scripts::ScanDM::new()]).await?;
+    let pending = *alice.process_commands(&mut a_cache, vec![scripts::ListShareUpgrades::new()]).await?
+        .remove(0).downcast::<Vec<PendingShareUpgrade>>()?;
+    assert_eq!(pending.len(), 1);
+    assert_eq!((&pending[0].requester, &pending[0].request.wanted), (&bob_did, &wanted));
+    alice.process_commands(&mut a_cache, vec![
+        scripts::AnswerShareUpgrade::approve(bob_did.clone(), path.clone())
+    ]).await?.remove(0).downcast::<()>()?;
+    assert!(alice.process_commands(&mut a_cache, vec![scripts::ListShareUpgrades::new()]).await?
+        .remove(0).downcast::<Vec<PendingShareUpgrade>>()?.is_empty());
+    //Answering again finds nothing to approve
+    let error = alice.run::<()>(&mut a_cache, scripts::AnswerShareUpgrade::approve(bob_did.clone(), path.clone())).await;
+    assert_eq!(error.unwrap_err().code(), "NOT_FOUND");
+
+    bob.process_commands(&mut b_cache, vec![scripts::ScanDM::new()]).await?;
+    let audit = *bob.process_commands(&mut b_cache, vec![scripts::ShareAudit::new()]).await?
+        .remove(0).downcast::<Vec<ShareAuditEntry>>()?;
+    assert_eq!(audit.len(), 1);
+    assert_eq!((&audit[0].sharer, &audit[0].granted), (&alice_did, &Some(wanted)));
+    let (shares, _) = *bob.process_commands(&mut b_cache, vec![scripts::ProcessShares::new(alice_did.clone())]).await?
+        .remove(0).downcast::<(Vec<SharedPermissions>, SharesNeedingRefresh)>()?;
+    let deletable = shares.into_iter().find(|s| s.perms.options().can_delete).unwrap();
+    commands::EnsureEmpty::is_empty(bob.run::<Responses>(&mut b_cache,
+        scripts::DeletePrivate::shared(deletable, alice_did)
+    ).await?)?;
+
+    assert!(alice.run::<Option<Record>>(&mut a_cache, scripts::ReadPrivate::new(path)).await?.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn protocol_mismatch() -> Result<(), Error> {
+    let (agent, _, _) = local_agent().await?;
+    let options = PermissionOptions::new(true, true, true, None);
+    let notes = Protocol::new("Notes", true, options.clone(), None, None)?;
+    let drafts = Protocol::new("Drafts", true, options, None, None)?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let (mut cache, mut other) = (CompilerCache::default(), CompilerCache::default());
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(path.clone(), notes.clone(), &[]), None)).await?;
+    agent.run::<Option<Record>>(&mut other, scripts::ReadPrivate::new(path.clone())).await?;
+
+    //Recreated under another protocol behind the second cache
+    agent.run::<()>(&mut cache, scripts::DeletePrivate::new(path.clone())).await?;
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(path.clone(), drafts.clone(), &[]), None)).await?;
+    let error = agent.run::<Option<Record>>(&mut other, scripts::ReadPrivate::new(path.clone())).await.unwrap_err();
+    assert_eq!(error.code(), "PROTOCOL_MISMATCH");
+    assert!(error.to_string().contains("expected Notes"));
+    let record = agent.run::<Option<Record>>(&mut other, scripts::ReadPrivate::new(path.clone())).await?.unwrap();
+    assert_eq!(record.protocol, drafts);
+
+    let create = scripts::CreatePrivate::new(Record::new(path.clone(), notes.clone(), &[]), None);
+    assert_eq!(agent.run::<()>(&mut cache, create).await.unwrap_err().code(), "PROTOCOL_MISMATCH");
+
+    agent.run::<()>(&mut cache, scripts::ReplaceRecordProtocol::new(Record::new(path.clone(), notes.clone(), &[]), None)).await?;
+    let record = agent.run::<Option<Record>>(&mut cache, scripts::ReadPrivate::new(path.clone())).await?.unwrap();
+    assert_eq!(record.protocol, notes);
+    let replace = scripts::ReplaceRecordProtocol::new(Record::new(path, notes, &[]), None);
+    assert_eq!(agent.run::<()>(&mut cache, replace).await.unwrap_err().code(), "BAD_REQUEST");
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_public_by_protocol() -> Result<(), Error> {
+    use crate::dwn::structs::PublicRecord;
+    use crate::prelude::Filter;
+
+    let (agent, _, _) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let schema = Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?);
+    let options = PermissionOptions::new(true, true, true, None);
+    let posts = Protocol::new("posts", true, options.clone(), schema.clone(), None)?;
+    let events = Protocol::new("events", true, options, schema, None)?;
+    let mut created = Vec::new();
+    for protocol in [&posts, &posts, &events] {
+        //No index of our own, the protocol is indexed by the Dwn
+        let record = PublicRecord::new(None, protocol.clone(), b"{}", None)?;
+        created.push(record.uuid);
+        agent.run::<()>(&mut cache, scripts::CreatePublic::new(record, None)).await?;
+    }
+
+    for (protocol, expected) in [(&posts, &created[..2]), (&events, &created[2..])] {
+        let (records, _) = agent.run::<(Vec<PublicRecord>, Option<Vec<u8>>)>(
+            &mut cache, scripts::ReadPublicByProtocol::new(protocol.uuid(), Filters::new(vec![]))
+        ).await?;
+        let mut uuids = records.iter().map(|r| r.uuid).collect::<Vec<_>>();
+        uuids.sort();
+        let mut expected = expected.to_vec();
+        expected.sort();
+        assert_eq!(uuids, expected);
+    }
+    let stranger = Filters::new(vec![("signer", Filter::equal("did:dht:stranger"))]);
+    let (records, _) = agent.run::<(Vec<PublicRecord>, Option<Vec<u8>>)>(
+        &mut cache, scripts::ReadPublicByProtocol::new(posts.uuid(), stranger)
+    ).await?;
+    assert!(records.is_empty());
+    Ok(())
+}
+
+#[cfg(feature = "unstable-internals")]
+#[tokio::test]
+async fn send_raw() -> Result<(), Error> {
+    use crate::agent::structs::{AgentRequest, PrivateRecord};
+    use crate::dids::signing::SignedObject;
+
+    let (agent, _, _) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(path.clone(), SystemProtocols::usize(), b"1"), None)).await?;
+    let (record, _) = agent.run::<(Option<Box<PrivateRecord>>, bool)>(
+        &mut cache, Box::new(commands::ReadPrivate::path(path))
+    ).await?;
+    let record = record.unwrap();
+    let endpoint = agent.endpoint_health().into_keys().next().unwrap();
+
+    let request = AgentRequest::ReadPrivate(record.perms.discover()).into_dwn_request()?;
+    let mut responses = agent.send_raw(endpoint.clone(), vec![request, DwnRequest::Capabilities]).await?;
+    assert_eq!(responses.len(), 2);
+    assert!(matches!(responses.pop(), Some(DwnResponse::Capabilities(_))));
+    let Some(DwnResponse::ReadPrivate(Some(item))) = responses.pop() else {panic!("Expected a record")};
+    let read = record.perms.read.secret_key().unwrap();
+    let plaintext = crate::common::Compression::decode(read.decrypt(&item.payload)?)?;
+    let raw = serde_json::from_slice::<SignedObject<PrivateRecord>>(&plaintext)?
+        .verify_with_key(&record.perms.create.public_key())?;
+    assert_eq!(raw.payload, record.payload);
+    assert_eq!(raw.protocol, record.protocol);
+
+    //A request the Dwn rejects is its response, not an error
+    let other = AgentRequest::ReadPrivate(SecretKey::new()).into_dwn_request()?;
+    assert_eq!(agent.send_raw(endpoint, vec![other]).await?, vec![DwnResponse::ReadPrivate(None)]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn rotate_com_key() -> Result<(), Error> {
+    let mut did_resolver = MemoryDidResolver::new();
+    let mut servers = Vec::new();
+    let mut users = Vec::new();
+    for _ in 0..2 {
+        let (id, doc) = get_server(1)?;
+        let (user, user_doc) = get_user(vec![doc.did()])?;
+        did_resolver.store(Box::new(doc.clone()));
+        did_resolver.store(Box::new(user_doc.clone()));
+        servers.push((id, doc));
+        users.push((user, user_doc.did()));
+    }
+    let bob_server = servers[1].1.did().to_string();
+    let resolver: Box<dyn DidResolver> = Box::new(did_resolver.clone());
+    let dwns = LocalDwns::new(&*resolver, servers).await?;
+    let (alice, alice_did) = users.remove(0);
+    let (mut bob, bob_did) = users.remove(0);
+    let alice = Agent::with_client(Wallet::new(alice).root(), resolver, Box::new(dwns.clone()), None).await?;
+    let mut a_cache = CompilerCache::default();
+
+    let protocol = Protocol::new("Rotated", true, PermissionOptions::new(false, true, false, None), None, None)?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    alice.run::<()>(&mut a_cache, scripts::CreatePrivate::new(Record::new(path.clone(), protocol, &[]), None)).await?;
+    //Shares go to the agents bob has published
+    Agent::with_client(Wallet::new(bob.clone()).root(), Box::new(did_resolver.clone()), Box::new(dwns.clone()), None).await?;
+    alice.run::<()>(&mut a_cache, scripts::Share::new(path.clone(), None, bob_did)).await?;
+
+    let (old, document) = bob.rotate_com_key(vec![bob_server])?;
+    did_resolver.store(Box::new(document));
+    let bob = Agent::with_client(Wallet::new(bob).root(), Box::new(did_resolver), Box::new(dwns.clone()), None).await?;
+    let mut b_cache = CompilerCache::default();
+
+    //The share was encrypted to the old key and stored under it
+    bob.process_commands(&mut b_cache, vec![scripts::ScanDM::new()]).await?;
+    let error = bob.process_commands(&mut b_cache, vec![scripts::ProcessShares::new(alice_did.clone())]).await.unwrap_err();
+    assert_eq!(error.code(), "NOT_FOUND");
+
+    assert_eq!(bob.run::<usize>(&mut b_cache, scripts::RotateComKey::new(old)).await?, 1);
+    bob.process_commands(&mut b_cache, vec![scripts::ScanDM::new()]).await?;
+    let (shares, _) = *bob.process_commands(&mut b_cache, vec![scripts::ProcessShares::new(alice_did)]).await?
+        .remove(0).downcast::<(Vec<SharedPermissions>, SharesNeedingRefresh)>()?;
+    assert_eq!(shares.len(), 1);
+    assert_eq!(shares[0].perms.path, path);
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_diagnostics() -> Result<(), Error> {
+    use crate::agent::{DropReason, ReadDiagnostics};
+    use crate::dwn::structs::{PublicDwnItem, PublicRecord};
+    use simple_database::database::Filter;
+
+    let (agent, dwns, url) = local_agent().await?;
+    let dwn = dwns.dwns[&url].clone();
+    let mut cache = CompilerCache::default();
+    let numbers = Protocol::new(
+        "numbers", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema_for!(Vec<u64>))?), None
+    )?;
+    let valid = PublicRecord::new(None, numbers.clone(), b"[1]", None)?;
+    agent.run::<()>(&mut cache, scripts::CreatePublic::new(valid.clone(), None)).await?;
+
+    //Stored around the Dwn's own checks
+    let key = either::Either::Right(SecretKey::new());
+    let forged = PublicRecord::new(None, numbers.clone(), b"[2]", None)?.into_item(key.clone())?;
+    let other = PublicRecord::new(None, numbers.clone(), b"[3]", None)?.into_item(key.clone())?;
+    let mut forged = serde_json::to_value(&forged)?;
+    forged["signature"] = serde_json::to_value(&other)?["signature"].clone();
+    dwn.public_database.set(&serde_json::from_value::<PublicDwnItem>(forged)?).await?;
+    let invalid = PublicRecord::new(None, numbers.clone(), b"{}", None)?;
+    dwn.public_database.set(&invalid.into_item(key)?).await?;
+
+    let filters = Filters::new(vec![("protocol", Filter::equal(numbers.uuid().to_string()))]);
+    let (records, _, diagnostics) = agent.run::<(Vec<PublicRecord>, Option<Vec<u8>>, ReadDiagnostics)>(
+        &mut cache, scripts::ReadPublic::with_diagnostics(filters.clone(), None)
+    ).await?;
+    assert_eq!(records.into_iter().map(|r| r.uuid).collect::<Vec<_>>(), vec![valid.uuid]);
+    assert_eq!(diagnostics.count(DropReason::BadSignature), 1);
+    assert_eq!(diagnostics.count(DropReason::InvalidPayload), 1);
+    assert_eq!(diagnostics.total(), 2);
+
+    //Plain reads are unchanged
+    let (records, _) = agent.run::<(Vec<PublicRecord>, Option<Vec<u8>>)>(
+        &mut cache, scripts::ReadPublic::new(filters, None)
+    ).await?;
+    assert_eq!(records.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_dm_diagnostics() -> Result<(), Error> {
+    use crate::agent::{DropReason, ReadDiagnostics};
+    use crate::agent::structs::DmMessage;
+    use crate::dids::signing::{Signer, SignedObject, VerifiedBy};
+    use crate::dwn::structs::{DmPage, DwnItem};
+
+    let net = LocalNet::new(1).await?;
+    let (_, com_key) = net.resolver.resolve_dwn_keys(&net.did(0)).await?;
+    let sender = SecretKey::new();
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let signed = |payload: &str| SignedObject::new(
+        Signer::Right(sender.clone()), DmMessage::RecordUpdated(RecordUpdated::new(&path, payload.as_bytes()))
+    );
+    let mut forged = serde_json::to_value(signed("forged")?)?;
+    forged["signature"] = serde_json::to_value(signed("other")?)?["signature"].clone();
+    let encode = crate::common::Compression::encode;
+    //Stored as is, the Dwn can not read DMs to check them
+    for payload in [
+        com_key.encrypt(&encode(serde_json::to_vec(&signed("valid")?)?))?,
+        com_key.encrypt(&encode(serde_json::to_vec(&forged)?))?,
+        com_key.encrypt(&encode(b"not a dm".to_vec()))?,
+        SecretKey::new().public_key().encrypt(&encode(serde_json::to_vec(&signed("misdelivered")?)?))?,
+    ] {
+        let item = DwnItem{discover: com_key.clone(), delete: None, payload, expires: None};
+        net.dwn(0).process_request(DwnRequest::CreateDM(item)).await?;
+    }
+
+    let agent = net.agent(0).await?;
+    let mut cache = CompilerCache::default();
+    let (messages, _, diagnostics) = agent.run::<(Vec<(VerifiedBy, DmMessage)>, DmPage, ReadDiagnostics)>(
+        &mut cache, scripts::ReadDM::diagnosed(None, 10)
+    ).await?;
+    assert!(matches!(&messages[..], [(_, DmMessage::RecordUpdated(u))] if u.payload == RecordUpdated::new(&path, b"valid").payload));
+    assert_eq!(diagnostics.count(DropReason::BadSignature), 1);
+    assert_eq!(diagnostics.count(DropReason::Malformed), 1);
+    assert_eq!(diagnostics.count(DropReason::Undecryptable), 1);
+    assert_eq!(diagnostics.total(), 3);
+
+    //Plain reads are unchanged
+    let (messages, _) = agent.run::<(Vec<(VerifiedBy, DmMessage)>, DmPage)>(&mut cache, scripts::ReadDM::new(None, 10)).await?;
+    assert_eq!(messages.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn did_web() -> Result<(), Error> {
+    use crate::dids::{WebDocument, DidMethod, DidKeyPurpose};
+    use crate::common::Convert;
+    use std::str::FromStr;
+
+    let did = Did::from_str("did:web:example.com")?;
+    assert_eq!(did.method, DidMethod::Web);
+    assert_eq!(did.to_string(), "did:web:example.com");
+    assert_eq!(WebDocument::url(&did.id)?.as_str(), "https://example.com/.well-known/did.json");
+    assert_eq!(WebDocument::url("example.com%3A8443:user:alice")?.as_str(), "https://example.com:8443/user/alice/did.json");
+    assert_eq!(Convert::Base58Btc.decode(&Convert::Base58Btc.encode(&[0, 0, 1, 2, 255]))?, vec![0, 0, 1, 2, 255]);
+    assert_eq!(Convert::Base58Btc.encode(&[0, 0, 1, 2, 255]), "11LiA");
+
+    let fixture = include_bytes!("dids/fixtures/did.json");
+    let doc = WebDocument::parse(&did.id, fixture)?;
+    //The Ed25519 key has no use here
+    assert_eq!(doc.keys.len(), 2);
+    assert_eq!(doc.keys["sig"].purposes, vec![DidKeyPurpose::Auth, DidKeyPurpose::Asm]);
+    assert_eq!(doc.keys["com"].purposes, vec![DidKeyPurpose::Auth, DidKeyPurpose::Agm]);
+    assert!(WebDocument::parse("other.com", fixture).is_err());
+
+    let mut did_resolver = MemoryDidResolver::new();
+    did_resolver.store(Box::new(doc.clone()));
+    let (sig, com) = did_resolver.resolve_dwn_keys(&did).await?;
+    assert_eq!((sig, com), (doc.keys["sig"].public_key.clone(), doc.keys["com"].public_key.clone()));
+    let endpoints = did_resolver.get_endpoints(std::slice::from_ref(&did)).await?;
+    assert_eq!(endpoints.into_iter().map(|e| e.1.to_string()).collect::<Vec<_>>(), vec![
+        "https://backup.example.com/", "https://dwn.example.com/"
+    ]);
+
+    //Stored and read back like any other document
+    let boxed: Box<dyn DidDocument> = Box::new(doc);
+    let round_trip = serde_json::from_str::<Box<dyn DidDocument>>(&serde_json::to_string(&boxed)?)?;
+    assert_eq!(round_trip.did(), did);
+    Ok(())
+}
+
+#[tokio::test]
+async fn send_failover() -> Result<(), Error> {
+    use crate::agent::{EndpointPolicy, ParentPolicy};
+    use crate::agent::structs::Responses;
+
+    let mut did_resolver = MemoryDidResolver::new();
+    let mut servers = Vec::new();
+    let mut dids = Vec::new();
+    for _ in 0..3 {
+        let (id, doc) = get_server(1)?;
+        did_resolver.store(Box::new(doc.clone()));
+        dids.push(doc.did());
+        servers.push((id, doc));
+    }
+    //The first Dwn is never started
+    servers.remove(0);
+    let (primary, primary_doc) = get_user(vec![dids[1].clone(), dids[2].clone()])?;
+    let (_, fallback_doc) = get_user(vec![dids[0].clone(), dids[2].clone()])?;
+    did_resolver.store(Box::new(primary_doc.clone()));
+    did_resolver.store(Box::new(fallback_doc.clone()));
+    let did_resolver: Box<dyn DidResolver> = Box::new(did_resolver);
+    let urls = did_resolver.get_ordered_endpoints(&primary_doc.did()).await?.into_iter().map(|ep| ep.1).collect::<Vec<_>>();
+    let dwns = LocalDwns::new(&*did_resolver, servers).await?;
+
+    let writes = |url: &url::Url| dwns.sent(url).into_iter().filter(|r| matches!(r, DwnRequest::CreatePrivate(_))).count();
+    let create = || scripts::CreatePrivate::with_policy(
+        Record::new(RecordPath::new(&[Uuid::new_v4()]).unwrap(), SystemProtocols::usize(), b"1"), None, ParentPolicy::Skip
+    );
+
+    let agent = Agent::with_client(Wallet::new(primary).root(), did_resolver, Box::new(dwns.clone()), None).await?;
+    let mut cache = CompilerCache::default();
+    agent.process_commands(&mut cache, vec![
+        scripts::Send::with_policy(create(), vec![primary_doc.did()], EndpointPolicy::Failover)
+    ]).await?;
+    assert_eq!((writes(&urls[0]), writes(&urls[1])), (1, 0));
+    agent.process_commands(&mut cache, vec![
+        scripts::Send::with_policy(create(), vec![primary_doc.did()], EndpointPolicy::First)
+    ]).await?;
+    assert_eq!((writes(&urls[0]), writes(&urls[1])), (2, 0));
+    agent.process_commands(&mut cache, vec![scripts::Send::new(create(), vec![primary_doc.did()])]).await?;
+    assert_eq!((writes(&urls[0]), writes(&urls[1])), (3, 1));
+
+    //Only an endpoint that can not be reached is skipped
+    let results = agent.process_commands(&mut cache, vec![
+        scripts::Send::with_policy(create(), vec![fallback_doc.did()], EndpointPolicy::Failover)
+    ]).await?;
+    assert!(results[0].downcast_ref::<Responses>().is_some_and(|r| r[0].downcast_ref::<()>().is_some()));
+    assert_eq!(writes(&urls[1]), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn parent_policy_on_foreign_endpoint() -> Result<(), Error> {
+    use crate::agent::ParentPolicy;
+    use crate::agent::structs::{BoxCommand, PrivateRecord, Responses};
+
+    let net = LocalNet::new(2).await?;
+    let bob_did = net.did(1);
+    let alice = net.agent(0).await?;
+    net.agent(1).await?;
+    let mut cache = CompilerCache::default();
+
+    let child = Protocol::new("Leaf", false, PermissionOptions::new(false, true, false, None), None, None)?;
+    let folder = Protocol::new(
+        "Folder", false, PermissionOptions::new(false, true, false, Some(ChannelPermissionOptions::new(true, true))),
+        None, Some(ChannelProtocol::new(Some(vec![&child])))
+    )?;
+    let folder_path = RecordPath::new(&[Uuid::new_v4()])?;
+    let path = folder_path.extend(&[Uuid::new_v4()])?;
+    alice.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(folder_path.clone(), folder, &[]), None)).await?;
+    let record = Record::new(path.clone(), child.clone(), &[]);
+
+    let on_bob = |command: BoxCommand| scripts::Send::new(command, vec![bob_did.clone()]);
+    let read = |path: &RecordPath| on_bob(Box::new(commands::ReadPrivate::path(path.clone())));
+    let found = |responses: Responses| responses.into_iter().next()
+        .and_then(|r| r.downcast::<(Option<Box<PrivateRecord>>, bool)>().ok())
+        .and_then(|r| r.0).map(|r| r.perms.path);
+
+    //Nothing of alice's hierarchy is on bob's Dwn, the folder is copied from hers before linking
+    assert!(alice.run::<Responses>(&mut cache, on_bob(scripts::CreatePrivate::new(record.clone(), None))).await.is_err());
+    alice.run::<Responses>(&mut cache, on_bob(scripts::CreatePrivate::with_policy(
+        record.clone(), None, ParentPolicy::CreateMissing
+    ))).await?;
+    assert_eq!(found(alice.run::<Responses>(&mut cache, read(&folder_path)).await?), Some(folder_path.clone()));
+    assert_eq!(found(alice.run::<Responses>(&mut cache, read(&path)).await?), Some(path.clone()));
+    let linked = alice.run::<Responses>(&mut cache, on_bob(Box::new(commands::ReadPrivateChild::new(folder_path, 0)))).await?;
+    assert_eq!(found(linked), Some(path));
+
+    //Skipped parents are not written and the record stays orphaned
+    let orphan_parent = RecordPath::new(&[Uuid::new_v4()])?;
+    let orphan = orphan_parent.extend(&[Uuid::new_v4()])?;
+    alice.run::<Responses>(&mut cache, on_bob(scripts::CreatePrivate::with_policy(
+        Record::new(orphan.clone(), child.clone(), &[]), None, ParentPolicy::Skip
+    ))).await?;
+    assert_eq!(found(alice.run::<Responses>(&mut cache, read(&orphan)).await?), Some(orphan));
+    assert_eq!(found(alice.run::<Responses>(&mut cache, read(&orphan_parent)).await?), None);
+
+    //A parent missing from alice's own endpoints too has nothing to be copied from
+    let missing = RecordPath::new(&[Uuid::new_v4(), Uuid::new_v4()])?;
+    let error = alice.run::<Responses>(&mut cache, on_bob(scripts::CreatePrivate::with_policy(
+        Record::new(missing.clone(), child, &[]), None, ParentPolicy::CreateMissing
+    ))).await.unwrap_err();
+    assert!(error.to_string().contains("Parent record on tenant endpoints"), "{}", error);
+    assert_eq!(found(alice.run::<Responses>(&mut cache, read(&missing)).await?), None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn derivation_cost() -> Result<(), Error> {
+    use crate::agent::MAX_PATH_DEPTH;
+    use crate::agent::structs::PrivateRecord;
+
+    let (agent, _, _) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let deep = (0..MAX_PATH_DEPTH+1).map(|_| Uuid::new_v4()).collect::<Vec<_>>();
+    assert_eq!(RecordPath::new(&deep).unwrap_err().code(), "VALIDATION");
+    assert!(RecordPath::new(&deep[..MAX_PATH_DEPTH]).is_ok());
+
+    //Paths read back from records are not checked on parse, deriving them is refused up front
+    let hostile = (0..200).map(|_| Uuid::new_v4()).collect::<Vec<_>>();
+    let hostile = serde_json::from_value::<RecordPath>(serde_json::to_value(RecordPath::from_segments(&hostile))?)?;
+    let before = cache.derivations();
+    let error = agent.run::<(Option<Box<PrivateRecord>>, bool)>(&mut cache, Box::new(commands::ReadPrivate::path(hostile))).await.unwrap_err();
+    assert_eq!(error.code(), "VALIDATION");
+    assert_eq!(cache.derivations(), before);
+
+    let parent = RecordPath::new(&[Uuid::new_v4()])?;
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(parent.clone(), SystemProtocols::root(), b""), None)).await?;
+    let create = || scripts::CreatePrivate::new(Record::new(parent.extend(&[Uuid::new_v4()]).unwrap(), SystemProtocols::usize(), b"1"), None);
+
+    let before = cache.derivations();
+    for _ in 0..50 {agent.run::<()>(&mut cache, create()).await?;}
+    let separate = cache.derivations()-before;
+    let before = cache.derivations();
+    agent.run_all::<()>(&mut cache, (0..50).map(|_| create()).collect()).await?;
+    let batched = cache.derivations()-before;
+    println!("50 siblings: {} derivations one at a time, {} in one batch", separate, batched);
+    assert!(batched < separate);
+    Ok(())
+}
+
+#[tokio::test]
+async fn record_pinning() -> Result<(), Error> {
+    use crate::agent::commands::{Init, ReadDM};
+
+    let (agent, _, _) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let rooms = Protocol::new(
+        "rooms_protocol", true,
+        PermissionOptions::new(true, true, true, Some(ChannelPermissionOptions::new(true, true))),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?),
+        Some(ChannelProtocol::new(Some(vec![&SystemProtocols::usize()])))
+    )?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let child = path.extend(&[Uuid::new_v4()])?;
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(path.clone(), rooms, b"{}"), None)).await?;
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(child.clone(), SystemProtocols::usize(), b"1"), None)).await?;
+
+    agent.run::<()>(&mut cache, scripts::PinRecord::new(path.clone())).await?;
+    agent.run::<()>(&mut cache, scripts::PinRecord::new(child.clone())).await?;
+    let pins = agent.run::<BTreeSet<RecordPath>>(&mut cache, scripts::ReadPins::new()).await?;
+    assert_eq!(pins, BTreeSet::from([path.clone(), child.clone()]));
+
+    let error = agent.run::<()>(&mut cache, scripts::DeletePrivate::new(path.clone())).await.unwrap_err();
+    assert_eq!(error.code(), "PINNED");
+    let error = agent.run::<()>(&mut cache, scripts::DeletePrivateChild::new(path.clone(), 0)).await.unwrap_err();
+    assert_eq!(error.code(), "PINNED");
+    let error = agent.run::<()>(&mut cache, scripts::RelocateRecord::new(path.clone(), RecordPath::new(&[Uuid::new_v4()])?)).await.unwrap_err();
+    assert_eq!(error.code(), "PINNED");
+    assert!(agent.run::<Option<Record>>(&mut cache, scripts::ReadPrivate::new(child.clone())).await?.is_some());
+
+    //System bookkeeping is pinned without being in the set
+    let error = agent.run::<()>(&mut cache, scripts::DeletePrivate::new(ReadDM::cursor_path())).await.unwrap_err();
+    assert_eq!(error.code(), "PINNED");
+    let error = agent.run::<()>(&mut cache, scripts::DeletePublic::new(Init::agent_keys_uuid(agent.tenant()), None)).await.unwrap_err();
+    assert_eq!(error.code(), "PINNED");
+
+    agent.run::<()>(&mut cache, scripts::UnpinRecord::new(child.clone())).await?;
+    agent.run::<()>(&mut cache, scripts::DeletePrivateChild::new(path.clone(), 0)).await?;
+    assert!(agent.run::<Option<Record>>(&mut cache, scripts::ReadPrivate::new(child)).await?.is_none());
+
+    agent.run::<()>(&mut cache, scripts::DeletePrivate::override_pins(path.clone())).await?;
+    assert!(agent.run::<Option<Record>>(&mut cache, scripts::ReadPrivate::new(path)).await?.is_none());
+    Ok(())
+}
+
+//Signatures a scan makes for its private reads, run with --ignored --nocapture. For 500 slots the
+//first scan signs 1058 times and a second one in the same session not at all
+#[tokio::test]
+#[ignore]
+async fn read_session_cost() -> Result<(), Error> {
+    use crate::dwn::structs::DwnCapabilities;
+
+    let (agent, _, _) = local_agent().await?;
+    let mut cache = CompilerCache::default();
+    let parent = RecordPath::new(&[Uuid::new_v4()])?;
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(parent.clone(), SystemProtocols::root(), b""), None)).await?;
+    let children = (0..500).map(|_| {
+        let record = Record::new(parent.extend(&[Uuid::new_v4()])?, SystemProtocols::usize(), b"1");
+        Ok(scripts::CreatePrivate::new(record, None))
+    }).collect::<Result<Vec<_>, Error>>()?;
+    agent.run_all::<()>(&mut cache, children).await?;
+
+    //A fresh session, the creates already proved the keys they read
+    let mut cache = CompilerCache::default();
+    agent.run::<DwnCapabilities>(&mut cache, scripts::ProbeCapabilities::new()).await?;
+    let mut signatures = Vec::new();
+    for _ in 0..2 {
+        let before = cache.read_signatures();
+        let records = agent.run::<Vec<Record>>(&mut cache, scripts::Scan::new(parent.clone(), 0)).await?;
+        assert_eq!(records.len(), 500);
+        signatures.push(cache.read_signatures()-before);
+    }
+    println!("500 slot scan: {} read signatures, {} when scanned again in the session", signatures[0], signatures[1]);
+    assert_eq!(signatures[1], 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_session() -> Result<(), Error> {
+    use crate::dids::signing::SignedObject;
+    use crate::dwn::structs::{DwnCapabilities, ReadChallenge, SessionRead, READ_SESSION_TTL};
+    use chrono::Utc;
+
+    let (agent, dwns, url) = local_agent().await?;
+    let parent = RecordPath::new(&[Uuid::new_v4()])?;
+    let mut cache = CompilerCache::default();
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(parent.clone(), SystemProtocols::root(), b""), None)).await?;
+    let children = (0..20).map(|_| {
+        let record = Record::new(parent.extend(&[Uuid::new_v4()])?, SystemProtocols::usize(), b"1");
+        Ok(scripts::CreatePrivate::new(record, None))
+    }).collect::<Result<Vec<_>, Error>>()?;
+    agent.run_all::<()>(&mut cache, children).await?;
+
+    //Reads are only signed once per key in a session with a Dwn probed to support them
+    let mut cache = CompilerCache::default();
+    let mut signatures = Vec::new();
+    for scan in 0..4 {
+        if scan == 2 {agent.run::<DwnCapabilities>(&mut cache, scripts::ProbeCapabilities::new()).await?;}
+        let before = cache.read_signatures();
+        let records = agent.run::<Vec<Record>>(&mut cache, scripts::Scan::new(parent.clone(), 0)).await?;
+        assert_eq!(records.len(), 20);
+        signatures.push(cache.read_signatures()-before);
+    }
+    //The first scan also reads the parent's info
+    assert!(signatures[1] > 20);
+    assert_eq!(signatures[1..], [signatures[1], signatures[1], 0]);
+
+    //Only the session's token reads without a proof, and only proofs for this Dwn are taken
+    let dwn = &dwns.dwns[&url];
+    let discover = SecretKey::new();
+    let read = |token: &[u8], dwn_did: Did, proof: bool| SessionRead{
+        discover: discover.public_key(), token: token.to_vec(),
+        proof: proof.then(|| SignedObject::from_key(&discover, ReadChallenge{
+            dwn: dwn_did, session: SessionRead::session(token), expires: Utc::now()+READ_SESSION_TTL
+        }).unwrap())
+    };
+    let accepted = |response: DwnResponse| !response.is_invalid_auth();
+    let dwn_did = dwn.com_key.public.did.clone();
+    assert!(!accepted(dwn.process_request(DwnRequest::ReadPrivateSession(read(b"a", dwn_did.clone(), false))).await?));
+    assert!(!accepted(dwn.process_request(DwnRequest::ReadPrivateSession(read(b"a", agent.tenant().clone(), true))).await?));
+    assert!(accepted(dwn.process_request(DwnRequest::ReadPrivateSession(read(b"a", dwn_did.clone(), true))).await?));
+    assert!(accepted(dwn.process_request(DwnRequest::ReadPrivateSession(read(b"a", dwn_did.clone(), false))).await?));
+    assert!(!accepted(dwn.process_request(DwnRequest::ReadPrivateSession(read(b"b", dwn_did, false))).await?));
+    Ok(())
+}
+
+#[tokio::test]
+async fn count_public() -> Result<(), Error> {
+    use crate::dwn::structs::{DwnCapabilities, PublicRecord};
+    use crate::prelude::Filter;
+    use simple_database::database::IndexBuilder;
+
+    for features in [true, false] {
+        let net = LocalNet::with_dwns(1, |dwn| if features {dwn} else {dwn.with_features(&[])}).await?;
+        let (dwns, url) = (&net.dwns, &net.urls[0]);
+        let agent = net.agent(0).await?;
+        let mut cache = CompilerCache::default();
+
+        let notes = Protocol::new(
+            "notes", true, PermissionOptions::new(true, true, true, None),
+            Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+        )?;
+        let records = (0..5u64).map(|n| Ok(scripts::CreatePublic::new(
+            PublicRecord::new(None, notes.clone(), b"{}", Some(IndexBuilder::build(vec![("n", n)])?))?, None
+        ))).collect::<Result<Vec<_>, Error>>()?;
+        agent.run_all::<()>(&mut cache, records).await?;
+        let protocol = Filters::new(vec![("protocol", Filter::equal(notes.uuid().to_string()))]);
+        let middle = Filters::new(vec![
+            ("protocol", Filter::equal(notes.uuid().to_string())), ("n", Filter::range(1u64, 3u64))
+        ]);
+
+        if features {
+            assert_eq!(agent.run::<usize>(&mut cache, scripts::CountPublic::new(protocol.clone())).await?, 5);
+            assert_eq!(agent.run::<usize>(&mut cache, scripts::CountPublic::new(middle.clone())).await?, 3);
+            assert!(dwns.sent(url).iter().any(|r| matches!(r, DwnRequest::CountPublic(_))));
+        } else {
+            //Unprobed the Dwn is asked and refuses, probed the records are read instead
+            assert!(agent.run::<usize>(&mut cache, scripts::CountPublic::new(protocol.clone())).await.is_err());
+            agent.run::<DwnCapabilities>(&mut cache, scripts::ProbeCapabilities::new()).await?;
+            let asked = dwns.sent(url).iter().filter(|r| matches!(r, DwnRequest::CountPublic(_))).count();
+            assert_eq!(agent.run::<usize>(&mut cache, scripts::CountPublic::new(protocol.clone())).await?, 5);
+            assert_eq!(dwns.sent(url).iter().filter(|r| matches!(r, DwnRequest::CountPublic(_))).count(), asked);
+        }
+        assert_eq!(agent.run::<usize>(&mut cache, scripts::CountPublic::verified(middle)).await?, 3);
+    }
+    Ok(())
+}
+
+async fn blob_round_trip_with(size: usize, chunk_size: usize) -> Result<(), Error> {
+    use crate::test_utils::TestNet;
+    use crate::agent::{BlobWriter, BlobReader};
+    use bitcoin_hashes::{sha256, Hash, HashEngine};
+    use rand::{RngCore, SeedableRng};
+
+    let (mut users, _) = TestNet::new().users(1).build().await?;
+    let alice = users.remove(0);
+
+    //Written in pieces that do not line up with the chunks, nothing holds the whole buffer
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+    let mut written = sha256::Hash::engine();
+    let mut writer = BlobWriter::create(&alice.agent, path.clone()).await?.with_chunk_size(chunk_size);
+    assert!(BlobReader::open(&alice.agent, path.clone()).await.is_err());
+    let mut left = size;
+    while left > 0 {
+        let mut piece = vec![0u8; left.min(chunk_size * 3 / 10 + 1)];
+        rng.fill_bytes(&mut piece);
+        written.input(&piece);
+        writer.write(&piece).await?;
+        left -= piece.len();
+    }
+    let manifest = writer.finish().await?;
+    assert_eq!(manifest.size, size as u64);
+    assert_eq!(manifest.chunks.len(), size.div_ceil(chunk_size));
+    assert!(manifest.chunks.iter().all(|c| c.size <= chunk_size));
+
+    let mut reader = BlobReader::open(&alice.agent, path.clone()).await?;
+    assert_eq!(reader.manifest(), &manifest);
+    let mut read = sha256::Hash::engine();
+    let mut buf = vec![0u8; chunk_size / 4];
+    let mut total = 0;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {break;}
+        read.input(&buf[..n]);
+        total += n;
+    }
+    assert_eq!(total, size);
+    assert_eq!(sha256::Hash::from_engine(read), sha256::Hash::from_engine(written));
+    assert!(BlobReader::open(&alice.agent, RecordPath::new(&[Uuid::new_v4()])?).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn blob_round_trip() -> Result<(), Error> {
+    blob_round_trip_with(128 * 1024 + 123, 16 * 1024).await
+}
+
+//Every envelope around a payload serializes it again as a json array and encrypts it, in a debug build
+//a 1MiB chunk takes over 20 seconds to write so this runs in release
+#[tokio::test]
+#[ignore]
+async fn blob_round_trip_10mb() -> Result<(), Error> {
+    blob_round_trip_with(10 * 1024 * 1024 + 12345, crate::agent::DEFAULT_BLOB_CHUNK).await
+}
+
+#[test]
+fn compression_sniffing() -> Result<(), Error> {
+    use crate::common::Compression;
+    //Items written before compression or without the feature are plain json
+    let plain = serde_json::to_vec(&vec!["redundant"; 1000])?;
+    assert_eq!(Compression::decode(plain.clone())?, plain);
+    assert_eq!(Compression::encode(b"{}".to_vec()), b"{}");
+    assert_eq!(Compression::decode(Compression::encode(plain.clone()))?, plain);
+    assert!(Compression::decode(vec![0, 1, 2, 3]).is_err());
+    Ok(())
+}
+
+#[cfg(feature = "compression")]
+#[tokio::test]
+async fn compressed_payloads() -> Result<(), Error> {
+    use crate::test_utils::TestNet;
+
+    let (mut users, dwns) = TestNet::new().users(1).build().await?;
+    let alice = users.remove(0);
+    let url = url::Url::parse("http://dwn0.test")?;
+    let mut cache = CompilerCache::default();
+    let notes = Protocol::new(
+        "notes", true, PermissionOptions::new(true, true, true, None),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?), None
+    )?;
+    let payload = serde_json::to_vec(&"abcdefgh".repeat(128 * 1024))?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    alice.agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(path.clone(), notes, &payload), None)).await?;
+
+    let stored = dwns.sent(&url).into_iter().find_map(|r| match r {
+        DwnRequest::CreatePrivate(item) => Some(item.inner().payload.len()),
+        _ => None
+    }).unwrap();
+    assert!(stored < payload.len() / 20);
+    let record = alice.agent.run::<Option<Record>>(&mut cache, scripts::ReadPrivate::new(path)).await?.unwrap();
+    assert_eq!(record.payload, payload);
+    Ok(())
+}
+
+fn random_perms(rng: &mut impl rand::Rng) -> Result<PermissionSet, Error> {
+    use crate::agent::structs::PathedKey;
+    let mut perms = PathedKey::roles_with(RecordPath::new(&[Uuid::new_v4()])?, None, |_| Ok(SecretKey::new()))?;
+    if rng.gen() {perms.delete = None;}
+    if rng.gen() {perms.channel = None;}
+    let channel = perms.channel.is_some() && rng.gen();
+    let options = PermissionOptions::new(rng.gen(), rng.gen(), perms.delete.is_some() && rng.gen(), channel.then(||
+        ChannelPermissionOptions::new(rng.gen(), rng.gen())
+    ));
+    perms.subset(&options)
+}
+
+//What validate_permission did before it stopped cloning
+fn validate_permission_cloned(protocol: &Protocol, perms: &PermissionSet) -> Result<(), Error> {
+    let trimmed = protocol.trim_permission(perms.clone());
+    if trimmed != *perms {
+        return Err(Error::validation(&format!("Protocol Restrictions Mismatch for {}", protocol.label())));
+    }
+    trimmed.subset(&protocol.permissions).or(Err(Error::validation("Insuffcient Permission")))?;
+    Ok(())
+}
+
+#[test]
+fn validate_permission_unchanged() -> Result<(), Error> {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1527);
+    let mut protocols = SystemProtocols::all();
+    protocols.push(Protocol::new("plain", false, PermissionOptions::new(false, true, false, None), None, None)?);
+    let (mut valid, mut invalid) = (0, 0);
+    for protocol in &protocols {
+        for _ in 0..200 {
+            let perms = random_perms(&mut rng)?;
+            let expected = validate_permission_cloned(protocol, &perms).is_ok();
+            assert_eq!(protocol.validate_permission(&perms).is_ok(), expected, "{} {:?}", protocol.name, perms.options());
+            if expected {valid += 1} else {invalid += 1}
+        }
+    }
+    assert!(valid > 0 && invalid > 0);
+    Ok(())
+}
+
+//Run with --ignored --nocapture, a read validates the permission of every record it returns
+#[test]
+#[ignore]
+fn validate_permission_cost() -> Result<(), Error> {
+    use crate::agent::structs::PathedKey;
+    let protocol = SystemProtocols::subscribers();
+    let perms = (0..100).map(|_| PathedKey::roles_with(
+        RecordPath::new(&[Uuid::new_v4()])?, Some(&protocol), |_| Ok(SecretKey::new())
+    )?.subset(&protocol.permissions)).collect::<Result<Vec<_>, Error>>()?;
+    let start = std::time::Instant::now();
+    for i in 0..10_000 {validate_permission_cloned(&protocol, std::hint::black_box(&perms[i % 100]))?;}
+    let cloned = start.elapsed();
+    let start = std::time::Instant::now();
+    for i in 0..10_000 {protocol.validate_permission(std::hint::black_box(&perms[i % 100]))?;}
+    let checked = start.elapsed();
+    println!("cloned: {:?} checked: {:?}", cloned, checked);
+    assert!(checked < cloned);
+    Ok(())
+}
+
+#[tokio::test]
+async fn scan_streaming() -> Result<(), Error> {
+    use crate::test_utils::TestNet;
+
+    let (mut users, dwns) = TestNet::new().users(1).build().await?;
+    let alice = users.remove(0);
+    let (agent, mut cache) = (alice.agent, alice.cache);
+    let url = url::Url::parse("http://dwn0.test")?;
+    let rooms = Protocol::new(
+        "rooms_protocol", true,
+        PermissionOptions::new(true, true, true, Some(ChannelPermissionOptions::new(true, true))),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?),
+        Some(ChannelProtocol::new(Some(vec![&SystemProtocols::usize()])))
+    )?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    agent.run::<()>(&mut cache, scripts::CreatePrivate::new(Record::new(path.clone(), rooms, b"{}"), None)).await?;
+    for i in 0..7 {
+        let record = Record::new(path.extend(&[Uuid::new_v4()])?, SystemProtocols::usize(), i.to_string().as_bytes());
+        agent.run::<()>(&mut cache, scripts::CreatePrivate::new(record, None)).await?;
+    }
+    agent.run::<()>(&mut cache, scripts::DeletePrivateChild::new(path.clone(), 3)).await?;
+
+    //Pages resume where the last one stopped, the deleted child is skipped without ending the scan
+    let mut pages = Vec::new();
+    let mut next = Some(0);
+    while let Some(index) = next {
+        let before = dwns.sent(&url).len();
+        let (records, resume) = agent.run::<(Vec<Record>, Option<usize>)>(
+            &mut cache, scripts::Scan::streaming(path.clone(), index, 2)
+        ).await?;
+        let reads = dwns.sent(&url)[before..].iter().filter(|r| matches!(r, DwnRequest::ReadPrivate(_))).count();
+        //Two reads a child, a page reads no further than it needs where a batch would read five
+        assert!(reads <= 6);
+        pages.push((records.into_iter().map(|r| r.payload).collect::<Vec<_>>(), resume));
+        next = resume;
+    }
+    assert_eq!(pages, vec![
+        (vec![b"0".to_vec(), b"1".to_vec()], Some(2)),
+        (vec![b"2".to_vec(), b"4".to_vec()], Some(5)),
+        (vec![b"5".to_vec(), b"6".to_vec()], Some(7)),
+        (vec![], None),
+    ]);
+    assert_eq!(agent.run::<Vec<Record>>(&mut cache, scripts::Scan::new(path, 0)).await?.len(), 6);
+    Ok(())
+}
+
+#[tokio::test]
+async fn agent_session() -> Result<(), Error> {
+    use crate::test_utils::TestNet;
+
+    let (mut users, dwns) = TestNet::new().users(1).build().await?;
+    let agent = users.remove(0).agent;
+    let url = url::Url::parse("http://dwn0.test")?;
+    let reads = || dwns.sent(&url).iter().filter(|r| matches!(r, DwnRequest::ReadPrivate(_) | DwnRequest::ReadPrivateSession(_))).count();
+    let rooms = Protocol::new(
+        "rooms_protocol", true,
+        PermissionOptions::new(true, true, true, Some(ChannelPermissionOptions::new(true, true))),
+        Some(serde_json::to_string(&schemars::schema::Schema::Bool(true))?),
+        Some(ChannelProtocol::new(Some(vec![&SystemProtocols::usize()])))
+    )?;
+    let path = RecordPath::new(&[Uuid::new_v4()])?;
+    let child = |i: usize| -> Result<_, Error> {Ok(scripts::CreatePrivate::new(
+        Record::new(path.extend(&[Uuid::new_v4()])?, SystemProtocols::usize(), i.to_string().as_bytes()), None
+    ))};
+
+    let mut session = agent.session(None);
+    session.run::<()>(scripts::CreatePrivate::new(Record::new(path.clone(), rooms, b"{}"), None)).await?;
+    session.run::<()>(child(0)?).await?;
+    let before = reads();
+    session.run::<()>(child(1)?).await?;
+    let in_session = reads() - before;
+
+    //Another session knows nothing of the first ones indexes and probes for the next one
+    let mut other = agent.session(None);
+    let before = reads();
+    other.run::<()>(child(2)?).await?;
+    let probed = reads() - before;
+    assert!(in_session < probed, "{} {}", in_session, probed);
+    other.close().await?;
+
+    let records = session.run::<Vec<Record>>(scripts::Scan::new(path, 0)).await?;
+    assert_eq!(records.into_iter().map(|r| r.payload).collect::<Vec<_>>(), vec![b"0".to_vec(), b"1".to_vec(), b"2".to_vec()]);
+    session.close().await?;
+    Ok(())
+}
@@ -0,0 +1,19 @@
+//Runs the examples so they keep compiling against the public api, run with
+//cargo test --features test-utils --test examples
+#![cfg(feature = "test-utils")]
+
+#[allow(dead_code)]
+#[path = "../examples/chat.rs"]
+mod chat;
+#[allow(dead_code)]
+#[path = "../examples/fileshare.rs"]
+mod fileshare;
+
+#[test]
+fn examples_compile_and_run() {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let start = std::time::Instant::now();
+    runtime.block_on(chat::run()).unwrap();
+    runtime.block_on(fileshare::run()).unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_secs(10));
+}
@@ -0,0 +1,39 @@
+//Smoke tests for each feature combination, run with
+//cargo test --no-default-features [--features dwn|agent|dwn,agent] --test feature_matrix
+use web5_rust::prelude::*;
+
+use std::str::FromStr;
+
+#[test]
+fn core() {
+    let did = Did::from_str("did:dht:8k8d8p5tm8bdqtz4yma6ru8qg4ombbr7h6ddyabxzryckenjuysy").unwrap();
+    assert_eq!(did.to_string(), "did:dht:8k8d8p5tm8bdqtz4yma6ru8qg4ombbr7h6ddyabxzryckenjuysy");
+    assert!(Did::from_str("not a did").is_err());
+}
+
+#[cfg(feature = "dwn")]
+#[tokio::test]
+async fn dwn() {
+    use web5_rust::dwn::DwnIdentity;
+    use simple_database::MemoryStore;
+
+    let (identity, _) = DwnIdentity::new(vec![]).unwrap();
+    let path = std::env::temp_dir().join(format!("web5-feature-matrix-{}", std::process::id()));
+    let dwn = Dwn::new::<MemoryStore>(identity, Some(path.clone()), None).await.unwrap();
+    assert_eq!(dwn.data_path(), path);
+
+    let protocol = Protocol::new("feature_matrix", true, PermissionOptions::new(true, true, true, None), None, None).unwrap();
+    let record = Record::new(RecordPath::root(), protocol.clone(), b"payload");
+    assert_eq!(record.protocol.uuid(), protocol.uuid());
+    drop(dwn);
+    let _ = std::fs::remove_dir_all(path);
+}
+
+#[cfg(feature = "agent")]
+#[test]
+fn agent() {
+    let (identity, _) = Identity::new(vec![]).unwrap();
+    let wallet = Wallet::new(identity);
+    let _root = wallet.root();
+    assert!(wallet.get_agent_key(RecordPath::root()).is_ok());
+}